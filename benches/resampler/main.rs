@@ -0,0 +1,62 @@
+//! Cost of building and running each resampler quality, next to the resampler they replaced.
+//!
+//! Construction is measured with an empty `FilterCache` each time, since a shared filter is free to reuse.
+
+mod baseline;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use udon::{
+    cycle::Cycle,
+    resampler::{FilterCache, Quality, Resampler},
+    source::{ChannelCount, Sample, SampleRate, Source},
+    Player,
+};
+
+const CHANNELS: u16 = 2;
+const FRAMES: usize = 1024;
+const QUALITIES: [Quality; 4] = [Quality::Linear, Quality::Cubic, Quality::SincMedium, Quality::SincBest];
+const RATES: [(u32, u32); 2] = [(44100, 48000), (48000, 44100)];
+
+fn looping(rate: u32) -> Cycle<Player> {
+    let samples = (0..4096 * usize::from(CHANNELS)).map(|i| (i as Sample * 0.01).sin()).collect::<Vec<_>>();
+    Cycle::new(Player::new(ChannelCount::new(CHANNELS).unwrap(), SampleRate::new(rate).unwrap(), samples.into()))
+}
+
+fn construct(c: &mut Criterion) {
+    let mut group = c.benchmark_group("construct");
+    for &(from, to) in &RATES {
+        let rate = SampleRate::new(to).unwrap();
+        let name = format!("{}-{}", from, to);
+        for &quality in &QUALITIES {
+            group.bench_function(BenchmarkId::new(format!("{:?}", quality), &name), |b| {
+                b.iter(|| Resampler::with_cache(looping(from), rate, quality, &FilterCache::new()))
+            });
+        }
+        group.bench_function(BenchmarkId::new("Baseline", &name), |b| {
+            b.iter(|| baseline::Resampler::new(looping(from), rate))
+        });
+    }
+    group.finish();
+}
+
+fn process(c: &mut Criterion) {
+    let mut group = c.benchmark_group("process");
+    group.throughput(Throughput::Elements(FRAMES as u64));
+    for &(from, to) in &RATES {
+        let rate = SampleRate::new(to).unwrap();
+        let name = format!("{}-{}", from, to);
+        let mut buffer = vec![0.0; FRAMES * usize::from(CHANNELS)];
+        for &quality in &QUALITIES {
+            let mut resampler = Resampler::with_quality(looping(from), rate, quality);
+            group.bench_function(BenchmarkId::new(format!("{:?}", quality), &name), |b| {
+                b.iter(|| resampler.write_samples(&mut buffer))
+            });
+        }
+        let mut resampler = baseline::Resampler::new(looping(from), rate);
+        group.bench_function(BenchmarkId::new("Baseline", &name), |b| b.iter(|| resampler.write_samples(&mut buffer)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, construct, process);
+criterion_main!(benches);
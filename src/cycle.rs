@@ -1,4 +1,8 @@
-use crate::{source::{ChannelCount, SampleRate, Sample, Source}};
+use crate::source::{ChannelCount, ChannelLayout, LoopPoints, Sample, SampleRate, Seekable, Source};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
 /// A Source which endlessly cycles another Source, calling reset() each time it ends.
 ///
@@ -23,6 +27,11 @@ impl<S:Source> Source for Cycle<S> {
         self.0.sample_rate()
     }
 
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.0.channel_layout()
+    }
+
     fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
         let mut written = self.0.write_samples(buffer);
         while written != buffer.len() {
@@ -40,3 +49,188 @@ impl<S:Source> Source for Cycle<S> {
         self.0.reset()
     }
 }
+
+/// A Source which plays a [`Seekable`] Source through to the end of a loop region, then jumps back to the loop's start
+/// with sample accuracy. This suits music with an intro that shouldn't be repeated.
+///
+/// The loop repeats forever, or for a set number of times if it has a count. After that, or once it's been released
+/// through a [`LoopRegionHandle`], playback carries on past the loop's end until the Source ends, so an outro can be
+/// placed after the loop. Resetting the LoopRegion starts again from the very beginning, and undoes any release.
+///
+/// An empty loop, such as the whole of a Source with no frames, never loops, so the Source just plays straight through.
+pub struct LoopRegion<S: Seekable> {
+    source: S,
+    points: Option<LoopPoints>, // None if the loop is empty
+    loops_left: Option<u32>, // How many more times the loop will jump back, or None for forever
+    released: Arc<AtomicBool>,
+}
+
+/// Used for releasing a [`LoopRegion`] while it's playing. Get one with `LoopRegion::handle()`.
+#[derive(Clone)]
+pub struct LoopRegionHandle(Arc<AtomicBool>);
+
+impl<S: Seekable> LoopRegion<S> {
+    /// Creates a new LoopRegion which uses the loop points stored with `source`, such as those in a .wav file's `smpl`
+    /// chunk. If there aren't any, the whole Source loops forever.
+    pub fn new(source: S) -> Self {
+        let points = source.loop_points().unwrap_or(LoopPoints { start: 0, end: source.frame_count(), count: None });
+        Self::with_points(source, points)
+    }
+
+    /// Creates a new LoopRegion with the given loop points.
+    ///
+    /// Panics if the loop ends before it starts, or ends after the end of `source`.
+    pub fn with_points(source: S, points: LoopPoints) -> Self {
+        assert!(points.start <= points.end, "Loop must not end before it starts");
+        assert!(points.end <= source.frame_count(), "Loop must end within the Source");
+        Self {
+            source,
+            points: Some(points).filter(|x| x.start < x.end),
+            loops_left: points.count.map(|x| x.saturating_sub(1)),
+            released: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Returns a handle for releasing this LoopRegion while it's playing.
+    pub fn handle(&self) -> LoopRegionHandle {
+        LoopRegionHandle(self.released.clone())
+    }
+
+    /// Returns the loop points being used, or None if the loop is empty.
+    pub fn loop_points(&self) -> Option<LoopPoints> {
+        self.points
+    }
+}
+
+impl<S: Seekable> Source for LoopRegion<S> {
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.source.channel_count()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.source.channel_layout()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        let channels = usize::from(self.source.channel_count().get());
+        let mut written = 0;
+        loop {
+            // While looping, stop each read at the loop's end, unless it's already been passed
+            let position = self.source.position();
+            let looping = self.loops_left != Some(0) && !self.released.load(Ordering::Acquire);
+            let points = self.points.filter(|_| looping);
+            let mut limit = buffer.len() - written;
+            if let Some(points) = points.filter(|x| position < x.end) {
+                limit = limit.min((points.end - position) * channels);
+            }
+            let count = self.source.write_samples(&mut buffer[written..written + limit]);
+            written += count;
+
+            // Jump back even if the buffer is full, otherwise the next call would carry on past the loop's end
+            if let Some(points) = points.filter(|x| self.source.position() == x.end) {
+                self.source.seek(points.start);
+                self.loops_left = self.loops_left.map(|x| x - 1);
+            } else if count < limit {
+                return written
+            }
+            if written == buffer.len() {
+                return written
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.loops_left = self.points.and_then(|x| x.count).map(|x| x.saturating_sub(1));
+        self.released.store(false, Ordering::Release);
+    }
+}
+
+impl LoopRegionHandle {
+    /// Stops looping, so playback carries on past the loop's end the next time it gets there.
+    pub fn release(&self) {
+        self.0.store(true, Ordering::Release)
+    }
+
+    /// Returns whether the LoopRegion has been released since it was created or last reset.
+    pub fn is_released(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Player;
+
+    fn player(frames: usize) -> Player {
+        let samples = (0..frames).map(|x| x as Sample).collect();
+        Player::new(ChannelCount::new(1).unwrap(), SampleRate::new(44100).unwrap(), samples)
+    }
+
+    fn play(source: &mut impl Source, buffer_len: usize) -> Vec<Sample> {
+        let mut output = Vec::new();
+        let mut buffer = vec![0.0; buffer_len];
+        loop {
+            let count = source.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                return output
+            }
+        }
+    }
+
+    #[test]
+    fn loops_count_times() {
+        // Each read is longer than the loop, so it has to jump back more than once per call
+        let mut region = LoopRegion::with_points(player(6), LoopPoints { start: 1, end: 3, count: Some(3) });
+        assert_eq!(play(&mut region, 5), [0.0, 1.0, 2.0, 1.0, 2.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+
+        region.reset();
+        assert_eq!(play(&mut region, 1), [0.0, 1.0, 2.0, 1.0, 2.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn release_plays_through() {
+        let mut region = LoopRegion::with_points(player(6), LoopPoints { start: 2, end: 4, count: None });
+        let handle = region.handle();
+        let mut buffer = [0.0; 7];
+        assert_eq!(region.write_samples(&mut buffer), 7);
+        assert_eq!(buffer, [0.0, 1.0, 2.0, 3.0, 2.0, 3.0, 2.0]);
+
+        handle.release();
+        assert!(handle.is_released());
+        assert_eq!(play(&mut region, 4), [3.0, 4.0, 5.0]);
+
+        region.reset();
+        assert!(!handle.is_released(), "reset should undo the release");
+        assert_eq!(region.write_samples(&mut buffer), 7);
+        assert_eq!(buffer, [0.0, 1.0, 2.0, 3.0, 2.0, 3.0, 2.0]);
+    }
+
+    #[test]
+    fn whole_source_loops_by_default() {
+        let mut region = LoopRegion::new(player(3));
+        let mut buffer = [0.0; 8];
+        assert_eq!(region.write_samples(&mut buffer), 8);
+        assert_eq!(buffer, [0.0, 1.0, 2.0, 0.0, 1.0, 2.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn empty_loop_plays_through() {
+        let mut region = LoopRegion::new(player(0));
+        assert_eq!(region.loop_points(), None);
+        assert_eq!(play(&mut region, 4), []);
+
+        let mut region = LoopRegion::with_points(player(4), LoopPoints { start: 2, end: 2, count: None });
+        assert_eq!(region.loop_points(), None);
+        assert_eq!(play(&mut region, 3), [0.0, 1.0, 2.0, 3.0]);
+    }
+}
@@ -0,0 +1,42 @@
+//! Effects which process the output of another Source.
+//!
+//! Each effect wraps a Source and is a Source itself, so they can be chained together and fed into a `Mixer`,
+//! `OutputStream` or anything else that expects one. Effects with parameters can have them changed while playing
+//! through a handle, which can be sent to another thread.
+
+mod biquad;
+mod bitcrusher;
+mod compressor;
+mod convolution;
+mod delay;
+mod distortion;
+mod envelope;
+mod equalizer;
+mod gain;
+mod gargle;
+mod modulation;
+mod oversample;
+mod pan;
+mod pitch;
+mod reverb;
+mod stretch;
+mod tail;
+
+pub use biquad::{Biquad, BiquadHandle, FilterType};
+pub use bitcrusher::{Bitcrusher, BitcrusherHandle};
+pub use compressor::{Compressor, CompressorHandle, Detection, SidechainKey, SidechainTap};
+pub use convolution::{ConvolutionReverb, ConvolutionReverbHandle, ImpulseResponse};
+pub use delay::{Delay, DelayHandle};
+pub use distortion::{Distortion, DistortionHandle, DistortionShape};
+pub use envelope::{Envelope, EnvelopeCurve, EnvelopeHandle};
+pub use equalizer::{EqBand, Equalizer, EqualizerHandle};
+pub use gain::{Gain, GainHandle};
+pub use gargle::{Gargle, GargleHandle, GargleWaveform};
+pub use modulation::{Chorus, Flanger, ModulationHandle, ModulationWaveform};
+pub use pan::{Pan, PanHandle, PanLaw};
+pub use pitch::{PitchShift, PitchShiftHandle};
+pub use reverb::{Reverb, ReverbHandle};
+pub use stretch::{TimeStretch, TimeStretchHandle};
+
+pub(crate) use convolution::read_all;
+pub(crate) use delay::DelayLine;
@@ -0,0 +1,424 @@
+use crate::{
+    param::{AtomicF32, Smoothed, SMOOTHING_TIME},
+    source::{ChannelCount, ChannelLayout, Sample, SampleRate, Source},
+};
+use std::{f32::consts::PI, sync::Arc};
+
+// While parameters are ramping, coefficients are recalculated every this many frames
+const UPDATE_INTERVAL: u32 = 16;
+
+/// The response shape of a [`Biquad`] filter. Coefficients follow Robert Bristow-Johnson's "Audio EQ Cookbook".
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub enum FilterType {
+    /// Passes frequencies below the cutoff. Gain is ignored.
+    LowPass,
+
+    /// Passes frequencies above the cutoff. Gain is ignored.
+    HighPass,
+
+    /// Passes frequencies around the centre frequency, with a peak gain of 0 dB. Gain is ignored.
+    BandPass,
+
+    /// Rejects frequencies around the centre frequency. Gain is ignored.
+    Notch,
+
+    /// Boosts or cuts frequencies below the corner frequency by the gain.
+    LowShelf,
+
+    /// Boosts or cuts frequencies above the corner frequency by the gain.
+    HighShelf,
+
+    /// Boosts or cuts frequencies around the centre frequency by the gain.
+    Peaking,
+}
+
+/// A two-pole, two-zero IIR filter applied to each channel of a Source independently.
+///
+/// Frequency, Q and gain can be changed while the filter is playing through a [`BiquadHandle`].
+/// Changes are ramped in over a short time, so sweeping them won't cause clicks or zipper noise.
+///
+/// For shelf filters, a Q of `FRAC_1_SQRT_2` gives the steepest slope without a bump at the corner frequency.
+pub struct Biquad<S>
+where
+    S: Source,
+{
+    source: S,
+    filter_type: FilterType,
+    params: Arc<BiquadParams>,
+    frequency: Smoothed, // Stored as a natural log, so sweeps sound even across the spectrum
+    q: Smoothed,
+    gain: Smoothed,
+    coefficients: Coefficients,
+    state: Box<[State]>,
+    channel: usize, // Which channel the next sample belongs to, since buffers don't have to end on a whole frame
+    update_countdown: u32,
+}
+
+/// Used for changing the parameters of a [`Biquad`] while it's playing. Get one with `Biquad::handle()`.
+#[derive(Clone)]
+pub struct BiquadHandle(Arc<BiquadParams>);
+
+struct BiquadParams {
+    frequency: AtomicF32,
+    q: AtomicF32,
+    gain: AtomicF32,
+}
+
+impl<S> Biquad<S>
+where
+    S: Source,
+{
+    /// Creates a new Biquad filter. `frequency` is in Hz and `gain` is in dB.
+    pub fn new(source: S, filter_type: FilterType, frequency: f32, q: f32, gain: f32) -> Self {
+        let channels = usize::from(source.channel_count().get());
+        let sample_rate = u32::from(source.sample_rate()) as f32;
+        let frequency = clamp_frequency(frequency, sample_rate);
+        let q = clamp_q(q);
+        Self {
+            filter_type,
+            params: Arc::new(BiquadParams {
+                frequency: AtomicF32::new(frequency),
+                q: AtomicF32::new(q),
+                gain: AtomicF32::new(gain),
+            }),
+            frequency: Smoothed::new(frequency.ln()),
+            q: Smoothed::new(q),
+            gain: Smoothed::new(gain),
+            coefficients: Coefficients::new(filter_type, frequency, q, gain, sample_rate),
+            state: vec![State::default(); channels].into_boxed_slice(),
+            channel: 0,
+            update_countdown: UPDATE_INTERVAL,
+            source,
+        }
+    }
+
+    /// Creates a low-pass filter with the given cutoff frequency in Hz.
+    pub fn low_pass(source: S, frequency: f32, q: f32) -> Self {
+        Self::new(source, FilterType::LowPass, frequency, q, 0.0)
+    }
+
+    /// Creates a high-pass filter with the given cutoff frequency in Hz.
+    pub fn high_pass(source: S, frequency: f32, q: f32) -> Self {
+        Self::new(source, FilterType::HighPass, frequency, q, 0.0)
+    }
+
+    /// Creates a band-pass filter with the given centre frequency in Hz.
+    pub fn band_pass(source: S, frequency: f32, q: f32) -> Self {
+        Self::new(source, FilterType::BandPass, frequency, q, 0.0)
+    }
+
+    /// Creates a notch filter with the given centre frequency in Hz.
+    pub fn notch(source: S, frequency: f32, q: f32) -> Self {
+        Self::new(source, FilterType::Notch, frequency, q, 0.0)
+    }
+
+    /// Creates a low shelf filter with the given corner frequency in Hz and gain in dB.
+    pub fn low_shelf(source: S, frequency: f32, q: f32, gain: f32) -> Self {
+        Self::new(source, FilterType::LowShelf, frequency, q, gain)
+    }
+
+    /// Creates a high shelf filter with the given corner frequency in Hz and gain in dB.
+    pub fn high_shelf(source: S, frequency: f32, q: f32, gain: f32) -> Self {
+        Self::new(source, FilterType::HighShelf, frequency, q, gain)
+    }
+
+    /// Creates a peaking EQ filter with the given centre frequency in Hz and gain in dB.
+    pub fn peaking(source: S, frequency: f32, q: f32, gain: f32) -> Self {
+        Self::new(source, FilterType::Peaking, frequency, q, gain)
+    }
+
+    /// Returns a handle for changing this filter's parameters while it's playing.
+    pub fn handle(&self) -> BiquadHandle {
+        BiquadHandle(self.params.clone())
+    }
+
+    /// Returns the type of this filter.
+    pub fn filter_type(&self) -> FilterType {
+        self.filter_type
+    }
+
+    #[inline]
+    fn is_ramping(&self) -> bool {
+        !(self.frequency.is_settled() && self.q.is_settled() && self.gain.is_settled())
+    }
+}
+
+impl<S> Source for Biquad<S>
+where
+    S: Source,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.source.channel_count()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.source.channel_layout()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        let count = self.source.write_samples(buffer);
+        let sample_rate = u32::from(self.source.sample_rate()) as f32;
+        let steps = (SMOOTHING_TIME * sample_rate) as u32;
+
+        self.frequency.set_target(clamp_frequency(self.params.frequency.load(), sample_rate).ln(), steps);
+        self.q.set_target(clamp_q(self.params.q.load()), steps);
+        self.gain.set_target(self.params.gain.load(), steps);
+
+        for sample in buffer[..count].iter_mut() {
+            // Parameters step once per frame, at its first channel
+            if self.channel == 0 && self.is_ramping() {
+                let (frequency, q, gain) = (self.frequency.next(), self.q.next(), self.gain.next());
+                self.update_countdown = self.update_countdown.saturating_sub(1);
+                if self.update_countdown == 0 || !self.is_ramping() {
+                    self.coefficients = Coefficients::new(self.filter_type, frequency.exp(), q, gain, sample_rate);
+                    self.update_countdown = UPDATE_INTERVAL;
+                }
+            }
+
+            *sample = self.state[self.channel].process(&self.coefficients, *sample);
+            self.channel = (self.channel + 1) % self.state.len();
+        }
+
+        count
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.state.iter_mut().for_each(|x| *x = State::default());
+        self.channel = 0;
+        if self.is_ramping() {
+            self.frequency.settle();
+            self.q.settle();
+            self.gain.settle();
+            let sample_rate = u32::from(self.source.sample_rate()) as f32;
+            let (frequency, q, gain) = (self.frequency.next().exp(), self.q.next(), self.gain.next());
+            self.coefficients = Coefficients::new(self.filter_type, frequency, q, gain, sample_rate);
+        }
+    }
+}
+
+impl BiquadHandle {
+    /// Sets the cutoff, corner or centre frequency in Hz, depending on the filter type.
+    pub fn set_frequency(&self, frequency: f32) {
+        self.0.frequency.store(frequency)
+    }
+
+    /// Sets the Q, or resonance. Higher values give a narrower band or a sharper peak at the cutoff.
+    pub fn set_q(&self, q: f32) {
+        self.0.q.store(q)
+    }
+
+    /// Sets the gain in dB. Only affects shelf and peaking filters.
+    pub fn set_gain(&self, gain: f32) {
+        self.0.gain.store(gain)
+    }
+
+    /// Returns the most recently set frequency in Hz. The filter may still be ramping towards it.
+    pub fn frequency(&self) -> f32 {
+        self.0.frequency.load()
+    }
+
+    /// Returns the most recently set Q.
+    pub fn q(&self) -> f32 {
+        self.0.q.load()
+    }
+
+    /// Returns the most recently set gain in dB.
+    pub fn gain(&self) -> f32 {
+        self.0.gain.load()
+    }
+}
+
+#[inline]
+pub(crate) fn clamp_frequency(frequency: f32, sample_rate: f32) -> f32 {
+    frequency.max(1.0).min(sample_rate * 0.499)
+}
+
+#[inline]
+fn clamp_q(q: f32) -> f32 {
+    q.max(0.01)
+}
+
+/// Normalised biquad coefficients, with a0 divided out.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Coefficients {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Coefficients {
+    /// Calculates coefficients for the given filter. `frequency` and `sample_rate` are in Hz and `gain` is in dB.
+    pub(crate) fn new(filter_type: FilterType, frequency: f32, q: f32, gain: f32, sample_rate: f32) -> Self {
+        let a = 10.0f32.powf(gain / 40.0);
+        let w0 = 2.0 * PI * frequency / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+        let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match filter_type {
+            FilterType::LowPass => {
+                ((1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha)
+            },
+            FilterType::HighPass => {
+                ((1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha)
+            },
+            FilterType::BandPass => (alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterType::Notch => (1.0, -2.0 * cos, 1.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterType::LowShelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha),
+                (a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha,
+            ),
+            FilterType::HighShelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha),
+                (a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha,
+            ),
+            FilterType::Peaking => {
+                (1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a, 1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a)
+            },
+        };
+
+        Self { b0: b0 / a0, b1: b1 / a0, b2: b2 / a0, a1: a1 / a0, a2: a2 / a0 }
+    }
+}
+
+/// Per-channel filter history, for processing in Direct Form I.
+/// This form copes better than the transposed forms with coefficients changing between samples.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct State {
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl State {
+    #[inline]
+    pub(crate) fn process(&mut self, c: &Coefficients, x: f32) -> f32 {
+        let y = c.b0 * x + c.b1 * self.x1 + c.b2 * self.x2 - c.a1 * self.y1 - c.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Player;
+    use std::f32::consts::FRAC_1_SQRT_2;
+
+    const SAMPLE_RATE: f32 = 48000.0;
+
+    // Returns |H(e^jw)| for a frequency in Hz
+    fn response(c: &Coefficients, frequency: f32) -> f64 {
+        let w = 2.0 * std::f64::consts::PI * f64::from(frequency) / f64::from(SAMPLE_RATE);
+        let [b0, b1, b2, a1, a2] = [c.b0, c.b1, c.b2, c.a1, c.a2].map(f64::from);
+        let (cos1, sin1, cos2, sin2) = (w.cos(), w.sin(), (2.0 * w).cos(), (2.0 * w).sin());
+        let numerator = (b0 + b1 * cos1 + b2 * cos2).hypot(b1 * sin1 + b2 * sin2);
+        let denominator = (1.0 + a1 * cos1 + a2 * cos2).hypot(a1 * sin1 + a2 * sin2);
+        numerator / denominator
+    }
+
+    #[test]
+    fn magnitude_responses() {
+        let (f0, q, gain) = (1000.0, 2.0, 12.0);
+        let a = 10.0f64.powf(gain / 40.0);
+        let nyquist = SAMPLE_RATE * 0.5;
+
+        // (filter type, expected magnitude at DC, at f0 and at Nyquist), from the cookbook's transfer functions
+        let cases = [
+            (FilterType::LowPass, 1.0, f64::from(q), 0.0),
+            (FilterType::HighPass, 0.0, f64::from(q), 1.0),
+            (FilterType::BandPass, 0.0, 1.0, 0.0),
+            (FilterType::Notch, 1.0, 0.0, 1.0),
+            (FilterType::Peaking, 1.0, a * a, 1.0),
+            (FilterType::LowShelf, a * a, a, 1.0),
+            (FilterType::HighShelf, 1.0, a, a * a),
+        ];
+        for &(filter_type, dc, centre, top) in &cases {
+            let c = Coefficients::new(filter_type, f0, q, gain as f32, SAMPLE_RATE);
+            for &(frequency, expected) in &[(0.0, dc), (f0, centre), (nyquist, top)] {
+                let magnitude = response(&c, frequency);
+                assert!(
+                    (magnitude - expected).abs() < 1e-3 * expected.max(1.0),
+                    "{:?} at {} Hz was {}, not {}",
+                    filter_type,
+                    frequency,
+                    magnitude,
+                    expected,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stopbands_roll_off() {
+        // Two poles give 12 dB per octave, so two octaves past the cutoff is around -24 dB
+        let low_pass = Coefficients::new(FilterType::LowPass, 1000.0, FRAC_1_SQRT_2, 0.0, SAMPLE_RATE);
+        let high_pass = Coefficients::new(FilterType::HighPass, 1000.0, FRAC_1_SQRT_2, 0.0, SAMPLE_RATE);
+        assert!((response(&low_pass, 1000.0) - f64::from(FRAC_1_SQRT_2)).abs() < 1e-4);
+        assert!(20.0 * response(&low_pass, 4000.0).log10() < -23.0);
+        assert!(20.0 * response(&high_pass, 250.0).log10() < -23.0);
+        assert!((response(&low_pass, 100.0) - 1.0).abs() < 1e-3);
+        assert!((response(&high_pass, 10000.0) - 1.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn filters_a_sine() {
+        // A peaking filter should scale a sine at its centre frequency by its gain
+        let samples = (0..48000).map(|i| (2.0 * PI * 1000.0 * i as f32 / SAMPLE_RATE).sin() * 0.25).collect();
+        let player = Player::new(ChannelCount::new(1).unwrap(), SampleRate::new(48000).unwrap(), samples);
+        let mut biquad = Biquad::peaking(player, 1000.0, 1.0, 6.0);
+        let mut buffer = vec![0.0; 48000];
+        assert_eq!(biquad.write_samples(&mut buffer), 48000);
+        let peak = buffer[24000..].iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+        assert!((peak / 0.25 - 10.0f32.powf(6.0 / 20.0)).abs() < 0.01, "peak was {}", peak);
+    }
+
+    #[test]
+    fn odd_buffers_keep_channels_apart() {
+        // Each channel must keep its own history, however the buffers split the frames
+        let samples = (0..3000).map(|i| if i % 3 == 0 { (i as f32 * 0.1).sin() } else { 0.0 }).collect::<Vec<_>>();
+        let (channels, sample_rate) = (ChannelCount::new(3).unwrap(), SampleRate::new(48000).unwrap());
+        let player = || Player::new(channels, sample_rate, samples.clone().into());
+        let mut expected = vec![0.0; 3000];
+        let mut biquad = Biquad::low_pass(player(), 2000.0, 1.0);
+        biquad.handle().set_frequency(500.0);
+        assert_eq!(biquad.write_samples(&mut expected), 3000);
+
+        let mut biquad = Biquad::low_pass(player(), 2000.0, 1.0);
+        biquad.handle().set_frequency(500.0);
+        let mut output = Vec::new();
+        for length in [7, 1, 2, 5].iter().cycle() {
+            let mut buffer = vec![0.0; *length];
+            let count = biquad.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(output, expected);
+        assert!(output.chunks_exact(3).all(|x| x[1] == 0.0 && x[2] == 0.0));
+    }
+}
@@ -0,0 +1,187 @@
+use crate::{
+    param::AtomicF32,
+    source::{ChannelCount, ChannelLayout, Sample, SampleRate, Source},
+};
+use std::sync::Arc;
+
+/// A lo-fi effect which reduces the bit depth and sample rate of a Source.
+///
+/// The bit depth is reduced by rounding each sample to the nearest of a smaller set of levels. The sample rate is
+/// reduced by holding each sample for several frames, without filtering, so it aliases the same way old hardware did.
+/// Neither of these changes the actual `sample_rate()` of the Source.
+pub struct Bitcrusher<S>
+where
+    S: Source,
+{
+    source: S,
+    params: Arc<BitcrusherParams>,
+    held: Box<[Sample]>,
+    phase: f32,     // How far through the current held frame, from 0.0 to 1.0
+    channel: usize, // Which channel the next sample belongs to, since buffers don't have to end on a whole frame
+    holding: bool,  // Whether the current frame is being held rather than sampled
+}
+
+/// Used for changing the parameters of a [`Bitcrusher`] while it's playing. Get one with `Bitcrusher::handle()`.
+#[derive(Clone)]
+pub struct BitcrusherHandle(Arc<BitcrusherParams>);
+
+struct BitcrusherParams {
+    bits: AtomicF32,
+    rate: AtomicF32,
+}
+
+impl<S> Bitcrusher<S>
+where
+    S: Source,
+{
+    /// Creates a new Bitcrusher.
+    ///
+    /// - `bits` is the bit depth to reduce to, from 1.0 to 24.0. Fractional values are allowed.
+    /// - `rate` is the sample rate in Hz to reduce to. Anything at or above the Source's sample rate has no effect.
+    pub fn new(source: S, bits: f32, rate: f32) -> Self {
+        let channels = usize::from(source.channel_count().get());
+        Self {
+            source,
+            params: Arc::new(BitcrusherParams { bits: AtomicF32::new(bits), rate: AtomicF32::new(rate) }),
+            held: vec![0.0; channels].into_boxed_slice(),
+            phase: 1.0,
+            channel: 0,
+            holding: false,
+        }
+    }
+
+    /// Returns a handle for changing this Bitcrusher's parameters while it's playing.
+    pub fn handle(&self) -> BitcrusherHandle {
+        BitcrusherHandle(self.params.clone())
+    }
+}
+
+impl<S> Source for Bitcrusher<S>
+where
+    S: Source,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.source.channel_count()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.source.channel_layout()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        let count = self.source.write_samples(buffer);
+        let sample_rate = u32::from(self.source.sample_rate()) as f32;
+        let levels = 2.0f32.powf(self.params.bits.load().clamp(1.0, 24.0) - 1.0);
+        let increment = (self.params.rate.load() / sample_rate).clamp(0.0, 1.0);
+
+        for sample in buffer[..count].iter_mut() {
+            if self.channel == 0 {
+                self.holding = self.phase < 1.0;
+                if !self.holding {
+                    self.phase -= 1.0;
+                }
+                self.phase += increment;
+            }
+            let held = &mut self.held[self.channel];
+            if !self.holding {
+                *held = (*sample * levels).round() / levels;
+            }
+            *sample = *held;
+            self.channel = (self.channel + 1) % self.held.len();
+        }
+
+        count
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.phase = 1.0;
+        self.channel = 0;
+    }
+}
+
+impl BitcrusherHandle {
+    /// Sets the bit depth to reduce to, from 1.0 to 24.0. Fractional values are allowed.
+    pub fn set_bits(&self, bits: f32) {
+        self.0.bits.store(bits)
+    }
+
+    /// Sets the sample rate in Hz to reduce to. Anything at or above the Source's sample rate has no effect.
+    pub fn set_rate(&self, rate: f32) {
+        self.0.rate.store(rate)
+    }
+
+    /// Returns the most recently set bit depth.
+    pub fn bits(&self) -> f32 {
+        self.0.bits.load()
+    }
+
+    /// Returns the most recently set sample rate in Hz.
+    pub fn rate(&self) -> f32 {
+        self.0.rate.load()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Player;
+
+    fn crush(samples: Vec<Sample>, channels: u16, bits: f32, rate: f32) -> Vec<Sample> {
+        let len = samples.len();
+        let player = Player::new(ChannelCount::new(channels).unwrap(), SampleRate::new(1000).unwrap(), samples.into());
+        let mut bitcrusher = Bitcrusher::new(player, bits, rate);
+        let mut buffer = vec![0.0; len + channels as usize];
+        assert_eq!(bitcrusher.write_samples(&mut buffer), len);
+        assert_eq!(bitcrusher.write_samples(&mut buffer), 0);
+        buffer.truncate(len);
+        buffer
+    }
+
+    #[test]
+    fn reduces_bit_depth() {
+        // 2 bits gives levels every 0.5
+        let output = crush(vec![0.1, 0.3, -0.2, -0.3, 0.9, -1.0], 1, 2.0, 1000.0);
+        assert_eq!(output, [0.0, 0.5, 0.0, -0.5, 1.0, -1.0]);
+    }
+
+    #[test]
+    fn reduces_sample_rate() {
+        // A quarter of the rate holds each frame for four frames
+        let input = (0..16).map(|x| x as Sample / 64.0).collect::<Vec<_>>();
+        let output = crush(input.clone(), 2, 24.0, 250.0);
+        for (i, frame) in output.chunks_exact(2).enumerate() {
+            let held = &input[i / 4 * 8..][..2];
+            assert!((frame[0] - held[0]).abs() < 1e-6 && (frame[1] - held[1]).abs() < 1e-6, "{}: {:?}", i, frame);
+        }
+    }
+
+    #[test]
+    fn full_rate_passes_through() {
+        let input = (0..32).map(|x| (x as Sample * 0.3).sin()).collect::<Vec<_>>();
+        let output = crush(input.clone(), 1, 24.0, 48000.0);
+        assert!(input.iter().zip(&output).all(|(x, y)| (x - y).abs() < 1e-6));
+    }
+
+    #[test]
+    fn odd_buffers_keep_channels_apart() {
+        let input = (0..64).map(|x| if x % 2 == 0 { x as Sample / 64.0 } else { -1.0 }).collect::<Vec<_>>();
+        let expected = crush(input.clone(), 2, 4.0, 300.0);
+        let player = Player::new(ChannelCount::new(2).unwrap(), SampleRate::new(1000).unwrap(), input.into());
+        let mut bitcrusher = Bitcrusher::new(player, 4.0, 300.0);
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 3];
+        while output.len() < expected.len() {
+            let count = bitcrusher.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+        }
+        assert_eq!(output, expected);
+    }
+}
@@ -0,0 +1,529 @@
+use super::{delay::DelayLine, tail::Tail};
+use crate::{
+    frame::PartialFrame,
+    param::{AtomicF32, Smoothed, SMOOTHING_TIME},
+    source::{db_to_linear, linear_to_db, ChannelCount, ChannelLayout, Sample, SampleRate, Source},
+};
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    Arc,
+};
+
+// The longest pre-delay that can be set, in seconds, which is the same as DirectSound's
+const MAX_PRE_DELAY: f32 = 0.004;
+
+// The averaging time used by RMS detection, in seconds
+const RMS_TIME: f32 = 0.01;
+
+// Levels are floored to this (-120 dB) before converting to dB, so silence doesn't give negative infinity
+const MIN_LEVEL: f32 = 0.000001;
+
+/// How a [`Compressor`] measures the level of its input.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub enum Detection {
+    /// Reacts to the loudest sample in each frame. This catches short transients.
+    Peak,
+
+    /// Reacts to the average power over a short window, which is closer to how loud a sound is perceived to be.
+    Rms,
+}
+
+/// A dynamic range compressor, which turns down the volume of a Source when it gets louder than a threshold.
+///
+/// The level can be detected from a separate sidechain instead of the input, so that the input gets turned down
+/// whenever the sidechain is loud. For example, music can duck under dialogue by playing the dialogue (or a Mixer
+/// it's played through) through a [`SidechainTap`], and keying the music's Compressor with its [`SidechainKey`].
+///
+/// The default parameters match DirectSound's compressor. Once the inner Source ends, the Compressor keeps playing
+/// until its pre-delay has emptied out.
+pub struct Compressor<S>
+where
+    S: Source,
+{
+    source: S,
+    sidechain: Option<Box<dyn Source + Send + 'static>>,
+    sidechain_buffer: Vec<Sample>,
+    key: Option<SidechainKey>,
+    gains: Vec<f32>,
+    params: Arc<CompressorParams>,
+    lines: Box<[DelayLine]>,
+    makeup: Smoothed,
+    power: f32,     // Smoothed mean square for RMS detection
+    reduction: f32, // Current gain reduction in dB, which is never positive
+    tail: Tail,
+    partial: PartialFrame,
+}
+
+/// Used for changing the parameters of a [`Compressor`] while it's playing. Get one with `Compressor::handle()`.
+#[derive(Clone)]
+pub struct CompressorHandle(Arc<CompressorParams>);
+
+/// A Source which plays another Source unchanged, while measuring its level for keying a [`Compressor`].
+///
+/// Unlike a sidechain Source passed to `Compressor::with_sidechain()`, a SidechainTap is still heard, so it suits
+/// ducking music under dialogue. The level is measured over each call to `write_samples`, so the Compressor reacts
+/// to it up to one block late, and the tap should be played alongside the Compressor, such as on the same Mixer.
+/// Once the tap ends, is reset, or is dropped, its level drops to silence.
+pub struct SidechainTap<S>
+where
+    S: Source,
+{
+    source: S,
+    level: Arc<SidechainLevel>,
+}
+
+/// The level of a [`SidechainTap`], for keying a Compressor with `Compressor::with_sidechain_key()`. Get one with
+/// `SidechainTap::key()`.
+#[derive(Clone)]
+pub struct SidechainKey(Arc<SidechainLevel>);
+
+struct SidechainLevel {
+    peak: AtomicF32,  // The loudest sample in the last block
+    power: AtomicF32, // The mean square of the last block
+}
+
+struct CompressorParams {
+    threshold: AtomicF32,
+    ratio: AtomicF32,
+    knee: AtomicF32,
+    attack: AtomicF32,
+    release: AtomicF32,
+    makeup: AtomicF32,
+    pre_delay: AtomicF32,
+    detection: AtomicU8,
+}
+
+impl<S> Compressor<S>
+where
+    S: Source,
+{
+    /// Creates a new Compressor with DirectSound's default parameters. They can be changed with a handle.
+    pub fn new(source: S) -> Self {
+        let channels = usize::from(source.channel_count().get());
+        let sample_rate = u32::from(source.sample_rate()) as f32;
+        let max_pre_delay = (MAX_PRE_DELAY * sample_rate).ceil() as usize + 1;
+        Self {
+            source,
+            sidechain: None,
+            sidechain_buffer: Vec::new(),
+            key: None,
+            gains: Vec::new(),
+            params: Arc::new(CompressorParams {
+                threshold: AtomicF32::new(-20.0),
+                ratio: AtomicF32::new(3.0),
+                knee: AtomicF32::new(0.0),
+                attack: AtomicF32::new(0.01),
+                release: AtomicF32::new(0.2),
+                makeup: AtomicF32::new(0.0),
+                pre_delay: AtomicF32::new(0.004),
+                detection: AtomicU8::new(Detection::Peak as u8),
+            }),
+            lines: (0..channels).map(|_| DelayLine::new(max_pre_delay)).collect(),
+            makeup: Smoothed::new(1.0),
+            power: 0.0,
+            reduction: 0.0,
+            tail: Tail::new(max_pre_delay),
+            partial: PartialFrame::new(channels),
+        }
+    }
+
+    /// Creates a new Compressor which detects the level of `sidechain` instead of `source`.
+    ///
+    /// The sidechain should have the same sample rate as `source`, but can have any number of channels.
+    /// Once it ends, it's treated as silence. The Compressor plays the sidechain itself and throws its output away,
+    /// so it isn't heard. To key from a Source which should still be heard, use `with_sidechain_key()` instead.
+    pub fn with_sidechain(source: S, sidechain: impl Source + Send + 'static) -> Self {
+        Self { sidechain: Some(Box::new(sidechain)), ..Self::new(source) }
+    }
+
+    /// Creates a new Compressor which detects the level of a [`SidechainTap`] instead of `source`.
+    pub fn with_sidechain_key(source: S, key: SidechainKey) -> Self {
+        Self { key: Some(key), ..Self::new(source) }
+    }
+
+    /// Returns a handle for changing this Compressor's parameters while it's playing.
+    pub fn handle(&self) -> CompressorHandle {
+        CompressorHandle(self.params.clone())
+    }
+
+    /// Returns the current gain reduction in dB, which is 0.0 or below.
+    pub fn gain_reduction(&self) -> f32 {
+        self.reduction
+    }
+
+    fn write_frames(&mut self, buffer: &mut [Sample]) -> usize {
+        if self.tail.read(&mut self.source, buffer).is_none() {
+            return 0
+        }
+
+        let channels = self.lines.len();
+        let frames = buffer.len() / channels;
+        let sample_rate = u32::from(self.source.sample_rate()) as f32;
+        let threshold = self.params.threshold.load().min(0.0);
+        let slope = 1.0 / self.params.ratio.load().max(1.0) - 1.0;
+        let knee = self.params.knee.load().max(0.0);
+        let attack = time_coefficient(self.params.attack.load(), sample_rate);
+        let release = time_coefficient(self.params.release.load(), sample_rate);
+        let rms = time_coefficient(RMS_TIME, sample_rate);
+        let detection = Detection::from_u8(self.params.detection.load(Ordering::Acquire));
+        let max_pre_delay = self.lines[0].max_delay() as f32 - 1.0;
+        let pre_delay = (self.params.pre_delay.load() * sample_rate).clamp(0.0, max_pre_delay).round() as usize;
+        let makeup = db_to_linear(self.params.makeup.load());
+        self.makeup.set_target(makeup, (SMOOTHING_TIME * sample_rate) as u32);
+
+        if let Some(sidechain) = self.sidechain.as_mut() {
+            let sidechain_channels = usize::from(sidechain.channel_count().get());
+            self.sidechain_buffer.resize(frames * sidechain_channels, 0.0);
+            let count = sidechain.write_samples(&mut self.sidechain_buffer);
+            self.sidechain_buffer[count..].iter_mut().for_each(|x| *x = 0.0);
+        }
+        let (detector, detector_channels) = match self.sidechain.as_ref() {
+            Some(sidechain) => (&self.sidechain_buffer[..], usize::from(sidechain.channel_count().get())),
+            None => (&buffer[..], channels),
+        };
+        let key = self.key.as_ref().map(|x| (x.0.peak.load(), x.0.power.load()));
+
+        // Work out the gain for each frame from the detector before the input is overwritten with the output.
+        // A key only has one level per block, so that's used for every frame.
+        self.gains.clear();
+        for frame in detector.chunks_exact(detector_channels).take(frames) {
+            let level = match (detection, key) {
+                (Detection::Peak, Some((peak, _))) => peak,
+                (Detection::Peak, None) => frame.iter().fold(0.0f32, |a, x| a.max(x.abs())),
+                (Detection::Rms, _) => {
+                    let square = match key {
+                        Some((_, power)) => power,
+                        None => frame.iter().map(|x| x * x).sum::<f32>() / detector_channels as f32,
+                    };
+                    self.power = square + (self.power - square) * rms;
+                    self.power.sqrt()
+                },
+            };
+            let target = gain_reduction(linear_to_db(level.max(MIN_LEVEL)), threshold, slope, knee);
+            let coefficient = if target < self.reduction { attack } else { release };
+            self.reduction = target + (self.reduction - target) * coefficient;
+            self.gains.push(db_to_linear(self.reduction));
+        }
+
+        for (frame, gain) in buffer.chunks_exact_mut(channels).zip(self.gains.iter()) {
+            let gain = gain * self.makeup.next();
+            for (sample, line) in frame.iter_mut().zip(self.lines.iter_mut()) {
+                line.write(*sample);
+                *sample = line.tap(pre_delay + 1) * gain;
+            }
+        }
+
+        self.tail.finish(buffer, channels)
+    }
+}
+
+impl<S> Source for Compressor<S>
+where
+    S: Source,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.source.channel_count()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.source.channel_layout()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        PartialFrame::write_with(self, buffer, |compressor| &mut compressor.partial, Self::write_frames)
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        if let Some(sidechain) = self.sidechain.as_mut() {
+            sidechain.reset();
+        }
+        self.lines.iter_mut().for_each(DelayLine::clear);
+        self.makeup.settle();
+        self.power = 0.0;
+        self.reduction = 0.0;
+        self.tail.reset();
+        self.partial.reset();
+    }
+}
+
+impl<S> SidechainTap<S>
+where
+    S: Source,
+{
+    /// Creates a new SidechainTap.
+    pub fn new(source: S) -> Self {
+        Self { source, level: Arc::new(SidechainLevel { peak: AtomicF32::new(0.0), power: AtomicF32::new(0.0) }) }
+    }
+
+    /// Returns a key for detecting this SidechainTap's level with a Compressor.
+    pub fn key(&self) -> SidechainKey {
+        SidechainKey(self.level.clone())
+    }
+
+    #[inline]
+    fn silence(&self) {
+        self.level.peak.store(0.0);
+        self.level.power.store(0.0);
+    }
+}
+
+impl<S> Source for SidechainTap<S>
+where
+    S: Source,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.source.channel_count()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.source.channel_layout()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        let count = self.source.write_samples(buffer);
+        if count == 0 {
+            self.silence();
+        } else {
+            let samples = &buffer[..count];
+            let peak = samples.iter().fold(0.0f32, |a, x| a.max(x.abs()));
+            self.level.peak.store(peak);
+            self.level.power.store(samples.iter().map(|x| x * x).sum::<f32>() / count as f32);
+        }
+        count
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.silence();
+    }
+}
+
+impl<S> Drop for SidechainTap<S>
+where
+    S: Source,
+{
+    fn drop(&mut self) {
+        self.silence();
+    }
+}
+
+impl Detection {
+    #[inline]
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Peak,
+            _ => Self::Rms,
+        }
+    }
+}
+
+impl CompressorHandle {
+    /// Sets the level in dB above which the input gets compressed, from 0.0 downwards.
+    pub fn set_threshold(&self, threshold: f32) {
+        self.0.threshold.store(threshold)
+    }
+
+    /// Sets how much the input gets compressed. A ratio of 4.0 means that for every 4 dB the input goes above the
+    /// threshold, the output only goes up by 1 dB. A ratio of 1.0 means no compression.
+    pub fn set_ratio(&self, ratio: f32) {
+        self.0.ratio.store(ratio)
+    }
+
+    /// Sets the width in dB of the region around the threshold where compression is gradually brought in.
+    /// A knee of 0.0, which is the default, starts compression abruptly at the threshold.
+    pub fn set_knee(&self, knee: f32) {
+        self.0.knee.store(knee)
+    }
+
+    /// Sets how long it takes for compression to kick in, in seconds.
+    pub fn set_attack(&self, attack: f32) {
+        self.0.attack.store(attack)
+    }
+
+    /// Sets how long it takes for compression to let go, in seconds.
+    pub fn set_release(&self, release: f32) {
+        self.0.release.store(release)
+    }
+
+    /// Sets the gain in dB applied to the output after compression.
+    pub fn set_makeup_gain(&self, makeup: f32) {
+        self.0.makeup.store(makeup)
+    }
+
+    /// Sets how far in seconds the output is delayed behind the level detection, up to 0.004.
+    /// This lets the Compressor react to transients before they're heard.
+    ///
+    /// If the Compressor has a sidechain, this delays the input relative to the sidechain.
+    pub fn set_pre_delay(&self, pre_delay: f32) {
+        self.0.pre_delay.store(pre_delay)
+    }
+
+    /// Sets how the level of the input or sidechain is measured.
+    pub fn set_detection(&self, detection: Detection) {
+        self.0.detection.store(detection as u8, Ordering::Release)
+    }
+
+    /// Returns the most recently set threshold in dB.
+    pub fn threshold(&self) -> f32 {
+        self.0.threshold.load()
+    }
+
+    /// Returns the most recently set ratio.
+    pub fn ratio(&self) -> f32 {
+        self.0.ratio.load()
+    }
+
+    /// Returns the most recently set knee width in dB.
+    pub fn knee(&self) -> f32 {
+        self.0.knee.load()
+    }
+
+    /// Returns the most recently set attack time in seconds.
+    pub fn attack(&self) -> f32 {
+        self.0.attack.load()
+    }
+
+    /// Returns the most recently set release time in seconds.
+    pub fn release(&self) -> f32 {
+        self.0.release.load()
+    }
+
+    /// Returns the most recently set makeup gain in dB.
+    pub fn makeup_gain(&self) -> f32 {
+        self.0.makeup.load()
+    }
+
+    /// Returns the most recently set pre-delay in seconds.
+    pub fn pre_delay(&self) -> f32 {
+        self.0.pre_delay.load()
+    }
+
+    /// Returns the most recently set detection mode.
+    pub fn detection(&self) -> Detection {
+        Detection::from_u8(self.0.detection.load(Ordering::Acquire))
+    }
+}
+
+// Returns the gain reduction in dB for an input level in dB, with a soft knee either side of the threshold
+#[inline]
+fn gain_reduction(level: f32, threshold: f32, slope: f32, knee: f32) -> f32 {
+    let over = level - threshold;
+    if 2.0 * over <= -knee {
+        0.0
+    } else if 2.0 * over < knee {
+        slope * (over + knee / 2.0).powi(2) / (2.0 * knee)
+    } else {
+        slope * over
+    }
+}
+
+// Returns the coefficient for a one-pole smoother which takes `time` seconds to get most of the way to its target
+#[inline]
+fn time_coefficient(time: f32, sample_rate: f32) -> f32 {
+    let samples = time.max(0.0) * sample_rate;
+    if samples < 1.0 { 0.0 } else { (-1.0 / samples).exp() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Player;
+
+    fn player(samples: Vec<Sample>) -> Player {
+        Player::new(ChannelCount::new(1).unwrap(), SampleRate::new(48000).unwrap(), samples.into())
+    }
+
+    #[test]
+    fn static_curve() {
+        // 3:1 above -20 dB, with a 10 dB soft knee
+        let slope = 1.0 / 3.0 - 1.0;
+        assert_eq!(gain_reduction(-30.0, -20.0, slope, 0.0), 0.0);
+        assert!((gain_reduction(-8.0, -20.0, slope, 0.0) + 8.0).abs() < 1e-5);
+        assert_eq!(gain_reduction(-25.0, -20.0, slope, 10.0), 0.0);
+        assert!((gain_reduction(-20.0, -20.0, slope, 10.0) - slope * 25.0 / 20.0).abs() < 1e-5);
+        assert!((gain_reduction(-15.0, -20.0, slope, 10.0) - slope * 5.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn gain_reduction_follows_key() {
+        // Dialogue is loud for the first half second and then silent, while music plays throughout
+        let dialogue = (0..48000).map(|i| if i < 24000 { 0.5 } else { 0.0 }).collect::<Vec<_>>();
+        let mut tap = SidechainTap::new(player(dialogue.clone()));
+        let mut compressor = Compressor::with_sidechain_key(player(vec![0.1; 48000]), tap.key());
+        let handle = compressor.handle();
+        handle.set_threshold(-30.0);
+        handle.set_ratio(10.0);
+        handle.set_release(0.05);
+
+        let (mut voice, mut music) = ([0.0; 480], [0.0; 480]);
+        let mut reductions = Vec::new();
+        for block in 0..100 {
+            assert_eq!(tap.write_samples(&mut voice), 480);
+            assert_eq!(voice[..], dialogue[block * 480..][..480], "the tap should pass its Source through unchanged");
+            compressor.write_samples(&mut music);
+            reductions.push(compressor.gain_reduction());
+        }
+
+        // 0.5 is about -6 dB, 24 dB over the threshold, so it should be pulled down by about 21.6 dB
+        assert!((reductions[40] + 21.6).abs() < 0.5, "reduction while the key was loud was {}", reductions[40]);
+        assert!(reductions[99] > -0.5, "reduction after the key went quiet was {}", reductions[99]);
+        assert!(music.iter().all(|&x| (x - 0.1).abs() < 0.01), "music should have recovered");
+    }
+
+    #[test]
+    fn dropped_key_is_silent() {
+        let tap = SidechainTap::new(player(vec![1.0; 4800]));
+        let key = tap.key();
+        key.0.peak.store(1.0);
+        drop(tap);
+        assert_eq!(key.0.peak.load(), 0.0);
+    }
+
+    #[test]
+    fn odd_buffers_keep_playing() {
+        let compressor = || {
+            let samples = (0..9600).map(|i| (i as f32 * 0.01).sin() * if i < 4800 { 0.8 } else { 0.05 }).collect();
+            let stereo = Player::new(ChannelCount::new(2).unwrap(), SampleRate::new(48000).unwrap(), samples);
+            let compressor = Compressor::new(stereo);
+            compressor.handle().set_detection(Detection::Rms);
+            compressor
+        };
+        let mut expected = vec![0.0; 20000];
+        let count = compressor().write_samples(&mut expected);
+        expected.truncate(count);
+
+        let mut compressor = compressor();
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 333];
+        loop {
+            let count = compressor.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(output.len() % 2, 0, "ended partway through a frame");
+
+        // The pre-delay empties out into silence, and only how much of that is trimmed can depend on the buffers
+        let length = output.len().min(expected.len());
+        assert!(length > 9600, "ended before the pre-delay emptied out");
+        assert_eq!(output[..length], expected[..length]);
+        assert!(output[length..].iter().chain(&expected[length..]).all(|&x| x == 0.0));
+    }
+}
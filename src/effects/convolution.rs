@@ -0,0 +1,442 @@
+use super::tail::{Tail, SILENCE_THRESHOLD};
+use crate::{
+    fft::{Complex, RealFft},
+    frame::PartialFrame,
+    param::{AtomicF32, Smoothed, SMOOTHING_TIME},
+    resampler::Resampler,
+    source::{ChannelCount, ChannelLayout, Sample, SampleRate, Source},
+};
+use std::sync::Arc;
+
+// The length of each partition of the impulse response, in frames. The reverb is delayed by this much.
+const BLOCK_SIZE: usize = 512;
+const BINS: usize = BLOCK_SIZE + 1;
+
+/// An impulse response for a [`ConvolutionReverb`], already split up and transformed ready for use.
+///
+/// Preparing an impulse response takes a while for long ones, so it's best to do it once while loading and then
+/// clone it for each reverb that uses it. Cloning is cheap, as the data is reference-counted.
+#[derive(Clone)]
+pub struct ImpulseResponse(Arc<ImpulseData>);
+
+struct ImpulseData {
+    channels: ChannelCount,
+    sample_rate: SampleRate,
+    frames: usize,
+    partitions: usize,
+    hold_frames: usize,
+    spectra: Box<[Complex]>, // Laid out by channel, then partition, then bin
+}
+
+/// A reverb which convolves a Source with a recorded [`ImpulseResponse`], to sound like it's playing in the space
+/// the impulse response was recorded in.
+///
+/// Each channel of the Source is convolved with one channel of the impulse response: with a mono impulse response,
+/// every channel uses the same one, and otherwise channel `n` uses channel `n` modulo the impulse response's channel
+/// count. The convolution is done in the frequency domain with uniform partitions, so impulse responses several
+/// seconds long can be used in real time. The reverb comes out 512 frames later than the dry signal.
+///
+/// Once the inner Source ends, the ConvolutionReverb keeps playing until its tail has died away.
+pub struct ConvolutionReverb<S>
+where
+    S: Source,
+{
+    source: S,
+    params: Arc<ConvolutionParams>,
+    impulse: ImpulseResponse,
+    fft: RealFft,
+    channels: Box<[ChannelState]>,
+    fill: usize,     // How many frames of the current block have been read
+    position: usize, // Where the newest input spectrum is in each channel's history
+    spectrum: Box<[Complex]>,
+    time: Box<[Sample]>,
+    mix: Smoothed,
+    tail: Tail,
+    partial: PartialFrame,
+}
+
+/// Used for changing the parameters of a [`ConvolutionReverb`] while it's playing.
+/// Get one with `ConvolutionReverb::handle()`.
+#[derive(Clone)]
+pub struct ConvolutionReverbHandle(Arc<ConvolutionParams>);
+
+struct ConvolutionParams {
+    mix: AtomicF32,
+}
+
+struct ChannelState {
+    input: Box<[Sample]>,    // The last two blocks of input
+    output: Box<[Sample]>,   // The block of reverb currently being played
+    history: Box<[Complex]>, // Spectra of the most recent blocks of input, as a ring buffer
+}
+
+impl ImpulseResponse {
+    /// Reads the whole of `source` as an impulse response, resampling it to `sample_rate` if needed.
+    /// The Source must end at some point, so this shouldn't be given a `Cycle`.
+    ///
+    /// The impulse response is normalised so that its loudest channel has a total energy of 1, and any silence at
+    /// the end is trimmed off.
+    pub fn new(source: impl Source, sample_rate: SampleRate) -> Self {
+        let channel_count = source.channel_count();
+        let samples = if source.sample_rate() == sample_rate {
+            read_all(source)
+        } else {
+            read_all(Resampler::new(source, sample_rate))
+        };
+        Self::from_samples(channel_count, sample_rate, samples)
+    }
+
+    /// Parses a .wav file as an impulse response, resampling it to `sample_rate` if needed. See `new()`.
+    #[cfg(feature = "wav")]
+    pub fn from_wav(file: impl Into<Vec<u8>>, sample_rate: SampleRate) -> Result<Self, crate::wav::Error> {
+        Ok(Self::new(crate::wav::WavPlayer::new(file)?, sample_rate))
+    }
+
+    /// Returns the number of channels in this impulse response.
+    pub fn channel_count(&self) -> ChannelCount {
+        self.0.channels
+    }
+
+    /// Returns the sample rate this impulse response was prepared for.
+    pub fn sample_rate(&self) -> SampleRate {
+        self.0.sample_rate
+    }
+
+    /// Returns the length of this impulse response in frames, after trimming.
+    pub fn frames(&self) -> usize {
+        self.0.frames
+    }
+
+    fn from_samples(channel_count: ChannelCount, sample_rate: SampleRate, mut samples: Vec<Sample>) -> Self {
+        let channels = usize::from(channel_count.get());
+
+        let energy = (0..channels)
+            .map(|c| samples.iter().skip(c).step_by(channels).map(|x| x * x).sum::<f32>())
+            .fold(0.0f32, f32::max);
+        if energy > 0.0 {
+            let scale = 1.0 / energy.sqrt();
+            samples.iter_mut().for_each(|x| *x *= scale);
+        }
+
+        let loud = |frame: &[Sample]| frame.iter().any(|x| x.abs() >= SILENCE_THRESHOLD);
+        let frames = samples.chunks_exact(channels).rposition(loud).map_or(0, |i| i + 1);
+        samples.truncate(frames * channels);
+
+        // The reverb can go silent for as long as the impulse response does, so the tail has to wait at least that
+        // long, plus the latency, before deciding it's over
+        let mut longest_gap = 0;
+        let mut gap = 0;
+        for frame in samples.chunks_exact(channels) {
+            gap = if loud(frame) { 0 } else { gap + 1 };
+            longest_gap = longest_gap.max(gap);
+        }
+
+        let partitions = frames.div_ceil(BLOCK_SIZE).max(1);
+        let mut fft = RealFft::new(BLOCK_SIZE * 2);
+        let mut spectra = vec![Complex::default(); channels * partitions * BINS].into_boxed_slice();
+        let mut block = vec![0.0; BLOCK_SIZE * 2];
+        for (c, channel_spectra) in spectra.chunks_exact_mut(partitions * BINS).enumerate() {
+            for (p, spectrum) in channel_spectra.chunks_exact_mut(BINS).enumerate() {
+                block.iter_mut().for_each(|x| *x = 0.0);
+                let partition = samples.chunks_exact(channels).skip(p * BLOCK_SIZE).take(BLOCK_SIZE);
+                for (x, frame) in block.iter_mut().zip(partition) {
+                    *x = frame[c];
+                }
+                fft.forward(&block, spectrum);
+            }
+        }
+
+        Self(Arc::new(ImpulseData {
+            channels: channel_count,
+            sample_rate,
+            frames,
+            partitions,
+            hold_frames: longest_gap + BLOCK_SIZE * 2,
+            spectra,
+        }))
+    }
+}
+
+impl<S> ConvolutionReverb<S>
+where
+    S: Source,
+{
+    /// Creates a new ConvolutionReverb. `mix` is the balance between the original sound and the reverb, from 0.0
+    /// (dry only) to 1.0 (wet only).
+    ///
+    /// Panics if the impulse response wasn't prepared for the Source's sample rate.
+    pub fn new(source: S, impulse: ImpulseResponse, mix: f32) -> Self {
+        assert_eq!(impulse.sample_rate(), source.sample_rate(), "impulse response is for the wrong sample rate");
+        let channels = usize::from(source.channel_count().get());
+        let mix = clamp_mix(mix);
+        Self {
+            params: Arc::new(ConvolutionParams { mix: AtomicF32::new(mix) }),
+            fft: RealFft::new(BLOCK_SIZE * 2),
+            channels: (0..channels)
+                .map(|_| ChannelState {
+                    input: vec![0.0; BLOCK_SIZE * 2].into_boxed_slice(),
+                    output: vec![0.0; BLOCK_SIZE].into_boxed_slice(),
+                    history: vec![Complex::default(); impulse.0.partitions * BINS].into_boxed_slice(),
+                })
+                .collect(),
+            fill: 0,
+            position: 0,
+            spectrum: vec![Complex::default(); BINS].into_boxed_slice(),
+            time: vec![0.0; BLOCK_SIZE * 2].into_boxed_slice(),
+            mix: Smoothed::new(mix),
+            tail: Tail::new(impulse.0.hold_frames),
+            partial: PartialFrame::new(channels),
+            impulse,
+            source,
+        }
+    }
+
+    /// Returns a handle for changing this ConvolutionReverb's parameters while it's playing.
+    pub fn handle(&self) -> ConvolutionReverbHandle {
+        ConvolutionReverbHandle(self.params.clone())
+    }
+
+    // Convolves the block of input that's just been filled, replacing each channel's output
+    fn process_block(&mut self) {
+        let impulse = &self.impulse.0;
+        let partitions = impulse.partitions;
+        let impulse_channels = usize::from(impulse.channels.get());
+        self.position = (self.position + 1) % partitions;
+
+        for (c, channel) in self.channels.iter_mut().enumerate() {
+            let history = &mut channel.history[self.position * BINS..(self.position + 1) * BINS];
+            self.fft.forward(&channel.input, history);
+
+            // Multiply each block of input by the matching partition of the impulse response and sum them all up
+            let spectra = &impulse.spectra[(c % impulse_channels) * partitions * BINS..];
+            self.spectrum.iter_mut().for_each(|x| *x = Complex::default());
+            for (p, partition) in spectra.chunks_exact(BINS).take(partitions).enumerate() {
+                let index = (self.position + partitions - p) % partitions;
+                let input = &channel.history[index * BINS..(index + 1) * BINS];
+                for ((sum, x), h) in self.spectrum.iter_mut().zip(input).zip(partition) {
+                    sum.re += x.re * h.re - x.im * h.im;
+                    sum.im += x.re * h.im + x.im * h.re;
+                }
+            }
+
+            // With overlap-save, only the second half of the result is valid
+            self.fft.inverse(&self.spectrum, &mut self.time);
+            channel.output.copy_from_slice(&self.time[BLOCK_SIZE..]);
+            channel.input.copy_within(BLOCK_SIZE.., 0);
+        }
+    }
+
+    fn write_frames(&mut self, buffer: &mut [Sample]) -> usize {
+        if self.tail.read(&mut self.source, buffer).is_none() {
+            return 0
+        }
+
+        let sample_rate = u32::from(self.source.sample_rate()) as f32;
+        self.mix.set_target(clamp_mix(self.params.mix.load()), (SMOOTHING_TIME * sample_rate) as u32);
+
+        let channel_count = self.channels.len();
+        for frame in buffer.chunks_exact_mut(channel_count) {
+            let mix = self.mix.next();
+            for (sample, channel) in frame.iter_mut().zip(self.channels.iter_mut()) {
+                channel.input[BLOCK_SIZE + self.fill] = *sample;
+                *sample = *sample * (1.0 - mix) + channel.output[self.fill] * mix;
+            }
+            self.fill += 1;
+            if self.fill == BLOCK_SIZE {
+                self.process_block();
+                self.fill = 0;
+            }
+        }
+
+        self.tail.finish(buffer, channel_count)
+    }
+}
+
+impl<S> Source for ConvolutionReverb<S>
+where
+    S: Source,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.source.channel_count()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.source.channel_layout()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        PartialFrame::write_with(self, buffer, |reverb| &mut reverb.partial, Self::write_frames)
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        for channel in self.channels.iter_mut() {
+            channel.input.iter_mut().for_each(|x| *x = 0.0);
+            channel.output.iter_mut().for_each(|x| *x = 0.0);
+            channel.history.iter_mut().for_each(|x| *x = Complex::default());
+        }
+        self.fill = 0;
+        self.position = 0;
+        self.tail.reset();
+        self.partial.reset();
+    }
+}
+
+impl ConvolutionReverbHandle {
+    /// Sets the balance between the original sound and the reverb, from 0.0 (dry only) to 1.0 (wet only).
+    pub fn set_mix(&self, mix: f32) {
+        self.0.mix.store(mix)
+    }
+
+    /// Returns the most recently set wet/dry mix.
+    pub fn mix(&self) -> f32 {
+        self.0.mix.load()
+    }
+}
+
+#[inline]
+fn clamp_mix(mix: f32) -> f32 {
+    mix.clamp(0.0, 1.0)
+}
+
+pub(crate) fn read_all(mut source: impl Source) -> Vec<Sample> {
+    let mut samples = Vec::new();
+    let mut count = 0;
+    loop {
+        samples.resize(count + 4096, 0.0);
+        let written = source.write_samples(&mut samples[count..]);
+        count += written;
+        if written < 4096 {
+            samples.truncate(count);
+            return samples
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Player;
+
+    fn player(channels: u16, samples: Vec<Sample>) -> Player {
+        Player::new(ChannelCount::new(channels).unwrap(), SampleRate::new(48000).unwrap(), samples.into())
+    }
+
+    fn noise(len: usize, seed: usize) -> Vec<Sample> {
+        (0..len).map(|i| (((i + seed) * 7919) % 101) as Sample / 50.0 - 1.0).collect()
+    }
+
+    // Reads until the reverb ends, checking that it keeps returning 0 afterwards
+    fn play(reverb: &mut ConvolutionReverb<Player>) -> Vec<Sample> {
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 300];
+        loop {
+            let count = reverb.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(reverb.write_samples(&mut buffer), 0);
+        output
+    }
+
+    #[test]
+    fn normalises_and_trims() {
+        let mut samples = vec![0.0; 2000];
+        samples[0] = 3.0;
+        samples[2] = -4.0;
+        samples[1001] = 1.0; // The quieter channel, which shouldn't affect the scale
+        let impulse = ImpulseResponse::new(player(2, samples), SampleRate::new(48000).unwrap());
+        assert_eq!(impulse.channel_count().get(), 2);
+        assert_eq!(impulse.frames(), 501);
+
+        // The left channel has an energy of 25, so everything is scaled by 1/5
+        let mut input = vec![0.0; 2000];
+        input[0] = 1.0;
+        input[1] = 1.0;
+        let output = play(&mut ConvolutionReverb::new(player(2, input), impulse, 1.0));
+        for &(i, expected) in &[(0, 0.6), (1, -0.8), (500, 0.0)] {
+            assert!((output[(BLOCK_SIZE + i) * 2] - expected).abs() < 1e-5);
+        }
+        assert!((output[(BLOCK_SIZE + 500) * 2 + 1] - 0.2).abs() < 1e-5);
+    }
+
+    #[test]
+    fn matches_direct_convolution() {
+        // Long enough to be split into three partitions
+        let response = noise(1300, 0);
+        let input = noise(3000, 17);
+        let impulse = ImpulseResponse::new(player(1, response.clone()), SampleRate::new(48000).unwrap());
+        let output = play(&mut ConvolutionReverb::new(player(1, input.clone()), impulse, 1.0));
+
+        let scale = 1.0 / response.iter().map(|x| x * x).sum::<f32>().sqrt();
+        let expected = |n: usize| -> f32 {
+            (0..response.len()).filter(|&k| k <= n && n - k < input.len()).map(|k| response[k] * input[n - k]).sum()
+        };
+        assert!(output[..BLOCK_SIZE].iter().all(|&x| x == 0.0));
+        for (n, &x) in output.iter().enumerate().skip(BLOCK_SIZE) {
+            let expected = expected(n - BLOCK_SIZE) * scale;
+            assert!((x - expected).abs() < 1e-3, "frame {}: {} != {}", n, x, expected);
+        }
+
+        // The tail plays through to the end of the convolution
+        assert!(output.len() >= input.len() + response.len() - 1 + BLOCK_SIZE);
+    }
+
+    #[test]
+    fn channels_use_matching_responses() {
+        // The left response passes the input straight through, the right one inverts it
+        let mut response = vec![0.0; 8];
+        response[0] = 1.0;
+        response[1] = -1.0;
+        let impulse = ImpulseResponse::new(player(2, response), SampleRate::new(48000).unwrap());
+        let input = noise(2000, 3);
+        let output = play(&mut ConvolutionReverb::new(player(2, input.clone()), impulse, 1.0));
+        for (out, x) in output[BLOCK_SIZE * 2..].chunks_exact(2).zip(input.chunks_exact(2)) {
+            assert!((out[0] - x[0]).abs() < 1e-4 && (out[1] + x[1]).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn dry_is_unchanged() {
+        let impulse = ImpulseResponse::new(player(1, noise(100, 5)), SampleRate::new(48000).unwrap());
+        let input = noise(1000, 9);
+        let output = play(&mut ConvolutionReverb::new(player(1, input.clone()), impulse, 0.0));
+        assert_eq!(output[..input.len()], input[..]);
+        assert!(output[input.len()..].iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn odd_buffers_keep_playing() {
+        let reverb = || {
+            let impulse = ImpulseResponse::new(player(2, noise(1300, 1)), SampleRate::new(48000).unwrap());
+            ConvolutionReverb::new(player(2, noise(3000, 2)), impulse, 0.5)
+        };
+        let expected = play(&mut reverb());
+        let mut reverb = reverb();
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 301];
+        loop {
+            let count = reverb.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(reverb.write_samples(&mut buffer), 0);
+        assert_eq!(output.len() % 2, 0, "ended partway through a frame");
+
+        // The two can only differ in how much silence is left at the end
+        let length = output.len().min(expected.len());
+        assert_eq!(output[..length], expected[..length]);
+        assert!(output[length..].iter().chain(&expected[length..]).all(|x| x.abs() < SILENCE_THRESHOLD));
+    }
+}
@@ -0,0 +1,363 @@
+use super::tail::Tail;
+use crate::{
+    frame::PartialFrame,
+    param::{AtomicF32, Smoothed, DELAY_SMOOTHING_TIME},
+    source::{ChannelCount, ChannelLayout, Sample, SampleRate, Source},
+};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+// Feedback is capped just below 1 so the echoes always die away eventually
+const MAX_FEEDBACK: f32 = 0.99;
+
+/// An echo effect, which plays back what it was given after a delay and feeds some of that back in again.
+///
+/// Each channel is delayed separately, unless ping-pong is enabled on a stereo Source. In that case, the input is
+/// mixed down and the echoes alternate between the left and right channels.
+///
+/// Once the inner Source ends, the Delay keeps playing until the echoes have died away.
+pub struct Delay<S>
+where
+    S: Source,
+{
+    source: S,
+    params: Arc<DelayParams>,
+    lines: Box<[DelayLine]>,
+    delay: Smoothed, // In frames
+    feedback: Smoothed,
+    mix: Smoothed,
+    ping_pong: bool,
+    tail: Tail,
+    partial: PartialFrame,
+}
+
+/// Used for changing the parameters of a [`Delay`] while it's playing. Get one with `Delay::handle()`.
+#[derive(Clone)]
+pub struct DelayHandle(Arc<DelayParams>);
+
+struct DelayParams {
+    delay: AtomicF32,
+    feedback: AtomicF32,
+    mix: AtomicF32,
+    ping_pong: AtomicBool,
+}
+
+impl<S> Delay<S>
+where
+    S: Source,
+{
+    /// Creates a new Delay.
+    ///
+    /// - `delay` is the time between echoes in seconds. It can't be changed to anything above `max_delay` later.
+    /// - `feedback` is how much of each echo is fed back into the next one, from 0.0 to just below 1.0.
+    /// - `mix` is the balance between the original sound and the echoes, from 0.0 (dry only) to 1.0 (wet only).
+    pub fn new(source: S, delay: f32, max_delay: f32, feedback: f32, mix: f32) -> Self {
+        let channels = usize::from(source.channel_count().get());
+        let sample_rate = u32::from(source.sample_rate()) as f32;
+        let max_frames = ((max_delay * sample_rate).ceil() as usize).max(1);
+        let delay = clamp_delay(delay * sample_rate, max_frames);
+        let feedback = clamp_feedback(feedback);
+        let mix = clamp_mix(mix);
+        Self {
+            params: Arc::new(DelayParams {
+                delay: AtomicF32::new(delay / sample_rate),
+                feedback: AtomicF32::new(feedback),
+                mix: AtomicF32::new(mix),
+                ping_pong: AtomicBool::new(false),
+            }),
+            lines: (0..channels).map(|_| DelayLine::new(max_frames)).collect(),
+            delay: Smoothed::new(delay),
+            feedback: Smoothed::new(feedback),
+            mix: Smoothed::new(mix),
+            ping_pong: false,
+            tail: Tail::new(max_frames),
+            partial: PartialFrame::new(channels),
+            source,
+        }
+    }
+
+    /// Returns a handle for changing this Delay's parameters while it's playing.
+    pub fn handle(&self) -> DelayHandle {
+        DelayHandle(self.params.clone())
+    }
+
+    fn write_frames(&mut self, buffer: &mut [Sample]) -> usize {
+        if self.tail.read(&mut self.source, buffer).is_none() {
+            return 0
+        }
+
+        let sample_rate = u32::from(self.source.sample_rate()) as f32;
+        let steps = (DELAY_SMOOTHING_TIME * sample_rate) as u32;
+        let max_frames = self.lines[0].max_delay();
+        self.delay.set_target(clamp_delay(self.params.delay.load() * sample_rate, max_frames), steps);
+        self.feedback.set_target(clamp_feedback(self.params.feedback.load()), steps);
+        self.mix.set_target(clamp_mix(self.params.mix.load()), steps);
+        self.ping_pong = self.params.ping_pong.load(Ordering::Acquire) && self.lines.len() == 2;
+
+        let channels = self.lines.len();
+        for frame in buffer.chunks_exact_mut(channels) {
+            let delay = self.delay.next();
+            let feedback = self.feedback.next();
+            let mix = self.mix.next();
+
+            if self.ping_pong {
+                let (left, right) = self.lines.split_at_mut(1);
+                let (left, right) = (&mut left[0], &mut right[0]);
+                let (echo_l, echo_r) = (left.read(delay), right.read(delay));
+                let input = (frame[0] + frame[1]) * 0.5;
+                left.write(input + echo_r * feedback);
+                right.write(echo_l * feedback);
+                frame[0] = frame[0] * (1.0 - mix) + echo_l * mix;
+                frame[1] = frame[1] * (1.0 - mix) + echo_r * mix;
+            } else {
+                for (sample, line) in frame.iter_mut().zip(self.lines.iter_mut()) {
+                    let echo = line.read(delay);
+                    line.write(*sample + echo * feedback);
+                    *sample = *sample * (1.0 - mix) + echo * mix;
+                }
+            }
+        }
+
+        self.tail.finish(buffer, channels)
+    }
+}
+
+impl<S> Source for Delay<S>
+where
+    S: Source,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.source.channel_count()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.source.channel_layout()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        PartialFrame::write_with(self, buffer, |delay| &mut delay.partial, Self::write_frames)
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.lines.iter_mut().for_each(DelayLine::clear);
+        self.tail.reset();
+        self.partial.reset();
+    }
+}
+
+impl DelayHandle {
+    /// Sets the time between echoes in seconds. This is capped to the `max_delay` the Delay was created with.
+    ///
+    /// Changes are ramped in over a short time, which will briefly bend the pitch of any echoes already playing.
+    pub fn set_delay(&self, delay: f32) {
+        self.0.delay.store(delay)
+    }
+
+    /// Sets how much of each echo is fed back into the next one, from 0.0 to just below 1.0.
+    pub fn set_feedback(&self, feedback: f32) {
+        self.0.feedback.store(feedback)
+    }
+
+    /// Sets the balance between the original sound and the echoes, from 0.0 (dry only) to 1.0 (wet only).
+    pub fn set_mix(&self, mix: f32) {
+        self.0.mix.store(mix)
+    }
+
+    /// Sets whether echoes alternate between the left and right channels. This only affects stereo Sources.
+    pub fn set_ping_pong(&self, ping_pong: bool) {
+        self.0.ping_pong.store(ping_pong, Ordering::Release)
+    }
+
+    /// Returns the most recently set delay in seconds.
+    pub fn delay(&self) -> f32 {
+        self.0.delay.load()
+    }
+
+    /// Returns the most recently set feedback.
+    pub fn feedback(&self) -> f32 {
+        self.0.feedback.load()
+    }
+
+    /// Returns the most recently set wet/dry mix.
+    pub fn mix(&self) -> f32 {
+        self.0.mix.load()
+    }
+
+    /// Returns whether ping-pong is enabled.
+    pub fn ping_pong(&self) -> bool {
+        self.0.ping_pong.load(Ordering::Acquire)
+    }
+}
+
+#[inline]
+fn clamp_delay(frames: f32, max_frames: usize) -> f32 {
+    frames.max(1.0).min(max_frames as f32)
+}
+
+#[inline]
+fn clamp_feedback(feedback: f32) -> f32 {
+    feedback.clamp(0.0, MAX_FEEDBACK)
+}
+
+#[inline]
+fn clamp_mix(mix: f32) -> f32 {
+    mix.clamp(0.0, 1.0)
+}
+
+/// A single channel's worth of delay, which can be read from at a fractional offset.
+#[derive(Clone, Debug)]
+pub(crate) struct DelayLine {
+    buffer: Box<[Sample]>,
+    write_pos: usize,
+}
+
+impl DelayLine {
+    /// Creates a DelayLine which can delay by up to `max_delay` samples.
+    pub(crate) fn new(max_delay: usize) -> Self {
+        // One extra sample for interpolating at the maximum delay, and one so that a delay of 0 can't read
+        // the sample that's about to be written
+        Self { buffer: vec![0.0; max_delay + 2].into_boxed_slice(), write_pos: 0 }
+    }
+
+    /// Returns the longest delay, in samples, this line can be read at.
+    #[inline]
+    pub(crate) fn max_delay(&self) -> usize {
+        self.buffer.len() - 2
+    }
+
+    /// Pushes a new sample into the line.
+    #[inline]
+    pub(crate) fn write(&mut self, sample: Sample) {
+        self.buffer[self.write_pos] = sample;
+        self.write_pos = (self.write_pos + 1) % self.buffer.len();
+    }
+
+    /// Reads the sample written `delay` samples ago, linearly interpolating between samples.
+    /// A delay of 1.0 returns the most recently written sample.
+    #[inline]
+    pub(crate) fn read(&self, delay: f32) -> Sample {
+        let delay = delay.max(1.0).min(self.max_delay() as f32);
+        let whole = delay as usize;
+        let fraction = delay - whole as f32;
+        let a = self.tap(whole);
+        let b = self.tap(whole + 1);
+        a + (b - a) * fraction
+    }
+
+    /// Reads the sample written exactly `delay` samples ago. A delay of 1 returns the most recently written sample.
+    #[inline]
+    pub(crate) fn tap(&self, delay: usize) -> Sample {
+        let len = self.buffer.len();
+        self.buffer[(self.write_pos + len - delay) % len]
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.buffer.iter_mut().for_each(|x| *x = 0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Player;
+
+    fn impulse(channels: u16, length: usize) -> Player {
+        let mut samples = vec![0.0; length * usize::from(channels)];
+        samples[0] = 1.0;
+        Player::new(ChannelCount::new(channels).unwrap(), SampleRate::new(1000).unwrap(), samples.into())
+    }
+
+    // Reads until the Delay ends, checking that it keeps returning 0 afterwards
+    fn play(delay: &mut Delay<Player>) -> Vec<Sample> {
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 64];
+        loop {
+            let count = delay.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(delay.write_samples(&mut buffer), 0);
+        output
+    }
+
+    #[test]
+    fn echoes_decay_and_end() {
+        // 10 frame delay and half feedback, so echo `k` is at frame 10k with a level of 0.5^k
+        let mut delay = Delay::new(impulse(1, 5), 0.01, 0.01, 0.5, 1.0);
+        let output = play(&mut delay);
+        for (i, &x) in output.iter().enumerate() {
+            let expected = if i % 10 == 0 && i > 0 { 0.5f32.powi(i as i32 / 10 - 1) } else { 0.0 };
+            assert!((x - expected).abs() < 1e-6, "frame {}: {} != {}", i, x, expected);
+        }
+
+        // 0.5^14 is the last echo above -90 dB, at frame 150. The tail holds for one delay's worth of silence after it.
+        assert_eq!(output.len(), 151);
+    }
+
+    #[test]
+    fn dry_is_unchanged() {
+        let mut delay = Delay::new(impulse(2, 5), 0.01, 0.01, 0.5, 0.0);
+        let output = play(&mut delay);
+        assert_eq!(output[..2], [1.0, 0.0]);
+        assert!(output[2..].iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn ping_pong_alternates() {
+        let mut delay = Delay::new(impulse(2, 5), 0.01, 0.01, 0.5, 1.0);
+        delay.handle().set_ping_pong(true);
+        let output = play(&mut delay);
+        // The input is mixed down to half, then echoes left, right, left...
+        for (k, &(left, right)) in [(0.5, 0.0), (0.0, 0.25), (0.125, 0.0)].iter().enumerate() {
+            let frame = &output[(k + 1) * 20..][..2];
+            assert!((frame[0] - left).abs() < 1e-6 && (frame[1] - right).abs() < 1e-6, "echo {}: {:?}", k, frame);
+        }
+    }
+
+    #[test]
+    fn reset_clears_echoes() {
+        let mut delay = Delay::new(impulse(1, 5), 0.01, 0.01, 0.5, 1.0);
+        let first = play(&mut delay);
+        delay.reset();
+        assert_eq!(play(&mut delay), first);
+    }
+
+    #[test]
+    fn odd_buffers_keep_playing() {
+        let delay = || {
+            let delay = Delay::new(impulse(2, 5), 0.01, 0.01, 0.5, 0.5);
+            delay.handle().set_ping_pong(true);
+            delay
+        };
+        let expected = play(&mut delay());
+        let mut delay = delay();
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 7];
+        loop {
+            let count = delay.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(delay.write_samples(&mut buffer), 0);
+
+        // The tail only trims silence from the buffer it ends in, so how much comes before that depends on the buffers
+        let length = output.len().min(expected.len());
+        assert_eq!(output[..length], expected[..length]);
+        assert!(output[length..].iter().chain(&expected[length..]).all(|&x| x == 0.0));
+        assert_eq!(output.len() % 2, 0, "ended partway through a frame");
+    }
+}
@@ -0,0 +1,369 @@
+use super::{delay::DelayLine, oversample::Oversampler};
+use crate::{
+    param::{AtomicF32, Smoothed, SMOOTHING_TIME},
+    source::{db_to_linear, ChannelCount, ChannelLayout, Sample, SampleRate, Source},
+};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+    Mutex,
+};
+
+/// The transfer curve a [`Distortion`] applies to each sample.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub enum DistortionShape {
+    /// Rounds off peaks smoothly with a tanh curve. This sounds warmer than hard clipping.
+    SoftClip,
+
+    /// Cuts off anything outside -1.0 to 1.0.
+    HardClip,
+
+    /// A user-defined curve, given as output values spaced evenly across inputs from -1.0 to 1.0 and linearly
+    /// interpolated between. Inputs outside that range use the value at the nearest end.
+    /// For example, `[-1.0, 0.0, 1.0]` passes everything from -1.0 to 1.0 through unchanged.
+    Curve(Arc<[f32]>),
+}
+
+/// A distortion effect, which boosts a Source by a drive gain and then passes it through a [`DistortionShape`].
+///
+/// Clipping and waveshaping add harmonics, and any above half the sample rate will alias back down as inharmonic
+/// noise. Oversampling runs the shape at a multiple of the sample rate and filters out those harmonics first.
+/// The filtering delays the output by around 100 frames, whatever the factor.
+pub struct Distortion<S>
+where
+    S: Source,
+{
+    source: S,
+    params: Arc<DistortionParams>,
+    shape: DistortionShape,
+    drive: Smoothed,
+    output_gain: Smoothed,
+    mix: Smoothed,
+    oversampler: Option<Oversampler>,
+    dry: Box<[DelayLine]>, // Delays the dry signal to line up with the oversampled one
+    channel: usize,        // Which channel the next sample belongs to, since buffers don't have to end on a whole frame
+}
+
+/// Used for changing the parameters of a [`Distortion`] while it's playing. Get one with `Distortion::handle()`.
+#[derive(Clone)]
+pub struct DistortionHandle(Arc<DistortionParams>);
+
+struct DistortionParams {
+    drive: AtomicF32,
+    output_gain: AtomicF32,
+    mix: AtomicF32,
+    shape: Mutex<DistortionShape>,
+    shape_changed: AtomicBool,
+}
+
+impl<S> Distortion<S>
+where
+    S: Source,
+{
+    /// Creates a new Distortion without oversampling. `drive` is the gain in dB applied before the shape.
+    pub fn new(source: S, shape: DistortionShape, drive: f32) -> Self {
+        Self::with_oversampling(source, shape, drive, 1)
+    }
+
+    /// Creates a new Distortion which runs the shape at `factor` times the Source's sample rate.
+    /// `drive` is the gain in dB applied before the shape.
+    ///
+    /// Panics if `factor` isn't 1, 2, 4 or 8.
+    pub fn with_oversampling(source: S, shape: DistortionShape, drive: f32, factor: usize) -> Self {
+        assert!(matches!(factor, 1 | 2 | 4 | 8), "oversampling factor must be 1, 2, 4 or 8");
+        let channels = usize::from(source.channel_count().get());
+        let oversampler = if factor > 1 { Some(Oversampler::new(factor, channels)) } else { None };
+        let latency = oversampler.as_ref().map_or(0, Oversampler::latency);
+        Self {
+            params: Arc::new(DistortionParams {
+                drive: AtomicF32::new(drive),
+                output_gain: AtomicF32::new(0.0),
+                mix: AtomicF32::new(1.0),
+                shape: Mutex::new(shape.clone()),
+                shape_changed: AtomicBool::new(false),
+            }),
+            shape,
+            drive: Smoothed::new(db_to_linear(drive)),
+            output_gain: Smoothed::new(1.0),
+            mix: Smoothed::new(1.0),
+            oversampler,
+            dry: (0..channels).map(|_| DelayLine::new(latency + 1)).collect(),
+            channel: 0,
+            source,
+        }
+    }
+
+    /// Returns a handle for changing this Distortion's parameters while it's playing.
+    pub fn handle(&self) -> DistortionHandle {
+        DistortionHandle(self.params.clone())
+    }
+}
+
+impl<S> Source for Distortion<S>
+where
+    S: Source,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.source.channel_count()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.source.channel_layout()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        let count = self.source.write_samples(buffer);
+
+        // If the handle's holding the lock, the new shape will be picked up next time
+        if self.params.shape_changed.swap(false, Ordering::AcqRel) {
+            match self.params.shape.try_lock() {
+                Ok(shape) => self.shape = shape.clone(),
+                Err(_) => self.params.shape_changed.store(true, Ordering::Release),
+            }
+        }
+
+        let sample_rate = u32::from(self.source.sample_rate()) as f32;
+        let steps = (SMOOTHING_TIME * sample_rate) as u32;
+        self.drive.set_target(db_to_linear(self.params.drive.load()), steps);
+        self.output_gain.set_target(db_to_linear(self.params.output_gain.load()), steps);
+        self.mix.set_target(self.params.mix.load().clamp(0.0, 1.0), steps);
+
+        let shape = &self.shape;
+        let (mut drive, mut output_gain, mut mix) = (self.drive.value(), self.output_gain.value(), self.mix.value());
+        for sample in buffer[..count].iter_mut() {
+            // Parameters step once per frame, at its first channel
+            if self.channel == 0 {
+                drive = self.drive.next();
+                output_gain = self.output_gain.next();
+                mix = self.mix.next();
+            }
+            let wet = match self.oversampler.as_mut() {
+                Some(oversampler) => oversampler.process(self.channel, *sample * drive, |x| shape.apply(x)),
+                None => shape.apply(*sample * drive),
+            };
+            let dry = &mut self.dry[self.channel];
+            dry.write(*sample);
+            *sample = dry.tap(dry.max_delay()) * (1.0 - mix) + wet * output_gain * mix;
+            self.channel = (self.channel + 1) % self.dry.len();
+        }
+
+        count
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        if let Some(oversampler) = self.oversampler.as_mut() {
+            oversampler.reset();
+        }
+        self.dry.iter_mut().for_each(DelayLine::clear);
+        self.channel = 0;
+    }
+}
+
+impl DistortionShape {
+    #[inline]
+    fn apply(&self, x: Sample) -> Sample {
+        match self {
+            Self::SoftClip => x.tanh(),
+            Self::HardClip => x.clamp(-1.0, 1.0),
+            Self::Curve(points) => match points.len() {
+                0 => 0.0,
+                1 => points[0],
+                len => {
+                    let position = ((x + 1.0) * 0.5 * (len - 1) as f32).clamp(0.0, (len - 1) as f32);
+                    let index = (position as usize).min(len - 2);
+                    let fraction = position - index as f32;
+                    points[index] + (points[index + 1] - points[index]) * fraction
+                },
+            },
+        }
+    }
+}
+
+impl DistortionHandle {
+    /// Sets the gain in dB applied before the shape. Higher values give more distortion.
+    pub fn set_drive(&self, drive: f32) {
+        self.0.drive.store(drive)
+    }
+
+    /// Sets the gain in dB applied to the distorted signal, to make up for the drive.
+    pub fn set_output_gain(&self, output_gain: f32) {
+        self.0.output_gain.store(output_gain)
+    }
+
+    /// Sets the balance between the original sound and the distorted one, from 0.0 (dry only) to 1.0 (wet only).
+    pub fn set_mix(&self, mix: f32) {
+        self.0.mix.store(mix)
+    }
+
+    /// Sets the shape the Source is passed through. Unlike the other parameters, this takes effect immediately.
+    pub fn set_shape(&self, shape: DistortionShape) {
+        *self.0.shape.lock().unwrap() = shape;
+        self.0.shape_changed.store(true, Ordering::Release);
+    }
+
+    /// Returns the most recently set drive in dB.
+    pub fn drive(&self) -> f32 {
+        self.0.drive.load()
+    }
+
+    /// Returns the most recently set output gain in dB.
+    pub fn output_gain(&self) -> f32 {
+        self.0.output_gain.load()
+    }
+
+    /// Returns the most recently set wet/dry mix.
+    pub fn mix(&self) -> f32 {
+        self.0.mix.load()
+    }
+
+    /// Returns the most recently set shape.
+    pub fn shape(&self) -> DistortionShape {
+        self.0.shape.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Player;
+    use std::f32::consts::PI;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    fn sine(frequency: f32, amplitude: f32, len: usize) -> Vec<Sample> {
+        (0..len).map(|i| (2.0 * PI * frequency * i as f32 / SAMPLE_RATE as f32).sin() * amplitude).collect()
+    }
+
+    fn distort(samples: &[Sample], shape: DistortionShape, drive: f32, factor: usize) -> Vec<Sample> {
+        let player =
+            Player::new(ChannelCount::new(1).unwrap(), SampleRate::new(SAMPLE_RATE).unwrap(), samples.into());
+        let mut distortion = Distortion::with_oversampling(player, shape, drive, factor);
+        let mut buffer = vec![0.0; samples.len() + 1];
+        assert_eq!(distortion.write_samples(&mut buffer), samples.len());
+        buffer.truncate(samples.len());
+        buffer
+    }
+
+    // Returns the magnitude of one frequency in `samples` with the Goertzel algorithm
+    fn magnitude(samples: &[Sample], frequency: f32) -> f32 {
+        let coefficient = 2.0 * (2.0 * PI * frequency / SAMPLE_RATE as f32).cos();
+        let (mut s1, mut s2) = (0.0f32, 0.0f32);
+        for &x in samples {
+            let s = x + coefficient * s1 - s2;
+            s2 = s1;
+            s1 = s;
+        }
+        (s1 * s1 + s2 * s2 - coefficient * s1 * s2).sqrt() / samples.len() as f32 * 2.0
+    }
+
+    #[test]
+    fn shapes() {
+        let curve = DistortionShape::Curve(vec![-1.0, 0.0, 1.0].into());
+        let steps = DistortionShape::Curve(vec![0.0, 1.0, 0.0, -1.0, 0.0].into());
+        for &x in &[-2.0, -1.0, -0.3, 0.0, 0.5, 1.0, 3.0] {
+            assert_eq!(DistortionShape::SoftClip.apply(x), x.tanh());
+            assert_eq!(DistortionShape::HardClip.apply(x), x.clamp(-1.0, 1.0));
+            assert!((curve.apply(x) - x.clamp(-1.0, 1.0)).abs() < 1e-6);
+            assert_eq!(DistortionShape::Curve(vec![0.25].into()).apply(x), 0.25);
+            assert_eq!(DistortionShape::Curve(Vec::new().into()).apply(x), 0.0);
+        }
+        for &(x, y) in &[(-1.0, 0.0), (-0.75, 0.5), (-0.5, 1.0), (0.0, 0.0), (0.25, -0.5), (1.0, 0.0), (2.0, 0.0)] {
+            assert!((steps.apply(x) - y).abs() < 1e-6, "{} -> {}", x, steps.apply(x));
+        }
+    }
+
+    #[test]
+    fn drive_before_clipping() {
+        // +6 dB takes 0.75 past the clipping point, but leaves 0.25 below it
+        let output = distort(&[0.25, 0.75, -0.75], DistortionShape::HardClip, 6.0, 1);
+        assert!((output[0] - 0.25 * db_to_linear(6.0)).abs() < 1e-6);
+        assert_eq!(output[1..], [1.0, -1.0]);
+    }
+
+    #[test]
+    fn oversampling_keeps_dry_aligned() {
+        // A quiet, low sine isn't clipped, so the wet signal should match the dry one it's mixed with
+        let input = sine(440.0, 0.1, 4800);
+        let player =
+            Player::new(ChannelCount::new(1).unwrap(), SampleRate::new(SAMPLE_RATE).unwrap(), input.clone().into());
+        let mut distortion = Distortion::with_oversampling(player, DistortionShape::HardClip, 0.0, 4);
+        let latency = distortion.oversampler.as_ref().unwrap().latency();
+        assert!(latency > 0);
+        distortion.handle().set_mix(0.5);
+        let mut output = vec![0.0; input.len()];
+        distortion.write_samples(&mut output);
+        // Skip the mix ramping down from fully wet
+        let ramp = (SMOOTHING_TIME * SAMPLE_RATE as f32) as usize;
+        for (x, y) in input.iter().zip(&output[latency..]).skip(ramp) {
+            assert!((x - y).abs() < 1e-3, "{} != {}", x, y);
+        }
+    }
+
+    #[test]
+    fn oversampling_reduces_aliasing() {
+        // Hard clipping a 5 kHz sine makes odd harmonics, which alias to multiples of 1 kHz that aren't multiples
+        // of 5 kHz. 4800 frames is a whole number of cycles of all of them, so they each fall exactly on one bin.
+        let input = sine(5000.0, 1.0, 9600 + 4800);
+        for &factor in &[2, 4, 8] {
+            let plain = distort(&input, DistortionShape::HardClip, 12.0, 1);
+            let oversampled = distort(&input, DistortionShape::HardClip, 12.0, factor);
+            let (plain, oversampled) = (&plain[9600..], &oversampled[9600..]);
+            assert!((magnitude(oversampled, 5000.0) / magnitude(plain, 5000.0) - 1.0).abs() < 0.05);
+            for &alias in &[3000.0, 13000.0, 17000.0] {
+                let (before, after) = (magnitude(plain, alias), magnitude(oversampled, alias));
+                assert!(after < before * 0.5, "{}x at {} Hz: {} then {}", factor, alias, before, after);
+            }
+        }
+    }
+
+    #[test]
+    fn dry_is_unchanged() {
+        let input = sine(440.0, 0.9, 2000);
+        let player =
+            Player::new(ChannelCount::new(1).unwrap(), SampleRate::new(SAMPLE_RATE).unwrap(), input.clone().into());
+        let mut distortion = Distortion::new(player, DistortionShape::SoftClip, 24.0);
+        distortion.handle().set_mix(0.0);
+        let mut output = vec![0.0; input.len()];
+        distortion.write_samples(&mut output);
+
+        // The mix ramps from fully wet to fully dry, so the distortion fades out rather than stopping suddenly
+        let ramp = (SMOOTHING_TIME * SAMPLE_RATE as f32) as usize;
+        let wet = distort(&input[..ramp], DistortionShape::SoftClip, 24.0, 1);
+        assert!((output[1] - wet[1]).abs() < 0.01);
+        assert!(output[..ramp / 2].iter().zip(&input).filter(|(_, y)| y.abs() > 0.1).all(|(x, y)| x.abs() > y.abs()));
+        assert_eq!(output[ramp..], input[ramp..]);
+    }
+
+    #[test]
+    fn odd_buffers_keep_channels_apart() {
+        // Only the left channel has anything in it, so the right one must stay silent however the frames are split
+        let input = sine(440.0, 0.9, 2000).iter().flat_map(|&x| vec![x, 0.0]).collect::<Vec<_>>();
+        let player = || {
+            Player::new(ChannelCount::new(2).unwrap(), SampleRate::new(SAMPLE_RATE).unwrap(), input.clone().into())
+        };
+        let mut distortion = Distortion::with_oversampling(player(), DistortionShape::HardClip, 12.0, 2);
+        distortion.handle().set_mix(0.5);
+        let mut expected = vec![0.0; input.len()];
+        assert_eq!(distortion.write_samples(&mut expected), input.len());
+
+        let mut distortion = Distortion::with_oversampling(player(), DistortionShape::HardClip, 12.0, 2);
+        distortion.handle().set_mix(0.5);
+        let mut output = Vec::new();
+        for &length in [5, 2, 7, 1].iter().cycle().take(1000) {
+            let mut buffer = vec![0.0; length];
+            let count = distortion.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+        }
+        assert_eq!(output, expected[..output.len()]);
+        assert!(output.chunks_exact(2).all(|x| x[1] == 0.0));
+    }
+}
@@ -0,0 +1,453 @@
+use crate::{
+    param::{AtomicF32, Smoothed, SMOOTHING_TIME},
+    source::{ChannelCount, ChannelLayout, Sample, SampleRate, Source},
+};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU8, Ordering},
+    Arc,
+};
+
+// How sharply exponential curves bend. At 5.0, a stage is about 99% of the way to its target by the end.
+const CURVE_STEEPNESS: f32 = 5.0;
+
+/// The shape of one stage of an [`Envelope`], going from the level it starts at to its target.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub enum EnvelopeCurve {
+    /// Changes at a steady rate.
+    Linear,
+
+    /// Changes quickly at first, then slows down as it approaches its target, like an analog synthesiser.
+    /// This sounds natural for decays and releases.
+    Exponential,
+
+    /// Eases in and out, changing slowly at both ends and quickly in the middle.
+    Smooth,
+}
+
+/// An attack, decay, sustain and release (ADSR) envelope, which shapes the volume of a Source like a note played on
+/// an instrument.
+///
+/// The volume rises from silence to full during the attack, falls to the sustain level during the decay, and stays
+/// there until [`EnvelopeHandle::note_off`] is called. It then fades to silence during the release, and the Envelope
+/// ends. A note-off during the attack or decay goes straight to the release, starting from wherever the volume got
+/// to. Resetting the Envelope starts the note again from the attack.
+pub struct Envelope<S>
+where
+    S: Source,
+{
+    source: S,
+    params: Arc<EnvelopeParams>,
+    stage: Stage,
+    progress: f32, // How far through the current stage, from 0.0 to 1.0
+    start: f32,    // The level the current stage started at
+    level: f32,
+    sustain: Smoothed,
+    started: bool,
+    channel: usize, // Which channel the next sample is for, since buffers can end partway through a frame
+}
+
+/// Used for changing the parameters of an [`Envelope`] while it's playing, and for ending its note.
+/// Get one with `Envelope::handle()`.
+#[derive(Clone)]
+pub struct EnvelopeHandle(Arc<EnvelopeParams>);
+
+struct EnvelopeParams {
+    attack: AtomicF32,
+    decay: AtomicF32,
+    sustain: AtomicF32,
+    release: AtomicF32,
+    attack_curve: AtomicU8,
+    decay_curve: AtomicU8,
+    release_curve: AtomicU8,
+    released: AtomicBool,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Finished,
+}
+
+// The parameters for one block, with times converted into how far each stage moves per frame
+struct Stages {
+    increments: [f32; 3],
+    curves: [EnvelopeCurve; 3],
+}
+
+impl<S> Envelope<S>
+where
+    S: Source,
+{
+    /// Creates a new Envelope. The attack, decay and release are in seconds, and the sustain level is a linear
+    /// amplitude from 0.0 to 1.0.
+    ///
+    /// The attack starts out linear, and the decay and release exponential. The curves can be changed with a handle.
+    pub fn new(source: S, attack: f32, decay: f32, sustain: f32, release: f32) -> Self {
+        let sustain = sustain.clamp(0.0, 1.0);
+        Self {
+            source,
+            params: Arc::new(EnvelopeParams {
+                attack: AtomicF32::new(attack),
+                decay: AtomicF32::new(decay),
+                sustain: AtomicF32::new(sustain),
+                release: AtomicF32::new(release),
+                attack_curve: AtomicU8::new(EnvelopeCurve::Linear as u8),
+                decay_curve: AtomicU8::new(EnvelopeCurve::Exponential as u8),
+                release_curve: AtomicU8::new(EnvelopeCurve::Exponential as u8),
+                released: AtomicBool::new(false),
+            }),
+            stage: Stage::Attack,
+            progress: 0.0,
+            start: 0.0,
+            level: 0.0,
+            sustain: Smoothed::new(sustain),
+            started: false,
+            channel: 0,
+        }
+    }
+
+    /// Returns a handle for changing this Envelope's parameters while it's playing.
+    pub fn handle(&self) -> EnvelopeHandle {
+        EnvelopeHandle(self.params.clone())
+    }
+
+    // Advances by one frame and returns the new level, or None if the release has finished.
+    // Stages are skipped over as soon as they're complete, so a stage with no length doesn't take up a frame.
+    #[inline]
+    fn next_level(&mut self, stages: &Stages) -> Option<f32> {
+        let sustain = self.sustain.next();
+        loop {
+            let (index, target) = match self.stage {
+                Stage::Attack => (0, 1.0),
+                Stage::Decay => (1, sustain),
+                Stage::Sustain => {
+                    self.level = sustain;
+                    return Some(sustain)
+                },
+                Stage::Release => (2, 0.0),
+                Stage::Finished => return None,
+            };
+
+            self.progress += stages.increments[index];
+            if self.progress < 1.0 {
+                self.level = self.start + (target - self.start) * stages.curves[index].at(self.progress);
+                return Some(self.level)
+            }
+
+            self.start = target;
+            self.progress = 0.0;
+            self.stage = match self.stage {
+                Stage::Attack => Stage::Decay,
+                Stage::Decay => Stage::Sustain,
+                _ => Stage::Finished,
+            };
+        }
+    }
+}
+
+impl<S> Source for Envelope<S>
+where
+    S: Source,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.source.channel_count()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.source.channel_layout()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        if self.stage == Stage::Finished {
+            return 0
+        }
+
+        let count = self.source.write_samples(buffer);
+        let channels = usize::from(self.source.channel_count().get());
+        let sample_rate = u32::from(self.source.sample_rate()) as f32;
+        let increment = |time: &AtomicF32| 1.0 / (time.load().max(0.0) * sample_rate);
+        let params = &self.params;
+        let stages = Stages {
+            increments: [increment(&params.attack), increment(&params.decay), increment(&params.release)],
+            curves: [
+                EnvelopeCurve::from_u8(params.attack_curve.load(Ordering::Acquire)),
+                EnvelopeCurve::from_u8(params.decay_curve.load(Ordering::Acquire)),
+                EnvelopeCurve::from_u8(params.release_curve.load(Ordering::Acquire)),
+            ],
+        };
+        self.sustain.set_target(self.params.sustain.load().clamp(0.0, 1.0), (SMOOTHING_TIME * sample_rate) as u32);
+        if !self.started {
+            self.sustain.settle();
+            self.started = true;
+        }
+
+        if self.params.released.load(Ordering::Acquire) && self.stage != Stage::Release {
+            self.stage = Stage::Release;
+            self.start = self.level;
+            self.progress = 0.0;
+        }
+
+        for (i, x) in buffer[..count].iter_mut().enumerate() {
+            if self.channel == 0 && self.next_level(&stages).is_none() {
+                return i
+            }
+            *x *= self.level;
+            self.channel = (self.channel + 1) % channels;
+        }
+        count
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.params.released.store(false, Ordering::Release);
+        self.stage = Stage::Attack;
+        self.progress = 0.0;
+        self.start = 0.0;
+        self.level = 0.0;
+        self.started = false;
+        self.channel = 0;
+    }
+}
+
+impl EnvelopeCurve {
+    #[inline]
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Linear,
+            1 => Self::Exponential,
+            _ => Self::Smooth,
+        }
+    }
+
+    // Returns how far from the start of a stage to its target the level is, from 0.0 to 1.0, at a progress through
+    // the stage from 0.0 to 1.0
+    #[inline]
+    fn at(self, progress: f32) -> f32 {
+        match self {
+            Self::Linear => progress,
+            Self::Exponential => (1.0 - (-CURVE_STEEPNESS * progress).exp()) / (1.0 - (-CURVE_STEEPNESS).exp()),
+            Self::Smooth => progress * progress * (3.0 - 2.0 * progress),
+        }
+    }
+}
+
+impl EnvelopeHandle {
+    /// Ends the note, so the Envelope goes into its release and then ends. Does nothing if that's already happened.
+    pub fn note_off(&self) {
+        self.0.released.store(true, Ordering::Release)
+    }
+
+    /// Returns whether the note has been ended with `note_off()` since the Envelope was created or last reset.
+    pub fn is_released(&self) -> bool {
+        self.0.released.load(Ordering::Acquire)
+    }
+
+    /// Sets the attack time in seconds. Changes apply to the next frame, even partway through the attack.
+    pub fn set_attack(&self, attack: f32) {
+        self.0.attack.store(attack)
+    }
+
+    /// Sets the decay time in seconds.
+    pub fn set_decay(&self, decay: f32) {
+        self.0.decay.store(decay)
+    }
+
+    /// Sets the sustain level, as a linear amplitude from 0.0 to 1.0.
+    pub fn set_sustain(&self, sustain: f32) {
+        self.0.sustain.store(sustain)
+    }
+
+    /// Sets the release time in seconds.
+    pub fn set_release(&self, release: f32) {
+        self.0.release.store(release)
+    }
+
+    /// Sets the shape of the attack.
+    pub fn set_attack_curve(&self, curve: EnvelopeCurve) {
+        self.0.attack_curve.store(curve as u8, Ordering::Release)
+    }
+
+    /// Sets the shape of the decay.
+    pub fn set_decay_curve(&self, curve: EnvelopeCurve) {
+        self.0.decay_curve.store(curve as u8, Ordering::Release)
+    }
+
+    /// Sets the shape of the release.
+    pub fn set_release_curve(&self, curve: EnvelopeCurve) {
+        self.0.release_curve.store(curve as u8, Ordering::Release)
+    }
+
+    /// Returns the most recently set attack time in seconds.
+    pub fn attack(&self) -> f32 {
+        self.0.attack.load()
+    }
+
+    /// Returns the most recently set decay time in seconds.
+    pub fn decay(&self) -> f32 {
+        self.0.decay.load()
+    }
+
+    /// Returns the most recently set sustain level.
+    pub fn sustain(&self) -> f32 {
+        self.0.sustain.load()
+    }
+
+    /// Returns the most recently set release time in seconds.
+    pub fn release(&self) -> f32 {
+        self.0.release.load()
+    }
+
+    /// Returns the most recently set attack curve.
+    pub fn attack_curve(&self) -> EnvelopeCurve {
+        EnvelopeCurve::from_u8(self.0.attack_curve.load(Ordering::Acquire))
+    }
+
+    /// Returns the most recently set decay curve.
+    pub fn decay_curve(&self) -> EnvelopeCurve {
+        EnvelopeCurve::from_u8(self.0.decay_curve.load(Ordering::Acquire))
+    }
+
+    /// Returns the most recently set release curve.
+    pub fn release_curve(&self) -> EnvelopeCurve {
+        EnvelopeCurve::from_u8(self.0.release_curve.load(Ordering::Acquire))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Player;
+
+    // 10 frame stages at 1 kHz, with every curve linear
+    fn envelope(channels: u16, attack: f32, decay: f32, release: f32) -> Envelope<Player> {
+        let samples = vec![1.0; 1000 * usize::from(channels)];
+        let player = Player::new(ChannelCount::new(channels).unwrap(), SampleRate::new(1000).unwrap(), samples.into());
+        let envelope = Envelope::new(player, attack, decay, 0.5, release);
+        let handle = envelope.handle();
+        handle.set_decay_curve(EnvelopeCurve::Linear);
+        handle.set_release_curve(EnvelopeCurve::Linear);
+        envelope
+    }
+
+    fn levels(envelope: &mut Envelope<Player>, frames: usize) -> Vec<Sample> {
+        let mut buffer = vec![0.0; frames];
+        let count = envelope.write_samples(&mut buffer);
+        buffer.truncate(count);
+        buffer
+    }
+
+    fn assert_close(actual: &[Sample], expected: &[Sample]) {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (a, b) in actual.iter().zip(expected) {
+            assert!((a - b).abs() < 1e-4, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn curves() {
+        for &curve in &[EnvelopeCurve::Linear, EnvelopeCurve::Exponential, EnvelopeCurve::Smooth] {
+            assert!(curve.at(0.0).abs() < 1e-6 && (curve.at(1.0) - 1.0).abs() < 1e-6, "{:?}", curve);
+            let points = (0..=10).map(|i| curve.at(i as f32 / 10.0)).collect::<Vec<_>>();
+            assert!(points.windows(2).all(|x| x[1] > x[0]), "{:?}", curve);
+        }
+        assert!(EnvelopeCurve::Exponential.at(0.5) > 0.9);
+        assert!((EnvelopeCurve::Smooth.at(0.25) + EnvelopeCurve::Smooth.at(0.75) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn stages_in_order() {
+        let mut envelope = envelope(1, 0.01, 0.01, 0.01);
+        let output = levels(&mut envelope, 40);
+        let attack = (1..10).map(|i| i as f32 / 10.0);
+        let decay = (0..10).map(|i| 1.0 - 0.05 * i as f32);
+        let expected = attack.chain(decay).chain(std::iter::repeat(0.5)).take(40).collect::<Vec<_>>();
+        // Rounding in the progress means a stage can finish a frame early or late, so allow for one frame's step
+        for (i, (a, b)) in output.iter().zip(&expected).enumerate() {
+            assert!((a - b).abs() <= 0.1 + 1e-4, "frame {}: {} != {}", i, a, b);
+        }
+        assert_close(&output[25..], &[0.5; 15]);
+    }
+
+    #[test]
+    fn ends_after_release() {
+        let mut envelope = envelope(2, 0.0, 0.0, 0.01);
+        assert_close(&levels(&mut envelope, 20), &[0.5; 20]);
+        envelope.handle().note_off();
+        let output = levels(&mut envelope, 100);
+        // The release steps down by 0.05 per frame, and the Envelope ends when it reaches 0
+        let frames = output.len() / 2;
+        assert_eq!(output.len() % 2, 0);
+        assert!((9..=10).contains(&frames), "{} frames", frames);
+        assert!(output.chunks_exact(2).all(|x| x[0] == x[1]));
+        assert!(output.windows(2).all(|x| x[1] <= x[0]));
+        assert!(output[0] < 0.5 && output[output.len() - 1] < 0.1);
+        assert_eq!(levels(&mut envelope, 100), []);
+    }
+
+    #[test]
+    fn early_note_off_releases_from_current_level() {
+        let mut envelope = envelope(1, 0.01, 0.01, 0.01);
+        let attack = levels(&mut envelope, 4);
+        assert_close(&attack, &[0.1, 0.2, 0.3, 0.4]);
+        envelope.handle().note_off();
+        let release = levels(&mut envelope, 100);
+        assert_close(&release[..3], &[0.36, 0.32, 0.28]);
+        assert!(release.len() < 100);
+    }
+
+    #[test]
+    fn instant_release_ends_immediately() {
+        let mut envelope = envelope(1, 0.0, 0.0, 0.0);
+        levels(&mut envelope, 10);
+        envelope.handle().note_off();
+        assert_eq!(levels(&mut envelope, 10), []);
+    }
+
+    #[test]
+    fn odd_buffers_shape_every_sample() {
+        let mut whole = envelope(2, 0.01, 0.01, 0.01);
+        let mut expected = levels(&mut whole, 60);
+        whole.handle().note_off();
+        expected.extend(levels(&mut whole, 100));
+
+        // The level steps once per frame, however the buffers split the frames up, and the note still ends on a frame
+        let mut odd = envelope(2, 0.01, 0.01, 0.01);
+        let mut output = Vec::new();
+        while output.len() < 60 {
+            output.extend(levels(&mut odd, 3));
+        }
+        odd.handle().note_off();
+        loop {
+            let buffer = levels(&mut odd, 3);
+            output.extend_from_slice(&buffer);
+            if buffer.len() < 3 {
+                break
+            }
+        }
+        assert_eq!(output, expected);
+        assert!(output.chunks(2).all(|x| x.len() == 2 && x[0] == x[1]));
+    }
+
+    #[test]
+    fn reset_starts_again() {
+        let mut envelope = envelope(1, 0.01, 0.01, 0.0);
+        let first = levels(&mut envelope, 30);
+        let handle = envelope.handle();
+        handle.note_off();
+        assert_eq!(levels(&mut envelope, 10), []);
+        envelope.reset();
+        assert!(!handle.is_released());
+        assert_eq!(levels(&mut envelope, 30), first);
+    }
+}
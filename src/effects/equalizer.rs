@@ -0,0 +1,353 @@
+use super::biquad::{clamp_frequency, Coefficients, FilterType, State};
+use crate::{
+    param::{AtomicF32, Smoothed, SMOOTHING_TIME},
+    source::{ChannelCount, ChannelLayout, Sample, SampleRate, Source},
+};
+use std::{f32::consts::LN_2, sync::Arc};
+
+// While parameters are ramping, coefficients are recalculated every this many frames
+const UPDATE_INTERVAL: u32 = 16;
+
+/// The settings for one band of an [`Equalizer`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub struct EqBand {
+    /// The centre frequency in Hz.
+    pub frequency: f32,
+
+    /// The width of the band in octaves, measured between the points where the gain is half of its peak in dB.
+    pub bandwidth: f32,
+
+    /// How much frequencies in the band are boosted or cut, in dB.
+    pub gain: f32,
+}
+
+/// A parametric equalizer with any number of bands, each of which boosts or cuts the frequencies around it.
+///
+/// Each band is a peaking filter, and they're applied one after the other to each channel.
+/// Bands can be changed while the Equalizer is playing through an [`EqualizerHandle`], though how many there are
+/// is fixed when it's created. A band with a gain of 0 dB has no effect, so spare bands can be kept that way.
+pub struct Equalizer<S>
+where
+    S: Source,
+{
+    source: S,
+    params: Arc<[BandParams]>,
+    bands: Box<[Band]>,
+    state: Box<[State]>, // One for each band of each channel, grouped by band
+    update_countdown: u32,
+    channel: usize, // Which channel the next sample belongs to, since buffers don't have to end on a whole frame
+}
+
+/// Used for changing the bands of an [`Equalizer`] while it's playing. Get one with `Equalizer::handle()`.
+#[derive(Clone)]
+pub struct EqualizerHandle(Arc<[BandParams]>);
+
+struct BandParams {
+    frequency: AtomicF32,
+    bandwidth: AtomicF32,
+    gain: AtomicF32,
+}
+
+struct Band {
+    frequency: Smoothed, // Stored as a natural log, like Biquad's
+    bandwidth: Smoothed,
+    gain: Smoothed,
+    coefficients: Coefficients,
+}
+
+impl<S> Equalizer<S>
+where
+    S: Source,
+{
+    /// Creates a new Equalizer with the given bands.
+    pub fn new(source: S, bands: impl IntoIterator<Item = EqBand>) -> Self {
+        let channels = usize::from(source.channel_count().get());
+        let sample_rate = u32::from(source.sample_rate()) as f32;
+        let params: Arc<[BandParams]> = bands
+            .into_iter()
+            .map(|band| BandParams {
+                frequency: AtomicF32::new(band.frequency),
+                bandwidth: AtomicF32::new(band.bandwidth),
+                gain: AtomicF32::new(band.gain),
+            })
+            .collect();
+        let bands: Box<[Band]> = params
+            .iter()
+            .map(|band| {
+                let frequency = clamp_frequency(band.frequency.load(), sample_rate);
+                let bandwidth = clamp_bandwidth(band.bandwidth.load());
+                let gain = band.gain.load();
+                Band {
+                    frequency: Smoothed::new(frequency.ln()),
+                    bandwidth: Smoothed::new(bandwidth),
+                    gain: Smoothed::new(gain),
+                    coefficients: coefficients(frequency, bandwidth, gain, sample_rate),
+                }
+            })
+            .collect();
+        Self {
+            source,
+            state: vec![State::default(); bands.len() * channels].into_boxed_slice(),
+            params,
+            bands,
+            update_countdown: UPDATE_INTERVAL,
+            channel: 0,
+        }
+    }
+
+    /// Returns a handle for changing this Equalizer's bands while it's playing.
+    pub fn handle(&self) -> EqualizerHandle {
+        EqualizerHandle(self.params.clone())
+    }
+}
+
+impl<S> Source for Equalizer<S>
+where
+    S: Source,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.source.channel_count()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.source.channel_layout()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        let count = self.source.write_samples(buffer);
+        if self.bands.is_empty() {
+            return count
+        }
+
+        let channels = usize::from(self.source.channel_count().get());
+        let sample_rate = u32::from(self.source.sample_rate()) as f32;
+        let steps = (SMOOTHING_TIME * sample_rate) as u32;
+        for (band, params) in self.bands.iter_mut().zip(self.params.iter()) {
+            band.frequency.set_target(clamp_frequency(params.frequency.load(), sample_rate).ln(), steps);
+            band.bandwidth.set_target(clamp_bandwidth(params.bandwidth.load()), steps);
+            band.gain.set_target(params.gain.load(), steps);
+        }
+
+        for sample in buffer[..count].iter_mut() {
+            // Parameters step once per frame, at its first channel
+            if self.channel == 0 {
+                self.update_countdown = self.update_countdown.saturating_sub(1);
+                let update = self.update_countdown == 0;
+                if update {
+                    self.update_countdown = UPDATE_INTERVAL;
+                }
+                for band in self.bands.iter_mut().filter(|band| band.is_ramping()) {
+                    let (frequency, bandwidth, gain) = (band.frequency.next(), band.bandwidth.next(), band.gain.next());
+                    if update || !band.is_ramping() {
+                        band.coefficients = coefficients(frequency.exp(), bandwidth, gain, sample_rate);
+                    }
+                }
+            }
+
+            let states = self.state[self.channel..].iter_mut().step_by(channels);
+            for (band, state) in self.bands.iter().zip(states) {
+                *sample = state.process(&band.coefficients, *sample);
+            }
+            self.channel = (self.channel + 1) % channels;
+        }
+
+        count
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.state.iter_mut().for_each(|x| *x = State::default());
+        self.channel = 0;
+        let sample_rate = u32::from(self.source.sample_rate()) as f32;
+        for band in self.bands.iter_mut().filter(|band| band.is_ramping()) {
+            band.frequency.settle();
+            band.bandwidth.settle();
+            band.gain.settle();
+            let (frequency, bandwidth, gain) = (band.frequency.next().exp(), band.bandwidth.next(), band.gain.next());
+            band.coefficients = coefficients(frequency, bandwidth, gain, sample_rate);
+        }
+    }
+}
+
+impl Band {
+    #[inline]
+    fn is_ramping(&self) -> bool {
+        !(self.frequency.is_settled() && self.bandwidth.is_settled() && self.gain.is_settled())
+    }
+}
+
+impl EqualizerHandle {
+    /// Replaces the settings of the band at `index`. Does nothing if there's no such band.
+    pub fn set_band(&self, index: usize, band: EqBand) {
+        if let Some(params) = self.0.get(index) {
+            params.frequency.store(band.frequency);
+            params.bandwidth.store(band.bandwidth);
+            params.gain.store(band.gain);
+        }
+    }
+
+    /// Sets the centre frequency in Hz of the band at `index`. Does nothing if there's no such band.
+    pub fn set_frequency(&self, index: usize, frequency: f32) {
+        if let Some(params) = self.0.get(index) {
+            params.frequency.store(frequency);
+        }
+    }
+
+    /// Sets the width in octaves of the band at `index`. Does nothing if there's no such band.
+    pub fn set_bandwidth(&self, index: usize, bandwidth: f32) {
+        if let Some(params) = self.0.get(index) {
+            params.bandwidth.store(bandwidth);
+        }
+    }
+
+    /// Sets the gain in dB of the band at `index`. Does nothing if there's no such band.
+    pub fn set_gain(&self, index: usize, gain: f32) {
+        if let Some(params) = self.0.get(index) {
+            params.gain.store(gain);
+        }
+    }
+
+    /// Returns the most recently set settings of the band at `index`, or `None` if there's no such band.
+    pub fn band(&self, index: usize) -> Option<EqBand> {
+        self.0.get(index).map(|params| EqBand {
+            frequency: params.frequency.load(),
+            bandwidth: params.bandwidth.load(),
+            gain: params.gain.load(),
+        })
+    }
+
+    /// Returns how many bands the Equalizer has.
+    pub fn band_count(&self) -> usize {
+        self.0.len()
+    }
+}
+
+#[inline]
+fn clamp_bandwidth(bandwidth: f32) -> f32 {
+    bandwidth.clamp(0.01, 10.0)
+}
+
+// Calculates peaking filter coefficients, converting the bandwidth to a Q as in the "Audio EQ Cookbook"
+#[inline]
+fn coefficients(frequency: f32, bandwidth: f32, gain: f32, sample_rate: f32) -> Coefficients {
+    let w0 = 2.0 * std::f32::consts::PI * frequency / sample_rate;
+    let q = 1.0 / (2.0 * (LN_2 / 2.0 * bandwidth * w0 / w0.sin()).sinh());
+    Coefficients::new(FilterType::Peaking, frequency, q, gain, sample_rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Player;
+    use std::f32::consts::PI;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    fn sine(frequency: f32) -> Player {
+        let samples = (0..SAMPLE_RATE).map(|i| (2.0 * PI * frequency * i as f32 / SAMPLE_RATE as f32).sin() * 0.25);
+        Player::new(ChannelCount::new(1).unwrap(), SampleRate::new(SAMPLE_RATE).unwrap(), samples.collect())
+    }
+
+    // Plays a sine through the bands, and returns its change in level once the filters have settled, in dB
+    fn gain_at(frequency: f32, bands: &[EqBand]) -> f32 {
+        let mut equalizer = Equalizer::new(sine(frequency), bands.iter().copied());
+        let mut buffer = vec![0.0; SAMPLE_RATE as usize];
+        assert_eq!(equalizer.write_samples(&mut buffer), buffer.len());
+        let peak = buffer[buffer.len() / 2..].iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+        20.0 * (peak / 0.25).log10()
+    }
+
+    #[test]
+    fn bandwidth_is_between_half_gain_points() {
+        let band = EqBand { frequency: 1000.0, bandwidth: 2.0, gain: 12.0 };
+        assert!((gain_at(1000.0, &[band]) - 12.0).abs() < 0.05);
+        for &frequency in &[500.0, 2000.0] {
+            let gain = gain_at(frequency, &[band]);
+            assert!((gain - 6.0).abs() < 0.2, "{} Hz was {} dB", frequency, gain);
+        }
+    }
+
+    #[test]
+    fn bands_apply_in_turn() {
+        let bands = [
+            EqBand { frequency: 200.0, bandwidth: 0.5, gain: -9.0 },
+            EqBand { frequency: 5000.0, bandwidth: 0.5, gain: 6.0 },
+        ];
+        assert!((gain_at(200.0, &bands) + 9.0).abs() < 0.1);
+        assert!((gain_at(5000.0, &bands) - 6.0).abs() < 0.1);
+        assert!(gain_at(1000.0, &bands).abs() < 0.1);
+    }
+
+    #[test]
+    fn handle_changes_bands() {
+        let band = EqBand { frequency: 200.0, bandwidth: 1.0, gain: 0.0 };
+        let mut equalizer = Equalizer::new(sine(1000.0), vec![band]);
+        let handle = equalizer.handle();
+        assert_eq!(handle.band_count(), 1);
+        handle.set_band(0, EqBand { frequency: 1000.0, gain: -12.0, ..band });
+        handle.set_gain(1, 6.0);
+        assert_eq!(handle.band(0), Some(EqBand { frequency: 1000.0, bandwidth: 1.0, gain: -12.0 }));
+        assert_eq!(handle.band(1), None);
+
+        let mut buffer = vec![0.0; SAMPLE_RATE as usize];
+        assert_eq!(equalizer.write_samples(&mut buffer), buffer.len());
+        let peak = buffer[buffer.len() / 2..].iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+        assert!((20.0 * (peak / 0.25).log10() + 12.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn flat_bands_do_nothing() {
+        let bands = [EqBand { frequency: 1000.0, bandwidth: 1.0, gain: 0.0 }; 3];
+        let mut equalizer = Equalizer::new(sine(440.0), bands.iter().copied());
+        let mut expected = sine(440.0);
+        let (mut buffer, mut original) = (vec![0.0; 1000], vec![0.0; 1000]);
+        equalizer.write_samples(&mut buffer);
+        expected.write_samples(&mut original);
+        assert!(buffer.iter().zip(&original).all(|(a, b)| (a - b).abs() < 1e-6));
+    }
+
+    #[test]
+    fn ends_with_its_source() {
+        let mut equalizer = Equalizer::new(sine(440.0), vec![EqBand { frequency: 1000.0, bandwidth: 1.0, gain: 3.0 }]);
+        let mut buffer = vec![0.0; 30000];
+        assert_eq!(equalizer.write_samples(&mut buffer), 30000);
+        assert_eq!(equalizer.write_samples(&mut buffer), 18000);
+        assert_eq!(equalizer.write_samples(&mut buffer), 0);
+    }
+
+    #[test]
+    fn odd_buffers_keep_the_channels_in_step() {
+        let stereo = || {
+            let samples = (0..4800).map(|i| (i as f32 * if i % 2 == 0 { 0.05 } else { 0.3 }).sin()).collect();
+            let player = Player::new(ChannelCount::new(2).unwrap(), SampleRate::new(SAMPLE_RATE).unwrap(), samples);
+            let bands = [
+                EqBand { frequency: 500.0, bandwidth: 1.0, gain: 6.0 },
+                EqBand { frequency: 3000.0, bandwidth: 2.0, gain: -12.0 },
+            ];
+            Equalizer::new(player, bands.iter().copied())
+        };
+
+        // Both get the same change partway through, so the bands are ramping across the odd buffers
+        let play = |equalizer: &mut Equalizer<Player>, length: usize| {
+            let mut output = Vec::new();
+            let mut buffer = vec![0.0; length];
+            while output.len() < 4800 {
+                if output.len() >= 1001 {
+                    equalizer.handle().set_gain(1, 6.0);
+                }
+                let count = equalizer.write_samples(&mut buffer);
+                output.extend_from_slice(&buffer[..count]);
+            }
+            output
+        };
+        assert_eq!(play(&mut stereo(), 7), play(&mut stereo(), 1001));
+    }
+}
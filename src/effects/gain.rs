@@ -0,0 +1,190 @@
+use crate::{
+    param::{AtomicF32, Smoothed, SMOOTHING_TIME},
+    source::{db_to_linear, linear_to_db, ChannelCount, ChannelLayout, Sample, SampleRate, Source},
+};
+use std::sync::Arc;
+
+/// Scales the volume of a Source.
+///
+/// The gain can be changed while playing through a [`GainHandle`], which can be shared with other threads.
+/// Changes are ramped in sample by sample over a short time, so they don't click.
+pub struct Gain<S>
+where
+    S: Source,
+{
+    source: S,
+    gain: Arc<AtomicF32>,
+    current: Smoothed,
+    channel: usize, // Which channel the next sample belongs to, since buffers don't have to end on a whole frame
+}
+
+/// Used for changing the gain of a [`Gain`] while it's playing. Get one with `Gain::handle()`.
+#[derive(Clone)]
+pub struct GainHandle(Arc<AtomicF32>);
+
+impl<S> Gain<S>
+where
+    S: Source,
+{
+    /// Creates a new Gain with a linear amplitude multiplier, where 1.0 leaves the volume unchanged.
+    pub fn new(source: S, gain: f32) -> Self {
+        Self { source, gain: Arc::new(AtomicF32::new(gain)), current: Smoothed::new(gain), channel: 0 }
+    }
+
+    /// Creates a new Gain with a gain in dB, where 0.0 leaves the volume unchanged.
+    pub fn with_db(source: S, db: f32) -> Self {
+        Self::new(source, db_to_linear(db))
+    }
+
+    /// Returns a handle for changing this Gain while it's playing.
+    pub fn handle(&self) -> GainHandle {
+        GainHandle(self.gain.clone())
+    }
+}
+
+impl<S> Source for Gain<S>
+where
+    S: Source,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.source.channel_count()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.source.channel_layout()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        let count = self.source.write_samples(buffer);
+        let sample_rate = u32::from(self.source.sample_rate()) as f32;
+        self.current.set_target(self.gain.load(), (SMOOTHING_TIME * sample_rate) as u32);
+
+        let channels = usize::from(self.source.channel_count().get());
+        if self.current.is_settled() {
+            let gain = self.current.value();
+            if gain != 1.0 {
+                buffer[..count].iter_mut().for_each(|x| *x *= gain);
+            }
+            self.channel = (self.channel + count) % channels;
+        } else {
+            let mut gain = self.current.value();
+            for sample in buffer[..count].iter_mut() {
+                // The gain steps once per frame, at its first channel
+                if self.channel == 0 {
+                    gain = self.current.next();
+                }
+                *sample *= gain;
+                self.channel = (self.channel + 1) % channels;
+            }
+        }
+        count
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.current.settle();
+        self.channel = 0;
+    }
+}
+
+impl GainHandle {
+    /// Sets the gain as a linear amplitude multiplier, where 1.0 leaves the volume unchanged.
+    pub fn set_gain(&self, gain: f32) {
+        self.0.store(gain)
+    }
+
+    /// Sets the gain in dB, where 0.0 leaves the volume unchanged.
+    pub fn set_db(&self, db: f32) {
+        self.0.store(db_to_linear(db))
+    }
+
+    /// Returns the most recently set gain as a linear amplitude multiplier.
+    pub fn gain(&self) -> f32 {
+        self.0.load()
+    }
+
+    /// Returns the most recently set gain in dB.
+    pub fn db(&self) -> f32 {
+        linear_to_db(self.0.load())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Player;
+
+    fn ones(channels: u16, frames: usize) -> Player {
+        let samples = vec![1.0; frames * usize::from(channels)];
+        Player::new(ChannelCount::new(channels).unwrap(), SampleRate::new(1000).unwrap(), samples.into())
+    }
+
+    #[test]
+    fn scales_by_gain() {
+        let mut gain = Gain::with_db(ones(2, 10), -6.0);
+        let mut buffer = [0.0; 32];
+        assert_eq!(gain.write_samples(&mut buffer), 20);
+        assert!(buffer[..20].iter().all(|&x| x == db_to_linear(-6.0)));
+        assert_eq!(gain.write_samples(&mut buffer), 0);
+    }
+
+    #[test]
+    fn changes_ramp_per_frame() {
+        // 20 ms at 1 kHz is 20 frames, so each frame moves a twentieth of the way and both channels move together
+        let mut gain = Gain::new(ones(2, 100), 1.0);
+        let handle = gain.handle();
+        handle.set_gain(0.0);
+        let mut buffer = [0.0; 60];
+        assert_eq!(gain.write_samples(&mut buffer), 60);
+        for (i, frame) in buffer.chunks_exact(2).enumerate() {
+            let expected = (1.0 - (i + 1) as f32 / 20.0).max(0.0);
+            assert!((frame[0] - expected).abs() < 1e-5 && frame[0] == frame[1], "frame {}: {:?}", i, frame);
+        }
+    }
+
+    #[test]
+    fn odd_buffers_ramp_every_sample() {
+        // Splitting frames between buffers mustn't leave any samples unscaled, or move the ramp off the frame
+        let mut gain = Gain::new(ones(2, 100), 1.0);
+        gain.handle().set_gain(0.0);
+        let mut output = Vec::new();
+        for &length in [3, 5, 1, 4].iter().cycle().take(20) {
+            let mut buffer = vec![0.0; length];
+            assert_eq!(gain.write_samples(&mut buffer), length);
+            output.extend_from_slice(&buffer);
+        }
+        for (i, frame) in output.chunks_exact(2).enumerate() {
+            let expected = (1.0 - (i + 1) as f32 / 20.0).max(0.0);
+            assert!((frame[0] - expected).abs() < 1e-5 && frame[0] == frame[1], "frame {}: {:?}", i, frame);
+        }
+    }
+
+    #[test]
+    fn reset_skips_the_ramp() {
+        let mut gain = Gain::new(ones(1, 100), 1.0);
+        gain.handle().set_db(-20.0);
+        let mut buffer = [0.0; 4];
+        gain.write_samples(&mut buffer);
+        gain.reset();
+        gain.write_samples(&mut buffer);
+        assert!(buffer.iter().all(|&x| (x - 0.1).abs() < 1e-6));
+    }
+
+    #[test]
+    fn handle_reports_db() {
+        let gain = Gain::new(ones(1, 1), 1.0);
+        let handle = gain.handle();
+        handle.set_db(-12.0);
+        assert!((handle.db() + 12.0).abs() < 1e-4);
+        assert!((handle.gain() - db_to_linear(-12.0)).abs() < 1e-6);
+        handle.set_gain(0.0);
+        assert_eq!(handle.db(), f32::NEG_INFINITY);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::source::{ChannelCount, ChannelLayout, Sample, SampleRate, Source};
+use std::sync::{
+    atomic::{AtomicU32, AtomicU8, Ordering},
+    Arc,
+};
+
+// Rate limits, which are the same as DirectSound's
+const MIN_RATE: u32 = 1;
+const MAX_RATE: u32 = 1000;
+
+/// The shape of the oscillator which modulates the volume in a [`Gargle`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub enum GargleWaveform {
+    Triangle,
+    Square,
+}
+
+/// A gargle effect, which modulates the volume of a Source with a fast oscillator.
+///
+/// This matches DirectSound's gargle, and starts with the same default parameters: a rate of 20 Hz and a triangle wave.
+pub struct Gargle<S>
+where
+    S: Source,
+{
+    source: S,
+    params: Arc<GargleParams>,
+    phase: f32,     // From 0.0 to 1.0
+    gain: f32,      // The gain for the current frame
+    channel: usize, // Which channel the next sample belongs to, since buffers don't have to end on a whole frame
+}
+
+/// Used for changing the parameters of a [`Gargle`] while it's playing. Get one with `Gargle::handle()`.
+#[derive(Clone)]
+pub struct GargleHandle(Arc<GargleParams>);
+
+struct GargleParams {
+    rate: AtomicU32,
+    waveform: AtomicU8,
+}
+
+impl<S> Gargle<S>
+where
+    S: Source,
+{
+    /// Creates a new Gargle with DirectSound's default parameters. They can be changed with a handle.
+    pub fn new(source: S) -> Self {
+        Self {
+            source,
+            params: Arc::new(GargleParams {
+                rate: AtomicU32::new(20),
+                waveform: AtomicU8::new(GargleWaveform::Triangle as u8),
+            }),
+            phase: 0.0,
+            gain: 0.0,
+            channel: 0,
+        }
+    }
+
+    /// Returns a handle for changing this Gargle's parameters while it's playing.
+    pub fn handle(&self) -> GargleHandle {
+        GargleHandle(self.params.clone())
+    }
+}
+
+impl<S> Source for Gargle<S>
+where
+    S: Source,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.source.channel_count()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.source.channel_layout()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        let count = self.source.write_samples(buffer);
+        let channels = usize::from(self.source.channel_count().get());
+        let sample_rate = u32::from(self.source.sample_rate()) as f32;
+        let rate = self.params.rate.load(Ordering::Acquire).clamp(MIN_RATE, MAX_RATE);
+        let increment = rate as f32 / sample_rate;
+        let waveform = GargleWaveform::from_u8(self.params.waveform.load(Ordering::Acquire));
+
+        for sample in buffer[..count].iter_mut() {
+            if self.channel == 0 {
+                self.gain = waveform.at(self.phase);
+                self.phase += increment;
+                if self.phase >= 1.0 {
+                    self.phase -= 1.0;
+                }
+            }
+            *sample *= self.gain;
+            self.channel = (self.channel + 1) % channels;
+        }
+        count
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.phase = 0.0;
+        self.channel = 0;
+    }
+}
+
+impl GargleWaveform {
+    #[inline]
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Triangle,
+            _ => Self::Square,
+        }
+    }
+
+    // Returns the oscillator's value, from 0.0 to 1.0, at a phase from 0.0 to 1.0
+    #[inline]
+    fn at(self, phase: f32) -> f32 {
+        match self {
+            Self::Triangle => 1.0 - 2.0 * (phase - 0.5).abs(),
+            Self::Square => (phase < 0.5) as u8 as f32,
+        }
+    }
+}
+
+impl GargleHandle {
+    /// Sets the rate of modulation in Hz, from 1 to 1000.
+    pub fn set_rate(&self, rate: u32) {
+        self.0.rate.store(rate, Ordering::Release)
+    }
+
+    /// Sets the shape of the oscillator which modulates the volume.
+    pub fn set_waveform(&self, waveform: GargleWaveform) {
+        self.0.waveform.store(waveform as u8, Ordering::Release)
+    }
+
+    /// Returns the most recently set rate in Hz.
+    pub fn rate(&self) -> u32 {
+        self.0.rate.load(Ordering::Acquire)
+    }
+
+    /// Returns the most recently set waveform.
+    pub fn waveform(&self) -> GargleWaveform {
+        GargleWaveform::from_u8(self.0.waveform.load(Ordering::Acquire))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Player;
+
+    fn gains(waveform: GargleWaveform) -> Vec<Sample> {
+        let ones = Player::new(ChannelCount::new(2).unwrap(), SampleRate::new(1000).unwrap(), vec![1.0; 20].into());
+        let mut gargle = Gargle::new(ones);
+        gargle.handle().set_rate(250);
+        gargle.handle().set_waveform(waveform);
+        let mut buffer = [0.0; 32];
+        assert_eq!(gargle.write_samples(&mut buffer), 20);
+        assert!(buffer[..20].chunks_exact(2).all(|x| x[0] == x[1]));
+        buffer[..20].iter().step_by(2).copied().collect()
+    }
+
+    #[test]
+    fn waveforms() {
+        assert_eq!(gains(GargleWaveform::Triangle)[..5], [0.0, 0.5, 1.0, 0.5, 0.0]);
+        assert_eq!(gains(GargleWaveform::Square)[..5], [1.0, 1.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn odd_buffers_keep_the_channels_in_step() {
+        let ones = Player::new(ChannelCount::new(2).unwrap(), SampleRate::new(1000).unwrap(), vec![1.0; 40].into());
+        let mut gargle = Gargle::new(ones);
+        gargle.handle().set_rate(250);
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 3];
+        while gargle.write_samples(&mut buffer) == buffer.len() {
+            output.extend_from_slice(&buffer);
+        }
+        let expected = [0.0, 0.5, 1.0, 0.5].iter().cycle().flat_map(|&x| [x, x]).take(output.len()).collect::<Vec<_>>();
+        assert_eq!(output, expected);
+    }
+}
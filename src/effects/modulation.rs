@@ -0,0 +1,479 @@
+use super::{delay::DelayLine, tail::Tail};
+use crate::{
+    frame::PartialFrame,
+    param::{AtomicF32, Smoothed, SMOOTHING_TIME},
+    source::{ChannelCount, ChannelLayout, Sample, SampleRate, Source},
+};
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    Arc,
+};
+
+// Parameter limits, which are the same as DirectSound's
+const MAX_FEEDBACK: f32 = 0.99;
+const MAX_FREQUENCY: f32 = 10.0;
+const CHORUS_MAX_DELAY: f32 = 0.02;
+const FLANGER_MAX_DELAY: f32 = 0.004;
+
+/// The shape of the low-frequency oscillator which sweeps the delay time of a [`Chorus`] or [`Flanger`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub enum ModulationWaveform {
+    Triangle,
+    Sine,
+}
+
+/// A chorus effect, which mixes the input with copies of itself on a short delay that slowly varies.
+///
+/// This matches DirectSound's chorus, and starts with the same default parameters. DirectSound's percentages map to
+/// 0.0 to 1.0 here, and its times in milliseconds are in seconds. The delay can be set up to 0.02 seconds.
+///
+/// Once the inner Source ends, the Chorus keeps playing until any feedback has died away.
+pub struct Chorus<S>(Modulation<S>)
+where
+    S: Source;
+
+/// A flanger effect, which mixes the input with a copy of itself on a very short delay that slowly varies.
+///
+/// This matches DirectSound's flanger, and starts with the same default parameters. DirectSound's percentages map to
+/// 0.0 to 1.0 here, and its times in milliseconds are in seconds. The delay can be set up to 0.004 seconds.
+///
+/// Once the inner Source ends, the Flanger keeps playing until any feedback has died away.
+pub struct Flanger<S>(Modulation<S>)
+where
+    S: Source;
+
+/// Used for changing the parameters of a [`Chorus`] or [`Flanger`] while it's playing.
+/// Get one with `Chorus::handle()` or `Flanger::handle()`.
+#[derive(Clone)]
+pub struct ModulationHandle(Arc<ModulationParams>);
+
+struct ModulationParams {
+    mix: AtomicF32,
+    depth: AtomicF32,
+    feedback: AtomicF32,
+    frequency: AtomicF32,
+    waveform: AtomicU8,
+    delay: AtomicF32,
+    phase: AtomicF32,
+}
+
+// The processing shared by Chorus and Flanger, which only differ in their defaults and delay limits
+struct Modulation<S>
+where
+    S: Source,
+{
+    source: S,
+    params: Arc<ModulationParams>,
+    max_delay: f32, // In frames
+    lines: Box<[DelayLine]>,
+    lfo_phase: f32, // From 0.0 to 1.0
+    mix: Smoothed,
+    depth: Smoothed,
+    feedback: Smoothed,
+    delay: Smoothed, // In frames
+    phase: Smoothed, // From -0.5 to 0.5
+    tail: Tail,
+    partial: PartialFrame,
+}
+
+impl<S> Chorus<S>
+where
+    S: Source,
+{
+    /// Creates a new Chorus with DirectSound's default parameters. They can be changed with a handle.
+    pub fn new(source: S) -> Self {
+        Self(Modulation::new(source, CHORUS_MAX_DELAY, ModulationParams {
+            mix: AtomicF32::new(0.5),
+            depth: AtomicF32::new(0.1),
+            feedback: AtomicF32::new(0.25),
+            frequency: AtomicF32::new(1.1),
+            waveform: AtomicU8::new(ModulationWaveform::Sine as u8),
+            delay: AtomicF32::new(0.016),
+            phase: AtomicF32::new(90.0),
+        }))
+    }
+
+    /// Returns a handle for changing this Chorus's parameters while it's playing.
+    pub fn handle(&self) -> ModulationHandle {
+        ModulationHandle(self.0.params.clone())
+    }
+}
+
+impl<S> Flanger<S>
+where
+    S: Source,
+{
+    /// Creates a new Flanger with DirectSound's default parameters. They can be changed with a handle.
+    pub fn new(source: S) -> Self {
+        Self(Modulation::new(source, FLANGER_MAX_DELAY, ModulationParams {
+            mix: AtomicF32::new(0.5),
+            depth: AtomicF32::new(1.0),
+            feedback: AtomicF32::new(-0.5),
+            frequency: AtomicF32::new(0.25),
+            waveform: AtomicU8::new(ModulationWaveform::Sine as u8),
+            delay: AtomicF32::new(0.002),
+            phase: AtomicF32::new(0.0),
+        }))
+    }
+
+    /// Returns a handle for changing this Flanger's parameters while it's playing.
+    pub fn handle(&self) -> ModulationHandle {
+        ModulationHandle(self.0.params.clone())
+    }
+}
+
+macro_rules! forward_source {
+    ($name:ident) => {
+        impl<S> Source for $name<S>
+        where
+            S: Source,
+        {
+            #[inline]
+            fn channel_count(&self) -> ChannelCount {
+                self.0.source.channel_count()
+            }
+
+            #[inline]
+            fn sample_rate(&self) -> SampleRate {
+                self.0.source.sample_rate()
+            }
+
+            #[inline]
+            fn channel_layout(&self) -> Option<ChannelLayout> {
+                self.0.source.channel_layout()
+            }
+
+            #[inline]
+            fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+                self.0.write_samples(buffer)
+            }
+
+            #[inline]
+            fn reset(&mut self) {
+                self.0.reset()
+            }
+        }
+    };
+}
+
+forward_source!(Chorus);
+forward_source!(Flanger);
+
+impl<S> Modulation<S>
+where
+    S: Source,
+{
+    fn new(source: S, max_delay: f32, params: ModulationParams) -> Self {
+        let channels = usize::from(source.channel_count().get());
+        let sample_rate = u32::from(source.sample_rate()) as f32;
+        let max_delay = max_delay * sample_rate;
+        let mix = clamp_mix(params.mix.load());
+        let depth = clamp_depth(params.depth.load());
+        let feedback = clamp_feedback(params.feedback.load());
+        let delay = clamp_delay(params.delay.load() * sample_rate, max_delay);
+        let phase = phase_fraction(params.phase.load());
+
+        // The delay swings up to twice its centre value at full depth
+        let line_len = (max_delay * 2.0).ceil() as usize + 1;
+        Self {
+            source,
+            params: Arc::new(params),
+            max_delay,
+            lines: (0..channels).map(|_| DelayLine::new(line_len)).collect(),
+            lfo_phase: 0.0,
+            mix: Smoothed::new(mix),
+            depth: Smoothed::new(depth),
+            feedback: Smoothed::new(feedback),
+            delay: Smoothed::new(delay),
+            phase: Smoothed::new(phase),
+            tail: Tail::new(line_len),
+            partial: PartialFrame::new(channels),
+        }
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        PartialFrame::write_with(self, buffer, |modulation| &mut modulation.partial, Self::write_frames)
+    }
+
+    fn write_frames(&mut self, buffer: &mut [Sample]) -> usize {
+        if self.tail.read(&mut self.source, buffer).is_none() {
+            return 0
+        }
+
+        let sample_rate = u32::from(self.source.sample_rate()) as f32;
+        let steps = (SMOOTHING_TIME * sample_rate) as u32;
+        self.mix.set_target(clamp_mix(self.params.mix.load()), steps);
+        self.depth.set_target(clamp_depth(self.params.depth.load()), steps);
+        self.feedback.set_target(clamp_feedback(self.params.feedback.load()), steps);
+        self.delay.set_target(clamp_delay(self.params.delay.load() * sample_rate, self.max_delay), steps);
+        self.phase.set_target(phase_fraction(self.params.phase.load()), steps);
+        let increment = self.params.frequency.load().clamp(0.0, MAX_FREQUENCY) / sample_rate;
+        let waveform = ModulationWaveform::from_u8(self.params.waveform.load(Ordering::Acquire));
+
+        let channels = self.lines.len();
+        for frame in buffer.chunks_exact_mut(channels) {
+            let mix = self.mix.next();
+            let depth = self.depth.next();
+            let feedback = self.feedback.next();
+            let delay = self.delay.next();
+            let phase = self.phase.next();
+
+            // Left and right are offset from each other by the phase, and any other channels follow one of them
+            let lfo = [waveform.at(self.lfo_phase), waveform.at((self.lfo_phase + phase).rem_euclid(1.0))];
+            for (i, (sample, line)) in frame.iter_mut().zip(self.lines.iter_mut()).enumerate() {
+                let wet = line.read(delay * (1.0 + depth * lfo[i % 2]));
+                line.write(*sample + wet * feedback);
+                *sample = *sample * (1.0 - mix) + wet * mix;
+            }
+
+            self.lfo_phase += increment;
+            if self.lfo_phase >= 1.0 {
+                self.lfo_phase -= 1.0;
+            }
+        }
+
+        self.tail.finish(buffer, channels)
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.lines.iter_mut().for_each(DelayLine::clear);
+        self.lfo_phase = 0.0;
+        self.tail.reset();
+        self.partial.reset();
+    }
+}
+
+impl ModulationWaveform {
+    #[inline]
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Triangle,
+            _ => Self::Sine,
+        }
+    }
+
+    // Returns the oscillator's value, from -1.0 to 1.0, at a phase from 0.0 to 1.0
+    #[inline]
+    fn at(self, phase: f32) -> f32 {
+        match self {
+            Self::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Self::Sine => (phase * std::f32::consts::TAU).sin(),
+        }
+    }
+}
+
+impl ModulationHandle {
+    /// Sets the balance between the original sound and the delayed copy, from 0.0 (dry only) to 1.0 (wet only).
+    pub fn set_mix(&self, mix: f32) {
+        self.0.mix.store(mix)
+    }
+
+    /// Sets how far the delay time sweeps either side of its centre value, from 0.0 to 1.0.
+    pub fn set_depth(&self, depth: f32) {
+        self.0.depth.store(depth)
+    }
+
+    /// Sets how much of the delayed copy is fed back into the delay, from -0.99 to 0.99.
+    pub fn set_feedback(&self, feedback: f32) {
+        self.0.feedback.store(feedback)
+    }
+
+    /// Sets the frequency of the oscillator which sweeps the delay time, in Hz from 0.0 to 10.0.
+    pub fn set_frequency(&self, frequency: f32) {
+        self.0.frequency.store(frequency)
+    }
+
+    /// Sets the shape of the oscillator which sweeps the delay time.
+    pub fn set_waveform(&self, waveform: ModulationWaveform) {
+        self.0.waveform.store(waveform as u8, Ordering::Release)
+    }
+
+    /// Sets the centre delay time in seconds.
+    pub fn set_delay(&self, delay: f32) {
+        self.0.delay.store(delay)
+    }
+
+    /// Sets the phase difference between the left and right oscillators in degrees, from -180.0 to 180.0.
+    pub fn set_phase(&self, phase: f32) {
+        self.0.phase.store(phase)
+    }
+
+    /// Returns the most recently set wet/dry mix.
+    pub fn mix(&self) -> f32 {
+        self.0.mix.load()
+    }
+
+    /// Returns the most recently set depth.
+    pub fn depth(&self) -> f32 {
+        self.0.depth.load()
+    }
+
+    /// Returns the most recently set feedback.
+    pub fn feedback(&self) -> f32 {
+        self.0.feedback.load()
+    }
+
+    /// Returns the most recently set oscillator frequency in Hz.
+    pub fn frequency(&self) -> f32 {
+        self.0.frequency.load()
+    }
+
+    /// Returns the most recently set oscillator waveform.
+    pub fn waveform(&self) -> ModulationWaveform {
+        ModulationWaveform::from_u8(self.0.waveform.load(Ordering::Acquire))
+    }
+
+    /// Returns the most recently set centre delay time in seconds.
+    pub fn delay(&self) -> f32 {
+        self.0.delay.load()
+    }
+
+    /// Returns the most recently set phase difference in degrees.
+    pub fn phase(&self) -> f32 {
+        self.0.phase.load()
+    }
+}
+
+#[inline]
+fn clamp_mix(mix: f32) -> f32 {
+    mix.clamp(0.0, 1.0)
+}
+
+#[inline]
+fn clamp_depth(depth: f32) -> f32 {
+    depth.clamp(0.0, 1.0)
+}
+
+#[inline]
+fn clamp_feedback(feedback: f32) -> f32 {
+    feedback.clamp(-MAX_FEEDBACK, MAX_FEEDBACK)
+}
+
+#[inline]
+fn clamp_delay(frames: f32, max_frames: f32) -> f32 {
+    frames.clamp(0.0, max_frames)
+}
+
+// Converts a phase in degrees to a fraction of a cycle
+#[inline]
+fn phase_fraction(degrees: f32) -> f32 {
+    degrees.clamp(-180.0, 180.0) / 360.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{effects::tail::SILENCE_THRESHOLD, Player};
+
+    fn impulse() -> Player {
+        let mut samples = vec![0.0; 10];
+        samples[0] = 1.0;
+        Player::new(ChannelCount::new(1).unwrap(), SampleRate::new(1000).unwrap(), samples.into())
+    }
+
+    // Reads until the Source ends, checking that it keeps returning 0 afterwards
+    fn play(source: &mut Modulation<Player>) -> Vec<Sample> {
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 16];
+        loop {
+            let count = source.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(source.write_samples(&mut buffer), 0);
+        output
+    }
+
+    fn assert_echoes(output: &[Sample], echoes: &[(usize, Sample)]) {
+        for (i, &x) in output.iter().enumerate() {
+            let expected = echoes.iter().find(|&&(at, _)| at == i).map_or(0.0, |&(_, x)| x);
+            assert!((x - expected).abs() < 1e-6, "frame {}: {} != {}", i, x, expected);
+        }
+    }
+
+    #[test]
+    fn waveforms() {
+        for &(phase, triangle, sine) in &[(0.0, -1.0, 0.0), (0.25, 0.0, 1.0), (0.5, 1.0, 0.0), (0.75, 0.0, -1.0)] {
+            assert!((ModulationWaveform::Triangle.at(phase) - triangle).abs() < 1e-6);
+            assert!((ModulationWaveform::Sine.at(phase) - sine).abs() < 1e-6);
+        }
+    }
+
+    // Sets the parameters up front, since changes made through a handle are ramped in
+    fn modulation(max_delay: f32, depth: f32, feedback: f32, delay: f32) -> Modulation<Player> {
+        Modulation::new(impulse(), max_delay, ModulationParams {
+            mix: AtomicF32::new(1.0),
+            depth: AtomicF32::new(depth),
+            feedback: AtomicF32::new(feedback),
+            frequency: AtomicF32::new(1.0),
+            waveform: AtomicU8::new(ModulationWaveform::Sine as u8),
+            delay: AtomicF32::new(delay),
+            phase: AtomicF32::new(0.0),
+        })
+    }
+
+    #[test]
+    fn delays_the_input() {
+        // With no depth, this is a plain delay of 16 frames
+        let output = play(&mut modulation(CHORUS_MAX_DELAY, 0.0, 0.0, 0.016));
+        assert_echoes(&output, &[(16, 1.0)]);
+    }
+
+    #[test]
+    fn negative_feedback_inverts() {
+        // Each echo 2 frames apart flips sign and halves
+        let output = play(&mut modulation(FLANGER_MAX_DELAY, 0.0, -0.5, 0.002));
+        let echoes = (1..20).map(|k| (k * 2, (-0.5f32).powi(k as i32 - 1))).collect::<Vec<_>>();
+        assert_echoes(&output, &echoes);
+        assert!(output.len() < 100, "tail never ended");
+    }
+
+    #[test]
+    fn depth_sweeps_the_delay() {
+        let swept = play(&mut modulation(FLANGER_MAX_DELAY, 1.0, 0.0, 0.002));
+        let fixed = play(&mut modulation(FLANGER_MAX_DELAY, 0.0, 0.0, 0.002));
+        assert_ne!(swept, fixed);
+        assert_eq!(fixed.iter().filter(|&&x| x != 0.0).count(), 1);
+    }
+
+    #[test]
+    fn defaults() {
+        let chorus = Chorus::new(impulse()).handle();
+        assert_eq!((chorus.mix(), chorus.depth(), chorus.feedback()), (0.5, 0.1, 0.25));
+        assert_eq!((chorus.frequency(), chorus.delay(), chorus.phase()), (1.1, 0.016, 90.0));
+        assert_eq!(chorus.waveform(), ModulationWaveform::Sine);
+        let flanger = Flanger::new(impulse()).handle();
+        assert_eq!((flanger.mix(), flanger.depth(), flanger.feedback()), (0.5, 1.0, -0.5));
+        assert_eq!((flanger.frequency(), flanger.delay(), flanger.phase()), (0.25, 0.002, 0.0));
+    }
+
+    #[test]
+    fn odd_buffers_keep_playing() {
+        let stereo = || {
+            let samples = (0..200).map(|x| if x % 2 == 0 { 1.0 } else { -0.5 } * (x as f32 * 0.1).sin()).collect();
+            Chorus::new(Player::new(ChannelCount::new(2).unwrap(), SampleRate::new(1000).unwrap(), samples)).0
+        };
+        let expected = play(&mut stereo());
+        let mut chorus = stereo();
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 7];
+        loop {
+            let count = chorus.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(chorus.write_samples(&mut buffer), 0);
+        assert_eq!(output.len() % 2, 0, "ended partway through a frame");
+
+        // Only the silence at the end of the tail can come out a different length
+        let length = output.len().min(expected.len());
+        assert_eq!(output[..length], expected[..length]);
+        assert!(output[length..].iter().chain(&expected[length..]).all(|x| x.abs() < SILENCE_THRESHOLD));
+    }
+}
@@ -0,0 +1,114 @@
+use crate::{
+    resampler::{sinc_filter, sinc_order},
+    simd,
+    source::Sample,
+};
+
+// Filter design: flat up to 0.45 of the original sample rate, and 80 dB of rejection from 0.5 onwards
+const CUTOFF: f64 = 0.475;
+const TRANSITION_WIDTH: f64 = 0.05;
+const REJECTION: f64 = 80.0;
+
+/// Runs a nonlinear function at a multiple of a Source's sample rate, so that the harmonics it adds above the
+/// original Nyquist frequency are filtered out instead of aliasing back down.
+pub(crate) struct Oversampler {
+    factor: usize,
+    up_phases: Box<[Box<[f32]>]>, // Upsampling filter split into one phase per output, each in oldest-first order
+    down: Box<[f32]>,             // Downsampling filter, in oldest-first order
+    channels: Box<[(History, History)]>, // Input history and oversampled history for each channel
+    scratch: Box<[Sample]>,
+}
+
+// A sliding window over the most recent samples, stored twice over so it can always be read as one slice
+struct History {
+    buffer: Box<[Sample]>,
+    position: usize,
+}
+
+impl Oversampler {
+    /// `factor` must be at least 2.
+    pub(crate) fn new(factor: usize, channels: usize) -> Self {
+        assert!(factor >= 2);
+        // An odd length which is a multiple of `factor` plus 1 delays the signal by a whole number of samples
+        let len = sinc_order(TRANSITION_WIDTH / factor as f64, REJECTION).next_multiple_of(factor) + 1;
+        let left = (len / 2) as u32;
+        let taps = |from: u32, to: u32| -> Vec<f32> {
+            (0..len as u32).rev().map(|i| sinc_filter(left, from, to, CUTOFF, REJECTION, i) as f32).collect()
+        };
+
+        // Zero-stuffing means each oversampled output only sees every `factor`th tap
+        let up = taps(1, factor as u32);
+        let phase_len = len.div_ceil(factor);
+        let up_phases = (0..factor)
+            .map(|p| {
+                let mut phase = vec![0.0; phase_len];
+                for (k, tap) in up.iter().rev().skip(p).step_by(factor).enumerate() {
+                    phase[phase_len - 1 - k] = *tap;
+                }
+                phase.into_boxed_slice()
+            })
+            .collect();
+
+        Self {
+            factor,
+            up_phases,
+            down: taps(factor as u32, 1).into_boxed_slice(),
+            channels: (0..channels).map(|_| (History::new(phase_len), History::new(len))).collect(),
+            scratch: vec![0.0; factor].into_boxed_slice(),
+        }
+    }
+
+    /// Upsamples one sample of `channel`, applies `f` to each oversampled sample, and downsamples the result.
+    #[inline]
+    pub(crate) fn process(&mut self, channel: usize, sample: Sample, mut f: impl FnMut(Sample) -> Sample) -> Sample {
+        let (input, output) = &mut self.channels[channel];
+        input.push(sample);
+        for (x, phase) in self.scratch.iter_mut().zip(self.up_phases.iter()) {
+            *x = f(simd::dot(input.window(), phase, 1));
+        }
+
+        // Only every `factor`th output of the downsampling filter is kept, so only that one is calculated.
+        // It's the one lined up with the input sample, rather than any of the ones in between.
+        output.push(self.scratch[0]);
+        let sample = simd::dot(output.window(), &self.down, 1);
+        self.scratch[1..].iter().for_each(|&x| output.push(x));
+        sample
+    }
+
+    /// Returns how many samples, at the original rate, the oversampling delays the signal by.
+    pub(crate) fn latency(&self) -> usize {
+        (self.down.len() - 1) / self.factor
+    }
+
+    pub(crate) fn reset(&mut self) {
+        for (input, output) in self.channels.iter_mut() {
+            input.clear();
+            output.clear();
+        }
+    }
+}
+
+impl History {
+    fn new(len: usize) -> Self {
+        Self { buffer: vec![0.0; len * 2].into_boxed_slice(), position: 0 }
+    }
+
+    #[inline]
+    fn push(&mut self, sample: Sample) {
+        let len = self.buffer.len() / 2;
+        self.buffer[self.position] = sample;
+        self.buffer[self.position + len] = sample;
+        self.position = (self.position + 1) % len;
+    }
+
+    // Returns the window, from the oldest sample to the newest
+    #[inline]
+    fn window(&self) -> &[Sample] {
+        let len = self.buffer.len() / 2;
+        &self.buffer[self.position..self.position + len]
+    }
+
+    fn clear(&mut self) {
+        self.buffer.iter_mut().for_each(|x| *x = 0.0);
+    }
+}
@@ -0,0 +1,302 @@
+use crate::{
+    frame::PartialFrame,
+    param::{AtomicF32, Smoothed, SMOOTHING_TIME},
+    rechanneler::MixMatrix,
+    source::{consts::CH_STEREO, ChannelCount, ChannelLayout, Sample, SampleRate, Source},
+};
+use std::{
+    f32::consts::FRAC_PI_4,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+};
+
+/// How the volume of each side changes as a [`Pan`] moves a sound across the stereo field.
+///
+/// Each law is named after how much quieter each side is with the sound panned to the centre.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub enum PanLaw {
+    /// Both sides stay at full volume in the centre, and the far side fades out linearly as the sound moves away
+    /// from it. Sounds in the centre come out noticeably louder than sounds at either side.
+    Linear,
+
+    /// The sides follow a quarter of a sine and cosine wave, so the total power stays constant as the sound moves.
+    /// This is usually the most natural-sounding law for speakers.
+    #[default]
+    Minus3Db,
+
+    /// A compromise between -3 dB and -6 dB, which some mixing desks use.
+    Minus4_5Db,
+
+    /// The sides crossfade linearly, so the total amplitude stays constant. This suits sounds that will be mixed
+    /// down to mono later, where a -3 dB law would come out louder in the centre.
+    Minus6Db,
+}
+
+/// Positions a Source in the stereo field.
+///
+/// The output is always stereo. Mono Sources are panned according to a [`PanLaw`]. Stereo Sources first have their
+/// width adjusted, then their balance: panning turns down the far side along the pan law's curve, and leaves the near
+/// side at full volume.
+/// Sources with more than two channels are mixed down to stereo first, in the same way as a `Rechanneler` would.
+pub struct Pan<S>
+where
+    S: Source,
+{
+    source: S,
+    params: Arc<PanParams>,
+    downmix: Option<MixMatrix>,
+    buffer: Vec<Sample>,
+    pan: Smoothed,
+    width: Smoothed,
+    partial: PartialFrame,
+}
+
+/// Used for changing the parameters of a [`Pan`] while it's playing. Get one with `Pan::handle()`.
+#[derive(Clone)]
+pub struct PanHandle(Arc<PanParams>);
+
+struct PanParams {
+    pan: AtomicF32,
+    width: AtomicF32,
+    law: AtomicU8,
+}
+
+impl<S> Pan<S>
+where
+    S: Source,
+{
+    /// Creates a new Pan. `pan` goes from -1.0 (left) to 1.0 (right).
+    pub fn new(source: S, pan: f32, law: PanLaw) -> Self {
+        let channels = source.channel_count();
+        let downmix = if channels.get() > 2 {
+            let layout = source.channel_layout().or_else(|| ChannelLayout::default_for(channels));
+            Some(match layout {
+                Some(layout) => MixMatrix::between(&layout, &ChannelLayout::STEREO),
+                None => MixMatrix::average(channels, CH_STEREO),
+            })
+        } else {
+            None
+        };
+        let pan = clamp_pan(pan);
+        Self {
+            source,
+            params: Arc::new(PanParams {
+                pan: AtomicF32::new(pan),
+                width: AtomicF32::new(1.0),
+                law: AtomicU8::new(law as u8),
+            }),
+            downmix,
+            buffer: Vec::new(),
+            pan: Smoothed::new(pan),
+            width: Smoothed::new(1.0),
+            partial: PartialFrame::new(2),
+        }
+    }
+
+    /// Returns a handle for changing this Pan's parameters while it's playing.
+    pub fn handle(&self) -> PanHandle {
+        PanHandle(self.params.clone())
+    }
+
+    // Writes a whole number of frames to `buffer`
+    fn write_frames(&mut self, buffer: &mut [Sample]) -> usize {
+        let channels = usize::from(self.source.channel_count().get());
+        let frames = buffer.len() / 2;
+        let count = if channels == 2 {
+            self.source.write_samples(buffer) / 2
+        } else {
+            self.buffer.resize(frames * channels, 0.0);
+            let count = self.source.write_samples(&mut self.buffer) / channels;
+            for (input, output) in self.buffer.chunks_exact(channels).zip(buffer.chunks_exact_mut(2)).take(count) {
+                match self.downmix.as_ref() {
+                    Some(matrix) => matrix.apply(input, output),
+                    None => output[0] = input[0],
+                }
+            }
+            count
+        };
+
+        let sample_rate = u32::from(self.source.sample_rate()) as f32;
+        let steps = (SMOOTHING_TIME * sample_rate) as u32;
+        self.pan.set_target(clamp_pan(self.params.pan.load()), steps);
+        self.width.set_target(self.params.width.load().clamp(0.0, 2.0), steps);
+        let law = PanLaw::from_u8(self.params.law.load(Ordering::Acquire));
+
+        for frame in buffer[..count * 2].chunks_exact_mut(2) {
+            let pan = self.pan.next();
+            let width = self.width.next();
+            if channels == 1 {
+                let (left, right) = law.gains(pan);
+                frame[1] = frame[0] * right;
+                frame[0] *= left;
+            } else {
+                let mid = (frame[0] + frame[1]) * 0.5;
+                let side = (frame[0] - frame[1]) * 0.5 * width;
+                let (left, right) = law.balance(pan);
+                frame[0] = (mid + side) * left;
+                frame[1] = (mid - side) * right;
+            }
+        }
+
+        count * 2
+    }
+}
+
+impl<S> Source for Pan<S>
+where
+    S: Source,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        CH_STEREO
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        Some(ChannelLayout::STEREO)
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        // A buffer can end halfway through a frame, in which case the other half starts the next one
+        PartialFrame::write_with(self, buffer, |pan| &mut pan.partial, Self::write_frames)
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.partial.reset();
+    }
+}
+
+impl PanLaw {
+    #[inline]
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Linear,
+            1 => Self::Minus3Db,
+            2 => Self::Minus4_5Db,
+            _ => Self::Minus6Db,
+        }
+    }
+
+    // Returns the (left, right) gains for panning a mono sound to `pan`
+    #[inline]
+    fn gains(self, pan: f32) -> (f32, f32) {
+        let (linear_left, linear_right) = ((1.0 - pan) * 0.5, (1.0 + pan) * 0.5);
+        let (sin, cos) = ((pan + 1.0) * FRAC_PI_4).sin_cos();
+        let (sin, cos) = (sin.max(0.0), cos.max(0.0)); // cos(pi / 2) comes out very slightly negative
+        match self {
+            Self::Linear => ((1.0 - pan).min(1.0), (1.0 + pan).min(1.0)),
+            Self::Minus3Db => (cos, sin),
+            Self::Minus4_5Db => ((linear_left * cos).sqrt(), (linear_right * sin).sqrt()),
+            Self::Minus6Db => (linear_left, linear_right),
+        }
+    }
+
+    // Returns the (left, right) gains for shifting the balance of a stereo sound, which are scaled so that the centre
+    // leaves both sides untouched and neither side ever gets louder
+    #[inline]
+    fn balance(self, pan: f32) -> (f32, f32) {
+        let (left, right) = self.gains(pan);
+        let (centre, _) = self.gains(0.0);
+        ((left / centre).min(1.0), (right / centre).min(1.0))
+    }
+}
+
+impl PanHandle {
+    /// Sets the position in the stereo field, from -1.0 (left) to 1.0 (right).
+    pub fn set_pan(&self, pan: f32) {
+        self.0.pan.store(pan)
+    }
+
+    /// Sets the stereo width, from 0.0 (mono) through 1.0 (unchanged) to 2.0 (extra wide).
+    /// This only affects Sources with more than one channel.
+    pub fn set_width(&self, width: f32) {
+        self.0.width.store(width)
+    }
+
+    /// Sets the pan law. Unlike the other parameters, this takes effect immediately.
+    pub fn set_law(&self, law: PanLaw) {
+        self.0.law.store(law as u8, Ordering::Release)
+    }
+
+    /// Returns the most recently set position.
+    pub fn pan(&self) -> f32 {
+        self.0.pan.load()
+    }
+
+    /// Returns the most recently set stereo width.
+    pub fn width(&self) -> f32 {
+        self.0.width.load()
+    }
+
+    /// Returns the most recently set pan law.
+    pub fn law(&self) -> PanLaw {
+        PanLaw::from_u8(self.0.law.load(Ordering::Acquire))
+    }
+}
+
+#[inline]
+fn clamp_pan(pan: f32) -> f32 {
+    pan.clamp(-1.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{source::linear_to_db, Player};
+
+    fn player(channels: u16, samples: &[Sample]) -> Player {
+        Player::new(ChannelCount::new(channels).unwrap(), SampleRate::new(44100).unwrap(), samples.into())
+    }
+
+    #[test]
+    fn pan_law_centre_gains() {
+        let expected = [(PanLaw::Linear, 1.0), (PanLaw::Minus3Db, 0.70710677), (PanLaw::Minus6Db, 0.5)];
+        for &(law, gain) in &expected {
+            let (left, right) = law.gains(0.0);
+            assert!((left - gain).abs() < 1e-6 && (right - gain).abs() < 1e-6, "{:?} gave {} {}", law, left, right);
+        }
+        let (left, right) = PanLaw::Minus4_5Db.gains(0.0);
+        assert!((linear_to_db(left) + 4.5).abs() < 0.05 && (left - right).abs() < 1e-6);
+
+        for &law in &[PanLaw::Linear, PanLaw::Minus3Db, PanLaw::Minus4_5Db, PanLaw::Minus6Db] {
+            assert_eq!(law.gains(-1.0), (1.0, 0.0), "{:?} should be fully left", law);
+            assert_eq!(law.gains(1.0), (0.0, 1.0), "{:?} should be fully right", law);
+            assert_eq!(law.balance(0.0), (1.0, 1.0), "{:?} should leave the centre untouched", law);
+        }
+    }
+
+    #[test]
+    fn odd_buffers_keep_playing() {
+        // Each buffer carries on from the last, with the left and right channels staying in place
+        let mut pan = Pan::new(player(1, &[1.0, 0.5, 0.25, 0.125]), -1.0, PanLaw::Minus3Db);
+        let mut buffer = [-1.0; 5];
+        assert_eq!(pan.write_samples(&mut buffer), 5);
+        assert_eq!(buffer, [1.0, 0.0, 0.5, 0.0, 0.25]);
+        assert_eq!(pan.write_samples(&mut buffer), 3);
+        assert_eq!(buffer[..3], [0.0, 0.125, 0.0]);
+        assert_eq!(pan.write_samples(&mut buffer), 0);
+
+        let samples = (0..8).map(|x| x as Sample).collect::<Vec<_>>();
+        let mut pan = Pan::new(player(2, &samples), 0.0, PanLaw::Minus3Db);
+        let mut buffer = [-1.0; 3];
+        let mut output = Vec::new();
+        loop {
+            let count = pan.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(output, samples);
+        assert_eq!(pan.write_samples(&mut buffer), 0);
+    }
+}
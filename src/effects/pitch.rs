@@ -0,0 +1,651 @@
+use super::stretch::{TimeStretch, TimeStretchHandle};
+use crate::{
+    frame::PartialFrame,
+    param::{AtomicF32, Smoothed, SMOOTHING_TIME},
+    resampler::{sinc_filter, sinc_order},
+    simd,
+    source::{ChannelCount, ChannelLayout, Sample, SampleRate, Source},
+};
+use std::{
+    collections::VecDeque,
+    f32::consts::TAU,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+// The furthest the pitch can be shifted either way, in semitones, which matches the limits of TimeStretch
+const MAX_SHIFT: f32 = 24.0;
+
+// How many frames are read from the TimeStretch at a time
+const CHUNK_FRAMES: usize = 256;
+
+// Interpolation filter design: a transition band from 0.45 to 0.55 of the lower of the two sample rates, and 60 dB of
+// rejection past that. Centring it on 0.5 puts its zeros on whole frames, so an unshifted Source passes through
+// unchanged. When shifting up, the filter is stretched out to cut off at the new Nyquist frequency instead of aliasing.
+const CUTOFF: f64 = 0.5;
+const TRANSITION_WIDTH: f64 = 0.1;
+const REJECTION: f64 = 60.0;
+
+// How many steps per frame the interpolation filter is stored at. Values in between are linearly interpolated.
+const FILTER_STEPS: u32 = 32;
+
+// Formant preservation: the spectral envelope is measured by linear prediction of this order, over a window of
+// FORMANT_WINDOW_TIME seconds, every FORMANT_HOP_TIME seconds
+const FORMANT_ORDER: usize = 24;
+const FORMANT_WINDOW_TIME: f32 = 0.02;
+const FORMANT_HOP_TIME: f32 = 0.005;
+
+// The envelope is smoothed over this many Hz, so that it follows the formants rather than the individual harmonics of
+// a voice, and given a floor this far below its peak, in dB, so the filter which puts it back doesn't ring
+const ENVELOPE_SMOOTHING: f64 = 100.0;
+const ENVELOPE_FLOOR: f64 = 40.0;
+
+// How many hops formant preservation takes to fade in or out when it's switched on or off
+const FORMANT_FADE_HOPS: f32 = 8.0;
+
+/// Changes the pitch of a Source without changing how long it plays for.
+///
+/// The Source is time-stretched by the same ratio as the pitch change, then played back faster or slower to bring it
+/// back to its original length. Formants shift along with the pitch, so a voice shifted up sounds like a chipmunk and
+/// a voice shifted down sounds like a giant, rather than like the same person singing higher or lower.
+///
+/// Formant preservation can be turned on with `PitchShiftHandle::set_preserve_formants()`, to keep a voice sounding
+/// like the same person. The spectral envelope is measured by linear prediction and filtered out before the
+/// time-stretched Source is played back faster or slower, then put back afterwards, so only the harmonics move. This
+/// works best on a single voice or instrument.
+///
+/// The shift is set in semitones and cents, which are added together, and can be changed while playing. The total
+/// can be anywhere from two octaves down to two octaves up. See [`TimeStretch`] for which sounds this works best on.
+/// When shifting up, anything that would end up above the Nyquist frequency is filtered out rather than aliasing.
+pub struct PitchShift<S>
+where
+    S: Source,
+{
+    source: TimeStretch<S>,
+    stretch: TimeStretchHandle,
+    params: Arc<PitchShiftParams>,
+    ratio: Smoothed,
+    input: Vec<Sample>,
+    frames: usize,
+    position: usize,
+    filter: Box<[f32]>, // The interpolation filter, stored at FILTER_STEPS per frame
+    reach: usize,       // How many frames either side of the current position the filter can reach at most
+    history: Box<[Sample]>, // The frames around the current position, stored twice over so it can be read as one slice
+    history_start: usize,   // Where the oldest frame of the history is
+    weights: Vec<f32>,
+    fraction: f32, // How far the output is past the middle frame of the history, at `reach - 1`
+    ended: bool,
+    flushed: usize, // How many frames of silence have been shifted in since the Source ended
+    started: bool,
+    shifted: usize, // How many frames have been shifted into the history
+    formants: Formants,
+    partial: PartialFrame,
+}
+
+/// Used for changing the parameters of a [`PitchShift`] while it's playing. Get one with `PitchShift::handle()`.
+#[derive(Clone)]
+pub struct PitchShiftHandle(Arc<PitchShiftParams>);
+
+struct PitchShiftParams {
+    semitones: AtomicF32,
+    cents: AtomicF32,
+    preserve_formants: AtomicBool,
+}
+
+// Flattens out the spectral envelope of each frame as it goes into the history, then puts it back on the output. Each
+// envelope is queued up along with the frame it starts at, so it's put back on the output from the same frames.
+struct Formants {
+    enabled: bool,
+    window: Box<[f32]>,
+    lag_window: [f64; FORMANT_ORDER + 1],
+    recent: Box<[f32]>, // A mono mixdown of the last window's worth of frames, for measuring the envelope
+    recent_start: usize,
+    windowed: Box<[f64]>,
+    hop: usize,
+    frames: usize, // How many frames have been flattened
+    next_measure: usize,
+    strength: f32,                 // How far formant preservation has faded in
+    flatten: [f32; FORMANT_ORDER], // The envelope being taken out as reflection coefficients, all zero when disabled
+    restore: [f32; FORMANT_ORDER], // The envelope being put back
+    envelopes: VecDeque<(usize, [f32; FORMANT_ORDER])>,
+    flattening: Box<[[f32; FORMANT_ORDER]]>, // The state of each channel's filters
+    restoring: Box<[[f32; FORMANT_ORDER]]>,
+}
+
+impl<S> PitchShift<S>
+where
+    S: Source,
+{
+    /// Creates a new PitchShift, which shifts the pitch up by `semitones` plus `cents`. Either can be negative to
+    /// shift the pitch down.
+    pub fn new(source: S, semitones: f32, cents: f32) -> Self {
+        let params = PitchShiftParams {
+            semitones: AtomicF32::new(semitones),
+            cents: AtomicF32::new(cents),
+            preserve_formants: AtomicBool::new(false),
+        };
+        let ratio = params.ratio();
+        let channels = usize::from(source.channel_count().get());
+        let sample_rate = source.sample_rate();
+        let source = TimeStretch::new(source, ratio);
+
+        // An odd length puts the middle of the filter on a whole step
+        let length = sinc_order(TRANSITION_WIDTH / f64::from(FILTER_STEPS), REJECTION) | 1;
+        let left = (length / 2) as u32;
+        let mut filter = (0..length as u32)
+            .map(|i| sinc_filter(left, 1, FILTER_STEPS, CUTOFF, REJECTION, i) as f32)
+            .collect::<Vec<_>>();
+        filter.push(0.0); // So the last step can be interpolated towards something
+        let reach = (left as f32 / FILTER_STEPS as f32 * 2.0f32.powf(MAX_SHIFT / 12.0)).ceil() as usize + 1;
+        Self {
+            stretch: source.handle(),
+            source,
+            params: Arc::new(params),
+            ratio: Smoothed::new(ratio),
+            input: vec![0.0; CHUNK_FRAMES * channels],
+            frames: 0,
+            position: 0,
+            filter: filter.into(),
+            reach,
+            history: vec![0.0; 4 * reach * channels].into(),
+            history_start: 0,
+            weights: Vec::with_capacity(2 * reach),
+            fraction: (reach + 1) as f32, // Fills the history so the first frame comes out first, rather than later
+            ended: false,
+            flushed: 0,
+            started: false,
+            shifted: 0,
+            formants: Formants::new(channels, sample_rate),
+            partial: PartialFrame::new(channels),
+        }
+    }
+
+    /// Returns a handle for changing this PitchShift's parameters while it's playing.
+    pub fn handle(&self) -> PitchShiftHandle {
+        PitchShiftHandle(self.params.clone())
+    }
+
+    // Shifts the next frame of the TimeStretch into the history, then silence once it's ended, until the last real
+    // frame has moved out of the middle of the history. Returns false once that's happened.
+    #[inline]
+    fn read(&mut self) -> bool {
+        let channels = usize::from(self.source.channel_count().get());
+        if self.position == self.frames {
+            if self.ended {
+                self.flushed += 1;
+                if self.flushed > self.reach {
+                    return false
+                }
+            } else {
+                let count = self.source.write_samples(&mut self.input);
+                self.ended = count < self.input.len();
+                self.frames = count / channels;
+                self.position = 0;
+                return self.read()
+            }
+        }
+
+        let length = 2 * self.reach;
+        let start = self.history_start * channels;
+        let last = &mut self.history[start..start + channels];
+        if self.position < self.frames {
+            last.copy_from_slice(&self.input[self.position * channels..(self.position + 1) * channels]);
+        } else {
+            last.iter_mut().for_each(|x| *x = 0.0);
+        }
+        self.formants.flatten(last);
+        self.history.copy_within(start..start + channels, start + length * channels);
+        self.history_start = (self.history_start + 1) % length;
+        self.position = (self.position + 1).min(self.frames);
+        self.shifted += 1;
+        true
+    }
+
+    // Fills `weights` with the interpolation filter for the current position, which cuts off at the lower of the two
+    // sample rates, and returns the first frame of the history it applies to
+    #[inline]
+    fn update_weights(&mut self, ratio: f32) -> usize {
+        let scale = ratio.max(1.0);
+        let left = (self.filter.len() / 2 - 1) as f32; // Not counting the padding at the end
+        let centre = (self.reach - 1) as f32 + self.fraction;
+        let half_width = left / FILTER_STEPS as f32 * scale;
+        let first = (centre - half_width).ceil().max(0.0) as usize;
+        let last = ((centre + half_width).floor() as usize).min(2 * self.reach - 1);
+
+        self.weights.clear();
+        let step = FILTER_STEPS as f32 / scale;
+        for frame in first..=last {
+            let index = ((frame as f32 - centre) * step + left).clamp(0.0, 2.0 * left);
+            let (whole, fraction) = (index as usize, index.fract());
+            let weight = self.filter[whole] + (self.filter[whole + 1] - self.filter[whole]) * fraction;
+            self.weights.push(weight);
+        }
+
+        // Normalised so the gain doesn't ripple with the position
+        let sum = self.weights.iter().sum::<f32>();
+        if sum != 0.0 {
+            self.weights.iter_mut().for_each(|x| *x /= sum);
+        }
+        first
+    }
+
+    fn write_frames(&mut self, buffer: &mut [Sample]) -> usize {
+        let channels = usize::from(self.source.channel_count().get());
+        let sample_rate = u32::from(self.source.sample_rate()) as f32;
+        let ratio = self.params.ratio();
+        self.stretch.set_stretch(ratio);
+        self.ratio.set_target(ratio, (SMOOTHING_TIME * sample_rate) as u32);
+        if !self.started {
+            self.ratio.settle();
+            self.started = true;
+        }
+        self.formants.enabled = self.params.preserve_formants.load(Ordering::Acquire);
+
+        for (i, frame) in buffer.chunks_exact_mut(channels).enumerate() {
+            while self.fraction >= 1.0 {
+                if !self.read() {
+                    return i * channels
+                }
+                self.fraction -= 1.0;
+            }
+
+            let ratio = self.ratio.next();
+            let first = self.update_weights(ratio);
+            let history = &self.history[(self.history_start + first) * channels..];
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                *sample = simd::dot(&history[channel..], &self.weights, channels);
+            }
+            let centre = (self.shifted - self.reach - 1) as f32 + self.fraction;
+            self.formants.restore(frame, centre);
+            self.fraction += ratio;
+        }
+        buffer.len()
+    }
+}
+
+impl<S> Source for PitchShift<S>
+where
+    S: Source,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.source.channel_count()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.source.channel_layout()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        PartialFrame::write_with(self, buffer, |shift| &mut shift.partial, Self::write_frames)
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.frames = 0;
+        self.position = 0;
+        self.history.iter_mut().for_each(|x| *x = 0.0);
+        self.history_start = 0;
+        self.fraction = (self.reach + 1) as f32;
+        self.ended = false;
+        self.flushed = 0;
+        self.started = false;
+        self.shifted = 0;
+        self.formants.reset();
+        self.partial.reset();
+    }
+}
+
+impl PitchShiftParams {
+    // Returns how much faster the shifted Source oscillates than the original
+    #[inline]
+    fn ratio(&self) -> f32 {
+        let semitones = self.semitones.load() + self.cents.load() / 100.0;
+        2.0f32.powf(semitones.clamp(-MAX_SHIFT, MAX_SHIFT) / 12.0)
+    }
+}
+
+impl Formants {
+    fn new(channels: usize, sample_rate: SampleRate) -> Self {
+        let sample_rate = u32::from(sample_rate) as f32;
+        let length = ((FORMANT_WINDOW_TIME * sample_rate) as usize).max(FORMANT_ORDER + 1);
+        Self {
+            enabled: false,
+            window: (0..length).map(|i| 0.5 - 0.5 * (TAU * (i as f32 + 0.5) / length as f32).cos()).collect(),
+            lag_window: std::array::from_fn(|lag| {
+                let width = std::f64::consts::TAU * ENVELOPE_SMOOTHING * lag as f64 / f64::from(sample_rate);
+                (-0.5 * width * width).exp()
+            }),
+            recent: vec![0.0; length].into(),
+            recent_start: 0,
+            windowed: vec![0.0; length].into(),
+            hop: ((FORMANT_HOP_TIME * sample_rate) as usize).max(1),
+            frames: 0,
+            next_measure: 0,
+            strength: 0.0,
+            flatten: [0.0; FORMANT_ORDER],
+            restore: [0.0; FORMANT_ORDER],
+            envelopes: VecDeque::new(),
+            flattening: vec![[0.0; FORMANT_ORDER]; channels].into(),
+            restoring: vec![[0.0; FORMANT_ORDER]; channels].into(),
+        }
+    }
+
+    // Takes the envelope out of the next frame going into the history, measuring a new one every hop
+    #[inline]
+    fn flatten(&mut self, frame: &mut [Sample]) {
+        if self.frames == self.next_measure {
+            self.measure();
+            self.next_measure += self.hop;
+            self.envelopes.push_back((self.frames, self.flatten));
+        }
+        self.recent[self.recent_start] = frame.iter().sum::<f32>() / frame.len() as f32;
+        self.recent_start = (self.recent_start + 1) % self.recent.len();
+        for (sample, lattice) in frame.iter_mut().zip(self.flattening.iter_mut()) {
+            *sample = analysis(*sample, &self.flatten, lattice);
+        }
+        self.frames += 1;
+    }
+
+    // Puts the envelope back on a frame of output, where `position` is the frame of the history it was taken from
+    #[inline]
+    fn restore(&mut self, frame: &mut [Sample], position: f32) {
+        while let Some(&(start, envelope)) = self.envelopes.front() {
+            if start as f32 > position {
+                break
+            }
+            self.restore = envelope;
+            self.envelopes.pop_front();
+        }
+        for (sample, lattice) in frame.iter_mut().zip(self.restoring.iter_mut()) {
+            *sample = synthesis(*sample, &self.restore, lattice);
+        }
+    }
+
+    // Measures the envelope of the last window of frames, if formants are being preserved. Scaling the reflection
+    // coefficients down flattens out the envelope, so that's how it fades in and out.
+    fn measure(&mut self) {
+        let target: f32 = if self.enabled { 1.0 } else { 0.0 };
+        let step = 1.0 / FORMANT_FADE_HOPS;
+        self.strength = target.clamp(self.strength - step, self.strength + step);
+        self.flatten = [0.0; FORMANT_ORDER];
+        if self.strength > 0.0 {
+            let (older, newer) = self.recent.split_at(self.recent_start);
+            let recent = newer.iter().chain(older.iter());
+            for ((x, &y), &weight) in self.windowed.iter_mut().zip(recent).zip(self.window.iter()) {
+                *x = f64::from(y * weight);
+            }
+            let mut correlation = [0.0; FORMANT_ORDER + 1];
+            for ((lag, x), &weight) in correlation.iter_mut().enumerate().zip(self.lag_window.iter()) {
+                *x = self.windowed.iter().zip(&self.windowed[lag..]).map(|(a, b)| a * b).sum::<f64>() * weight;
+            }
+            correlation[0] *= 1.0 + 10.0f64.powf(-ENVELOPE_FLOOR / 10.0);
+            linear_prediction(&correlation, &mut self.flatten);
+            let strength = self.strength;
+            self.flatten.iter_mut().for_each(|x| *x *= strength);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.recent.iter_mut().for_each(|x| *x = 0.0);
+        self.recent_start = 0;
+        self.frames = 0;
+        self.next_measure = 0;
+        self.strength = 0.0;
+        self.flatten = [0.0; FORMANT_ORDER];
+        self.restore = [0.0; FORMANT_ORDER];
+        self.envelopes.clear();
+        self.flattening.iter_mut().for_each(|x| *x = [0.0; FORMANT_ORDER]);
+        self.restoring.iter_mut().for_each(|x| *x = [0.0; FORMANT_ORDER]);
+    }
+}
+
+// Finds the reflection coefficients of the linear prediction filter for a signal from its autocorrelation, by the
+// Levinson-Durbin recursion. Silence gives all zeros, which leaves the signal unchanged.
+fn linear_prediction(correlation: &[f64; FORMANT_ORDER + 1], reflections: &mut [f32; FORMANT_ORDER]) {
+    let mut filter = [0.0f64; FORMANT_ORDER + 1];
+    filter[0] = 1.0;
+    let mut error = correlation[0];
+    for i in 1..=FORMANT_ORDER {
+        if error <= correlation[0] * 1e-9 {
+            break
+        }
+        let sum = (0..i).map(|j| filter[j] * correlation[i - j]).sum::<f64>();
+        let reflection = (-sum / error).clamp(-0.9999, 0.9999);
+        let previous = filter;
+        for j in 1..i {
+            filter[j] = previous[j] + reflection * previous[i - j];
+        }
+        filter[i] = reflection;
+        reflections[i - 1] = reflection as f32;
+        error *= 1.0 - reflection * reflection;
+    }
+}
+
+// Filters one sample through the lattice form of a linear prediction filter, which flattens out the envelope given by
+// `reflections`. `lattice` holds each stage's backward error from the last sample. The lattice form stays stable
+// however the envelope changes from one sample to the next, and `synthesis` undoes it exactly.
+#[inline]
+fn analysis(sample: Sample, reflections: &[f32; FORMANT_ORDER], lattice: &mut [f32; FORMANT_ORDER]) -> Sample {
+    let (mut forward, mut backward) = (sample, sample);
+    for (&reflection, state) in reflections.iter().zip(lattice.iter_mut()) {
+        let previous = std::mem::replace(state, backward);
+        backward = previous + reflection * forward;
+        forward += reflection * previous;
+    }
+    forward
+}
+
+// Puts back the envelope which `analysis` flattened out, with the same `reflections`
+#[inline]
+fn synthesis(sample: Sample, reflections: &[f32; FORMANT_ORDER], lattice: &mut [f32; FORMANT_ORDER]) -> Sample {
+    let mut forward = sample;
+    for i in (0..FORMANT_ORDER).rev() {
+        forward -= reflections[i] * lattice[i];
+        if i + 1 < FORMANT_ORDER {
+            lattice[i + 1] = lattice[i] + reflections[i] * forward;
+        }
+    }
+    lattice[0] = forward;
+    forward
+}
+
+impl PitchShiftHandle {
+    /// Sets how many semitones to shift the pitch by. 12 semitones is an octave, and negative values shift it down.
+    pub fn set_semitones(&self, semitones: f32) {
+        self.0.semitones.store(semitones)
+    }
+
+    /// Sets how many cents to shift the pitch by, on top of the semitones. There are 100 cents in a semitone.
+    pub fn set_cents(&self, cents: f32) {
+        self.0.cents.store(cents)
+    }
+
+    /// Sets whether formants are preserved, so that a voice keeps sounding like the same person when its pitch is
+    /// shifted.
+    pub fn set_preserve_formants(&self, preserve: bool) {
+        self.0.preserve_formants.store(preserve, Ordering::Release)
+    }
+
+    /// Returns the most recently set shift in semitones.
+    pub fn semitones(&self) -> f32 {
+        self.0.semitones.load()
+    }
+
+    /// Returns the most recently set shift in cents.
+    pub fn cents(&self) -> f32 {
+        self.0.cents.load()
+    }
+
+    /// Returns whether formants are being preserved.
+    pub fn preserve_formants(&self) -> bool {
+        self.0.preserve_formants.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Player;
+
+    fn sine(frequency: f32, frames: usize) -> Player {
+        let samples = (0..frames).map(|i| (TAU * frequency * i as f32 / 44100.0).sin() * 0.5).collect();
+        Player::new(ChannelCount::new(1).unwrap(), SampleRate::new(44100).unwrap(), samples)
+    }
+
+    fn play(source: &mut impl Source) -> Vec<Sample> {
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 512];
+        loop {
+            let count = source.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                assert_eq!(source.write_samples(&mut buffer), 0, "should stay ended");
+                return output
+            }
+        }
+    }
+
+    fn rms(samples: &[Sample]) -> f32 {
+        (samples.iter().map(|x| x * x).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn unshifted_is_unchanged() {
+        let input = play(&mut sine(440.0, 10000));
+        let output = play(&mut PitchShift::new(sine(440.0, 10000), 0.0, 0.0));
+        assert_eq!(output.len(), input.len());
+        for (i, (a, b)) in input.iter().zip(output.iter()).enumerate() {
+            assert!((a - b).abs() < 1e-4, "sample {} was {}, not {}", i, b, a);
+        }
+    }
+
+    #[test]
+    fn keeps_length() {
+        for &semitones in &[-24.0, -7.0, 5.0, 12.0, 24.0] {
+            let output = play(&mut PitchShift::new(sine(440.0, 22050), semitones, 0.0));
+            let difference = (output.len() as isize - 22050).abs();
+            assert!(difference <= 4, "shifting by {} gave {} frames", semitones, output.len());
+        }
+    }
+
+    #[test]
+    fn odd_buffers_keep_playing() {
+        let stereo = || {
+            let samples = (0..20000).map(|i| (i as f32 * 0.03).sin() * if i % 2 == 0 { 0.5 } else { -0.25 }).collect();
+            Player::new(ChannelCount::new(2).unwrap(), SampleRate::new(44100).unwrap(), samples)
+        };
+        let expected = play(&mut PitchShift::new(stereo(), 5.0, 0.0));
+        let mut shift = PitchShift::new(stereo(), 5.0, 0.0);
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 301];
+        loop {
+            let count = shift.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn shifts_an_octave() {
+        let output = play(&mut PitchShift::new(sine(441.0, 44100), 12.0, 0.0));
+        let middle = &output[11025..33075];
+        let crossings = middle.windows(2).filter(|x| x[0] < 0.0 && x[1] >= 0.0).count();
+        assert!((crossings as isize - 441).abs() <= 2, "{} cycles in half a second", crossings);
+    }
+
+    #[test]
+    fn shifting_up_does_not_alias() {
+        // Shifted up an octave, this would be above the Nyquist frequency, so it should be filtered out
+        let input = play(&mut sine(15000.0, 44100));
+        let output = play(&mut PitchShift::new(sine(15000.0, 44100), 12.0, 0.0));
+        let ratio = rms(&output[11025..33075]) / rms(&input);
+        assert!(ratio < 0.01, "aliasing came through at {} dB", 20.0 * ratio.log10());
+    }
+
+    // A vowel-like sound: the harmonics of `pitch` Hz, shaped by a single formant at 1 kHz
+    fn vowel(pitch: f64, frames: usize) -> Player {
+        let formant = |frequency: f64| (-((frequency - 1000.0) / 250.0).powi(2)).exp() + 0.05;
+        let harmonics = (1..).map(|k| k as f64 * pitch).take_while(|&x| x < 8000.0).collect::<Vec<_>>();
+        let samples = (0..frames)
+            .map(|i| {
+                let time = i as f64 / 44100.0;
+                let sum = harmonics.iter().map(|&x| formant(x) * (std::f64::consts::TAU * x * time).sin());
+                (sum.sum::<f64>() * 0.2) as f32
+            })
+            .collect();
+        Player::new(ChannelCount::new(1).unwrap(), SampleRate::new(44100).unwrap(), samples)
+    }
+
+    // The power of `samples` at one frequency
+    fn power_at(samples: &[Sample], frequency: f64) -> f64 {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (i, &x) in samples.iter().enumerate() {
+            let (sin, cos) = (std::f64::consts::TAU * frequency * i as f64 / 44100.0).sin_cos();
+            re += f64::from(x) * cos;
+            im += f64::from(x) * sin;
+        }
+        (re * re + im * im) / samples.len() as f64
+    }
+
+    #[test]
+    fn unshifted_formants_are_unchanged() {
+        let input = play(&mut vowel(150.0, 10000));
+        let mut shift = PitchShift::new(vowel(150.0, 10000), 0.0, 0.0);
+        shift.handle().set_preserve_formants(true);
+        let output = play(&mut shift);
+        assert_eq!(output.len(), input.len());
+        for (i, (a, b)) in input.iter().zip(output.iter()).enumerate() {
+            assert!((a - b).abs() < 1e-3, "sample {} was {}, not {}", i, b, a);
+        }
+    }
+
+    #[test]
+    fn preserves_formants() {
+        let pitch = 150.0 * 2.0f64.powf(5.0 / 12.0);
+        for &preserve in &[false, true] {
+            let mut shift = PitchShift::new(vowel(150.0, 44100), 5.0, 0.0);
+            shift.handle().set_preserve_formants(preserve);
+            let output = play(&mut shift);
+            let middle = &output[11025..33075];
+
+            // Either way, the harmonics move up to the new pitch
+            let new = (1..40).map(|k| (k as f64 * pitch, power_at(middle, k as f64 * pitch))).collect::<Vec<_>>();
+            let old = (1..50).map(|k| power_at(middle, k as f64 * 150.0)).sum::<f64>();
+            assert!(new.iter().map(|x| x.1).sum::<f64>() > old * 10.0, "the pitch didn't change");
+
+            // But the loudest harmonic only moves along with them if the formant isn't preserved
+            let loudest = new.iter().fold((0.0, 0.0), |a, &b| if b.1 > a.1 { b } else { a }).0;
+            if preserve {
+                assert!((loudest - 1000.0).abs() < 150.0, "the formant moved to {} Hz", loudest);
+            } else {
+                assert!(loudest > 1200.0, "the formant stayed at {} Hz", loudest);
+            }
+        }
+    }
+
+    #[test]
+    fn switching_formant_preservation_stays_stable() {
+        let mut shift = PitchShift::new(vowel(150.0, 44100), -7.0, 0.0);
+        let handle = shift.handle();
+        let mut buffer = [0.0; 2205];
+        for i in 0..20 {
+            handle.set_preserve_formants(i % 2 == 0);
+            assert_eq!(handle.preserve_formants(), i % 2 == 0);
+            assert_eq!(shift.write_samples(&mut buffer), buffer.len());
+            let peak = buffer.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+            assert!(peak < 1.0, "peaked at {} in block {}", peak, i);
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use super::{delay::DelayLine, tail::Tail};
+use crate::{
+    frame::PartialFrame,
+    param::{AtomicF32, Smoothed, DELAY_SMOOTHING_TIME},
+    source::{ChannelCount, ChannelLayout, Sample, SampleRate, Source},
+};
+use std::sync::Arc;
+
+// The longest pre-delay that can be set, in seconds
+const MAX_PRE_DELAY: f32 = 0.5;
+
+// Filter lengths from the original Freeverb, which are in samples at 44.1kHz
+const TUNING_RATE: f32 = 44100.0;
+const COMB_TUNING: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNING: [usize; 4] = [556, 441, 341, 225];
+const STEREO_SPREAD: usize = 23;
+
+// Scaling constants, also from Freeverb
+const INPUT_GAIN: f32 = 0.015;
+const WET_GAIN: f32 = 3.0;
+const ROOM_SCALE: f32 = 0.28;
+const ROOM_OFFSET: f32 = 0.7;
+const DAMP_SCALE: f32 = 0.4;
+const ALLPASS_FEEDBACK: f32 = 0.5;
+
+/// A reverb effect based on Jezar's Freeverb, built from parallel comb filters followed by all-pass filters.
+///
+/// The input is mixed down to mono and fed through two slightly different filter networks, one for each side of a
+/// stereo image. Mono Sources only use the left network. On Sources with more than two channels, even-numbered
+/// channels get the left side and odd-numbered channels get the right.
+///
+/// Once the inner Source ends, the Reverb keeps playing until its tail has died away.
+pub struct Reverb<S>
+where
+    S: Source,
+{
+    source: S,
+    params: Arc<ReverbParams>,
+    pre_delay_line: DelayLine,
+    sides: Box<[Network]>,
+    room_size: Smoothed,
+    damping: Smoothed,
+    width: Smoothed,
+    mix: Smoothed,
+    pre_delay: Smoothed, // In frames
+    tail: Tail,
+    partial: PartialFrame,
+}
+
+/// Used for changing the parameters of a [`Reverb`] while it's playing. Get one with `Reverb::handle()`.
+#[derive(Clone)]
+pub struct ReverbHandle(Arc<ReverbParams>);
+
+struct ReverbParams {
+    room_size: AtomicF32,
+    damping: AtomicF32,
+    width: AtomicF32,
+    mix: AtomicF32,
+    pre_delay: AtomicF32,
+}
+
+// One side's worth of filters
+struct Network {
+    combs: [Comb; 8],
+    allpasses: [DelayLine; 4],
+}
+
+struct Comb {
+    line: DelayLine,
+    store: Sample,
+}
+
+impl<S> Reverb<S>
+where
+    S: Source,
+{
+    /// Creates a new Reverb.
+    ///
+    /// - `room_size` controls how long the reverb takes to die away, from 0.0 to 1.0.
+    /// - `damping` is how much high frequencies are absorbed as the reverb decays, from 0.0 to 1.0.
+    /// - `width` is the stereo width of the reverb, from 0.0 (mono) to 1.0.
+    /// - `mix` is the balance between the original sound and the reverb, from 0.0 (dry only) to 1.0 (wet only).
+    /// - `pre_delay` is the time in seconds before the reverb starts, up to 0.5.
+    pub fn new(source: S, room_size: f32, damping: f32, width: f32, mix: f32, pre_delay: f32) -> Self {
+        let channels = usize::from(source.channel_count().get());
+        let sample_rate = u32::from(source.sample_rate()) as f32;
+        let scale = |len: usize| ((len as f32 * sample_rate / TUNING_RATE) as usize).max(1);
+        let sides: Box<[Network]> = (0..channels.min(2))
+            .map(|side| side * STEREO_SPREAD)
+            .map(|spread| Network {
+                combs: COMB_TUNING.map(|len| Comb { line: DelayLine::new(scale(len + spread)), store: 0.0 }),
+                allpasses: ALLPASS_TUNING.map(|len| DelayLine::new(scale(len + spread))),
+            })
+            .collect();
+
+        let max_pre_delay = ((MAX_PRE_DELAY * sample_rate).ceil() as usize).max(1);
+        let pre_delay = clamp_pre_delay(pre_delay * sample_rate, max_pre_delay);
+        let longest_path = scale(COMB_TUNING[7] + STEREO_SPREAD) + scale(ALLPASS_TUNING.iter().sum::<usize>());
+        let (room_size, damping, width, mix) = (clamp(room_size), clamp(damping), clamp(width), clamp(mix));
+        Self {
+            params: Arc::new(ReverbParams {
+                room_size: AtomicF32::new(room_size),
+                damping: AtomicF32::new(damping),
+                width: AtomicF32::new(width),
+                mix: AtomicF32::new(mix),
+                pre_delay: AtomicF32::new(pre_delay / sample_rate),
+            }),
+            pre_delay_line: DelayLine::new(max_pre_delay),
+            sides,
+            room_size: Smoothed::new(room_size),
+            damping: Smoothed::new(damping),
+            width: Smoothed::new(width),
+            mix: Smoothed::new(mix),
+            pre_delay: Smoothed::new(pre_delay),
+            tail: Tail::new(max_pre_delay + longest_path),
+            partial: PartialFrame::new(channels),
+            source,
+        }
+    }
+
+    /// Returns a handle for changing this Reverb's parameters while it's playing.
+    pub fn handle(&self) -> ReverbHandle {
+        ReverbHandle(self.params.clone())
+    }
+
+    fn write_frames(&mut self, buffer: &mut [Sample]) -> usize {
+        if self.tail.read(&mut self.source, buffer).is_none() {
+            return 0
+        }
+
+        let sample_rate = u32::from(self.source.sample_rate()) as f32;
+        let steps = (DELAY_SMOOTHING_TIME * sample_rate) as u32;
+        let max_pre_delay = self.pre_delay_line.max_delay();
+        self.room_size.set_target(clamp(self.params.room_size.load()), steps);
+        self.damping.set_target(clamp(self.params.damping.load()), steps);
+        self.width.set_target(clamp(self.params.width.load()), steps);
+        self.mix.set_target(clamp(self.params.mix.load()), steps);
+        self.pre_delay.set_target(clamp_pre_delay(self.params.pre_delay.load() * sample_rate, max_pre_delay), steps);
+
+        let channels = usize::from(self.source.channel_count().get());
+        for frame in buffer.chunks_exact_mut(channels) {
+            let feedback = self.room_size.next() * ROOM_SCALE + ROOM_OFFSET;
+            let damping = self.damping.next() * DAMP_SCALE;
+            let width = self.width.next();
+            let mix = self.mix.next();
+            let pre_delay = self.pre_delay.next();
+
+            // Reading after writing, so that a pre-delay of 0 gets the sample that was just written
+            self.pre_delay_line.write(frame.iter().sum::<Sample>() / channels as f32 * INPUT_GAIN);
+            let input = self.pre_delay_line.read(pre_delay + 1.0);
+
+            let mut wet = [0.0; 2];
+            for (out, side) in wet.iter_mut().zip(self.sides.iter_mut()) {
+                *out = side.process(input, feedback, damping);
+            }
+            let (left, right) = match self.sides.len() {
+                1 => (wet[0], wet[0]),
+                _ => {
+                    let (near, far) = (0.5 + width * 0.5, 0.5 - width * 0.5);
+                    (wet[0] * near + wet[1] * far, wet[1] * near + wet[0] * far)
+                },
+            };
+
+            let wet_gain = mix * WET_GAIN;
+            for (i, sample) in frame.iter_mut().enumerate() {
+                let wet = if i % 2 == 0 { left } else { right };
+                *sample = *sample * (1.0 - mix) + wet * wet_gain;
+            }
+        }
+
+        self.tail.finish(buffer, channels)
+    }
+}
+
+impl<S> Source for Reverb<S>
+where
+    S: Source,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.source.channel_count()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.source.channel_layout()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        PartialFrame::write_with(self, buffer, |reverb| &mut reverb.partial, Self::write_frames)
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.pre_delay_line.clear();
+        for side in self.sides.iter_mut() {
+            for comb in side.combs.iter_mut() {
+                comb.line.clear();
+                comb.store = 0.0;
+            }
+            side.allpasses.iter_mut().for_each(DelayLine::clear);
+        }
+        self.tail.reset();
+        self.partial.reset();
+    }
+}
+
+impl Network {
+    #[inline]
+    fn process(&mut self, input: Sample, feedback: f32, damping: f32) -> Sample {
+        let mut output = 0.0;
+        for comb in self.combs.iter_mut() {
+            let delayed = comb.line.tap(comb.line.max_delay());
+            comb.store = delayed * (1.0 - damping) + comb.store * damping;
+            comb.line.write(input + comb.store * feedback);
+            output += delayed;
+        }
+        for allpass in self.allpasses.iter_mut() {
+            let delayed = allpass.tap(allpass.max_delay());
+            allpass.write(output + delayed * ALLPASS_FEEDBACK);
+            output = delayed - output;
+        }
+        output
+    }
+}
+
+impl ReverbHandle {
+    /// Sets how long the reverb takes to die away, from 0.0 to 1.0.
+    pub fn set_room_size(&self, room_size: f32) {
+        self.0.room_size.store(room_size)
+    }
+
+    /// Sets how much high frequencies are absorbed as the reverb decays, from 0.0 to 1.0.
+    pub fn set_damping(&self, damping: f32) {
+        self.0.damping.store(damping)
+    }
+
+    /// Sets the stereo width of the reverb, from 0.0 (mono) to 1.0.
+    pub fn set_width(&self, width: f32) {
+        self.0.width.store(width)
+    }
+
+    /// Sets the balance between the original sound and the reverb, from 0.0 (dry only) to 1.0 (wet only).
+    pub fn set_mix(&self, mix: f32) {
+        self.0.mix.store(mix)
+    }
+
+    /// Sets the time in seconds before the reverb starts, up to 0.5.
+    pub fn set_pre_delay(&self, pre_delay: f32) {
+        self.0.pre_delay.store(pre_delay)
+    }
+
+    /// Returns the most recently set room size.
+    pub fn room_size(&self) -> f32 {
+        self.0.room_size.load()
+    }
+
+    /// Returns the most recently set damping.
+    pub fn damping(&self) -> f32 {
+        self.0.damping.load()
+    }
+
+    /// Returns the most recently set stereo width.
+    pub fn width(&self) -> f32 {
+        self.0.width.load()
+    }
+
+    /// Returns the most recently set wet/dry mix.
+    pub fn mix(&self) -> f32 {
+        self.0.mix.load()
+    }
+
+    /// Returns the most recently set pre-delay in seconds.
+    pub fn pre_delay(&self) -> f32 {
+        self.0.pre_delay.load()
+    }
+}
+
+#[inline]
+fn clamp(value: f32) -> f32 {
+    value.clamp(0.0, 1.0)
+}
+
+#[inline]
+fn clamp_pre_delay(frames: f32, max_frames: usize) -> f32 {
+    frames.max(0.0).min(max_frames as f32 - 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{effects::tail::SILENCE_THRESHOLD, Player};
+
+    const RATE: u32 = 8000;
+
+    fn impulse(channels: u16) -> Player {
+        let mut samples = vec![0.0; 100 * usize::from(channels)];
+        samples[0] = 1.0;
+        Player::new(ChannelCount::new(channels).unwrap(), SampleRate::new(RATE).unwrap(), samples.into())
+    }
+
+    // Reads until the Reverb ends, checking that it keeps returning 0 afterwards
+    fn play(reverb: &mut Reverb<Player>) -> Vec<Sample> {
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 256];
+        loop {
+            let count = reverb.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+            assert!(output.len() < RATE as usize * 60, "tail never ended");
+        }
+        assert_eq!(reverb.write_samples(&mut buffer), 0);
+        output
+    }
+
+    fn onset(output: &[Sample]) -> usize {
+        output.iter().position(|&x| x != 0.0).unwrap()
+    }
+
+    #[test]
+    fn tail_rings_out_and_ends() {
+        let mut reverb = Reverb::new(impulse(1), 0.5, 0.5, 1.0, 1.0, 0.0);
+        let output = play(&mut reverb);
+        assert!(output.len() > RATE as usize / 2, "{} frames", output.len());
+        assert!(output.iter().all(|x| x.is_finite()));
+
+        // Bigger rooms take longer to die away
+        let mut bigger = Reverb::new(impulse(1), 0.9, 0.5, 1.0, 1.0, 0.0);
+        assert!(play(&mut bigger).len() > output.len());
+    }
+
+    #[test]
+    fn pre_delay_shifts_the_tail() {
+        let direct = play(&mut Reverb::new(impulse(1), 0.5, 0.5, 1.0, 1.0, 0.0));
+        let delayed = play(&mut Reverb::new(impulse(1), 0.5, 0.5, 1.0, 1.0, 0.1));
+        let shift = RATE as usize / 10;
+        assert_eq!(onset(&delayed), onset(&direct) + shift);
+        for (a, b) in direct.iter().zip(&delayed[shift..]) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn width() {
+        let narrow = play(&mut Reverb::new(impulse(2), 0.5, 0.5, 0.0, 1.0, 0.0));
+        assert!(narrow.chunks_exact(2).all(|x| x[0] == x[1]));
+        let wide = play(&mut Reverb::new(impulse(2), 0.5, 0.5, 1.0, 1.0, 0.0));
+        assert!(wide.chunks_exact(2).any(|x| (x[0] - x[1]).abs() > 1e-3));
+    }
+
+    #[test]
+    fn dry_is_unchanged() {
+        let output = play(&mut Reverb::new(impulse(1), 0.5, 0.5, 1.0, 0.0, 0.0));
+        assert_eq!(output[0], 1.0);
+        assert!(output[1..].iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn odd_buffers_keep_playing() {
+        let expected = play(&mut Reverb::new(impulse(2), 0.5, 0.5, 1.0, 0.5, 0.0));
+        let mut reverb = Reverb::new(impulse(2), 0.5, 0.5, 1.0, 0.5, 0.0);
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 255];
+        loop {
+            let count = reverb.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(reverb.write_samples(&mut buffer), 0);
+
+        // Where the tail gets cut off depends on where the buffers fall, but only ever in the silence
+        let length = output.len().min(expected.len());
+        assert_eq!(output[..length], expected[..length]);
+        assert!(output[length..].iter().chain(&expected[length..]).all(|x| x.abs() < SILENCE_THRESHOLD));
+        assert_eq!(output.len() % 2, 0, "ended partway through a frame");
+    }
+}
@@ -0,0 +1,364 @@
+use crate::{
+    frame::PartialFrame,
+    param::AtomicF32,
+    simd,
+    source::{ChannelCount, ChannelLayout, Sample, SampleRate, Source},
+};
+use std::{f32::consts::TAU, sync::Arc};
+
+// The length of each window of input which is overlapped to build the output, in seconds. This needs to be longer
+// than the period of the lowest pitch in the Source, or it'll warble.
+const WINDOW_TIME: f32 = 0.04;
+
+// How far either side of its nominal position each window is allowed to move to line up with the previous one
+const SEEK_TIME: f32 = 0.01;
+
+// Stretch factor limits
+const MIN_STRETCH: f32 = 0.25;
+const MAX_STRETCH: f32 = 4.0;
+
+// How many frames are read from the inner Source at a time
+const CHUNK_FRAMES: usize = 1024;
+
+/// Changes how fast a Source plays without changing its pitch, using WSOLA (waveform-similarity overlap-add).
+///
+/// The Source is cut into short overlapping windows, which are spaced further apart or closer together in the output
+/// than they were in the input. Each window is nudged a little so its waveform lines up with the one before it,
+/// which avoids the phasing that a plain overlap-add would cause. This works well for speech and most sound effects,
+/// though it can smear sharp transients and sounds with many overlapping pitches, such as a full music mix.
+///
+/// The stretch factor is how much longer the output is than the input, so 2.0 plays at half speed and 0.5 plays at
+/// double speed. It can be changed while playing, from 0.25 to 4.0. A factor of exactly 1.0 plays the Source back
+/// unchanged.
+pub struct TimeStretch<S>
+where
+    S: Source,
+{
+    source: S,
+    stretch: Arc<AtomicF32>,
+    window: Box<[f32]>,
+    hop: usize,  // Frames between windows in the output, which is half a window
+    seek: usize, // In frames
+    input: Vec<Sample>,
+    mono: Vec<f32>,     // A mono mixdown of `input`, for lining up windows
+    input_start: usize, // The position of the start of `input`, in frames since the start of the padding
+    input_end: Option<usize>,
+    position: f64,            // Where the next window would start if it wasn't nudged, in frames
+    previous: Option<usize>, // Where the previous window started
+    output: Box<[Sample]>,    // Overlapping windows, the first `available` frames of which are complete
+    available: usize,
+    read: usize, // How many of the available frames have been written out
+    skip: usize, // How many frames are still to be dropped from the start of the output
+    finished: bool,
+    partial: PartialFrame,
+}
+
+/// Used for changing the stretch factor of a [`TimeStretch`] while it's playing. Get one with `TimeStretch::handle()`.
+#[derive(Clone)]
+pub struct TimeStretchHandle(Arc<AtomicF32>);
+
+impl<S> TimeStretch<S>
+where
+    S: Source,
+{
+    /// Creates a new TimeStretch, where `stretch` is how much longer the output should be than the input.
+    pub fn new(source: S, stretch: f32) -> Self {
+        let channels = usize::from(source.channel_count().get());
+        let sample_rate = u32::from(source.sample_rate()) as f32;
+        let hop = ((WINDOW_TIME * sample_rate * 0.5) as usize).max(1);
+        let length = hop * 2;
+        let mut stretch = Self {
+            source,
+            stretch: Arc::new(AtomicF32::new(stretch)),
+            window: (0..length).map(|i| 0.5 - 0.5 * (TAU * i as f32 / length as f32).cos()).collect(),
+            hop,
+            seek: (SEEK_TIME * sample_rate) as usize,
+            input: Vec::new(),
+            mono: Vec::new(),
+            input_start: 0,
+            input_end: None,
+            position: 0.0,
+            previous: None,
+            output: vec![0.0; length * channels].into(),
+            available: 0,
+            read: 0,
+            skip: 0,
+            finished: false,
+            partial: PartialFrame::new(channels),
+        };
+        stretch.start();
+        stretch
+    }
+
+    /// Returns a handle for changing this TimeStretch's stretch factor while it's playing.
+    pub fn handle(&self) -> TimeStretchHandle {
+        TimeStretchHandle(self.stretch.clone())
+    }
+
+    // Sets up the state for the start of the Source. The first half window of output is dropped, since it's only
+    // covered by one window, so the input is padded with silence to make the second window start on the Source's
+    // first frame.
+    fn start(&mut self) {
+        let channels = usize::from(self.source.channel_count().get());
+        let stretch = f64::from(self.stretch.load().clamp(MIN_STRETCH, MAX_STRETCH));
+        let padding = (self.hop as f32 / MIN_STRETCH).ceil() as usize;
+        self.input.clear();
+        self.input.resize(padding * channels, 0.0);
+        self.mono.clear();
+        self.mono.resize(padding, 0.0);
+        self.input_start = 0;
+        self.input_end = None;
+        self.position = padding as f64 - self.hop as f64 / stretch;
+        self.previous = None;
+        self.output.iter_mut().for_each(|x| *x = 0.0);
+        self.available = 0;
+        self.read = 0;
+        self.skip = self.hop;
+        self.finished = false;
+    }
+
+    // Reads from the inner Source until the input reaches `end`, in frames. Past the end of the Source, it's padded
+    // with silence.
+    fn fill(&mut self, end: usize) {
+        let channels = usize::from(self.source.channel_count().get());
+        while self.input_start + self.mono.len() < end {
+            let frames = CHUNK_FRAMES.min(end - self.input_start - self.mono.len());
+            let offset = self.input.len();
+            self.input.resize(offset + frames * channels, 0.0);
+            if self.input_end.is_none() {
+                let count = self.source.write_samples(&mut self.input[offset..]);
+                if count < frames * channels {
+                    self.input_end = Some(self.input_start + (offset + count) / channels);
+                }
+            }
+            let mono = self.input[offset..].chunks_exact(channels).map(|x| x.iter().sum::<f32>() / channels as f32);
+            self.mono.extend(mono);
+        }
+    }
+
+    // Finds the start of the window between `low` and `high` which best continues on from the previous window, by
+    // comparing each candidate against what would have followed the previous window in the input. Only the first half
+    // of each window overlaps the previous one, so that's all that gets compared.
+    fn best_match(&self, natural: usize, nominal: usize, low: usize, high: usize) -> usize {
+        let mono = &self.mono[low - self.input_start..];
+        let template = &self.mono[natural - self.input_start..][..self.hop];
+
+        // The candidates overlap, so each one's energy is found from the last by adding the sample that slides in and
+        // subtracting the one that slides out. That's summed in f64 so the error doesn't build up.
+        let mut energy = mono[..self.hop].iter().map(|&x| f64::from(x) * f64::from(x)).sum::<f64>();
+        let mut best = (nominal, f32::MIN);
+        for (i, start) in (low..=high).enumerate() {
+            if i > 0 {
+                let (old, new) = (f64::from(mono[i - 1]), f64::from(mono[i - 1 + self.hop]));
+                energy += new * new - old * old;
+            }
+            let candidate = &mono[i..][..self.hop];
+            let score = if energy > 0.0 { simd::dot(candidate, template, 1) / energy.sqrt() as f32 } else { 0.0 };
+
+            // Stay at the nominal position unless there's somewhere strictly better, so silence doesn't wander
+            if score > best.1 || (score == best.1 && start == nominal) {
+                best = (start, score);
+            }
+        }
+        best.0
+    }
+
+    // Overlap-adds the next window onto the output, and returns how many frames at the start of the output are now
+    // ready. That's normally half a window, but the last window stops where the input ended.
+    fn step(&mut self) -> usize {
+        let channels = usize::from(self.source.channel_count().get());
+        let length = self.window.len();
+        let nominal = self.position.round() as usize;
+        let natural = self.previous.map(|x| x + self.hop);
+        self.fill(natural.unwrap_or(0).max(nominal + self.seek) + length);
+
+        let start = match natural {
+            Some(natural) => {
+                let low = nominal.saturating_sub(self.seek).max(self.input_start);
+                self.best_match(natural, nominal, low, nominal + self.seek)
+            },
+            None => nominal,
+        };
+        let input = &self.input[(start - self.input_start) * channels..];
+        for ((output, input), &weight) in
+            self.output.chunks_exact_mut(channels).zip(input.chunks_exact(channels)).zip(self.window.iter())
+        {
+            output.iter_mut().zip(input).for_each(|(x, y)| *x += y * weight);
+        }
+
+        let stretch = f64::from(self.stretch.load().clamp(MIN_STRETCH, MAX_STRETCH));
+        let position = self.position;
+        self.previous = Some(start);
+        self.position += self.hop as f64 / stretch;
+        if let Some(end) = self.input_end.filter(|&end| self.position >= end as f64) {
+            self.finished = true;
+            return (((end as f64 - position) * stretch).round().max(0.0) as usize).min(self.hop)
+        }
+
+        // Drop any input that the next window can't reach
+        let keep = (self.position.round() as usize).saturating_sub(self.seek).min(start + self.hop);
+        if keep > self.input_start {
+            let drop = keep - self.input_start;
+            self.input.drain(..drop * channels);
+            self.mono.drain(..drop);
+            self.input_start = keep;
+        }
+        self.hop
+    }
+
+    fn write_frames(&mut self, buffer: &mut [Sample]) -> usize {
+        let channels = usize::from(self.source.channel_count().get());
+        let frames = buffer.len() / channels;
+        let mut written = 0;
+        while written < frames {
+            if self.read < self.available {
+                let skipped = self.skip.min(self.available - self.read);
+                self.skip -= skipped;
+                self.read += skipped;
+                let count = (self.available - self.read).min(frames - written);
+                buffer[written * channels..(written + count) * channels]
+                    .copy_from_slice(&self.output[self.read * channels..(self.read + count) * channels]);
+                self.read += count;
+                written += count;
+            } else if self.finished {
+                break
+            } else {
+                // Move the overlap from the last window to the front, then add the next window on top of it
+                self.output.copy_within(self.available * channels.., 0);
+                let length = self.output.len();
+                self.output[length - self.available * channels..].iter_mut().for_each(|x| *x = 0.0);
+                self.read = 0;
+                self.available = self.step();
+            }
+        }
+        written * channels
+    }
+}
+
+impl<S> Source for TimeStretch<S>
+where
+    S: Source,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.source.channel_count()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.source.channel_layout()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        PartialFrame::write_with(self, buffer, |stretch| &mut stretch.partial, Self::write_frames)
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.start();
+        self.partial.reset();
+    }
+}
+
+impl TimeStretchHandle {
+    /// Sets how much longer the output should be than the input, from 0.25 to 4.0. For example, 2.0 plays at half
+    /// speed, and 0.5 plays at double speed.
+    pub fn set_stretch(&self, stretch: f32) {
+        self.0.store(stretch)
+    }
+
+    /// Returns the most recently set stretch factor.
+    pub fn stretch(&self) -> f32 {
+        self.0.load()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Player;
+
+    fn player(channels: u16, frames: usize) -> Player {
+        // A chirp, so no two windows look alike
+        let samples = (0..frames * usize::from(channels))
+            .map(|i| (i as f32 * (0.01 + i as f32 * 1e-6)).sin() * 0.5)
+            .collect();
+        Player::new(ChannelCount::new(channels).unwrap(), SampleRate::new(44100).unwrap(), samples)
+    }
+
+    fn play(source: &mut impl Source) -> Vec<Sample> {
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 500];
+        loop {
+            let count = source.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                assert_eq!(source.write_samples(&mut buffer), 0, "should stay ended");
+                return output
+            }
+        }
+    }
+
+    #[test]
+    fn unity_is_unchanged() {
+        let input = play(&mut player(2, 10000));
+        let output = play(&mut TimeStretch::new(player(2, 10000), 1.0));
+        assert_eq!(output.len(), input.len());
+        for (i, (a, b)) in input.iter().zip(output.iter()).enumerate() {
+            assert!((a - b).abs() < 1e-5, "sample {} was {}, not {}", i, b, a);
+        }
+    }
+
+    #[test]
+    fn stretched_lengths() {
+        for &stretch in &[0.25, 0.5, 1.5, 2.0, 4.0] {
+            let output = play(&mut TimeStretch::new(player(1, 8820), stretch));
+            let expected = (8820.0 * stretch).round() as usize;
+            assert!(
+                (output.len() as isize - expected as isize).abs() <= 1,
+                "stretching by {} gave {} frames, not {}",
+                stretch,
+                output.len(),
+                expected,
+            );
+        }
+    }
+
+    #[test]
+    fn odd_buffers_keep_playing() {
+        let expected = play(&mut TimeStretch::new(player(2, 5000), 1.5));
+        let mut stretch = TimeStretch::new(player(2, 5000), 1.5);
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 333];
+        loop {
+            let count = stretch.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn best_match_finds_highest_correlation() {
+        let mut stretch = TimeStretch::new(player(1, 20000), 1.3);
+        stretch.fill(10000);
+        let (natural, nominal) = (5000, 5100);
+        let (low, high) = (nominal - stretch.seek, nominal + stretch.seek);
+
+        let template = &stretch.mono[natural - stretch.input_start..][..stretch.hop];
+        let score = |start: usize| {
+            let candidate = &stretch.mono[start - stretch.input_start..][..stretch.hop];
+            let energy = candidate.iter().map(|x| x * x).sum::<f32>();
+            candidate.iter().zip(template).map(|(a, b)| a * b).sum::<f32>() / energy.sqrt()
+        };
+        let best = (low..=high).max_by(|&a, &b| score(a).partial_cmp(&score(b)).unwrap()).unwrap();
+        assert_eq!(stretch.best_match(natural, nominal, low, high), best);
+    }
+}
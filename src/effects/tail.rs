@@ -0,0 +1,72 @@
+use crate::source::{Sample, Source};
+
+// Output quieter than this (-90 dB) counts as silence for ending a tail
+pub(crate) const SILENCE_THRESHOLD: Sample = 0.0000316;
+
+/// Tracks the decay tail of an effect which keeps producing output after its inner Source ends, such as an echo.
+///
+/// Once the inner Source has ended, the effect is fed silence, and its tail ends after its output has stayed below
+/// -90 dB for a set number of frames. This follows the `Source::write_samples` contract: the call in which the tail
+/// ends returns less than the full buffer, and every call after that returns 0.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Tail {
+    hold_frames: usize,
+    silent_frames: usize,
+    ended_at: Option<usize>, // Sample offset into the current buffer at which the inner Source ended
+    finished: bool,
+}
+
+impl Tail {
+    /// `hold_frames` is how long the output must stay silent before the tail ends.
+    /// It should be at least as long as the effect's longest internal delay.
+    pub(crate) fn new(hold_frames: usize) -> Self {
+        Self { hold_frames, silent_frames: 0, ended_at: None, finished: false }
+    }
+
+    /// Fills `buffer` from `source`, then with silence if `source` has ended.
+    /// Returns how many samples actually came from `source`, or `None` if the tail has already finished.
+    pub(crate) fn read(&mut self, source: &mut impl Source, buffer: &mut [Sample]) -> Option<usize> {
+        if self.finished {
+            return None
+        }
+        let count = if self.ended_at.is_some() {
+            0
+        } else {
+            let count = source.write_samples(buffer);
+            if count < buffer.len() {
+                self.ended_at = Some(count);
+            }
+            count
+        };
+        buffer[count..].iter_mut().for_each(|x| *x = 0.0);
+        Some(count)
+    }
+
+    /// Checks the effect's processed output for the end of the tail.
+    /// Returns how many samples of `buffer` should be reported as written.
+    pub(crate) fn finish(&mut self, buffer: &[Sample], channels: usize) -> usize {
+        let start = match self.ended_at {
+            Some(start) => start,
+            None => return buffer.len(),
+        };
+        for (i, frame) in buffer.chunks_exact(channels).enumerate().skip(start / channels) {
+            if frame.iter().all(|x| x.abs() < SILENCE_THRESHOLD) {
+                self.silent_frames += 1;
+                if self.silent_frames >= self.hold_frames {
+                    // Cut off the silence, or as much of it as is in this buffer
+                    self.finished = true;
+                    return (i + 1).saturating_sub(self.silent_frames) * channels
+                }
+            } else {
+                self.silent_frames = 0;
+            }
+        }
+        // The whole of every later buffer is tail, so scan from the start next time
+        self.ended_at = Some(0);
+        buffer.len()
+    }
+
+    pub(crate) fn reset(&mut self) {
+        *self = Self::new(self.hold_frames);
+    }
+}
@@ -0,0 +1,223 @@
+//! A small FFT for the effects which need to work in the frequency domain.
+//!
+//! Only power-of-two lengths are supported. Real signals are transformed with a complex FFT of half their length,
+//! which is about twice as fast as transforming them as complex numbers with an imaginary part of zero.
+
+use std::{
+    f32::consts::TAU,
+    ops::{Add, Mul, Sub},
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Complex {
+    pub(crate) re: f32,
+    pub(crate) im: f32,
+}
+
+/// Forward and inverse transforms between `len` real samples and `len / 2 + 1` complex bins.
+#[derive(Clone, Debug)]
+pub(crate) struct RealFft {
+    complex: ComplexFft,
+    twiddles: Box<[Complex]>, // For splitting the half-length transform into the real one
+    scratch: Box<[Complex]>,
+}
+
+#[derive(Clone, Debug)]
+struct ComplexFft {
+    twiddles: Box<[Complex]>,
+    bit_reverse: Box<[u32]>,
+}
+
+impl Complex {
+    #[inline]
+    pub(crate) fn new(re: f32, im: f32) -> Self {
+        Self { re, im }
+    }
+
+    #[inline]
+    pub(crate) fn conj(self) -> Self {
+        Self { re: self.re, im: -self.im }
+    }
+
+    // Returns exp(i * angle)
+    #[inline]
+    fn from_angle(angle: f32) -> Self {
+        let (im, re) = angle.sin_cos();
+        Self { re, im }
+    }
+}
+
+impl Add for Complex {
+    type Output = Self;
+
+    #[inline]
+    fn add(self, rhs: Self) -> Self {
+        Self { re: self.re + rhs.re, im: self.im + rhs.im }
+    }
+}
+
+impl Sub for Complex {
+    type Output = Self;
+
+    #[inline]
+    fn sub(self, rhs: Self) -> Self {
+        Self { re: self.re - rhs.re, im: self.im - rhs.im }
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+
+    #[inline]
+    fn mul(self, rhs: Self) -> Self {
+        Self { re: self.re * rhs.re - self.im * rhs.im, im: self.re * rhs.im + self.im * rhs.re }
+    }
+}
+
+impl Mul<f32> for Complex {
+    type Output = Self;
+
+    #[inline]
+    fn mul(self, rhs: f32) -> Self {
+        Self { re: self.re * rhs, im: self.im * rhs }
+    }
+}
+
+impl RealFft {
+    /// Panics if `len` isn't a power of two, or is less than 4.
+    pub(crate) fn new(len: usize) -> Self {
+        assert!(len.is_power_of_two() && len >= 4, "FFT length must be a power of two, and at least 4");
+        let half = len / 2;
+        Self {
+            complex: ComplexFft::new(half),
+            twiddles: (0..half).map(|k| Complex::from_angle(-TAU * k as f32 / len as f32)).collect(),
+            scratch: vec![Complex::default(); half].into_boxed_slice(),
+        }
+    }
+
+    /// Transforms `len` real samples into `len / 2 + 1` bins. The output is not scaled.
+    pub(crate) fn forward(&mut self, input: &[f32], output: &mut [Complex]) {
+        let half = self.scratch.len();
+        assert!(input.len() == half * 2 && output.len() == half + 1);
+
+        // Pack even samples into the real parts and odd ones into the imaginary parts
+        for (z, pair) in self.scratch.iter_mut().zip(input.chunks_exact(2)) {
+            *z = Complex::new(pair[0], pair[1]);
+        }
+        self.complex.process(&mut self.scratch, false);
+
+        // Then separate the transforms of the even and odd samples again, and combine them
+        let z = &self.scratch;
+        output[0] = Complex::new(z[0].re + z[0].im, 0.0);
+        output[half] = Complex::new(z[0].re - z[0].im, 0.0);
+        for k in 1..half {
+            let (a, b) = (z[k], z[half - k].conj());
+            let even = (a + b) * 0.5;
+            let odd = (a - b) * Complex::new(0.0, -0.5);
+            output[k] = even + self.twiddles[k] * odd;
+        }
+    }
+
+    /// Transforms `len / 2 + 1` bins back into `len` real samples, scaling by `1 / len` so that a forward transform
+    /// followed by an inverse one gives back the original samples. `input` is left unchanged.
+    pub(crate) fn inverse(&mut self, input: &[Complex], output: &mut [f32]) {
+        let half = self.scratch.len();
+        assert!(input.len() == half + 1 && output.len() == half * 2);
+
+        for k in 0..half {
+            let (a, b) = (input[k], input[half - k].conj());
+            let even = (a + b) * 0.5;
+            let odd = (a - b) * 0.5 * self.twiddles[k].conj();
+            self.scratch[k] = even + odd * Complex::new(0.0, 1.0);
+        }
+        self.complex.process(&mut self.scratch, true);
+
+        let scale = 1.0 / half as f32;
+        for (pair, z) in output.chunks_exact_mut(2).zip(self.scratch.iter()) {
+            pair[0] = z.re * scale;
+            pair[1] = z.im * scale;
+        }
+    }
+}
+
+impl ComplexFft {
+    fn new(len: usize) -> Self {
+        let bits = len.trailing_zeros();
+        Self {
+            twiddles: (0..len / 2).map(|k| Complex::from_angle(-TAU * k as f32 / len as f32)).collect(),
+            bit_reverse: (0..len as u32).map(|i| i.reverse_bits().checked_shr(32 - bits).unwrap_or(0)).collect(),
+        }
+    }
+
+    // An in-place iterative radix-2 transform. The inverse is not scaled.
+    fn process(&self, data: &mut [Complex], inverse: bool) {
+        let len = data.len();
+        for (i, &j) in self.bit_reverse.iter().enumerate() {
+            let j = j as usize;
+            if i < j {
+                data.swap(i, j);
+            }
+        }
+
+        let mut size = 2;
+        while size <= len {
+            let half = size / 2;
+            let step = len / size;
+            for chunk in data.chunks_exact_mut(size) {
+                let (low, high) = chunk.split_at_mut(half);
+                for (k, (a, b)) in low.iter_mut().zip(high.iter_mut()).enumerate() {
+                    let twiddle = self.twiddles[k * step];
+                    let twiddle = if inverse { twiddle.conj() } else { twiddle };
+                    let t = *b * twiddle;
+                    *b = *a - t;
+                    *a = *a + t;
+                }
+            }
+            size *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signal(len: usize) -> Vec<f32> {
+        (0..len).map(|i| ((i * 7919) % 101) as f32 / 50.0 - 1.0).collect()
+    }
+
+    #[test]
+    fn forward_matches_dft() {
+        for &len in &[4, 8, 64, 1024] {
+            let input = signal(len);
+            let mut output = vec![Complex::default(); len / 2 + 1];
+            RealFft::new(len).forward(&input, &mut output);
+            for (k, bin) in output.iter().enumerate() {
+                let (mut re, mut im) = (0.0f64, 0.0f64);
+                for (n, &x) in input.iter().enumerate() {
+                    let angle = -std::f64::consts::TAU * (k * n) as f64 / len as f64;
+                    re += f64::from(x) * angle.cos();
+                    im += f64::from(x) * angle.sin();
+                }
+                let tolerance = 1e-5 * len as f64;
+                assert!((f64::from(bin.re) - re).abs() < tolerance, "length {} bin {}: {} != {}", len, k, bin.re, re);
+                assert!((f64::from(bin.im) - im).abs() < tolerance, "length {} bin {}: {} != {}", len, k, bin.im, im);
+            }
+        }
+    }
+
+    #[test]
+    fn inverse_round_trips() {
+        for &len in &[4, 8, 64, 1024] {
+            let input = signal(len);
+            let mut fft = RealFft::new(len);
+            let mut spectrum = vec![Complex::default(); len / 2 + 1];
+            let mut output = vec![0.0; len];
+            fft.forward(&input, &mut spectrum);
+            fft.inverse(&spectrum, &mut output);
+            for (x, y) in input.iter().zip(&output) {
+                assert!((x - y).abs() < 1e-5, "length {}: {} != {}", len, x, y);
+            }
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Helpers for Sources which can only produce whole frames at a time.
+
+use crate::source::Sample;
+
+/// Lets a Source which works in whole frames write to buffers which end partway through a frame.
+///
+/// When a buffer has room for only part of a frame at the end, the whole frame is produced here instead, and the
+/// samples which didn't fit are written at the start of the next buffer. That way the stream carries on exactly where
+/// it left off, with no samples added or lost and every buffer after it starting on the right channel.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PartialFrame {
+    samples: Box<[Sample]>,
+    offset: usize, // How many of `samples` have already been written out
+}
+
+impl PartialFrame {
+    pub(crate) fn new(channels: usize) -> Self {
+        Self { samples: vec![0.0; channels].into_boxed_slice(), offset: channels }
+    }
+
+    /// Fills `buffer`, starting with whatever was left over from the last one. `write_frames` is given a whole number
+    /// of frames to fill and returns how many samples it wrote, where fewer than it was given means the Source ended.
+    ///
+    /// Returns the number of samples written, following the contract of `Source::write_samples`.
+    pub(crate) fn write(
+        &mut self,
+        buffer: &mut [Sample],
+        mut write_frames: impl FnMut(&mut [Sample]) -> usize,
+    ) -> usize {
+        let channels = self.samples.len();
+        let start = (channels - self.offset).min(buffer.len());
+        buffer[..start].copy_from_slice(&self.samples[self.offset..self.offset + start]);
+        self.offset += start;
+
+        let end = start + (buffer.len() - start) / channels * channels;
+        let count = if end > start { start + write_frames(&mut buffer[start..end]) } else { start };
+        if count < end || end == buffer.len() {
+            return count
+        }
+
+        // The buffer ends partway through a frame, so keep what doesn't fit for next time
+        if write_frames(&mut self.samples) < channels {
+            return count
+        }
+        self.offset = buffer.len() - end;
+        buffer[end..].copy_from_slice(&self.samples[..self.offset]);
+        buffer.len()
+    }
+
+    /// The same as `write`, for when `write_frames` needs the Source which owns this PartialFrame.
+    /// `partial` picks the PartialFrame out of `owner`.
+    pub(crate) fn write_with<T>(
+        owner: &mut T,
+        buffer: &mut [Sample],
+        partial: fn(&mut T) -> &mut PartialFrame,
+        write_frames: fn(&mut T, &mut [Sample]) -> usize,
+    ) -> usize {
+        // Take it out of `owner` while writing, so `write_frames` can borrow all of `owner`
+        let mut frame = std::mem::take(partial(owner));
+        let count = frame.write(buffer, |buffer| write_frames(owner, buffer));
+        *partial(owner) = frame;
+        count
+    }
+
+    /// Drops anything left over, for when the Source is reset.
+    #[inline]
+    pub(crate) fn reset(&mut self) {
+        self.offset = self.samples.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes frames of the form [n, n + 0.5] from a counter, ending after `frames`
+    fn counter(frames: usize) -> impl FnMut(&mut [Sample]) -> usize {
+        let mut next = 0;
+        move |buffer| {
+            let count = (buffer.len() / 2).min(frames - next);
+            for (i, frame) in buffer.chunks_exact_mut(2).take(count).enumerate() {
+                frame[0] = (next + i) as Sample;
+                frame[1] = (next + i) as Sample + 0.5;
+            }
+            next += count;
+            count * 2
+        }
+    }
+
+    #[test]
+    fn carries_frames_across_buffers() {
+        let mut partial = PartialFrame::new(2);
+        let mut write_frames = counter(6);
+        let mut output = Vec::new();
+        for &length in &[3, 1, 1, 4, 5, 5] {
+            let mut buffer = vec![-1.0; length];
+            let count = partial.write(&mut buffer, &mut write_frames);
+            output.extend_from_slice(&buffer[..count]);
+            if count < length {
+                assert_eq!(length, 5, "ended early");
+                break
+            }
+        }
+        let expected = (0..6).flat_map(|x| vec![x as Sample, x as Sample + 0.5]).collect::<Vec<_>>();
+        assert_eq!(output, expected);
+        assert_eq!(partial.write(&mut [0.0; 4], &mut write_frames), 0);
+    }
+
+    #[test]
+    fn reset_drops_leftovers() {
+        let mut partial = PartialFrame::new(2);
+        let mut buffer = [0.0; 3];
+        assert_eq!(partial.write(&mut buffer, counter(4)), 3);
+        partial.reset();
+        assert_eq!(partial.write(&mut buffer, counter(4)), 3);
+        assert_eq!(buffer, [0.0, 0.5, 1.0]);
+    }
+}
@@ -0,0 +1,75 @@
+//! Sources which synthesise sound rather than reading it from somewhere.
+//!
+//! These are useful for simple beeps and placeholder sounds, and for test tones. An [`Oscillator`] writes the same
+//! signal to every channel, while [`Noise`] gives each channel its own. Both play forever, unless they're given a
+//! duration, in which case they end after exactly that much time.
+//!
+//! [`Granular`] is a granular synthesiser, for building ambient textures out of a sample.
+
+mod granular;
+mod noise;
+mod oscillator;
+
+pub use granular::{Granular, GranularHandle};
+pub use noise::{Noise, NoiseColor};
+pub use oscillator::{Oscillator, OscillatorHandle, Waveform};
+
+use crate::source::SampleRate;
+
+// A fast pseudo-random number generator (xorshift32). It always starts from the same seed, so anything using it plays
+// exactly the same samples every time, which is handy for tests.
+#[derive(Clone, Copy, Debug)]
+struct Random(u32);
+
+impl Random {
+    const SEED: u32 = 0x9E37_79B9;
+
+    #[inline]
+    fn new() -> Self {
+        Self(Self::SEED)
+    }
+
+    // Returns a random number from -1.0 to 1.0
+    #[inline]
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 >> 8) as f32 / (1 << 23) as f32 - 1.0
+    }
+}
+
+// Converts an optional duration in seconds into a number of frames
+fn duration_frames(duration: Option<f32>, sample_rate: SampleRate) -> Option<u64> {
+    duration.map(|x| (x.max(0.0) as f64 * f64::from(u32::from(sample_rate))).round() as u64)
+}
+
+// Works out how many frames of a `buffer` can be written, given how many the Source has left
+#[inline]
+fn frames_to_write(buffer_len: usize, channels: usize, remaining: Option<u64>) -> usize {
+    let frames = buffer_len / channels;
+    match remaining {
+        Some(remaining) => remaining.min(frames as u64) as usize,
+        None => frames,
+    }
+}
+
+// Averages the power spectrum of `signal` over Hann-windowed blocks of `length` samples, overlapping by half
+#[cfg(test)]
+fn power_spectrum(signal: &[f32], length: usize) -> Vec<f32> {
+    use crate::fft::{Complex, RealFft};
+    use std::f32::consts::TAU;
+
+    let window = (0..length).map(|i| 0.5 - 0.5 * (TAU * i as f32 / length as f32).cos()).collect::<Vec<_>>();
+    let mut fft = RealFft::new(length);
+    let (mut block, mut bins) = (vec![0.0; length], vec![Complex::default(); length / 2 + 1]);
+    let mut power = vec![0.0; length / 2 + 1];
+    let blocks = signal.windows(length).step_by(length / 2);
+    let count = blocks.len();
+    for input in blocks {
+        block.iter_mut().zip(input.iter().zip(window.iter())).for_each(|(x, (y, w))| *x = y * w);
+        fft.forward(&block, &mut bins);
+        power.iter_mut().zip(bins.iter()).for_each(|(x, y)| *x += (y.re * y.re + y.im * y.im) / count as f32);
+    }
+    power
+}
@@ -0,0 +1,479 @@
+use super::Random;
+use crate::{
+    frame::PartialFrame,
+    param::AtomicF32,
+    source::{ChannelCount, Sample, SampleRate, Source},
+};
+use std::{
+    f32::consts::{FRAC_PI_4, TAU},
+    sync::Arc,
+};
+
+// The most grains that can play at once. Any that would go over this are skipped.
+const MAX_GRAINS: usize = 256;
+
+// The length of the lookup table for the grain window, which is linearly interpolated
+const WINDOW_LENGTH: usize = 1024;
+
+// Parameter limits
+const MIN_DURATION: f32 = 0.001;
+const MAX_DURATION: f32 = 10.0;
+const MIN_DENSITY: f32 = 0.1;
+const MAX_DENSITY: f32 = 1000.0;
+const MAX_PITCH: f32 = 48.0;
+
+/// A granular synthesiser, which builds a sound out of many short, overlapping snippets ("grains") of a sample.
+///
+/// Each grain is taken from around a position in the sample, and is faded in and out with a smooth window. Grains can
+/// also be pitched up or down and panned, and each of those parameters, as well as how long grains are and how often
+/// they start, can be randomised within a spread. A few long grains from one position blur a sound into a drone,
+/// while lots of short grains scattered across the sample make a shimmering texture.
+///
+/// The sample is mixed down to mono, and the output is always stereo. Grains which run off the end of the sample wrap
+/// around to its start. Granular never ends by itself, and the randomness always plays out the same way after it's
+/// created or reset. Overlapping grains add together, so dense or long grains can get loud. Use a `Gain` to
+/// compensate if needed.
+pub struct Granular {
+    sample: Box<[Sample]>,
+    sample_rate: SampleRate,
+    params: Arc<GranularParams>,
+    window: Box<[f32]>,
+    grains: Vec<Grain>,
+    random: Random,
+    until_next: f32, // Frames until the next grain starts
+    partial: PartialFrame,
+}
+
+/// Used for changing the parameters of a [`Granular`] while it's playing. Get one with `Granular::handle()`.
+///
+/// Each parameter has a spread, which is how far it can be randomised either way for each grain. Changes only affect
+/// grains which start after they're made.
+#[derive(Clone)]
+pub struct GranularHandle(Arc<GranularParams>);
+
+struct GranularParams {
+    position: AtomicF32,
+    position_spread: AtomicF32,
+    duration: AtomicF32,
+    duration_spread: AtomicF32,
+    pitch: AtomicF32,
+    pitch_spread: AtomicF32,
+    pan: AtomicF32,
+    pan_spread: AtomicF32,
+    density: AtomicF32,
+    density_spread: AtomicF32,
+}
+
+struct Grain {
+    position: f64, // In frames of the sample
+    step: f64,     // How far the position moves per frame of output
+    age: usize,
+    length: usize,
+    gains: [f32; 2],
+}
+
+impl Granular {
+    /// Creates a new Granular which plays grains from `samples`, interleaved with `channels` channels.
+    ///
+    /// It starts with grains of 0.1 seconds from the start of the sample, 20 times per second, with no randomness.
+    pub fn new(channels: ChannelCount, sample_rate: SampleRate, samples: Box<[Sample]>) -> Self {
+        let channels = usize::from(channels.get());
+        let scale = 1.0 / channels as f32;
+        let sample = match channels {
+            1 => samples,
+            _ => samples.chunks_exact(channels).map(|x| x.iter().sum::<Sample>() * scale).collect(),
+        };
+        Self {
+            sample,
+            sample_rate,
+            params: Arc::new(GranularParams {
+                position: AtomicF32::new(0.0),
+                position_spread: AtomicF32::new(0.0),
+                duration: AtomicF32::new(0.1),
+                duration_spread: AtomicF32::new(0.0),
+                pitch: AtomicF32::new(0.0),
+                pitch_spread: AtomicF32::new(0.0),
+                pan: AtomicF32::new(0.0),
+                pan_spread: AtomicF32::new(0.0),
+                density: AtomicF32::new(20.0),
+                density_spread: AtomicF32::new(0.0),
+            }),
+            window: window(),
+            grains: Vec::with_capacity(MAX_GRAINS),
+            random: Random::new(),
+            until_next: 0.0,
+            partial: PartialFrame::new(2),
+        }
+    }
+
+    /// Returns a handle for changing this Granular's parameters while it's playing.
+    pub fn handle(&self) -> GranularHandle {
+        GranularHandle(self.params.clone())
+    }
+
+    // Starts a new grain with randomised parameters, unless there are already too many playing
+    fn spawn(&mut self) {
+        if self.grains.len() == MAX_GRAINS || self.sample.is_empty() {
+            return
+        }
+
+        let [a, b, c, d] = [(); 4].map(|_| self.random.next());
+        let params = &self.params;
+        let sample_rate = u32::from(self.sample_rate) as f32;
+        let length = self.sample.len() as f64;
+        let position = f64::from(params.position.load() + params.position_spread.load() * a) * length;
+        let duration = params.duration.load() * (1.0 + params.duration_spread.load().clamp(0.0, 1.0) * b);
+        let pitch = (params.pitch.load() + params.pitch_spread.load() * c).clamp(-MAX_PITCH, MAX_PITCH);
+        let pan = (params.pan.load() + params.pan_spread.load() * d).clamp(-1.0, 1.0);
+        let angle = (pan + 1.0) * FRAC_PI_4;
+
+        self.grains.push(Grain {
+            // rem_euclid() can round up to `length` for tiny negative positions, so that needs wrapping too
+            position: position.rem_euclid(length) % length,
+            step: f64::from(2.0f32.powf(pitch / 12.0)),
+            age: 0,
+            length: ((duration.clamp(MIN_DURATION, MAX_DURATION) * sample_rate) as usize).max(1),
+            gains: [angle.cos(), angle.sin()],
+        });
+    }
+
+    // Returns how many frames to wait before starting the next grain
+    fn interval(&mut self) -> f32 {
+        let sample_rate = u32::from(self.sample_rate) as f32;
+        let density = self.params.density.load().clamp(MIN_DENSITY, MAX_DENSITY);
+        let spread = self.params.density_spread.load().clamp(0.0, 1.0);
+        sample_rate / density * (1.0 + spread * self.random.next())
+    }
+
+    fn write_frames(&mut self, buffer: &mut [Sample]) -> usize {
+        buffer.iter_mut().for_each(|x| *x = 0.0);
+        let frames = buffer.len() / 2;
+
+        // Render every playing grain up to the next time one starts, then start it, and so on
+        let mut frame = 0;
+        while frame < frames {
+            if self.until_next <= 0.0 {
+                self.spawn();
+                self.until_next += self.interval();
+                continue
+            }
+            let end = frames.min(frame + self.until_next.ceil() as usize);
+            for grain in self.grains.iter_mut() {
+                grain.render(&self.sample, &self.window, &mut buffer[frame * 2..end * 2]);
+            }
+            self.grains.retain(|x| x.age < x.length);
+            self.until_next -= (end - frame) as f32;
+            frame = end;
+        }
+        buffer.len()
+    }
+}
+
+impl Source for Granular {
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        ChannelCount::new(2).unwrap()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.sample_rate
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        PartialFrame::write_with(self, buffer, |granular| &mut granular.partial, Self::write_frames)
+    }
+
+    fn reset(&mut self) {
+        self.grains.clear();
+        self.random = Random::new();
+        self.until_next = 0.0;
+        self.partial.reset();
+    }
+}
+
+impl Grain {
+    // Adds as much of the grain as will fit into a stereo `buffer`
+    #[inline]
+    fn render(&mut self, sample: &[Sample], window: &[f32], buffer: &mut [Sample]) {
+        let length = sample.len() as f64;
+        let scale = (window.len() - 1) as f32 / self.length as f32;
+        for frame in buffer.chunks_exact_mut(2).take(self.length - self.age) {
+            let index = self.position as usize;
+            let fraction = (self.position - index as f64) as f32;
+            let next = if index + 1 == sample.len() { 0 } else { index + 1 };
+            let value = sample[index] + (sample[next] - sample[index]) * fraction;
+
+            let position = self.age as f32 * scale;
+            let window_index = position as usize;
+            let window_fraction = position - window_index as f32;
+            let gain = window[window_index] + (window[window_index + 1] - window[window_index]) * window_fraction;
+
+            frame[0] += value * gain * self.gains[0];
+            frame[1] += value * gain * self.gains[1];
+            self.position += self.step;
+            if self.position >= length {
+                self.position %= length;
+            }
+            self.age += 1;
+        }
+    }
+}
+
+impl GranularHandle {
+    /// Sets where in the sample grains are taken from, from 0.0 (the start) to 1.0 (the end).
+    pub fn set_position(&self, position: f32) {
+        self.0.position.store(position)
+    }
+
+    /// Sets how far either way each grain's position can be randomised, as a fraction of the sample's length.
+    pub fn set_position_spread(&self, spread: f32) {
+        self.0.position_spread.store(spread)
+    }
+
+    /// Sets how long each grain plays for, in seconds.
+    pub fn set_duration(&self, duration: f32) {
+        self.0.duration.store(duration)
+    }
+
+    /// Sets how far either way each grain's duration can be randomised, as a fraction of the duration from 0.0 to 1.0.
+    pub fn set_duration_spread(&self, spread: f32) {
+        self.0.duration_spread.store(spread)
+    }
+
+    /// Sets how far grains are pitched up, in semitones. Negative values pitch them down.
+    pub fn set_pitch(&self, pitch: f32) {
+        self.0.pitch.store(pitch)
+    }
+
+    /// Sets how far either way each grain's pitch can be randomised, in semitones.
+    pub fn set_pitch_spread(&self, spread: f32) {
+        self.0.pitch_spread.store(spread)
+    }
+
+    /// Sets where grains are panned, from -1.0 (left) to 1.0 (right).
+    pub fn set_pan(&self, pan: f32) {
+        self.0.pan.store(pan)
+    }
+
+    /// Sets how far either way each grain's pan can be randomised.
+    pub fn set_pan_spread(&self, spread: f32) {
+        self.0.pan_spread.store(spread)
+    }
+
+    /// Sets how many grains start per second, on average.
+    pub fn set_density(&self, density: f32) {
+        self.0.density.store(density)
+    }
+
+    /// Sets how far either way the time between grains can be randomised, as a fraction of the average time from 0.0
+    /// to 1.0. At 0.0, grains start at perfectly regular intervals.
+    pub fn set_density_spread(&self, spread: f32) {
+        self.0.density_spread.store(spread)
+    }
+
+    /// Returns the most recently set position.
+    pub fn position(&self) -> f32 {
+        self.0.position.load()
+    }
+
+    /// Returns the most recently set position spread.
+    pub fn position_spread(&self) -> f32 {
+        self.0.position_spread.load()
+    }
+
+    /// Returns the most recently set duration in seconds.
+    pub fn duration(&self) -> f32 {
+        self.0.duration.load()
+    }
+
+    /// Returns the most recently set duration spread.
+    pub fn duration_spread(&self) -> f32 {
+        self.0.duration_spread.load()
+    }
+
+    /// Returns the most recently set pitch in semitones.
+    pub fn pitch(&self) -> f32 {
+        self.0.pitch.load()
+    }
+
+    /// Returns the most recently set pitch spread in semitones.
+    pub fn pitch_spread(&self) -> f32 {
+        self.0.pitch_spread.load()
+    }
+
+    /// Returns the most recently set pan.
+    pub fn pan(&self) -> f32 {
+        self.0.pan.load()
+    }
+
+    /// Returns the most recently set pan spread.
+    pub fn pan_spread(&self) -> f32 {
+        self.0.pan_spread.load()
+    }
+
+    /// Returns the most recently set density in grains per second.
+    pub fn density(&self) -> f32 {
+        self.0.density.load()
+    }
+
+    /// Returns the most recently set density spread.
+    pub fn density_spread(&self) -> f32 {
+        self.0.density_spread.load()
+    }
+}
+
+// Returns a Hann window, which fades grains in and out smoothly
+fn window() -> Box<[f32]> {
+    (0..WINDOW_LENGTH).map(|i| 0.5 - 0.5 * (TAU * i as f32 / (WINDOW_LENGTH - 1) as f32).cos()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn granular(position: f32, spread: f32) -> Granular {
+        let samples = vec![0.5; 3].into_boxed_slice();
+        let granular = Granular::new(ChannelCount::new(1).unwrap(), SampleRate::new(44100).unwrap(), samples);
+        let handle = granular.handle();
+        handle.set_position(position);
+        handle.set_position_spread(spread);
+        handle.set_density(1000.0);
+        granular
+    }
+
+    #[test]
+    fn negative_positions_wrap() {
+        for &position in &[-1e-9, -f32::EPSILON, -0.0, 0.0, 1e-9, -0.5, -1.0, 1.0, 2.5] {
+            let mut granular = granular(position, 0.0);
+            let mut buffer = [0.0; 512];
+            assert_eq!(granular.write_samples(&mut buffer), buffer.len(), "granular ended at position {}", position);
+            assert!(buffer.iter().any(|&x| x != 0.0), "no grains played at position {}", position);
+        }
+    }
+
+    #[test]
+    fn spread_around_zero_stays_in_bounds() {
+        let mut granular = granular(0.0, 1e-7);
+        let mut buffer = [0.0; 4096];
+        for _ in 0..16 {
+            assert_eq!(granular.write_samples(&mut buffer), buffer.len());
+        }
+        for grain in &granular.grains {
+            assert!(grain.position >= 0.0 && grain.position < 3.0, "grain position {} out of range", grain.position);
+        }
+    }
+
+    // A second of a 441 Hz sine, which loops seamlessly, as grains of one second each played at the given pitch
+    fn sine(pitch: f32) -> Granular {
+        let samples = (0..44100).map(|i| (TAU * 441.0 * i as f32 / 44100.0).sin()).collect();
+        let granular = Granular::new(ChannelCount::new(1).unwrap(), SampleRate::new(44100).unwrap(), samples);
+        let handle = granular.handle();
+        handle.set_duration(1.0);
+        handle.set_density(0.5);
+        handle.set_pitch(pitch);
+        granular
+    }
+
+    fn play(granular: &mut Granular, frames: usize) -> Vec<Sample> {
+        let mut buffer = vec![0.0; frames * 2];
+        assert_eq!(granular.write_samples(&mut buffer), buffer.len());
+        buffer
+    }
+
+    #[test]
+    fn odd_buffers_keep_playing() {
+        let spread = || {
+            let granular = sine(0.0);
+            let handle = granular.handle();
+            handle.set_duration(0.01);
+            handle.set_density(500.0);
+            handle.set_pitch_spread(5.0);
+            handle.set_pan_spread(1.0);
+            handle.set_density_spread(0.5);
+            granular
+        };
+        let expected = play(&mut spread(), 1505);
+        let mut granular = spread();
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 301];
+        for _ in 0..10 {
+            assert_eq!(granular.write_samples(&mut buffer), buffer.len());
+            output.extend_from_slice(&buffer);
+        }
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn density_sets_how_often_grains_start() {
+        for &density in &[10.0, 100.0, 400.0] {
+            let mut granular = granular(0.0, 0.0);
+            granular.handle().set_duration(0.001);
+            granular.handle().set_density(density);
+            let output = play(&mut granular, 44100);
+
+            // Grains fade in from exactly zero, and nothing else plays in between them
+            let left = output.chunks_exact(2).map(|x| x[0]).collect::<Vec<_>>();
+            let starts = left.windows(2).filter(|x| x[0] == 0.0 && x[1] > 0.0).count() as f32;
+            assert!((starts - density).abs() <= 1.0, "{} grains started at a density of {}", starts, density);
+        }
+    }
+
+    #[test]
+    fn duration_sets_how_long_grains_play() {
+        for &duration in &[0.01, 0.05, 0.2] {
+            let mut granular = granular(0.0, 0.0);
+            granular.handle().set_duration(duration);
+            granular.handle().set_density(MIN_DENSITY);
+            let output = play(&mut granular, 44100);
+            let playing = output.chunks_exact(2).filter(|x| x[0] != 0.0).count() as f32;
+            let expected = duration * 44100.0;
+            assert!((playing - expected).abs() <= 2.0, "a grain of {} s played for {} frames", duration, playing);
+        }
+    }
+
+    #[test]
+    fn pitch_changes_the_playback_rate() {
+        for &(pitch, cycles) in &[(0.0, 220.5), (12.0, 441.0), (-12.0, 110.25), (7.0, 220.5 * 1.4983)] {
+            // Count the cycles in the middle half-second of the first grain
+            let output = play(&mut sine(pitch), 44100);
+            let left = output.chunks_exact(2).map(|x| x[0]).skip(11025).take(22050).collect::<Vec<_>>();
+            let crossings = left.windows(2).filter(|x| x[0] < 0.0 && x[1] >= 0.0).count() as f32;
+            assert!((crossings - cycles).abs() <= 1.5, "{} cycles at a pitch of {}", crossings, pitch);
+        }
+    }
+
+    #[test]
+    fn pan_moves_the_gains() {
+        let total = play(&mut sine(0.0), 4410).iter().map(|x| x * x).sum::<f32>();
+        for &pan in &[-1.0, -0.5, 0.0, 0.5, 1.0] {
+            let mut granular = sine(0.0);
+            granular.handle().set_pan(pan);
+            let output = play(&mut granular, 4410);
+            let power = |channel: usize| output.chunks_exact(2).map(|x| x[channel] * x[channel]).sum::<f32>();
+            let (left, right) = (power(0), power(1));
+
+            // Equal power panning, from all on the left, through half and half, to all on the right
+            let share = right / (left + right);
+            let expected = ((pan + 1.0) * FRAC_PI_4).sin().powi(2);
+            assert!((share - expected).abs() < 1e-3, "pan {} put {} of the power on the right", pan, share);
+            assert!(((left + right) / total - 1.0).abs() < 1e-3, "pan {} changed the power", pan);
+        }
+    }
+
+    #[test]
+    fn reset_plays_the_same_again() {
+        let mut granular = sine(0.0);
+        let handle = granular.handle();
+        handle.set_duration(0.02);
+        handle.set_duration_spread(0.5);
+        handle.set_density(200.0);
+        handle.set_density_spread(0.5);
+        handle.set_position_spread(0.5);
+        handle.set_pitch_spread(12.0);
+        handle.set_pan_spread(1.0);
+        let first = play(&mut granular, 22050);
+        granular.reset();
+        assert_eq!(play(&mut granular, 22050), first);
+    }
+}
@@ -0,0 +1,238 @@
+use super::{duration_frames, frames_to_write, Random};
+use crate::{
+    frame::PartialFrame,
+    source::{ChannelCount, Sample, SampleRate, Source},
+};
+use std::f32::consts::TAU;
+
+// Roughly brings pink and brown noise to the same peak level as white noise
+const PINK_SCALE: f32 = 0.11;
+const BROWN_SCALE: f32 = 0.4;
+
+// Brown noise stops getting louder below this frequency in Hz, so that it doesn't drift off
+const BROWN_CORNER: f32 = 20.0;
+
+/// The spectrum of a [`Noise`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub enum NoiseColor {
+    /// Equal power at every frequency, which sounds like a harsh hiss.
+    White,
+    /// Power falls by 3 dB per octave, which sounds more even to the ear, like rain or a waterfall.
+    Pink,
+    /// Power falls by 6 dB per octave, which sounds like a deep rumble.
+    Brown,
+}
+
+/// A Source which generates white, pink or brown noise.
+///
+/// Each channel gets its own noise, so stereo noise sounds wide rather than coming from the centre. The noise is
+/// pseudo-random and starts from the same point every time, so a Noise always plays exactly the same samples after
+/// it's created or reset. Use a `Gain` to change its volume while it's playing.
+pub struct Noise {
+    color: NoiseColor,
+    channels: ChannelCount,
+    sample_rate: SampleRate,
+    amplitude: f32,
+    random: Random,
+    filters: Box<[[f32; 7]]>, // For each channel, the state of the filter which shapes pink or brown noise
+    leak: f32,                // How much of brown noise's integrator is kept each sample
+    duration: Option<u64>,
+    remaining: Option<u64>,
+    partial: PartialFrame,
+}
+
+impl Noise {
+    /// Creates a new Noise with a linear amplitude, where 1.0 peaks at roughly full scale.
+    /// If `duration` is set, the Noise ends after that many seconds, otherwise it plays forever.
+    pub fn new(
+        color: NoiseColor,
+        channels: ChannelCount,
+        sample_rate: SampleRate,
+        amplitude: f32,
+        duration: Option<f32>,
+    ) -> Self {
+        let duration = duration_frames(duration, sample_rate);
+        Self {
+            color,
+            channels,
+            sample_rate,
+            amplitude,
+            random: Random::new(),
+            filters: vec![[0.0; 7]; usize::from(channels.get())].into(),
+            leak: (-TAU * BROWN_CORNER / u32::from(sample_rate) as f32).exp(),
+            duration,
+            remaining: duration,
+            partial: PartialFrame::new(channels.get().into()),
+        }
+    }
+
+    fn write_frames(&mut self, buffer: &mut [Sample]) -> usize {
+        let channels = usize::from(self.channels.get());
+        let frames = frames_to_write(buffer.len(), channels, self.remaining);
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= frames as u64;
+        }
+
+        for frame in buffer[..frames * channels].chunks_exact_mut(channels) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let white = self.random.next();
+                let b = &mut self.filters[channel];
+                let value = match self.color {
+                    NoiseColor::White => white,
+                    // Paul Kellet's refined method, which is accurate to within 0.05 dB above 9 Hz at 44.1 kHz
+                    NoiseColor::Pink => {
+                        b[0] = 0.99886 * b[0] + white * 0.0555179;
+                        b[1] = 0.99332 * b[1] + white * 0.0750759;
+                        b[2] = 0.96900 * b[2] + white * 0.153852;
+                        b[3] = 0.86650 * b[3] + white * 0.3104856;
+                        b[4] = 0.55000 * b[4] + white * 0.5329522;
+                        b[5] = -0.7616 * b[5] - white * 0.0168980;
+                        let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+                        b[6] = white * 0.115926;
+                        pink * PINK_SCALE
+                    },
+                    // Integrated white noise, leaking away below BROWN_CORNER. The input is scaled so the level
+                    // doesn't depend on the sample rate.
+                    NoiseColor::Brown => {
+                        b[0] = self.leak * b[0] + (1.0 - self.leak * self.leak).sqrt() * white;
+                        b[0] * BROWN_SCALE
+                    },
+                };
+                *sample = value * self.amplitude;
+            }
+        }
+        frames * channels
+    }
+}
+
+impl Source for Noise {
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.channels
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.sample_rate
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        PartialFrame::write_with(self, buffer, |noise| &mut noise.partial, Self::write_frames)
+    }
+
+    fn reset(&mut self) {
+        self.random = Random::new();
+        self.filters.iter_mut().for_each(|x| *x = [0.0; 7]);
+        self.remaining = self.duration;
+        self.partial.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::power_spectrum;
+
+    fn noise(color: NoiseColor, channels: u16, duration: Option<f32>) -> Noise {
+        let (channels, sample_rate) = (ChannelCount::new(channels).unwrap(), SampleRate::new(44100).unwrap());
+        Noise::new(color, channels, sample_rate, 1.0, duration)
+    }
+
+    #[test]
+    fn duration_is_exact() {
+        let mut noise = noise(NoiseColor::White, 3, Some(0.1));
+        let mut buffer = [0.0; 999];
+        let mut total = 0;
+        loop {
+            let count = noise.write_samples(&mut buffer);
+            total += count;
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(total, 4410 * 3);
+        assert_eq!(noise.write_samples(&mut buffer), 0);
+    }
+
+    #[test]
+    fn odd_buffers_keep_playing() {
+        let mut whole = noise(NoiseColor::Pink, 2, Some(0.01));
+        let mut expected = vec![0.0; 1000];
+        assert_eq!(whole.write_samples(&mut expected), 882);
+
+        // Buffers which end partway through a frame carry on from exactly where the last one stopped
+        let mut odd = noise(NoiseColor::Pink, 2, Some(0.01));
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 5];
+        loop {
+            let count = odd.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(output[..], expected[..882]);
+    }
+
+    #[test]
+    fn repeats_after_reset() {
+        for &color in &[NoiseColor::White, NoiseColor::Pink, NoiseColor::Brown] {
+            let mut noise = noise(color, 2, None);
+            let (mut first, mut second) = ([0.0; 512], [0.0; 512]);
+            noise.write_samples(&mut first);
+            noise.reset();
+            noise.write_samples(&mut second);
+            assert_eq!(first[..], second[..], "{:?} noise changed after reset", color);
+            assert_ne!(first[0], first[1], "{:?} noise should differ between channels", color);
+        }
+    }
+
+    #[test]
+    fn stays_in_range() {
+        for &color in &[NoiseColor::White, NoiseColor::Pink, NoiseColor::Brown] {
+            let mut noise = noise(color, 1, None);
+            let mut buffer = vec![0.0; 441000];
+            noise.write_samples(&mut buffer);
+            let peak = buffer.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+            assert!(peak > 0.2 && peak <= 1.5, "{:?} noise peaked at {}", color, peak);
+        }
+    }
+
+    #[test]
+    fn brown_level_is_the_same_at_any_rate() {
+        let levels = [22050, 44100, 96000].map(|rate| {
+            let (channels, sample_rate) = (ChannelCount::new(1).unwrap(), SampleRate::new(rate).unwrap());
+            let mut noise = Noise::new(NoiseColor::Brown, channels, sample_rate, 1.0, None);
+            let mut buffer = vec![0.0; rate as usize * 10];
+            noise.write_samples(&mut buffer);
+            (buffer.iter().map(|x| x * x).sum::<f32>() / buffer.len() as f32).sqrt()
+        });
+        for &level in &levels {
+            assert!((level / levels[1] - 1.0).abs() < 0.1, "levels were {:?}", levels);
+        }
+    }
+
+    #[test]
+    fn spectrum_has_the_right_slope() {
+        // The average change in power per octave, from 125 Hz up to 8 kHz
+        for &(color, slope) in &[(NoiseColor::White, 0.0), (NoiseColor::Pink, -3.0), (NoiseColor::Brown, -6.0)] {
+            let mut noise = noise(color, 1, None);
+            let mut buffer = vec![0.0; 1 << 20];
+            noise.write_samples(&mut buffer);
+            let power = power_spectrum(&buffer, 4096);
+            let bin = |frequency: f32| (frequency * 4096.0 / 44100.0).round() as usize;
+            let levels = (0..7)
+                .map(|octave| {
+                    let centre = 125.0 * 2.0f32.powi(octave);
+                    let band = &power[bin(centre / 2.0f32.sqrt())..bin(centre * 2.0f32.sqrt())];
+                    10.0 * (band.iter().sum::<f32>() / band.len() as f32).log10()
+                })
+                .collect::<Vec<_>>();
+            for (octave, pair) in levels.windows(2).enumerate() {
+                let change = pair[1] - pair[0];
+                assert!((change - slope).abs() < 1.0, "{:?} changed by {} dB in octave {}", color, change, octave);
+            }
+        }
+    }
+}
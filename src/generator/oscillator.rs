@@ -0,0 +1,345 @@
+use super::{duration_frames, frames_to_write};
+use crate::{
+    frame::PartialFrame,
+    param::{AtomicF32, Smoothed, SMOOTHING_TIME},
+    source::{ChannelCount, Sample, SampleRate, Source},
+};
+use std::{
+    f32::consts::TAU,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+};
+
+/// The shape of the wave an [`Oscillator`] generates.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub enum Waveform {
+    Sine,
+    /// Spends part of each cycle at full amplitude and the rest at full negative amplitude, depending on the duty
+    /// cycle.
+    Square,
+    Triangle,
+    /// A rising sawtooth.
+    Sawtooth,
+}
+
+/// A Source which generates a sine, square, triangle or sawtooth wave.
+///
+/// The square and sawtooth waves are band-limited with PolyBLEP, which smooths out their sharp edges so they don't
+/// alias into a mess of inharmonic tones at high frequencies. The frequency, amplitude, duty cycle and waveform can be
+/// changed while playing through a handle, and changes to the first three are ramped in over a short time.
+pub struct Oscillator {
+    params: Arc<OscillatorParams>,
+    channels: ChannelCount,
+    sample_rate: SampleRate,
+    frequency: Smoothed,
+    amplitude: Smoothed,
+    duty_cycle: Smoothed,
+    phase: f32, // From 0.0 to 1.0
+    duration: Option<u64>,
+    remaining: Option<u64>,
+    started: bool,
+    partial: PartialFrame,
+}
+
+/// Used for changing the parameters of an [`Oscillator`] while it's playing. Get one with `Oscillator::handle()`.
+#[derive(Clone)]
+pub struct OscillatorHandle(Arc<OscillatorParams>);
+
+struct OscillatorParams {
+    waveform: AtomicU8,
+    frequency: AtomicF32,
+    amplitude: AtomicF32,
+    duty_cycle: AtomicF32,
+}
+
+impl Oscillator {
+    /// Creates a new Oscillator with a frequency in Hz and a linear amplitude, where 1.0 is full scale.
+    /// If `duration` is set, the Oscillator ends after that many seconds, otherwise it plays forever.
+    ///
+    /// The duty cycle starts at 0.5, which only affects square waves.
+    pub fn new(
+        waveform: Waveform,
+        channels: ChannelCount,
+        sample_rate: SampleRate,
+        frequency: f32,
+        amplitude: f32,
+        duration: Option<f32>,
+    ) -> Self {
+        let duration = duration_frames(duration, sample_rate);
+        Self {
+            params: Arc::new(OscillatorParams {
+                waveform: AtomicU8::new(waveform as u8),
+                frequency: AtomicF32::new(frequency),
+                amplitude: AtomicF32::new(amplitude),
+                duty_cycle: AtomicF32::new(0.5),
+            }),
+            channels,
+            sample_rate,
+            frequency: Smoothed::new(frequency),
+            amplitude: Smoothed::new(amplitude),
+            duty_cycle: Smoothed::new(0.5),
+            phase: 0.0,
+            duration,
+            remaining: duration,
+            started: false,
+            partial: PartialFrame::new(channels.get().into()),
+        }
+    }
+
+    /// Returns a handle for changing this Oscillator's parameters while it's playing.
+    pub fn handle(&self) -> OscillatorHandle {
+        OscillatorHandle(self.params.clone())
+    }
+
+    fn write_frames(&mut self, buffer: &mut [Sample]) -> usize {
+        let channels = usize::from(self.channels.get());
+        let frames = frames_to_write(buffer.len(), channels, self.remaining);
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= frames as u64;
+        }
+
+        let sample_rate = u32::from(self.sample_rate) as f32;
+        let steps = (SMOOTHING_TIME * sample_rate) as u32;
+        let waveform = Waveform::from_u8(self.params.waveform.load(Ordering::Acquire));
+        self.frequency.set_target(self.params.frequency.load().clamp(0.0, sample_rate * 0.5), steps);
+        self.amplitude.set_target(self.params.amplitude.load(), steps);
+        self.duty_cycle.set_target(self.params.duty_cycle.load().clamp(0.0, 1.0), steps);
+        if !self.started {
+            self.frequency.settle();
+            self.amplitude.settle();
+            self.duty_cycle.settle();
+            self.started = true;
+        }
+
+        for frame in buffer[..frames * channels].chunks_exact_mut(channels) {
+            let increment = self.frequency.next() / sample_rate;
+            let amplitude = self.amplitude.next();
+            let duty_cycle = self.duty_cycle.next();
+            let value = waveform.at(self.phase, increment, duty_cycle) * amplitude;
+            frame.iter_mut().for_each(|x| *x = value);
+            self.phase += increment;
+            if self.phase >= 1.0 {
+                self.phase -= 1.0;
+            }
+        }
+        frames * channels
+    }
+}
+
+impl Source for Oscillator {
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.channels
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.sample_rate
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        PartialFrame::write_with(self, buffer, |oscillator| &mut oscillator.partial, Self::write_frames)
+    }
+
+    fn reset(&mut self) {
+        self.phase = 0.0;
+        self.remaining = self.duration;
+        self.started = false;
+        self.partial.reset();
+    }
+}
+
+impl Waveform {
+    #[inline]
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Sine,
+            1 => Self::Square,
+            2 => Self::Triangle,
+            _ => Self::Sawtooth,
+        }
+    }
+
+    // Returns the wave's value, from -1.0 to 1.0, at a phase from 0.0 to 1.0.
+    // `increment` is how far the phase moves each sample, which sets how wide the PolyBLEP corrections are.
+    #[inline]
+    fn at(self, phase: f32, increment: f32, duty_cycle: f32) -> f32 {
+        match self {
+            Self::Sine => (phase * TAU).sin(),
+            Self::Square => {
+                let naive = if phase < duty_cycle { 1.0 } else { -1.0 };
+                let falling = (phase - duty_cycle).rem_euclid(1.0);
+                naive + poly_blep(phase, increment) - poly_blep(falling, increment)
+            },
+            Self::Triangle => 1.0 - 4.0 * ((phase + 0.25).fract() - 0.5).abs(),
+            Self::Sawtooth => 2.0 * phase - 1.0 - poly_blep(phase, increment),
+        }
+    }
+}
+
+// The PolyBLEP residual for a step of height 2 at phase 0.0, which is added to a naive waveform to smooth the step out
+// over the samples either side of it
+#[inline]
+fn poly_blep(phase: f32, increment: f32) -> f32 {
+    if phase < increment {
+        let t = phase / increment;
+        2.0 * t - t * t - 1.0
+    } else if phase > 1.0 - increment {
+        let t = (phase - 1.0) / increment;
+        t * t + 2.0 * t + 1.0
+    } else {
+        0.0
+    }
+}
+
+impl OscillatorHandle {
+    /// Sets the shape of the wave. This takes effect immediately, so it may click.
+    pub fn set_waveform(&self, waveform: Waveform) {
+        self.0.waveform.store(waveform as u8, Ordering::Release)
+    }
+
+    /// Sets the frequency in Hz, up to half the sample rate.
+    pub fn set_frequency(&self, frequency: f32) {
+        self.0.frequency.store(frequency)
+    }
+
+    /// Sets the amplitude as a linear multiplier, where 1.0 is full scale.
+    pub fn set_amplitude(&self, amplitude: f32) {
+        self.0.amplitude.store(amplitude)
+    }
+
+    /// Sets the fraction of each cycle a square wave spends high, from 0.0 to 1.0. 0.5 gives an even square wave.
+    pub fn set_duty_cycle(&self, duty_cycle: f32) {
+        self.0.duty_cycle.store(duty_cycle)
+    }
+
+    /// Returns the most recently set waveform.
+    pub fn waveform(&self) -> Waveform {
+        Waveform::from_u8(self.0.waveform.load(Ordering::Acquire))
+    }
+
+    /// Returns the most recently set frequency in Hz.
+    pub fn frequency(&self) -> f32 {
+        self.0.frequency.load()
+    }
+
+    /// Returns the most recently set amplitude.
+    pub fn amplitude(&self) -> f32 {
+        self.0.amplitude.load()
+    }
+
+    /// Returns the most recently set duty cycle.
+    pub fn duty_cycle(&self) -> f32 {
+        self.0.duty_cycle.load()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::power_spectrum;
+
+    fn oscillator(waveform: Waveform, channels: u16, frequency: f32, duration: Option<f32>) -> Oscillator {
+        let (channels, sample_rate) = (ChannelCount::new(channels).unwrap(), SampleRate::new(48000).unwrap());
+        Oscillator::new(waveform, channels, sample_rate, frequency, 0.5, duration)
+    }
+
+    #[test]
+    fn duration_is_exact() {
+        let mut oscillator = oscillator(Waveform::Sine, 2, 440.0, Some(0.01));
+        let mut buffer = [0.0; 98];
+        let mut total = 0;
+        loop {
+            let count = oscillator.write_samples(&mut buffer);
+            total += count;
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(total, 480 * 2);
+        assert_eq!(oscillator.write_samples(&mut buffer), 0);
+
+        oscillator.reset();
+        let mut buffer = vec![0.0; 2000];
+        assert_eq!(oscillator.write_samples(&mut buffer), 960);
+    }
+
+    #[test]
+    fn odd_buffers_keep_playing() {
+        let mut whole = oscillator(Waveform::Sawtooth, 2, 440.0, Some(0.01));
+        let mut expected = vec![0.0; 1000];
+        assert_eq!(whole.write_samples(&mut expected), 960);
+
+        // Buffers which end partway through a frame carry on from exactly where the last one stopped
+        let mut odd = oscillator(Waveform::Sawtooth, 2, 440.0, Some(0.01));
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 7];
+        loop {
+            let count = odd.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(output[..], expected[..960]);
+        assert_eq!(odd.write_samples(&mut buffer[..1]), 0);
+
+        let mut ended = oscillator(Waveform::Sine, 2, 440.0, Some(0.0));
+        assert_eq!(ended.write_samples(&mut buffer[..1]), 0);
+    }
+
+    #[test]
+    fn sine_follows_frequency() {
+        // 1200 Hz at 48 kHz has a period of 40 frames
+        let mut oscillator = oscillator(Waveform::Sine, 1, 1200.0, None);
+        let mut buffer = [0.0; 40];
+        oscillator.write_samples(&mut buffer);
+        for (i, sample) in buffer.iter().enumerate() {
+            let expected = 0.5 * (TAU * i as f32 / 40.0).sin();
+            assert!((sample - expected).abs() < 1e-4, "frame {} was {}, not {}", i, sample, expected);
+        }
+    }
+
+    #[test]
+    fn waveforms_stay_in_range() {
+        for &waveform in &[Waveform::Sine, Waveform::Square, Waveform::Triangle, Waveform::Sawtooth] {
+            let mut oscillator = oscillator(waveform, 1, 1000.0, None);
+            let mut buffer = [0.0; 4800];
+            oscillator.write_samples(&mut buffer);
+            let peak = buffer.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+            let mean = buffer.iter().sum::<f32>() / buffer.len() as f32;
+            assert!(peak > 0.45 && peak < 0.6, "{:?} peaked at {}", waveform, peak);
+            assert!(mean.abs() < 0.01, "{:?} had a DC offset of {}", waveform, mean);
+        }
+    }
+
+    #[test]
+    fn sawtooth_is_band_limited() {
+        // Harmonics above half the sample rate fold back down between the real ones, so anything away from the
+        // harmonics is aliasing. 2500 Hz isn't a divisor of 48 kHz, so none of it lands on a harmonic.
+        let frequency = 2500.0;
+        let aliasing = |signal: &[Sample]| {
+            let power = power_spectrum(signal, 4096);
+            let bin = |frequency: f32| frequency * 4096.0 / 48000.0;
+            let near_harmonic = |i: usize| {
+                let harmonic = (i as f32 / bin(frequency)).round();
+                harmonic >= 1.0 && (i as f32 - harmonic * bin(frequency)).abs() <= 3.0
+            };
+            let total = power.iter().sum::<f32>();
+            let aliased = power.iter().enumerate().filter(|&(i, _)| !near_harmonic(i)).map(|(_, x)| x).sum::<f32>();
+            10.0 * (aliased / total).log10()
+        };
+
+        let mut oscillator = oscillator(Waveform::Sawtooth, 1, frequency, None);
+        let mut blep = vec![0.0; 1 << 16];
+        oscillator.write_samples(&mut blep);
+        let naive = (0..blep.len()).map(|i| (i as f32 * frequency / 48000.0).fract() - 0.5).collect::<Vec<_>>();
+
+        let (blep, naive) = (aliasing(&blep), aliasing(&naive));
+        assert!(blep < -25.0, "aliasing was {} dB", blep);
+        assert!(blep < naive - 10.0, "aliasing was {} dB, against {} dB without PolyBLEP", blep, naive);
+    }
+}
@@ -1,16 +1,27 @@
 //pub mod buffer;
 mod error;
 pub mod cycle;
+pub mod effects;
+mod fft;
+mod frame;
+pub mod generator;
 pub mod mixer;
+mod param;
 pub mod rechanneler;
 pub mod resampler;
+pub mod reverse;
 pub mod session;
+mod simd;
 pub mod source;
+pub mod spatial;
 
 #[cfg(feature = "wav")]
 pub mod wav;
 
-use crate::source::{ChannelCount, Sample, SampleRate, Source};
+#[doc(hidden)]
+pub use simd::bench as simd_bench;
+
+use crate::source::{ChannelCount, Sample, SampleRate, Seekable, Source};
 
 /// A basic sound-playing object. When fed to an output stream, will play the samples it contains until it has no more.
 /// If the samples have a different sample rate than the output stream, the output will sound sped up or slowed down.
@@ -58,3 +69,19 @@ impl Source for Player {
         self.offset = 0;
     }
 }
+
+impl Seekable for Player {
+    #[inline]
+    fn frame_count(&self) -> usize {
+        self.samples.len() / usize::from(self.channels.get())
+    }
+
+    #[inline]
+    fn position(&self) -> usize {
+        self.offset.min(self.samples.len()) / usize::from(self.channels.get())
+    }
+
+    fn seek(&mut self, frame: usize) {
+        self.offset = frame.min(self.frame_count()) * usize::from(self.channels.get());
+    }
+}
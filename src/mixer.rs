@@ -1,4 +1,4 @@
-use crate::{source::{ChannelCount, SampleRate, Sample, Source}};
+use crate::{simd, source::{ChannelCount, SampleRate, Sample, Source}};
 use std::{sync::Arc, sync::{atomic::{AtomicBool, Ordering}, mpsc::{self, Receiver, Sender}}};
 
 const INIT_CAPACITY: usize = 16;
@@ -63,9 +63,7 @@ impl Source for Mixer {
                 input_buffer.resize_with(buffer.len(), Default::default);
                 let count = source.write_samples(input_buffer);
 
-                for (in_sample, out_sample) in input_buffer.iter().take(count).copied().zip(buffer.iter_mut()) {
-                    *out_sample += in_sample;
-                }
+                simd::mix(buffer, &input_buffer[..count]);
 
                 let running = count == input_buffer.len();
                 info.running.store(running, Ordering::Release);
@@ -0,0 +1,92 @@
+//! Helpers for effect parameters which can be changed from another thread while a Source is playing.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// An f32 which can be shared between threads. It's stored as its bit pattern in an AtomicU32.
+#[derive(Debug)]
+pub(crate) struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    #[inline]
+    pub(crate) fn new(value: f32) -> Self {
+        Self(AtomicU32::new(value.to_bits()))
+    }
+
+    #[inline]
+    pub(crate) fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Acquire))
+    }
+
+    #[inline]
+    pub(crate) fn store(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Release)
+    }
+}
+
+/// How long a parameter change takes to ramp in, in seconds.
+pub(crate) const SMOOTHING_TIME: f32 = 0.02;
+
+/// How long a parameter change takes to ramp in, in seconds, for effects with delay times. These ramp more slowly,
+/// since a changing delay time bends the pitch of everything in the delay line.
+pub(crate) const DELAY_SMOOTHING_TIME: f32 = 0.05;
+
+/// A value which ramps linearly towards its target over a set number of steps, rather than jumping straight to it.
+/// Ramping parameter changes like this avoids the clicks and "zipper noise" that sudden changes would cause.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Smoothed {
+    current: f32,
+    target: f32,
+    step: f32,
+    remaining: u32,
+}
+
+impl Smoothed {
+    #[inline]
+    pub(crate) fn new(value: f32) -> Self {
+        Self { current: value, target: value, step: 0.0, remaining: 0 }
+    }
+
+    /// Sets a new target, to be reached after `steps` calls to `next()`. Does nothing if that's already the target.
+    #[inline]
+    pub(crate) fn set_target(&mut self, target: f32, steps: u32) {
+        if target != self.target {
+            self.target = target;
+            if steps == 0 {
+                self.current = target;
+                self.remaining = 0;
+            } else {
+                self.step = (target - self.current) / steps as f32;
+                self.remaining = steps;
+            }
+        }
+    }
+
+    /// Jumps straight to the current target.
+    #[inline]
+    pub(crate) fn settle(&mut self) {
+        self.current = self.target;
+        self.remaining = 0;
+    }
+
+    /// Advances by one step and returns the new value.
+    #[inline]
+    pub(crate) fn next(&mut self) -> f32 {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.current = if self.remaining == 0 { self.target } else { self.current + self.step };
+        }
+        self.current
+    }
+
+    /// Returns the value most recently returned by `next()`, without advancing.
+    #[inline]
+    pub(crate) fn value(&self) -> f32 {
+        self.current
+    }
+
+    /// Returns whether the target has been reached.
+    #[inline]
+    pub(crate) fn is_settled(&self) -> bool {
+        self.remaining == 0
+    }
+}
@@ -1,13 +1,21 @@
-use crate::source::{ChannelCount, Sample, SampleRate, Source};
+use crate::{
+    frame::PartialFrame,
+    source::{ChannelCount, ChannelId, ChannelLayout, Sample, SampleRate, Source},
+};
 
 /// Converts the number of channels in a Source to the target channel count.
 ///
 /// Channel mixing strategy is as follows:
-/// - if input and output channel count is the same, pass straight through
-/// - otherwise: take the average input sample from each frame and duplicate it across each output frame
+/// - if input and output layouts are the same, pass straight through
+/// - otherwise: mix each input frame into an output frame through a [`MixMatrix`]
 ///
-/// This suffices for most use-cases, but a more specialized strategy would be needed for mixing a multi-channel input
-/// to a >2 channel output (5-channel multimedia, for example) non-naively.
+/// `Rechanneler::new()` mixes from the source's `channel_layout()` to libsoundio's default layout for the target
+/// channel count (see `ChannelLayout::default_for()`) with `MixMatrix::between()`. Sources which don't report a
+/// layout are assumed to use the default layout for their channel count. If either layout is still unknown, it falls
+/// back to averaging every input channel and copying the result to each output channel.
+///
+/// To mix to a specific layout, use `Rechanneler::with_layout()`. To override the source's layout as well, use
+/// `Rechanneler::with_layouts()`. To supply your own gains, use `Rechanneler::with_matrix()`.
 pub struct Rechanneler<S>
 where
     S: Source,
@@ -15,7 +23,10 @@ where
     source: S,
     source_channels: ChannelCount,
     target_channels: ChannelCount,
+    target_layout: Option<ChannelLayout>,
+    matrix: Option<MixMatrix>,
     buffer: Vec<Sample>,
+    partial: PartialFrame,
 }
 
 impl<S> Rechanneler<S>
@@ -24,7 +35,63 @@ where
 {
     pub fn new(source: S, target_channels: ChannelCount) -> Self {
         let source_channels = source.channel_count();
-        Self { source, source_channels, target_channels, buffer: Vec::new() }
+        match ChannelLayout::default_for(target_channels) {
+            Some(target_layout) => Self::with_layout(source, target_layout),
+            None if source_channels == target_channels => {
+                let target_layout = source.channel_layout();
+                let (matrix, buffer, partial) = (None, Vec::new(), PartialFrame::new(target_channels.get().into()));
+                Self { source, source_channels, target_channels, target_layout, matrix, buffer, partial }
+            },
+            None => Self::with_matrix(source, MixMatrix::average(source_channels, target_channels)),
+        }
+    }
+
+    /// Creates a Rechanneler which mixes from the source's layout to `target_layout` using `MixMatrix::between()`.
+    ///
+    /// If the source doesn't report a layout, the default for its channel count is assumed. If there's no default
+    /// either, every input channel is averaged and copied to each output channel.
+    pub fn with_layout(source: S, target_layout: ChannelLayout) -> Self {
+        let source_channels = source.channel_count();
+        match source.channel_layout().or_else(|| ChannelLayout::default_for(source_channels)) {
+            Some(source_layout) => Self::with_layouts(source, &source_layout, target_layout),
+            None => {
+                let mut rechanneler =
+                    Self::with_matrix(source, MixMatrix::average(source_channels, target_layout.channel_count()));
+                rechanneler.target_layout = Some(target_layout);
+                rechanneler
+            },
+        }
+    }
+
+    /// Creates a Rechanneler which mixes from `source_layout` to `target_layout` using `MixMatrix::between()`,
+    /// ignoring whatever layout the source reports.
+    ///
+    /// Panics if `source_layout` doesn't have the same number of channels as `source`.
+    pub fn with_layouts(source: S, source_layout: &ChannelLayout, target_layout: ChannelLayout) -> Self {
+        let source_channels = source.channel_count();
+        assert_eq!(source_channels, source_layout.channel_count());
+        let target_channels = target_layout.channel_count();
+        let matrix = if *source_layout == target_layout {
+            None
+        } else {
+            Some(MixMatrix::between(source_layout, &target_layout))
+        };
+        let target_layout = Some(target_layout);
+        let partial = PartialFrame::new(target_channels.get().into());
+        Self { source, source_channels, target_channels, target_layout, matrix, buffer: Vec::new(), partial }
+    }
+
+    /// Creates a Rechanneler which mixes through the given matrix.
+    /// The output will have as many channels as the matrix has outputs.
+    ///
+    /// Panics if the matrix doesn't have the same number of inputs as `source` has channels.
+    pub fn with_matrix(source: S, matrix: MixMatrix) -> Self {
+        let source_channels = source.channel_count();
+        assert_eq!(source_channels, matrix.inputs());
+        let target_channels = matrix.outputs();
+        let matrix = Some(matrix);
+        let partial = PartialFrame::new(target_channels.get().into());
+        Self { source, source_channels, target_channels, target_layout: None, matrix, buffer: Vec::new(), partial }
     }
 }
 
@@ -42,28 +109,285 @@ where
         self.target_channels
     }
 
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.target_layout.clone()
+    }
+
     fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
-        if self.source_channels == self.target_channels {
-            self.source.write_samples(buffer)
+        let Self { source, source_channels, target_channels, matrix, buffer: input, partial, .. } = self;
+        if let Some(matrix) = matrix {
+            let from: usize = source_channels.get().into();
+            let to: usize = target_channels.get().into();
+
+            // Only whole frames are read from the source, so it doesn't lose its place between channels
+            partial.write(buffer, |buffer| {
+                input.resize_with(buffer.len() / to * from, Default::default);
+                let written_count = source.write_samples(input);
+                let iter = input.chunks_exact(from).take(written_count / from).zip(buffer.chunks_exact_mut(to));
+                let frame_count = iter.len();
+
+                for (in_samples, out_samples) in iter {
+                    matrix.apply(in_samples, out_samples);
+                }
+
+                frame_count * to
+            })
         } else {
-            let from: usize = self.source_channels.get().into();
-            let to: usize = self.target_channels.get().into();
+            source.write_samples(buffer)
+        }
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.partial.reset();
+    }
+}
+
+/// A matrix of gains for mixing each frame of one set of channels into another.
+///
+/// Each output sample is the sum of every input sample in the frame, each multiplied by its gain for that output.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub struct MixMatrix {
+    inputs: ChannelCount,
+    outputs: ChannelCount,
+
+    // One row of `inputs` gains for each output channel
+    gains: Box<[f32]>,
+}
 
-            self.buffer.resize_with(buffer.len() * from / to, Default::default);
-            let written_count = self.source.write_samples(&mut self.buffer);
-            let iter = self.buffer.chunks_exact(from).take(written_count / from).zip(buffer.chunks_exact_mut(to));
-            let frame_count = iter.len();
+// -3 dB, used for splitting one channel between two speakers or folding it into a neighbouring one
+const MINUS_3DB: f32 = std::f32::consts::FRAC_1_SQRT_2;
 
-            for (in_samples, out_samples) in iter {
-                let sample = in_samples.iter().sum::<Sample>() / in_samples.len() as Sample;
-                out_samples.iter_mut().for_each(|x| *x = sample);
+impl MixMatrix {
+    /// Creates a MixMatrix from a list of gains. The list should contain one row for each output channel,
+    /// and each row should contain one gain for each input channel. That is, the gain for input `i` going into
+    /// output `o` is at `gains[o * inputs + i]`.
+    ///
+    /// Returns `None` if the list doesn't contain exactly `inputs * outputs` gains.
+    pub fn new(inputs: ChannelCount, outputs: ChannelCount, gains: impl Into<Box<[f32]>>) -> Option<Self> {
+        let gains = gains.into();
+        if gains.len() == usize::from(inputs.get()) * usize::from(outputs.get()) {
+            Some(Self { inputs, outputs, gains })
+        } else {
+            None
+        }
+    }
+
+    /// Creates a MixMatrix which averages every input channel and copies the result to each output channel.
+    pub fn average(inputs: ChannelCount, outputs: ChannelCount) -> Self {
+        let gain = 1.0 / f32::from(inputs.get());
+        let gains = vec![gain; usize::from(inputs.get()) * usize::from(outputs.get())];
+        Self { inputs, outputs, gains: gains.into_boxed_slice() }
+    }
+
+    /// Creates a MixMatrix for mixing between two channel layouts, following the downmix coefficients given in
+    /// ITU-R BS.775 and extending them in the same manner for upmixing and for less common channels.
+    ///
+    /// Each input channel goes to the output channel with the same [`ChannelId`] if there is one. Otherwise:
+    /// - a centre channel is split between left and right at -3 dB, and left and right are folded into a centre
+    ///   channel at -3 dB (so mono becomes stereo at -3 dB per side, and stereo becomes mono as `0.707 * (L + R)`)
+    /// - side and back channels swap for each other if possible, or are otherwise folded into the front channel
+    ///   on the same side at -3 dB
+    /// - less common speaker positions are treated as the nearest common one
+    /// - mid/side and X-Y pairs are decoded to left and right
+    /// - LFE channels only go to an LFE output. As in BS.775, they're dropped when downmixing without one.
+    /// - ambisonic, auxiliary and non-speaker channels (such as a click track) are dropped
+    ///
+    /// Downmixes are not normalised, so loud multichannel content may clip when mixed to fewer channels.
+    pub fn between(from: &ChannelLayout, to: &ChannelLayout) -> Self {
+        // If the fallback chain hasn't found a usable output by this point, the channel is dropped
+        const MAX_DEPTH: usize = 4;
+
+        fn route(id: ChannelId, gain: f32, to: &ChannelLayout, depth: usize, row: &mut [f32], inputs: usize) {
+            if let Some(i) = to.find(id) {
+                row[i * inputs] += gain;
+            } else if depth < MAX_DEPTH {
+                // Take the first alternative whose channels all exist, or the last one if none of them do
+                let alternatives = fallbacks(id);
+                let alternative = alternatives
+                    .iter()
+                    .find(|alt| alt.iter().all(|&(x, _)| to.find(x).is_some()))
+                    .or_else(|| alternatives.last());
+                for &(x, g) in alternative.into_iter().flat_map(|x| x.iter()) {
+                    route(x, gain * g, to, depth + 1, row, inputs);
+                }
             }
+        }
 
-            frame_count * to
+        let inputs = from.channel_count();
+        let outputs = to.channel_count();
+        let input_count = usize::from(inputs.get());
+        let mut gains = vec![0.0; input_count * usize::from(outputs.get())];
+        for (i, &id) in from.channels().iter().enumerate() {
+            route(id, 1.0, to, 0, &mut gains[i..], input_count);
         }
+        Self { inputs, outputs, gains: gains.into_boxed_slice() }
     }
 
-    fn reset(&mut self) {
-        self.source.reset()
+    /// Returns the number of input channels this matrix expects.
+    #[inline]
+    pub fn inputs(&self) -> ChannelCount {
+        self.inputs
+    }
+
+    /// Returns the number of output channels this matrix produces.
+    #[inline]
+    pub fn outputs(&self) -> ChannelCount {
+        self.outputs
+    }
+
+    /// Returns the gain applied to `input` when mixing it into `output`.
+    ///
+    /// Panics if either index is out of range.
+    #[inline]
+    pub fn gain(&self, input: usize, output: usize) -> f32 {
+        assert!(input < usize::from(self.inputs.get()));
+        self.gains[output * usize::from(self.inputs.get()) + input]
+    }
+
+    // Mixes a single frame. `input` and `output` must have the same lengths as the matrix has inputs and outputs.
+    #[inline]
+    pub(crate) fn apply(&self, input: &[Sample], output: &mut [Sample]) {
+        for (out, row) in output.iter_mut().zip(self.gains.chunks_exact(input.len())) {
+            *out = row.iter().zip(input).map(|(g, x)| g * x).sum();
+        }
+    }
+}
+
+// Lists the ways a channel can be redistributed if the output layout doesn't have it, in order of preference
+fn fallbacks(id: ChannelId) -> &'static [&'static [(ChannelId, f32)]] {
+    use ChannelId::*;
+    match id {
+        FrontLeft => &[&[(FrontCenter, MINUS_3DB)]],
+        FrontRight => &[&[(FrontCenter, MINUS_3DB)]],
+        FrontCenter => &[&[(FrontLeft, MINUS_3DB), (FrontRight, MINUS_3DB)]],
+        SideLeft => &[&[(BackLeft, 1.0)], &[(FrontLeft, MINUS_3DB)]],
+        SideRight => &[&[(BackRight, 1.0)], &[(FrontRight, MINUS_3DB)]],
+        BackLeft => &[&[(SideLeft, 1.0)], &[(FrontLeft, MINUS_3DB)]],
+        BackRight => &[&[(SideRight, 1.0)], &[(FrontRight, MINUS_3DB)]],
+        BackCenter => &[&[(BackLeft, MINUS_3DB), (BackRight, MINUS_3DB)], &[
+            (SideLeft, MINUS_3DB),
+            (SideRight, MINUS_3DB),
+        ]],
+
+        FrontLeftCenter | FrontLeftWide | FrontLeftHigh | TopFrontLeft | TopFrontLeftCenter | BottomLeftCenter => {
+            &[&[(FrontLeft, 1.0)]]
+        },
+        FrontRightCenter | FrontRightWide | FrontRightHigh | TopFrontRight | TopFrontRightCenter
+        | BottomRightCenter => &[&[(FrontRight, 1.0)]],
+        TopCenter | TopFrontCenter | FrontCenterHigh | BottomCenter | DialogCentricMix => &[&[(FrontCenter, 1.0)]],
+        TopSideLeft => &[&[(SideLeft, 1.0)]],
+        TopSideRight => &[&[(SideRight, 1.0)]],
+        TopBackLeft | BackLeftCenter => &[&[(BackLeft, 1.0)]],
+        TopBackRight | BackRightCenter => &[&[(BackRight, 1.0)]],
+        TopBackCenter => &[&[(BackCenter, 1.0)]],
+        LeftLfe | RightLfe | Lfe2 => &[&[(Lfe, 1.0)]],
+
+        HeadphonesLeft | XyX => &[&[(FrontLeft, 1.0)]],
+        HeadphonesRight | XyY => &[&[(FrontRight, 1.0)]],
+        MsMid => &[&[(FrontLeft, MINUS_3DB), (FrontRight, MINUS_3DB)]],
+        MsSide => &[&[(FrontLeft, MINUS_3DB), (FrontRight, -MINUS_3DB)]],
+
+        Lfe | AmbisonicW | AmbisonicX | AmbisonicY | AmbisonicZ | ClickTrack | ForeignLanguage | HearingImpaired
+        | Narration | Haptic | Aux | Aux0 | Aux1 | Aux2 | Aux3 | Aux4 | Aux5 | Aux6 | Aux7 | Aux8 | Aux9 | Aux10
+        | Aux11 | Aux12 | Aux13 | Aux14 | Aux15 => &[],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Player;
+
+    fn player(channels: u16, samples: Vec<Sample>) -> Player {
+        Player::new(ChannelCount::new(channels).unwrap(), SampleRate::new(48000).unwrap(), samples.into())
+    }
+
+    fn gains(matrix: &MixMatrix) -> Vec<Vec<f32>> {
+        let (inputs, outputs) = (usize::from(matrix.inputs().get()), usize::from(matrix.outputs().get()));
+        (0..outputs).map(|o| (0..inputs).map(|i| matrix.gain(i, o)).collect()).collect()
+    }
+
+    #[test]
+    fn downmixes_5_1_to_stereo() {
+        // ITU-R BS.775: L = FL + 0.707 C + 0.707 SL, R = FR + 0.707 C + 0.707 SR, with the LFE dropped
+        let matrix = MixMatrix::between(&ChannelLayout::SURROUND_5_1, &ChannelLayout::STEREO);
+        assert_eq!(gains(&matrix), vec![
+            vec![1.0, 0.0, MINUS_3DB, MINUS_3DB, 0.0, 0.0],
+            vec![0.0, 1.0, MINUS_3DB, 0.0, MINUS_3DB, 0.0],
+        ]);
+    }
+
+    #[test]
+    fn mono_and_stereo() {
+        let up = MixMatrix::between(&ChannelLayout::MONO, &ChannelLayout::STEREO);
+        assert_eq!(gains(&up), vec![vec![MINUS_3DB], vec![MINUS_3DB]]);
+        let down = MixMatrix::between(&ChannelLayout::STEREO, &ChannelLayout::MONO);
+        assert_eq!(gains(&down), vec![vec![MINUS_3DB, MINUS_3DB]]);
+    }
+
+    #[test]
+    fn swaps_side_and_back() {
+        // 5.1 (back) into 5.1 (side) moves the back pair to the sides rather than folding them into the front
+        let matrix = MixMatrix::between(&ChannelLayout::SURROUND_5_1_BACK, &ChannelLayout::SURROUND_5_1);
+        for (i, row) in gains(&matrix).iter().enumerate() {
+            let expected = (0..6).map(|x| if x == i { 1.0 } else { 0.0 }).collect::<Vec<_>>();
+            assert_eq!(*row, expected);
+        }
+
+        // 7.1 into 5.1 has both, so the back pair folds into the sides
+        let matrix = MixMatrix::between(&ChannelLayout::SURROUND_7_1, &ChannelLayout::SURROUND_5_1);
+        assert_eq!(gains(&matrix)[3], vec![0.0, 0.0, 0.0, 1.0, 0.0, 1.0, 0.0, 0.0]);
+        assert_eq!(gains(&matrix)[5], vec![0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn same_layout_passes_through() {
+        let samples = vec![0.1, 0.2, 0.3, 0.4];
+        let mut rechanneler = Rechanneler::new(player(2, samples.clone()), ChannelCount::new(2).unwrap());
+        assert_eq!(rechanneler.channel_layout(), Some(ChannelLayout::STEREO));
+        let mut buffer = [0.0; 6];
+        assert_eq!(rechanneler.write_samples(&mut buffer), 4);
+        assert_eq!(buffer[..4], samples[..]);
+    }
+
+    #[test]
+    fn mixes_each_frame() {
+        let source = player(2, vec![1.0, 0.0, 0.0, 1.0, 0.5, 0.5]);
+        let mut rechanneler = Rechanneler::new(source, ChannelCount::new(1).unwrap());
+        let mut buffer = [0.0; 4];
+        assert_eq!(rechanneler.write_samples(&mut buffer), 3);
+        assert_eq!(buffer[..3], [MINUS_3DB, MINUS_3DB, MINUS_3DB]);
+        assert_eq!(rechanneler.write_samples(&mut buffer), 0);
+    }
+
+    #[test]
+    fn odd_buffers_keep_playing() {
+        // Buffers which don't hold a whole number of output frames carry on where the last one left off
+        let samples = (0..60).map(|x| x as Sample).collect::<Vec<_>>();
+        let mut rechanneler = Rechanneler::with_matrix(
+            player(3, samples),
+            MixMatrix::new(ChannelCount::new(3).unwrap(), ChannelCount::new(2).unwrap(), vec![
+                1.0, 0.0, 0.0, //
+                0.0, 0.0, 1.0,
+            ])
+            .unwrap(),
+        );
+        let mut buffer = [-1.0; 5];
+        let mut output = Vec::new();
+        loop {
+            let count = rechanneler.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(rechanneler.write_samples(&mut buffer), 0);
+
+        // Every frame comes out once, in order, with its channels in place and nothing added between them
+        let expected = (0..20).flat_map(|x| vec![(x * 3) as Sample, (x * 3 + 2) as Sample]).collect::<Vec<_>>();
+        assert_eq!(output, expected);
     }
 }
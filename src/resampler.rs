@@ -1,7 +1,20 @@
-use crate::source::{ChannelCount, Sample, SampleRate, Source};
-
-/// Implementation of a PQF resampler. Construct with: Resampler::new(source, source_rate, dest_rate)
+use crate::{
+    simd,
+    source::{ChannelCount, ChannelLayout, Sample, SampleRate, Source},
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+/// Implementation of a PQF resampler. Construct with: Resampler::new(source, dest_rate)
 /// Once constructed, it will behave as a Source object which outputs samples at the target sample rate.
+///
+/// To trade accuracy for speed, construct with Resampler::with_quality(source, dest_rate, quality) instead.
+/// See [`Quality`] for the available options.
+///
+/// Filters are shared between Resamplers with the same conversion ratio and quality through a [`FilterCache`],
+/// so only the first Resampler created for a given ratio has to pay the cost of building its filter.
 pub struct Resampler<S>
 where
     S: Source,
@@ -11,7 +24,7 @@ where
     to: u32,
     dest_rate: SampleRate, // Actual output rate - different from `to` because that is scaled down by GCD
     left_offset: usize,
-    kaiser_values: Box<[Box<[f32]>]>,
+    filter_bank: Arc<FilterBank>,
     filter_1: Box<[Sample]>,
     filter_2: Box<[Sample]>,
 
@@ -31,88 +44,238 @@ where
     last_sample: Option<usize>,
 }
 
-impl<S: Source> Resampler<S> {
-    pub fn new(mut source: S, dest_rate: SampleRate) -> Self {
-        #[inline]
-        fn gcd(a: u32, b: u32) -> u32 {
-            if b == 0 { a } else { gcd(b, a % b) }
-        }
+/// Quality setting for a [`Resampler`].
+///
+/// Figures are given relative to the lower of the two sample rates ("fs") for the sinc qualities,
+/// and relative to the source sample rate for the interpolating qualities, which have no anti-aliasing filter.
+/// Higher qualities use longer filters, so they cost more per output sample and take longer to construct.
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub enum Quality {
+    /// Linear interpolation between adjacent samples.
+    ///
+    /// Passband droops by 1.8 dB at 0.25fs and 6.2 dB at 0.45fs.
+    /// Images of content below 0.25fs are rejected by at least 21 dB.
+    Linear,
+
+    /// Cubic Hermite (Catmull-Rom) interpolation across four samples.
+    ///
+    /// Passband droops by 0.6 dB at 0.25fs and 4.3 dB at 0.45fs.
+    /// Images of content below 0.25fs are rejected by at least 24 dB.
+    Cubic,
+
+    /// Kaiser-windowed sinc filter with a transition band from 0.45fs to 0.5fs.
+    ///
+    /// Passband is flat up to 0.45fs and stopband rejection is 65 dB from 0.5fs onwards.
+    #[default]
+    SincMedium,
+
+    /// Kaiser-windowed sinc filter with a transition band from 0.475fs to 0.5fs.
+    ///
+    /// Passband is flat up to 0.475fs and stopband rejection is 100 dB from 0.5fs onwards.
+    /// The filter is a little over three times as long as [`Quality::SincMedium`].
+    SincBest,
+}
 
-        fn sinc_filter(left: u32, gain: f64, cutoff: f64, i: u32) -> f64 {
-            #[inline]
-            fn sinc(x: f64) -> f64 {
-                if x == 0.0 {
-                    1.0
-                } else {
-                    let x_pi = x * std::f64::consts::PI;
-                    x_pi.sin() / x_pi
-                }
+impl Quality {
+    // Builds the polyphase filter for resampling at the ratio `from`:`to`, which should already be reduced by GCD.
+    // Returns one set of weights for each of the `to` phases, along with the length of the whole filter,
+    // as measured in the upscaled sample space.
+    fn filter(self, from: u32, to: u32) -> FilterBank {
+        // Catmull-Rom spline, where `x` is measured in source samples
+        fn cubic(x: f64) -> f64 {
+            let x = x.abs();
+            if x < 1.0 {
+                1.5 * x.powi(3) - 2.5 * x.powi(2) + 1.0
+            } else if x < 2.0 {
+                -0.5 * x.powi(3) + 2.5 * x.powi(2) - 4.0 * x + 2.0
+            } else {
+                0.0
             }
+        }
 
-            #[inline]
-            fn bessel_i0(x: f64) -> f64 {
-                // Just trust me on this one
-                let ax = x.abs();
-                if ax < 3.75 {
-                    let y = (x / 3.75).powi(2);
-                    1.0 + y
-                        * (3.5156229
-                            + y * (3.0899424 + y * (1.2067492 + y * (0.2659732 + y * (0.0360768 + y * 0.0045813)))))
-                } else {
-                    let y = 3.75 / ax;
-                    (ax.exp() / ax.sqrt())
-                        * (0.39894228
-                            + y * (0.01328592
-                                + y * (0.00225319
-                                    + y * (-0.00157565
-                                        + y * (0.00916281
-                                            + y * (-0.02057706
-                                                + y * (0.02635537 + y * (-0.01647633 + y * 0.00392377))))))))
-                }
+        let step = to as usize;
+        let value_count = match self {
+            Quality::Linear => step * 2 + 1,
+            Quality::Cubic => step * 4 + 1,
+            Quality::SincMedium => sinc_order(0.05 / f64::from(to.max(from)), 65.0) + 1,
+            Quality::SincBest => sinc_order(0.025 / f64::from(to.max(from)), 100.0) + 1,
+        };
+        let left_offset = value_count / 2;
+
+        let value = |i: usize| -> f64 {
+            let x = (i as f64 - left_offset as f64) / f64::from(to);
+            match self {
+                Quality::Linear => (1.0 - x.abs()).max(0.0),
+                Quality::Cubic => cubic(x),
+                Quality::SincMedium => sinc_filter(left_offset as _, from, to, 0.475, 65.0, i as _),
+                Quality::SincBest => sinc_filter(left_offset as _, from, to, 0.4875, 100.0, i as _),
             }
+        };
+
+        let values = (0..step)
+            .map(|start_val| {
+                let phase = (start_val..value_count).step_by(step).rev().map(|i| value(i) as f32);
+                phase.collect::<Vec<_>>().into_boxed_slice()
+            })
+            .collect::<Vec<_>>()
+            .into_boxed_slice();
+        FilterBank { phases: values, len: value_count }
+    }
+}
 
-            #[inline]
-            fn kaiser(k: f64) -> f64 {
-                if !(-1.0..=1.0).contains(&k) {
-                    0.0
-                } else {
-                    // 6.20426 is the Kaiser beta value for a rejection of 65 dB.
-                    // The magic number at the end is bessel_i0(6.20426)
-                    bessel_i0(6.20426 * (1.0 - k.powi(2)).sqrt()) / 81.0332923199
-                }
-            }
+// A polyphase filter, as built by Quality::filter()
+struct FilterBank {
+    // One set of weights for each phase. These are interpolation weights rather than a Kaiser window
+    // for non-sinc qualities.
+    phases: Box<[Box<[f32]>]>,
+
+    // The length of the whole filter, measured in the upscaled sample space
+    len: usize,
+}
+
+/// A cache of resampling filters, keyed by conversion ratio and [`Quality`].
+///
+/// Building a filter is by far the most expensive part of constructing a [`Resampler`], especially for
+/// the sinc qualities. A FilterCache builds each filter once and shares it with every subsequent Resampler
+/// through an `Arc`, so creating a Resampler for a ratio that's already cached costs almost nothing.
+///
+/// `Resampler::new()` and `Resampler::with_quality()` use the global cache, which lives for the rest of the program.
+/// If you'd rather control that memory yourself, create your own cache and use `Resampler::with_cache()`.
+#[derive(Default)]
+pub struct FilterCache(Mutex<HashMap<(u32, u32, Quality), Arc<FilterBank>>>);
+
+impl FilterCache {
+    /// Creates a new, empty FilterCache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the global FilterCache, as used by `Resampler::new()` and `Resampler::with_quality()`.
+    pub fn global() -> &'static Self {
+        static GLOBAL: OnceLock<FilterCache> = OnceLock::new();
+        GLOBAL.get_or_init(Self::new)
+    }
+
+    /// Builds the filter for converting from `source_rate` to `dest_rate` at the given quality, if it isn't already
+    /// cached. This can be used to avoid building filters at inconvenient times, such as during gameplay.
+    pub fn preload(&self, source_rate: SampleRate, dest_rate: SampleRate, quality: Quality) {
+        let (from, to) = reduce_ratio(source_rate, dest_rate);
+        self.get(from, to, quality);
+    }
+
+    /// Removes every filter from the cache.
+    ///
+    /// Resamplers which are already using a filter will keep it alive until they're dropped.
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear()
+    }
 
-            let left = f64::from(left);
-            let x = f64::from(i) - left;
-            kaiser(x / left) * 2.0 * gain * cutoff * sinc(2.0 * cutoff * x)
+    fn get(&self, from: u32, to: u32, quality: Quality) -> Arc<FilterBank> {
+        if let Some(filter) = self.0.lock().unwrap().get(&(from, to, quality)) {
+            return filter.clone()
         }
 
-        #[inline]
-        fn kaiser_order(transition_width: f64) -> usize {
-            // Calculate kaiser order for given transition width and a rejection of 65 dB.
-            // Kaiser's original formula for this is: (rejection - 7.95) / (2.285 * 2 * pi * width)
-            ((65.0 - 7.95) / (2.285 * 2.0 * std::f64::consts::PI * transition_width)).ceil() as usize
+        // The lock isn't held while building, so other threads aren't kept waiting on a slow filter. If another thread
+        // builds the same one at the same time, whichever finishes first is kept.
+        let filter = Arc::new(quality.filter(from, to));
+        self.0.lock().unwrap().entry((from, to, quality)).or_insert(filter).clone()
+    }
+}
+
+// Scales a conversion ratio down by its GCD, returning (from, to)
+fn reduce_ratio(source_rate: SampleRate, dest_rate: SampleRate) -> (u32, u32) {
+    #[inline]
+    fn gcd(a: u32, b: u32) -> u32 {
+        if b == 0 { a } else { gcd(b, a % b) }
+    }
+
+    let src = u32::from(source_rate);
+    let dst = u32::from(dest_rate);
+    let gcd = gcd(src, dst);
+    (src / gcd, dst / gcd)
+}
+
+// Calculates a Kaiser-windowed sinc filter value at index `i`, with the filter centered on `left`.
+// `cutoff` is relative to the lower of the two sample rates, and `rejection` is the stopband attenuation in dB.
+pub(crate) fn sinc_filter(left: u32, from: u32, to: u32, cutoff: f64, rejection: f64, i: u32) -> f64 {
+    #[inline]
+    fn sinc(x: f64) -> f64 {
+        if x == 0.0 {
+            1.0
+        } else {
+            let x_pi = x * std::f64::consts::PI;
+            x_pi.sin() / x_pi
         }
+    }
 
-        let src = u32::from(source.sample_rate());
-        let dst = u32::from(dest_rate);
-        let gcd = gcd(src, dst);
-        let from = src / gcd;
-        let to = dst / gcd;
+    #[inline]
+    fn bessel_i0(x: f64) -> f64 {
+        // Just trust me on this one
+        let ax = x.abs();
+        if ax < 3.75 {
+            let y = (x / 3.75).powi(2);
+            1.0 + y
+                * (3.5156229
+                    + y * (3.0899424 + y * (1.2067492 + y * (0.2659732 + y * (0.0360768 + y * 0.0045813)))))
+        } else {
+            let y = 3.75 / ax;
+            (ax.exp() / ax.sqrt())
+                * (0.39894228
+                    + y * (0.01328592
+                        + y * (0.00225319
+                            + y * (-0.00157565
+                                + y * (0.00916281
+                                    + y * (-0.02057706
+                                        + y * (0.02635537 + y * (-0.01647633 + y * 0.00392377))))))))
+        }
+    }
 
-        let downscale_factor = f64::from(to.max(from));
-        let cutoff = 0.475 / downscale_factor;
-        let transition_width = 0.05 / downscale_factor;
+    #[inline]
+    fn kaiser(k: f64, beta: f64) -> f64 {
+        if !(-1.0..=1.0).contains(&k) {
+            0.0
+        } else {
+            bessel_i0(beta * (1.0 - k.powi(2)).sqrt()) / bessel_i0(beta)
+        }
+    }
 
-        let kaiser_value_count = kaiser_order(transition_width) + 1;
-        let left_offset = kaiser_value_count / 2;
+    // Kaiser's formula for the beta value giving a rejection above 50 dB.
+    // For a rejection of 65 dB this is 6.20426.
+    let beta = 0.1102 * (rejection - 8.7);
+    // The filter runs in the upscaled sample space, where each phase only sees every `to`th value,
+    // so a gain of `to` gives unity gain in the output.
+    let gain = f64::from(to);
+    let cutoff = cutoff / f64::from(to.max(from));
+    let left = f64::from(left);
+    let x = f64::from(i) - left;
+    kaiser(x / left, beta) * 2.0 * gain * cutoff * sinc(2.0 * cutoff * x)
+}
 
-        let step = to as usize;
-        let kaiser_values: Box<[Box<[f32]>]> = (0..step).map(|start_val| {
-            (start_val..kaiser_value_count).step_by(step).rev().map(|i| {
-                sinc_filter(left_offset as _, downscale_factor, cutoff, i as _) as f32
-            }).collect::<Vec<_>>().into_boxed_slice()
-        }).collect::<Vec<_>>().into_boxed_slice();
+#[inline]
+pub(crate) fn sinc_order(transition_width: f64, rejection: f64) -> usize {
+    // Calculate kaiser order for given transition width and rejection in dB.
+    // Kaiser's original formula for this is: (rejection - 7.95) / (2.285 * 2 * pi * width)
+    ((rejection - 7.95) / (2.285 * 2.0 * std::f64::consts::PI * transition_width)).ceil() as usize
+}
+
+impl<S: Source> Resampler<S> {
+    /// Creates a new Resampler using the default quality, [`Quality::SincMedium`].
+    pub fn new(source: S, dest_rate: SampleRate) -> Self {
+        Self::with_quality(source, dest_rate, Quality::default())
+    }
+
+    /// Creates a new Resampler using the given [`Quality`].
+    pub fn with_quality(source: S, dest_rate: SampleRate, quality: Quality) -> Self {
+        Self::with_cache(source, dest_rate, quality, FilterCache::global())
+    }
+
+    /// Creates a new Resampler using the given [`Quality`], taking its filter from `cache` instead of the global one.
+    pub fn with_cache(mut source: S, dest_rate: SampleRate, quality: Quality, cache: &FilterCache) -> Self {
+        let (from, to) = reduce_ratio(source.sample_rate(), dest_rate);
+        let filter_bank = cache.get(from, to, quality);
+        let kaiser_value_count = filter_bank.len;
+        let left_offset = kaiser_value_count / 2;
 
         let filter_samples = ((kaiser_value_count + to as usize) / to as usize) * usize::from(source.channel_count().get());
         let mut filter_1 = Vec::with_capacity(filter_samples);
@@ -131,7 +294,7 @@ impl<S: Source> Resampler<S> {
             to,
             dest_rate,
             left_offset,
-            kaiser_values,
+            filter_bank,
             filter_1: filter_1.into_boxed_slice(),
             filter_2: filter_2.into_boxed_slice(),
             whole_filter_size: filter_samples * 2,
@@ -154,6 +317,11 @@ impl<S: Source> Source for Resampler<S> {
         self.dest_rate
     }
 
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.source.channel_layout()
+    }
+
     fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
         let from = u64::from(self.from);
         let to = u64::from(self.to);
@@ -179,9 +347,9 @@ impl<S: Source> Source for Resampler<S> {
 
             // And now get a set of kaiser values to multiply by the filter.
             let kaiser_values = unsafe {
-                // SAFETY: self.kaiser_values is a boxed slice with length `to`, and
+                // SAFETY: self.filter_bank.phases is a boxed slice with length `to`, and
                 // kaiser_index is calculated as a modulo of `to`
-                self.kaiser_values.get_unchecked(kaiser_index as usize)
+                self.filter_bank.phases.get_unchecked(kaiser_index as usize)
             };
 
             // sample_index is our last (inclusive) sample, so if it's beyond the length of our filter,
@@ -190,8 +358,9 @@ impl<S: Source> Source for Resampler<S> {
             while (sample_index >= self.whole_filter_size as u64) && self.last_sample.is_none() {
                 // Read new samples into filter 1, which is now fully depleted, so it's fine to overwrite it.
                 let len = self.source.write_samples(&mut self.filter_1);
-                // Handle our source being empty
+                // Handle our source being empty. Anything after its last sample counts as silence.
                 if len != self.filter_1.len() {
+                    self.filter_1[len..].iter_mut().for_each(|x| *x = 0.0);
                     self.last_sample = Some(self.buffer_size + len);
                 }
                 // Swap filters 1 and 2. Now the new samples are in filter_2. Turbofish here guarantees O(1) ptr swap
@@ -202,9 +371,12 @@ impl<S: Source> Source for Resampler<S> {
                 self.input_offset += sample_count;
             }
 
-            // If we are past the end of our audio, exit early and indicate how much of the buffer we filled
+            // If we are past the end of our audio, exit early and indicate how much of the buffer we filled.
+            // That's once this output frame's position in the input, rather than the end of its filter, is past
+            // the last input frame, so the last part of the sound still gets played.
             if let Some(end) = self.last_sample {
-                if sample_index as usize + (self.left_offset * channels) > end {
+                let input_frames = (self.input_offset + end as u64) / channels as u64;
+                if from * (self.output_count / channels) as u64 >= input_frames * to {
                     return i
                 }
             }
@@ -232,44 +404,29 @@ impl<S> Resampler<S> where S: Source {
     // That's because, strangely, it's the most efficient way of calculating a stream position.
     #[inline(always)]
     fn get_sample(&self, kaiser_values: &[f32], channels: usize, channel: usize, sample_index: usize) -> Sample {
-        unsafe {
-            let kaiser_offset = kaiser_values.len() - 1;
-            let (filter_skip_1, kaiser_skip) = {
-                match sample_index.checked_sub(kaiser_offset * channels) {
-                    Some(x) => (x, 0),
-                    None => (channel, kaiser_offset - (sample_index / channels)),
-                }
-            };
-
-            let filter_skip_2 = {
-                match sample_index.checked_sub(kaiser_offset * channels + self.buffer_size) {
-                    Some(x) => x,
-                    None => channel,
-                }
-            };
-
-            let mut output: Sample = 0.0;
-            let mut f1_ptr = self.filter_1.as_ptr().add(filter_skip_1);
-            let mut f2_ptr = self.filter_2.as_ptr().add(filter_skip_2);
-            let mut kaiser_ptr = kaiser_values.as_ptr().add(kaiser_skip);
-            let f1_end = self.filter_1.as_ptr().add(self.buffer_size);
-            let f2_end = self.filter_2.as_ptr().add(self.buffer_size);
-            let kaiser_end = kaiser_values.as_ptr().add(kaiser_values.len());
-
-            while f1_ptr < f1_end && kaiser_ptr < kaiser_end {
-                output += (*f1_ptr) * (*kaiser_ptr);
-                kaiser_ptr = kaiser_ptr.add(1);
-                f1_ptr = f1_ptr.add(channels);
+        let kaiser_offset = kaiser_values.len() - 1;
+        let (filter_skip_1, kaiser_skip) = {
+            match sample_index.checked_sub(kaiser_offset * channels) {
+                Some(x) => (x, 0),
+                None => (channel, kaiser_offset - (sample_index / channels)),
             }
+        };
 
-            while f2_ptr < f2_end && kaiser_ptr < kaiser_end {
-                output += (*f2_ptr) * (*kaiser_ptr);
-                kaiser_ptr = kaiser_ptr.add(1);
-                f2_ptr = f2_ptr.add(channels);
+        let filter_skip_2 = {
+            match sample_index.checked_sub(kaiser_offset * channels + self.buffer_size) {
+                Some(x) => x,
+                None => channel,
             }
+        };
 
-            output
-        }
+        // The filter is split across both buffers, so this is two dot products: one over whatever's left of
+        // filter_1, and one over the start of filter_2 with the remaining kaiser values.
+        let kaiser_values = &kaiser_values[kaiser_skip..];
+        let f1 = self.filter_1.get(filter_skip_1..).unwrap_or(&[]);
+        let f2 = self.filter_2.get(filter_skip_2..).unwrap_or(&[]);
+        let f1_count = kaiser_values.len().min(f1.len().div_ceil(channels));
+
+        simd::dot(f1, &kaiser_values[..f1_count], channels) + simd::dot(f2, &kaiser_values[f1_count..], channels)
     }
 
     // Initializes a filter from a Sample, returning the optional last_sample
@@ -277,9 +434,110 @@ impl<S> Resampler<S> where S: Source {
         let len = source.write_samples(filter_1);
         if len == filter_1.len() {
             let len = source.write_samples(filter_2);
-            if len == filter_2.len() { None } else { Some(len) }
+            filter_2[len..].iter_mut().for_each(|x| *x = 0.0);
+            if len == filter_2.len() { None } else { Some(filter_1.len() + len) }
         } else {
+            filter_1[len..].iter_mut().for_each(|x| *x = 0.0);
+            filter_2.iter_mut().for_each(|x| *x = 0.0);
             Some(len)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Player;
+
+    #[test]
+    fn cache_shares_filters() {
+        let cache = Arc::new(FilterCache::new());
+        let threads = (0..4)
+            .map(|_| {
+                let cache = cache.clone();
+                std::thread::spawn(move || cache.get(147, 160, Quality::SincBest))
+            })
+            .collect::<Vec<_>>();
+        let filters = threads.into_iter().map(|x| x.join().unwrap()).collect::<Vec<_>>();
+        let cached = cache.get(147, 160, Quality::SincBest);
+        assert!(filters.iter().all(|x| Arc::ptr_eq(x, &cached)), "every thread should get the cached filter");
+
+        cache.preload(SampleRate::new(44100).unwrap(), SampleRate::new(48000).unwrap(), Quality::Linear);
+        assert_eq!(cache.0.lock().unwrap().len(), 2);
+        cache.clear();
+        assert!(cache.0.lock().unwrap().is_empty());
+        assert!(!Arc::ptr_eq(&cache.get(147, 160, Quality::SincBest), &cached));
+    }
+
+    const QUALITIES: [Quality; 4] = [Quality::Linear, Quality::Cubic, Quality::SincMedium, Quality::SincBest];
+
+    fn sine(channels: u16, rate: u32, frequency: f32, frames: usize) -> Player {
+        let samples = (0..frames)
+            .flat_map(|i| {
+                let x = (2.0 * std::f32::consts::PI * frequency * i as f32 / rate as f32).sin() * 0.5;
+                vec![x; usize::from(channels)]
+            })
+            .collect::<Vec<_>>();
+        Player::new(ChannelCount::new(channels).unwrap(), SampleRate::new(rate).unwrap(), samples.into())
+    }
+
+    // Reads until the Resampler ends, checking that it keeps returning 0 afterwards
+    fn play(resampler: &mut Resampler<Player>) -> Vec<Sample> {
+        let mut output = Vec::new();
+        let mut buffer = [0.0; 1000];
+        loop {
+            let count = resampler.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(resampler.write_samples(&mut buffer), 0);
+        output
+    }
+
+    #[test]
+    fn keeps_passband_level_and_length() {
+        for &quality in &QUALITIES {
+            for &(from, to) in &[(44100, 48000), (48000, 44100), (22050, 48000)] {
+                let input = sine(2, from, 1000.0, from as usize);
+                let mut resampler = Resampler::with_quality(input, SampleRate::new(to).unwrap(), quality);
+                let output = play(&mut resampler);
+                assert_eq!(output.len() % 2, 0, "{:?} {}:{} ended partway through a frame", quality, from, to);
+
+                // One second in should be one second out, including the end of the sound under the filter's tail
+                let frames = output.len() / 2;
+                assert!((frames as i64 - to as i64).abs() <= 1, "{:?} {}:{} gave {} frames", quality, from, to, frames);
+
+                let middle = &output[frames / 4 * 2..frames * 3 / 4 * 2];
+                let peak = middle.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+                assert!((20.0 * (peak / 0.5).log10()).abs() < 0.1, "{:?} {}:{} peaked at {}", quality, from, to, peak);
+                let worst = middle.chunks_exact(2).map(|x| (x[0] - x[1]).abs()).fold(0.0f32, f32::max);
+                assert!(worst < 1e-6, "{:?} {}:{} channels differ by {}", quality, from, to, worst);
+            }
+        }
+    }
+
+    #[test]
+    fn interpolation_is_exact_at_unity() {
+        for &quality in &[Quality::Linear, Quality::Cubic] {
+            let mut input = sine(1, 48000, 1000.0, 500);
+            let mut expected = vec![0.0; 500];
+            input.write_samples(&mut expected);
+            input.reset();
+            let output = play(&mut Resampler::with_quality(input, SampleRate::new(48000).unwrap(), quality));
+            assert_eq!(output.len(), 500, "{:?}", quality);
+            for (x, y) in output.iter().zip(&expected) {
+                assert!((x - y).abs() < 1e-6, "{:?}: {} != {}", quality, x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn reset_starts_again() {
+        let mut resampler = Resampler::new(sine(1, 44100, 440.0, 4410), SampleRate::new(48000).unwrap());
+        let first = play(&mut resampler);
+        resampler.reset();
+        assert_eq!(play(&mut resampler), first);
+    }
+}
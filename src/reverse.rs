@@ -0,0 +1,284 @@
+use crate::{
+    frame::PartialFrame,
+    source::{ChannelCount, ChannelLayout, Sample, SampleRate, Seekable, Source},
+};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// A Source which can play a [`Seekable`] Source backwards, such as a `Player` or `WavPlayer`.
+///
+/// The direction can be switched while playing through a [`ReverseHandle`], for rewind effects. Playback carries on
+/// from the same point in the other direction, so there's no jump in the sound. The switch takes effect from the next
+/// call to `write_samples`, after the end of any frame which was left unfinished by the last one.
+///
+/// A Reverse starts from whichever end of the Source it's heading away from, and ends when it reaches the other end.
+/// Resetting it works the same way, so wrapping it in a `Cycle` loops it in the current direction.
+pub struct Reverse<S>
+where
+    S: Seekable,
+{
+    source: S,
+    reversed: Arc<AtomicBool>,
+    position: Option<usize>, // In frames, or None if playback hasn't started since being created or reset
+    was_reversed: bool,      // Which direction the last frames were played in
+    ended: bool,
+    partial: PartialFrame,
+}
+
+/// Used for changing the direction of a [`Reverse`] while it's playing. Get one with `Reverse::handle()`.
+#[derive(Clone)]
+pub struct ReverseHandle(Arc<AtomicBool>);
+
+impl<S> Reverse<S>
+where
+    S: Seekable,
+{
+    /// Creates a new Reverse, which starts out playing backwards.
+    #[inline]
+    pub fn new(source: S) -> Self {
+        Self::with_direction(source, true)
+    }
+
+    /// Creates a new Reverse, which plays backwards if `reversed` is true, and forwards otherwise.
+    pub fn with_direction(source: S, reversed: bool) -> Self {
+        let channels = usize::from(source.channel_count().get());
+        Self {
+            source,
+            reversed: Arc::new(AtomicBool::new(reversed)),
+            position: None,
+            was_reversed: reversed,
+            ended: false,
+            partial: PartialFrame::new(channels),
+        }
+    }
+
+    /// Returns a handle for changing this Reverse's direction while it's playing.
+    pub fn handle(&self) -> ReverseHandle {
+        ReverseHandle(self.reversed.clone())
+    }
+
+    fn write_frames(&mut self, buffer: &mut [Sample]) -> usize {
+        if self.ended {
+            return 0
+        }
+
+        let channels = usize::from(self.source.channel_count().get());
+        let reversed = self.reversed.load(Ordering::Acquire);
+        let started = self.position.is_some();
+        let position = self.position.unwrap_or(if reversed { self.source.frame_count() } else { 0 });
+        let count = if reversed {
+            // Read the frames just before the current position, then flip them around
+            let frames = (buffer.len() / channels).min(position);
+            self.source.seek(position - frames);
+            let count = self.source.write_samples(&mut buffer[..frames * channels]);
+            let written = count / channels;
+            for i in 0..written / 2 {
+                for channel in 0..channels {
+                    buffer.swap(i * channels + channel, (written - 1 - i) * channels + channel);
+                }
+            }
+            self.position = Some(position - frames);
+            count
+        } else {
+            // Playing forwards leaves the Source where it needs to be, unless it was just playing backwards
+            if !started || self.was_reversed {
+                self.source.seek(position);
+            }
+            let count = self.source.write_samples(buffer);
+            self.position = Some(position + count / channels);
+            count
+        };
+
+        self.was_reversed = reversed;
+        self.ended = count < buffer.len();
+        count
+    }
+}
+
+impl<S> Source for Reverse<S>
+where
+    S: Seekable,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.source.channel_count()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.source.channel_layout()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        PartialFrame::write_with(self, buffer, |reverse| &mut reverse.partial, Self::write_frames)
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.position = None;
+        self.ended = false;
+        self.partial.reset();
+    }
+}
+
+impl ReverseHandle {
+    /// Sets whether the Source should play backwards.
+    pub fn set_reversed(&self, reversed: bool) {
+        self.0.store(reversed, Ordering::Release)
+    }
+
+    /// Returns the most recently set direction, which is true for backwards.
+    pub fn is_reversed(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cycle::Cycle, Player};
+
+    fn player(frames: usize) -> Player {
+        let samples = (0..frames * 2).map(|x| x as Sample).collect();
+        Player::new(ChannelCount::new(2).unwrap(), SampleRate::new(44100).unwrap(), samples)
+    }
+
+    // A Seekable which claims to be longer than it is, so reads near the end come up short
+    struct Truncated(Player, usize);
+
+    impl Source for Truncated {
+        fn channel_count(&self) -> ChannelCount {
+            self.0.channel_count()
+        }
+
+        fn sample_rate(&self) -> SampleRate {
+            self.0.sample_rate()
+        }
+
+        fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+            self.0.write_samples(buffer)
+        }
+
+        fn reset(&mut self) {
+            self.0.reset()
+        }
+    }
+
+    impl Seekable for Truncated {
+        fn frame_count(&self) -> usize {
+            self.0.frame_count() + self.1
+        }
+
+        fn position(&self) -> usize {
+            self.0.position()
+        }
+
+        fn seek(&mut self, frame: usize) {
+            self.0.seek(frame)
+        }
+    }
+
+    #[test]
+    fn plays_backwards() {
+        let mut reverse = Reverse::new(player(5));
+        let mut buffer = [0.0; 6];
+        assert_eq!(reverse.write_samples(&mut buffer), 6);
+        assert_eq!(buffer, [8.0, 9.0, 6.0, 7.0, 4.0, 5.0]);
+        assert_eq!(reverse.write_samples(&mut buffer), 4, "should end at the start of the source");
+        assert_eq!(buffer[..4], [2.0, 3.0, 0.0, 1.0]);
+        assert_eq!(reverse.write_samples(&mut buffer), 0);
+    }
+
+    #[test]
+    fn switches_direction() {
+        let mut reverse = Reverse::with_direction(player(8), false);
+        let handle = reverse.handle();
+        let mut buffer = [0.0; 6];
+        assert_eq!(reverse.write_samples(&mut buffer), 6);
+        assert_eq!(buffer, [0.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+
+        // Carries on backwards from frame 3, so the next frame is the one just played
+        handle.set_reversed(true);
+        assert!(handle.is_reversed());
+        let mut buffer = [0.0; 4];
+        assert_eq!(reverse.write_samples(&mut buffer), 4);
+        assert_eq!(buffer, [4.0, 5.0, 2.0, 3.0]);
+
+        handle.set_reversed(false);
+        assert_eq!(reverse.write_samples(&mut buffer), 4);
+        assert_eq!(buffer, [2.0, 3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn ends_and_resets_in_current_direction() {
+        let mut reverse = Reverse::with_direction(player(3), false);
+        let mut buffer = [0.0; 8];
+        assert_eq!(reverse.write_samples(&mut buffer), 6);
+        assert_eq!(reverse.write_samples(&mut buffer), 0);
+
+        reverse.handle().set_reversed(true);
+        reverse.reset();
+        assert_eq!(reverse.write_samples(&mut buffer), 6);
+        assert_eq!(buffer[..6], [4.0, 5.0, 2.0, 3.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn short_reads_only_reverse_what_was_written() {
+        let mut reverse = Reverse::new(Truncated(player(4), 2));
+        let mut buffer = [-1.0; 8];
+        assert_eq!(reverse.write_samples(&mut buffer), 4);
+        assert_eq!(buffer[..4], [6.0, 7.0, 4.0, 5.0]);
+        assert_eq!(reverse.write_samples(&mut buffer), 0);
+    }
+
+    // Plays `source` in buffers of `length` until it's written `samples`, or ends
+    fn read(source: &mut impl Source, length: usize, samples: usize) -> Vec<Sample> {
+        let mut output = Vec::new();
+        let mut buffer = vec![0.0; length];
+        while output.len() < samples {
+            let count = source.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn odd_buffers_keep_playing() {
+        for &reversed in &[false, true] {
+            let mut expected = read(&mut Reverse::with_direction(player(50), reversed), 100, 100);
+            let mut output = read(&mut Reverse::with_direction(player(50), reversed), 7, 100);
+            assert_eq!(output, expected, "reversed: {}", reversed);
+
+            // Switching direction after an unfinished frame finishes it, then carries on from there
+            let mut reverse = Reverse::with_direction(player(50), reversed);
+            output = read(&mut reverse, 7, 21);
+            reverse.handle().set_reversed(!reversed);
+            output.extend(read(&mut reverse, 7, 21));
+            expected.truncate(22);
+            expected.extend_from_within(20..22);
+            let frames = expected[..20].chunks_exact(2).rev().flatten().copied().collect::<Vec<_>>();
+            expected.extend(frames);
+            assert_eq!(output, expected[..42], "reversed: {}", reversed);
+        }
+    }
+
+    #[test]
+    fn cycles_in_odd_buffers() {
+        for &reversed in &[false, true] {
+            let once = read(&mut Reverse::with_direction(player(5), reversed), 10, 10);
+            let output = read(&mut Cycle::new(Reverse::with_direction(player(5), reversed)), 7, 70);
+            let expected = once.iter().cycle().take(70).copied().collect::<Vec<_>>();
+            assert_eq!(output, expected, "reversed: {}", reversed);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{error::Error, source::{ChannelCount, SampleRate, Source}};
+use crate::{error::Error, source::{ChannelCount, ChannelLayout, SampleRate, Source}};
 
 macro_rules! backends {
     (
@@ -41,6 +41,9 @@ macro_rules! backends {
             impl Device(DeviceImpl) <- $( $variant if $cfg ),* {
                 pub fn channel_count(&self) -> ChannelCount;
                 pub fn sample_rate(&self) -> SampleRate;
+
+                /// Returns the speaker position of each of the device's channels, if the API reports them.
+                pub fn channel_layout(&self) -> Option<ChannelLayout>;
             }
         }
 
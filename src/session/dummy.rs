@@ -1,4 +1,4 @@
-use crate::{error::Error, session, source::{self, ChannelCount, SampleRate, Source}};
+use crate::{error::Error, session, source::{self, ChannelCount, ChannelLayout, SampleRate, Source}};
 
 pub struct Device;
 pub struct OutputStream;
@@ -12,6 +12,10 @@ impl Device {
     pub fn sample_rate(&self) -> SampleRate {
         source::consts::SR_48000
     }
+
+    pub fn channel_layout(&self) -> Option<ChannelLayout> {
+        Some(ChannelLayout::STEREO)
+    }
 }
 
 impl Session {
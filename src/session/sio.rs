@@ -1,5 +1,5 @@
-use crate::{error::Error, session, source::{ChannelCount, SampleRate, Source}};
-use std::{sync::Mutex, os::raw::c_int};
+use crate::{error::Error, session, source::{ChannelCount, ChannelId, ChannelLayout, SampleRate, Source}};
+use std::{convert::TryFrom, sync::Mutex, os::raw::c_int};
 use libsoundio_sys::*;
 
 use super::DeviceImpl;
@@ -35,6 +35,10 @@ impl Device {
     pub fn sample_rate(&self) -> SampleRate {
         unsafe { SampleRate::new_unchecked(self.sample_rate as _) }
     }
+
+    pub fn channel_layout(&self) -> Option<ChannelLayout> {
+        channel_layout(&self.layout)
+    }
 }
 
 impl std::ops::Drop for Device {
@@ -218,8 +222,11 @@ impl OutputStream {
             let mut guard = self.stream.lock().unwrap();
             let mut extra = Vec::with_capacity(32768);
             let mut exit = false;
+            let map = channel_map(&*source, &(**guard).layout);
             let mut param = UdonCallbackParam {
                 source: &mut source as *mut Box<dyn Source> as _,
+                source_channels: usize::from(source.channel_count().get()),
+                map: map.as_slice(),
                 extra: &mut extra,
                 exit: &mut exit,
                 err: Ok(()),
@@ -250,6 +257,9 @@ impl std::ops::Drop for OutputStream {
 
 struct UdonCallbackParam {
     source: *mut Box<dyn Source>,
+    source_channels: usize,
+    // For each device channel, the Source channel to write to it, if any
+    map: *const [Option<usize>],
     extra: *mut Vec<f32>,
     exit: *mut bool,
     err: Result<(), Error>,
@@ -263,6 +273,8 @@ unsafe extern "C" fn udon_callback(
     let param = (*outstream).userdata as *mut UdonCallbackParam;
     let format = (*outstream).format;
     let channel_count = (*outstream).layout.channel_count as usize;
+    let source_channels = (*param).source_channels;
+    let map = &*(*param).map;
     let mut areas: *mut SoundIoChannelArea = std::ptr::null_mut();
     let mut frames_left: c_int = frame_count_max;
     let mut err: c_int;
@@ -277,28 +289,38 @@ unsafe extern "C" fn udon_callback(
         if frame_count == 0 {
             break;
         }
-        let units = frame_count as usize * channel_count;
+        let units = frame_count as usize * source_channels;
         let extra = &mut *(*param).extra;
         extra.clear();
         extra.reserve(units);
         extra.set_len(units);
         let total = (*(*param).source).write_samples(extra.as_mut_slice());
-        extra.set_len(total);
+        let frames = total / source_channels;
+        extra.set_len(frames * source_channels);
         match format {
             SoundIoFormat::SoundIoFormatFloat32LE | SoundIoFormat::SoundIoFormatFloat32BE => {
                 for ch in 0..channel_count {
                     let area = *areas.offset(ch as _);
                     let p = area.ptr;
+                    let src = match map.get(ch).copied().flatten() {
+                        Some(src) => src,
+                        None => {
+                            for i in 0..frame_count as usize {
+                                *p.add(area.step as usize * i).cast::<u32>() = 0;
+                            }
+                            continue
+                        },
+                    };
                     if cfg!(target_endian = "little") == (matches!(format, SoundIoFormat::SoundIoFormatFloat32LE)) {
-                        for (i, sample) in extra.iter().copied().skip(ch as _).step_by(channel_count).enumerate() {
+                        for (i, sample) in extra.iter().copied().skip(src).step_by(source_channels).enumerate() {
                             *p.add(area.step as usize * i).cast::<f32>() = sample;
                         }
                     } else {
-                        for (i, sample) in extra.iter().copied().skip(ch as _).step_by(channel_count).enumerate() {
+                        for (i, sample) in extra.iter().copied().skip(src).step_by(source_channels).enumerate() {
                             *p.add(area.step as usize * i).cast::<u32>() = sample.to_bits().swap_bytes();
                         }
                     }
-                    for i in (total / channel_count)..frame_count as usize {
+                    for i in frames..frame_count as usize {
                         *p.add(area.step as usize * i).cast::<u32>() = 0;
                     }
                 }
@@ -318,3 +340,99 @@ unsafe extern "C" fn udon_callback(
         frames_left -= frame_count;
     }
 }
+
+// For each device channel, finds the Source channel which should be written to it.
+// Channels are matched by speaker position if every Source channel has a place on the device. Otherwise they're
+// matched by index, which is also what happens if either layout is unknown.
+fn channel_map(source: &dyn Source, device_layout: &SoundIoChannelLayout) -> Vec<Option<usize>> {
+    let source_channels = usize::from(source.channel_count().get());
+    let source_layout = source.channel_layout().or_else(|| ChannelLayout::default_for(source.channel_count()));
+    if let (Some(source_layout), Some(device_layout)) = (source_layout, channel_layout(device_layout)) {
+        if source_layout.channels().iter().all(|&id| device_layout.find(id).is_some()) {
+            return device_layout.channels().iter().map(|&id| source_layout.find(id)).collect()
+        }
+    }
+    (0..device_layout.channel_count as usize).map(|i| Some(i).filter(|&i| i < source_channels)).collect()
+}
+
+// Converts a libsoundio channel layout, returning None if it contains any channel we don't recognise
+fn channel_layout(layout: &SoundIoChannelLayout) -> Option<ChannelLayout> {
+    let count = usize::try_from(layout.channel_count).ok()?;
+    let channels = layout.channels.get(..count)?.iter().map(|&id| channel_id(id)).collect::<Option<Vec<_>>>()?;
+    ChannelLayout::new(channels)
+}
+
+fn channel_id(id: SoundIoChannelId) -> Option<ChannelId> {
+    use SoundIoChannelId::*;
+    Some(match id {
+        SoundIoChannelIdInvalid => return None,
+        SoundIoChannelIdFrontLeft => ChannelId::FrontLeft,
+        SoundIoChannelIdFrontRight => ChannelId::FrontRight,
+        SoundIoChannelIdFrontCenter => ChannelId::FrontCenter,
+        SoundIoChannelIdLfe => ChannelId::Lfe,
+        SoundIoChannelIdBackLeft => ChannelId::BackLeft,
+        SoundIoChannelIdBackRight => ChannelId::BackRight,
+        SoundIoChannelIdFrontLeftCenter => ChannelId::FrontLeftCenter,
+        SoundIoChannelIdFrontRightCenter => ChannelId::FrontRightCenter,
+        SoundIoChannelIdBackCenter => ChannelId::BackCenter,
+        SoundIoChannelIdSideLeft => ChannelId::SideLeft,
+        SoundIoChannelIdSideRight => ChannelId::SideRight,
+        SoundIoChannelIdTopCenter => ChannelId::TopCenter,
+        SoundIoChannelIdTopFrontLeft => ChannelId::TopFrontLeft,
+        SoundIoChannelIdTopFrontCenter => ChannelId::TopFrontCenter,
+        SoundIoChannelIdTopFrontRight => ChannelId::TopFrontRight,
+        SoundIoChannelIdTopBackLeft => ChannelId::TopBackLeft,
+        SoundIoChannelIdTopBackCenter => ChannelId::TopBackCenter,
+        SoundIoChannelIdTopBackRight => ChannelId::TopBackRight,
+        SoundIoChannelIdBackLeftCenter => ChannelId::BackLeftCenter,
+        SoundIoChannelIdBackRightCenter => ChannelId::BackRightCenter,
+        SoundIoChannelIdFrontLeftWide => ChannelId::FrontLeftWide,
+        SoundIoChannelIdFrontRightWide => ChannelId::FrontRightWide,
+        SoundIoChannelIdFrontLeftHigh => ChannelId::FrontLeftHigh,
+        SoundIoChannelIdFrontCenterHigh => ChannelId::FrontCenterHigh,
+        SoundIoChannelIdFrontRightHigh => ChannelId::FrontRightHigh,
+        SoundIoChannelIdTopFrontLeftCenter => ChannelId::TopFrontLeftCenter,
+        SoundIoChannelIdTopFrontRightCenter => ChannelId::TopFrontRightCenter,
+        SoundIoChannelIdTopSideLeft => ChannelId::TopSideLeft,
+        SoundIoChannelIdTopSideRight => ChannelId::TopSideRight,
+        SoundIoChannelIdLeftLfe => ChannelId::LeftLfe,
+        SoundIoChannelIdRightLfe => ChannelId::RightLfe,
+        SoundIoChannelIdLfe2 => ChannelId::Lfe2,
+        SoundIoChannelIdBottomCenter => ChannelId::BottomCenter,
+        SoundIoChannelIdBottomLeftCenter => ChannelId::BottomLeftCenter,
+        SoundIoChannelIdBottomRightCenter => ChannelId::BottomRightCenter,
+        SoundIoChannelIdMsMid => ChannelId::MsMid,
+        SoundIoChannelIdMsSide => ChannelId::MsSide,
+        SoundIoChannelIdAmbisonicW => ChannelId::AmbisonicW,
+        SoundIoChannelIdAmbisonicX => ChannelId::AmbisonicX,
+        SoundIoChannelIdAmbisonicY => ChannelId::AmbisonicY,
+        SoundIoChannelIdAmbisonicZ => ChannelId::AmbisonicZ,
+        SoundIoChannelIdXyX => ChannelId::XyX,
+        SoundIoChannelIdXyY => ChannelId::XyY,
+        SoundIoChannelIdHeadphonesLeft => ChannelId::HeadphonesLeft,
+        SoundIoChannelIdHeadphonesRight => ChannelId::HeadphonesRight,
+        SoundIoChannelIdClickTrack => ChannelId::ClickTrack,
+        SoundIoChannelIdForeignLanguage => ChannelId::ForeignLanguage,
+        SoundIoChannelIdHearingImpaired => ChannelId::HearingImpaired,
+        SoundIoChannelIdNarration => ChannelId::Narration,
+        SoundIoChannelIdHaptic => ChannelId::Haptic,
+        SoundIoChannelIdDialogCentricMix => ChannelId::DialogCentricMix,
+        SoundIoChannelIdAux => ChannelId::Aux,
+        SoundIoChannelIdAux0 => ChannelId::Aux0,
+        SoundIoChannelIdAux1 => ChannelId::Aux1,
+        SoundIoChannelIdAux2 => ChannelId::Aux2,
+        SoundIoChannelIdAux3 => ChannelId::Aux3,
+        SoundIoChannelIdAux4 => ChannelId::Aux4,
+        SoundIoChannelIdAux5 => ChannelId::Aux5,
+        SoundIoChannelIdAux6 => ChannelId::Aux6,
+        SoundIoChannelIdAux7 => ChannelId::Aux7,
+        SoundIoChannelIdAux8 => ChannelId::Aux8,
+        SoundIoChannelIdAux9 => ChannelId::Aux9,
+        SoundIoChannelIdAux10 => ChannelId::Aux10,
+        SoundIoChannelIdAux11 => ChannelId::Aux11,
+        SoundIoChannelIdAux12 => ChannelId::Aux12,
+        SoundIoChannelIdAux13 => ChannelId::Aux13,
+        SoundIoChannelIdAux14 => ChannelId::Aux14,
+        SoundIoChannelIdAux15 => ChannelId::Aux15,
+    })
+}
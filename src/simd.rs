@@ -0,0 +1,326 @@
+//! Vectorised inner loops, with runtime feature detection and a scalar fallback.
+//!
+//! Each function here checks what the CPU supports every time it's called. The standard library caches the result
+//! of that check, so the cost is a single atomic load, which is negligible next to the loops themselves.
+
+use crate::source::Sample;
+
+/// Calculates the dot product of `weights` with every `stride`th value of `samples`, starting from the first.
+///
+/// Stops at the end of whichever of the two runs out first.
+#[inline]
+pub(crate) fn dot(samples: &[Sample], weights: &[f32], stride: usize) -> Sample {
+    let count = weights.len().min(samples.len().div_ceil(stride));
+    let weights = &weights[..count];
+
+    // Not worth the setup for very short filters, such as those used for linear and cubic resampling
+    if count < 8 {
+        return scalar::dot(samples, weights, stride)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if stride == 1 && is_x86_feature_detected!("avx") {
+            // SAFETY: we just checked that AVX is supported
+            return unsafe { x86::dot_avx(samples, weights) }
+        }
+        if stride <= 2 && is_x86_feature_detected!("sse2") {
+            // SAFETY: we just checked that SSE2 is supported
+            return unsafe { x86::dot_sse2(samples, weights, stride) }
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        if stride <= 2 && std::arch::is_aarch64_feature_detected!("neon") {
+            // SAFETY: we just checked that NEON is supported
+            return unsafe { neon::dot(samples, weights, stride) }
+        }
+    }
+
+    scalar::dot(samples, weights, stride)
+}
+
+/// Adds each value of `input` to the corresponding value in `output`.
+///
+/// Stops at the end of whichever of the two runs out first.
+#[inline]
+pub(crate) fn mix(output: &mut [Sample], input: &[Sample]) {
+    let count = output.len().min(input.len());
+    let (output, input) = (&mut output[..count], &input[..count]);
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx") {
+            // SAFETY: we just checked that AVX is supported
+            return unsafe { x86::mix_avx(output, input) }
+        }
+        if is_x86_feature_detected!("sse2") {
+            // SAFETY: we just checked that SSE2 is supported
+            return unsafe { x86::mix_sse2(output, input) }
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            // SAFETY: we just checked that NEON is supported
+            return unsafe { neon::mix(output, input) }
+        }
+    }
+
+    scalar::mix(output, input)
+}
+
+/// The vectorised loops next to their scalar fallbacks, exported only so the benchmarks can compare the two.
+#[doc(hidden)]
+pub mod bench {
+    use crate::source::Sample;
+
+    #[inline]
+    pub fn dot(samples: &[Sample], weights: &[f32], stride: usize) -> Sample {
+        super::dot(samples, weights, stride)
+    }
+
+    #[inline]
+    pub fn dot_scalar(samples: &[Sample], weights: &[f32], stride: usize) -> Sample {
+        let count = weights.len().min(samples.len().div_ceil(stride));
+        super::scalar::dot(samples, &weights[..count], stride)
+    }
+
+    #[inline]
+    pub fn mix(output: &mut [Sample], input: &[Sample]) {
+        super::mix(output, input)
+    }
+
+    #[inline]
+    pub fn mix_scalar(output: &mut [Sample], input: &[Sample]) {
+        let count = output.len().min(input.len());
+        super::scalar::mix(&mut output[..count], &input[..count])
+    }
+}
+
+mod scalar {
+    use crate::source::Sample;
+
+    // `samples` can be any length, `weights` must already be trimmed to the number of samples available.
+    #[inline]
+    pub(super) fn dot(samples: &[Sample], weights: &[f32], stride: usize) -> Sample {
+        samples.iter().step_by(stride).zip(weights).map(|(s, w)| s * w).sum()
+    }
+
+    #[inline]
+    pub(super) fn mix(output: &mut [Sample], input: &[Sample]) {
+        output.iter_mut().zip(input).for_each(|(out, x)| *out += x);
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    use crate::source::Sample;
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn sum_sse2(v: __m128) -> Sample {
+        let mut lanes = [0.0; 4];
+        _mm_storeu_ps(lanes.as_mut_ptr(), v);
+        (lanes[0] + lanes[1]) + (lanes[2] + lanes[3])
+    }
+
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn dot_avx(samples: &[Sample], weights: &[f32]) -> Sample {
+        let blocks = weights.len() / 8;
+        let mut acc = _mm256_setzero_ps();
+        for i in 0..blocks {
+            let s = _mm256_loadu_ps(samples.as_ptr().add(i * 8));
+            let w = _mm256_loadu_ps(weights.as_ptr().add(i * 8));
+            acc = _mm256_add_ps(acc, _mm256_mul_ps(s, w));
+        }
+        let acc = _mm_add_ps(_mm256_castps256_ps128(acc), _mm256_extractf128_ps(acc, 1));
+        sum_sse2(acc) + super::scalar::dot(&samples[blocks * 8..], &weights[blocks * 8..], 1)
+    }
+
+    // Only strides of 1 and 2 are supported here.
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn dot_sse2(samples: &[Sample], weights: &[f32], stride: usize) -> Sample {
+        let mut acc = _mm_setzero_ps();
+        let blocks = if stride == 1 {
+            let blocks = weights.len() / 4;
+            for i in 0..blocks {
+                let s = _mm_loadu_ps(samples.as_ptr().add(i * 4));
+                let w = _mm_loadu_ps(weights.as_ptr().add(i * 4));
+                acc = _mm_add_ps(acc, _mm_mul_ps(s, w));
+            }
+            blocks
+        } else {
+            // Each block reads 8 samples and keeps the even ones, so the last block has to fit entirely in `samples`
+            let blocks = (weights.len() / 4).min(samples.len() / 8);
+            for i in 0..blocks {
+                let lo = _mm_loadu_ps(samples.as_ptr().add(i * 8));
+                let hi = _mm_loadu_ps(samples.as_ptr().add(i * 8 + 4));
+                let s = _mm_shuffle_ps(lo, hi, 0b10_00_10_00);
+                let w = _mm_loadu_ps(weights.as_ptr().add(i * 4));
+                acc = _mm_add_ps(acc, _mm_mul_ps(s, w));
+            }
+            blocks
+        };
+        sum_sse2(acc) + super::scalar::dot(&samples[blocks * 4 * stride..], &weights[blocks * 4..], stride)
+    }
+
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn mix_avx(output: &mut [Sample], input: &[Sample]) {
+        let blocks = output.len() / 8;
+        for i in 0..blocks {
+            let out = output.as_mut_ptr().add(i * 8);
+            _mm256_storeu_ps(out, _mm256_add_ps(_mm256_loadu_ps(out), _mm256_loadu_ps(input.as_ptr().add(i * 8))));
+        }
+        super::scalar::mix(&mut output[blocks * 8..], &input[blocks * 8..])
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn mix_sse2(output: &mut [Sample], input: &[Sample]) {
+        let blocks = output.len() / 4;
+        for i in 0..blocks {
+            let out = output.as_mut_ptr().add(i * 4);
+            _mm_storeu_ps(out, _mm_add_ps(_mm_loadu_ps(out), _mm_loadu_ps(input.as_ptr().add(i * 4))));
+        }
+        super::scalar::mix(&mut output[blocks * 4..], &input[blocks * 4..])
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use crate::source::Sample;
+    use std::arch::aarch64::*;
+
+    // Only strides of 1 and 2 are supported here.
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn dot(samples: &[Sample], weights: &[f32], stride: usize) -> Sample {
+        let mut acc = vdupq_n_f32(0.0);
+        let blocks = if stride == 1 {
+            let blocks = weights.len() / 4;
+            for i in 0..blocks {
+                let s = vld1q_f32(samples.as_ptr().add(i * 4));
+                acc = vmlaq_f32(acc, s, vld1q_f32(weights.as_ptr().add(i * 4)));
+            }
+            blocks
+        } else {
+            // vld2q reads 8 samples and splits them into even and odd lanes, so the last block has to fit entirely
+            let blocks = (weights.len() / 4).min(samples.len() / 8);
+            for i in 0..blocks {
+                let s = vld2q_f32(samples.as_ptr().add(i * 8)).0;
+                acc = vmlaq_f32(acc, s, vld1q_f32(weights.as_ptr().add(i * 4)));
+            }
+            blocks
+        };
+        vaddvq_f32(acc) + super::scalar::dot(&samples[blocks * 4 * stride..], &weights[blocks * 4..], stride)
+    }
+
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn mix(output: &mut [Sample], input: &[Sample]) {
+        let blocks = output.len() / 4;
+        for i in 0..blocks {
+            let out = output.as_mut_ptr().add(i * 4);
+            vst1q_f32(out, vaddq_f32(vld1q_f32(out), vld1q_f32(input.as_ptr().add(i * 4))));
+        }
+        super::scalar::mix(&mut output[blocks * 4..], &input[blocks * 4..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LENGTHS: [usize; 9] = [0, 1, 3, 7, 8, 9, 15, 17, 33];
+
+    // Deterministic values with no particular pattern, so a misplaced lane can't cancel out
+    fn values(length: usize, seed: u32) -> Vec<Sample> {
+        let mut state = seed.wrapping_mul(0x9e37_79b9) | 1;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state as f32 / u32::MAX as f32) * 2.0 - 1.0
+            })
+            .collect()
+    }
+
+    fn assert_close(actual: Sample, expected: Sample, length: usize) {
+        // Vector code sums in a different order, so allow for rounding that grows with the length
+        let tolerance = 1e-6 * (length as f32 + 1.0);
+        assert!((actual - expected).abs() <= tolerance, "{} != {} (length {})", actual, expected, length);
+    }
+
+    #[test]
+    fn dot_matches_scalar() {
+        for stride in 1..=3 {
+            for &length in &LENGTHS {
+                // Every combination of which side runs out first, including odd tails for strides above 1
+                for &samples_length in &[length * stride, length * stride + 1, (length * stride).saturating_sub(1)] {
+                    for &weights_length in &[length, length + 1, length.saturating_sub(1)] {
+                        let samples = values(samples_length, 1);
+                        let weights = values(weights_length, 2);
+                        let count = weights.len().min(samples.len().div_ceil(stride));
+                        let expected = scalar::dot(&samples, &weights[..count], stride);
+                        assert_close(dot(&samples, &weights, stride), expected, count);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dot_ignores_excess_weights() {
+        let samples = values(17, 3);
+        let weights = values(64, 4);
+        assert_close(dot(&samples, &weights, 1), dot(&samples, &weights[..17], 1), 17);
+        assert_close(dot(&samples, &weights, 2), dot(&samples, &weights[..9], 2), 9);
+    }
+
+    #[test]
+    fn mix_matches_scalar() {
+        for &length in &LENGTHS {
+            for &input_length in &[length, length + 1, length.saturating_sub(1)] {
+                let mut output = values(length, 5);
+                let mut expected = output.clone();
+                let input = values(input_length, 6);
+                mix(&mut output, &input);
+                let count = length.min(input_length);
+                scalar::mix(&mut expected[..count], &input[..count]);
+                // Addition is done lane by lane, so the results are exact
+                assert_eq!(output, expected, "length {}, input {}", length, input_length);
+            }
+        }
+    }
+
+    // `dot` and `mix` only reach the SSE2 versions on CPUs without AVX, so check them directly as well
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn sse2_matches_scalar() {
+        if !is_x86_feature_detected!("sse2") {
+            return
+        }
+        for stride in 1..=2 {
+            for &length in &LENGTHS {
+                let samples = values(length * stride + 1, 7);
+                let weights = values(length, 8);
+                // SAFETY: we just checked that SSE2 is supported
+                let actual = unsafe { x86::dot_sse2(&samples, &weights, stride) };
+                assert_close(actual, scalar::dot(&samples, &weights, stride), length);
+            }
+        }
+        for &length in &LENGTHS {
+            let mut output = values(length, 9);
+            let mut expected = output.clone();
+            let input = values(length, 10);
+            // SAFETY: as above
+            unsafe { x86::mix_sse2(&mut output, &input) };
+            scalar::mix(&mut expected, &input);
+            assert_eq!(output, expected, "length {}", length);
+        }
+    }
+}
@@ -5,6 +5,21 @@ pub type Sample = f32;
 pub type SampleRate = NonZeroU32;
 
 pub mod consts;
+pub mod layout;
+
+pub use layout::{ChannelId, ChannelLayout};
+
+/// Converts a gain in decibels to a linear amplitude multiplier. 0 dB is 1.0, and -6 dB is roughly 0.5.
+#[inline]
+pub fn db_to_linear(db: f32) -> f32 {
+    10.0f32.powf(db / 20.0)
+}
+
+/// Converts a linear amplitude multiplier to a gain in decibels. 1.0 is 0 dB, and 0.0 is negative infinity.
+#[inline]
+pub fn linear_to_db(linear: f32) -> f32 {
+    20.0 * linear.abs().log10()
+}
 
 /// Trait for a source of audio that outputs PCM at a given sample rate.
 pub trait Source {
@@ -18,6 +33,17 @@ pub trait Source {
     /// This function must always return the same value.
     fn sample_rate(&self) -> SampleRate;
 
+    /// Returns the speaker position of each of this `Source`'s channels, if known.
+    ///
+    /// If this returns `None`, consumers will assume the default layout for `channel_count()`,
+    /// as given by [`ChannelLayout::default_for`]. If it returns a layout, that layout must have the same number
+    /// of channels as `channel_count()`.
+    ///
+    /// This function must always return the same value.
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        None
+    }
+
     /// Writes the next set of samples to an output `buffer`.
     ///
     /// The implementor is expected to "remember" its progress through the sound it's playing,
@@ -36,3 +62,59 @@ pub trait Source {
     /// although things such as file players usually will.
     fn reset(&mut self);
 }
+
+/// Trait for a `Source` with a known length, which can jump to any point in it.
+///
+/// Positions and lengths are in frames, where one frame is one sample for each channel.
+pub trait Seekable: Source {
+    /// Returns how many frames long this `Seekable` is in total.
+    ///
+    /// This function must always return the same value.
+    fn frame_count(&self) -> usize;
+
+    /// Returns the frame which the next call to `write_samples` will start from.
+    fn position(&self) -> usize;
+
+    /// Moves to `frame`, so the next call to `write_samples` starts there. Positions past the end are clamped to it.
+    ///
+    /// This also allows the `Seekable` to continue after it has ended, as if it had been reset.
+    fn seek(&mut self, frame: usize);
+
+    /// Returns the loop points stored with this `Seekable`, if it has any, such as those in a .wav file's `smpl` chunk.
+    ///
+    /// This function must always return the same value.
+    fn loop_points(&self) -> Option<LoopPoints> {
+        None
+    }
+}
+
+/// A region of a [`Seekable`] which is meant to be looped, such as the part of a piece of music after its intro.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub struct LoopPoints {
+    /// The first frame of the loop.
+    pub start: usize,
+
+    /// The frame just after the last frame of the loop, which is where it jumps back to `start`.
+    pub end: usize,
+
+    /// How many times the loop plays in total before playback continues past its end, or `None` to loop forever.
+    pub count: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decibels() {
+        assert_eq!(db_to_linear(0.0), 1.0);
+        assert!((db_to_linear(-6.0) - 0.501).abs() < 1e-3);
+        assert!((db_to_linear(20.0) - 10.0).abs() < 1e-5);
+        assert_eq!(linear_to_db(0.0), f32::NEG_INFINITY);
+        for &db in &[-60.0, -3.0, 0.0, 12.0] {
+            assert!((linear_to_db(db_to_linear(db)) - db).abs() < 1e-4);
+            assert!((linear_to_db(-db_to_linear(db)) - db).abs() < 1e-4);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Channel layouts, which describe the speaker position each channel is intended for.
+//!
+//! The channel names and built-in layouts here mirror those used by libsoundio.
+
+use super::ChannelCount;
+use std::{borrow::Cow, convert::TryFrom};
+
+/// Identifies the speaker position (or other purpose) of a single channel.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChannelId {
+    FrontLeft,
+    FrontRight,
+    FrontCenter,
+    Lfe,
+    BackLeft,
+    BackRight,
+    FrontLeftCenter,
+    FrontRightCenter,
+    BackCenter,
+    SideLeft,
+    SideRight,
+    TopCenter,
+    TopFrontLeft,
+    TopFrontCenter,
+    TopFrontRight,
+    TopBackLeft,
+    TopBackCenter,
+    TopBackRight,
+
+    BackLeftCenter,
+    BackRightCenter,
+    FrontLeftWide,
+    FrontRightWide,
+    FrontLeftHigh,
+    FrontCenterHigh,
+    FrontRightHigh,
+    TopFrontLeftCenter,
+    TopFrontRightCenter,
+    TopSideLeft,
+    TopSideRight,
+    LeftLfe,
+    RightLfe,
+    Lfe2,
+    BottomCenter,
+    BottomLeftCenter,
+    BottomRightCenter,
+
+    // Mid/side recording
+    MsMid,
+    MsSide,
+
+    // First-order ambisonic channels
+    AmbisonicW,
+    AmbisonicX,
+    AmbisonicY,
+    AmbisonicZ,
+
+    // X-Y recording
+    XyX,
+    XyY,
+
+    HeadphonesLeft,
+    HeadphonesRight,
+    ClickTrack,
+    ForeignLanguage,
+    HearingImpaired,
+    Narration,
+    Haptic,
+    DialogCentricMix,
+
+    Aux,
+    Aux0,
+    Aux1,
+    Aux2,
+    Aux3,
+    Aux4,
+    Aux5,
+    Aux6,
+    Aux7,
+    Aux8,
+    Aux9,
+    Aux10,
+    Aux11,
+    Aux12,
+    Aux13,
+    Aux14,
+    Aux15,
+}
+
+/// An ordered list of [`ChannelId`]s, one for each channel in an interleaved frame.
+///
+/// Common layouts are available as associated constants. Their names and channel orders match libsoundio's.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ChannelLayout(Cow<'static, [ChannelId]>);
+
+macro_rules! layout_consts {
+    ( $( $(#[$outer:meta])* $name:ident = [ $( $id:ident ),* $(,)? ] ),* $(,)? ) => {
+        $( $(#[$outer])* pub const $name: Self = Self(Cow::Borrowed(&[ $( ChannelId::$id ),* ])); )*
+    };
+}
+
+impl ChannelLayout {
+    layout_consts! {
+        /// Mono
+        MONO = [FrontCenter],
+
+        /// Stereo
+        STEREO = [FrontLeft, FrontRight],
+
+        /// 2.1
+        SURROUND_2_1 = [FrontLeft, FrontRight, Lfe],
+
+        /// 3.0
+        SURROUND_3_0 = [FrontLeft, FrontRight, FrontCenter],
+
+        /// 3.0 (back)
+        SURROUND_3_0_BACK = [FrontLeft, FrontRight, BackCenter],
+
+        /// 3.1
+        SURROUND_3_1 = [FrontLeft, FrontRight, FrontCenter, Lfe],
+
+        /// 4.0
+        SURROUND_4_0 = [FrontLeft, FrontRight, FrontCenter, BackCenter],
+
+        /// Quad
+        QUAD = [FrontLeft, FrontRight, BackLeft, BackRight],
+
+        /// Quad (side)
+        QUAD_SIDE = [FrontLeft, FrontRight, SideLeft, SideRight],
+
+        /// 4.1
+        SURROUND_4_1 = [FrontLeft, FrontRight, FrontCenter, BackCenter, Lfe],
+
+        /// 5.0 (back)
+        SURROUND_5_0_BACK = [FrontLeft, FrontRight, FrontCenter, BackLeft, BackRight],
+
+        /// 5.0 (side)
+        SURROUND_5_0_SIDE = [FrontLeft, FrontRight, FrontCenter, SideLeft, SideRight],
+
+        /// 5.1
+        SURROUND_5_1 = [FrontLeft, FrontRight, FrontCenter, SideLeft, SideRight, Lfe],
+
+        /// 5.1 (back)
+        SURROUND_5_1_BACK = [FrontLeft, FrontRight, FrontCenter, BackLeft, BackRight, Lfe],
+
+        /// 6.1
+        SURROUND_6_1 = [FrontLeft, FrontRight, FrontCenter, SideLeft, SideRight, BackCenter, Lfe],
+
+        /// 7.1
+        SURROUND_7_1 = [FrontLeft, FrontRight, FrontCenter, SideLeft, SideRight, BackLeft, BackRight, Lfe],
+    }
+
+    /// Creates a ChannelLayout from a list of channels, in the order they appear in each frame.
+    ///
+    /// Returns `None` if the list is empty or has more channels than a [`ChannelCount`] can represent.
+    pub fn new(channels: impl Into<Vec<ChannelId>>) -> Option<Self> {
+        let channels = channels.into();
+        if channels.is_empty() || u16::try_from(channels.len()).is_err() {
+            None
+        } else {
+            Some(Self(Cow::Owned(channels)))
+        }
+    }
+
+    /// Returns the layout conventionally assumed for the given number of channels, if there is one.
+    ///
+    /// This matches libsoundio's defaults, which cover one to eight channels.
+    pub fn default_for(channels: ChannelCount) -> Option<Self> {
+        match channels.get() {
+            1 => Some(Self::MONO),
+            2 => Some(Self::STEREO),
+            3 => Some(Self::SURROUND_3_0),
+            4 => Some(Self::SURROUND_4_0),
+            5 => Some(Self::SURROUND_5_0_BACK),
+            6 => Some(Self::SURROUND_5_1_BACK),
+            7 => Some(Self::SURROUND_6_1),
+            8 => Some(Self::SURROUND_7_1),
+            _ => None,
+        }
+    }
+
+    /// Returns the channels in this layout, in the order they appear in each frame.
+    #[inline]
+    pub fn channels(&self) -> &[ChannelId] {
+        &self.0
+    }
+
+    /// Returns the number of channels in this layout.
+    #[inline]
+    pub fn channel_count(&self) -> ChannelCount {
+        // SAFETY: a layout can't be constructed with zero channels or more than u16::MAX
+        unsafe { ChannelCount::new_unchecked(self.0.len() as u16) }
+    }
+
+    /// Returns the index of the given channel within each frame, or `None` if this layout doesn't contain it.
+    #[inline]
+    pub fn find(&self, channel: ChannelId) -> Option<usize> {
+        self.0.iter().position(|&x| x == channel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAYOUTS: [ChannelLayout; 16] = [
+        ChannelLayout::MONO,
+        ChannelLayout::STEREO,
+        ChannelLayout::SURROUND_2_1,
+        ChannelLayout::SURROUND_3_0,
+        ChannelLayout::SURROUND_3_0_BACK,
+        ChannelLayout::SURROUND_3_1,
+        ChannelLayout::SURROUND_4_0,
+        ChannelLayout::QUAD,
+        ChannelLayout::QUAD_SIDE,
+        ChannelLayout::SURROUND_4_1,
+        ChannelLayout::SURROUND_5_0_BACK,
+        ChannelLayout::SURROUND_5_0_SIDE,
+        ChannelLayout::SURROUND_5_1,
+        ChannelLayout::SURROUND_5_1_BACK,
+        ChannelLayout::SURROUND_6_1,
+        ChannelLayout::SURROUND_7_1,
+    ];
+
+    #[test]
+    fn defaults_match_channel_counts() {
+        for channels in 1..=8 {
+            let count = ChannelCount::new(channels).unwrap();
+            assert_eq!(ChannelLayout::default_for(count).unwrap().channel_count(), count);
+        }
+        assert_eq!(ChannelLayout::default_for(ChannelCount::new(9).unwrap()), None);
+    }
+
+    #[test]
+    fn layouts_have_no_duplicates() {
+        for layout in LAYOUTS.iter() {
+            for (i, &id) in layout.channels().iter().enumerate() {
+                assert_eq!(layout.find(id), Some(i), "{:?}", layout);
+            }
+        }
+    }
+
+    #[test]
+    fn new_checks_length() {
+        assert_eq!(ChannelLayout::new(Vec::new()), None);
+        let layout = ChannelLayout::new(vec![ChannelId::FrontLeft, ChannelId::FrontRight]).unwrap();
+        assert_eq!(layout, ChannelLayout::STEREO);
+        assert_eq!(layout.find(ChannelId::FrontRight), Some(1));
+        assert_eq!(layout.find(ChannelId::FrontCenter), None);
+        assert_eq!(ChannelLayout::new(vec![ChannelId::Aux; usize::from(u16::MAX) + 1]), None);
+    }
+}
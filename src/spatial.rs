@@ -0,0 +1,137 @@
+//! 3D positional audio.
+//!
+//! A [`Listener`] describes where the sound is being heard from, and each [`Emitter`] is a Source which places another
+//! Source somewhere in the world around it. Emitters pan, attenuate and Doppler-shift their sound as they and the
+//! Listener move, and their output can be fed into a `Mixer` alongside everything else.
+//!
+//! Positions use a right-handed coordinate system. In the Listener's default orientation it faces towards -Z, with +X
+//! to its right and +Y above it. Distances can be in any unit, as long as the speed of sound uses the same one.
+
+mod ambisonic;
+mod emitter;
+mod hrtf;
+mod listener;
+
+pub use ambisonic::{AmbisonicBus, AmbisonicOrder, BinauralDecoder};
+pub use emitter::{Cone, DistanceModel, Emitter, EmitterHandle};
+pub use hrtf::{Hrir, Hrtf};
+pub use listener::Listener;
+
+use crate::param::AtomicF32;
+use std::ops::{Add, Mul, Neg, Sub};
+
+/// A position, direction or velocity in 3D space.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub struct Vec3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Vec3 {
+    pub const ZERO: Self = Self::new(0.0, 0.0, 0.0);
+
+    #[inline]
+    pub const fn new(x: f32, y: f32, z: f32) -> Self {
+        Self { x, y, z }
+    }
+
+    #[inline]
+    pub fn dot(self, other: Self) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    #[inline]
+    pub fn cross(self, other: Self) -> Self {
+        Self::new(
+            self.y * other.z - self.z * other.y,
+            self.z * other.x - self.x * other.z,
+            self.x * other.y - self.y * other.x,
+        )
+    }
+
+    #[inline]
+    pub fn length(self) -> f32 {
+        self.dot(self).sqrt()
+    }
+
+    /// Returns a vector pointing the same way with a length of 1, or [`Vec3::ZERO`] if this has no length.
+    #[inline]
+    pub fn normalize(self) -> Self {
+        let length = self.length();
+        if length > 0.0 { self * (1.0 / length) } else { Self::ZERO }
+    }
+}
+
+impl Add for Vec3 {
+    type Output = Self;
+
+    #[inline]
+    fn add(self, other: Self) -> Self {
+        Self::new(self.x + other.x, self.y + other.y, self.z + other.z)
+    }
+}
+
+impl Sub for Vec3 {
+    type Output = Self;
+
+    #[inline]
+    fn sub(self, other: Self) -> Self {
+        Self::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
+
+impl Mul<f32> for Vec3 {
+    type Output = Self;
+
+    #[inline]
+    fn mul(self, scale: f32) -> Self {
+        Self::new(self.x * scale, self.y * scale, self.z * scale)
+    }
+}
+
+impl Neg for Vec3 {
+    type Output = Self;
+
+    #[inline]
+    fn neg(self) -> Self {
+        Self::new(-self.x, -self.y, -self.z)
+    }
+}
+
+/// Error type for loading an [`Hrtf`]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub enum Error {
+    /// This does not appear to be an HRIR file
+    InvalidFile,
+
+    /// The file uses a newer version of the format than this crate supports
+    UnknownVersion,
+
+    /// The data in this file is malformed
+    MalformedData,
+}
+
+// A Vec3 which can be shared between threads. Each component is atomic on its own, so a reader racing with a writer
+// might see a mix of the old and new values for one block, which is harmless here.
+struct AtomicVec3([AtomicF32; 3]);
+
+impl AtomicVec3 {
+    fn new(value: Vec3) -> Self {
+        Self([AtomicF32::new(value.x), AtomicF32::new(value.y), AtomicF32::new(value.z)])
+    }
+
+    #[inline]
+    fn load(&self) -> Vec3 {
+        Vec3::new(self.0[0].load(), self.0[1].load(), self.0[2].load())
+    }
+
+    #[inline]
+    fn store(&self, value: Vec3) {
+        self.0[0].store(value.x);
+        self.0[1].store(value.y);
+        self.0[2].store(value.z);
+    }
+}
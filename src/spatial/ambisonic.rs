@@ -0,0 +1,737 @@
+use super::{
+    emitter::Speakers,
+    hrtf::Hrtf,
+    listener::{ListenerFrame, ListenerParams},
+    Listener,
+    Vec3,
+};
+use crate::{
+    frame::PartialFrame,
+    mixer::{Mixer, MixerHandle},
+    param::Smoothed,
+    rechanneler::MixMatrix,
+    simd,
+    source::{ChannelCount, ChannelLayout, Sample, SampleRate, Source},
+};
+use std::{f32::consts::PI, sync::Arc};
+
+// How many evenly spread directions are used for designing decoders and rotations
+const VIRTUAL_SPEAKERS: usize = 64;
+
+/// How much detail an ambisonic sound field has. Higher orders place sounds more precisely, but need more channels:
+/// 4 for first order, 9 for second order and 16 for third order.
+///
+/// Sound fields follow the AmbiX conventions, with channels in ACN order and SN3D normalisation.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub enum AmbisonicOrder {
+    First,
+    Second,
+    Third,
+}
+
+/// A `Mixer` for ambisonic sound fields, which rotates the mix to match a [`Listener`]'s orientation.
+///
+/// Emitters created with `Emitter::with_ambisonics()` are added through the MixerHandle returned alongside the bus.
+/// They're encoded relative to the world's axes, so the bus only needs to rotate the finished mix once, rather than
+/// each Emitter being spatialised separately. The rotation is ramped over each block, so turning doesn't click.
+///
+/// The output is the rotated sound field, which needs decoding before it can be played. For speakers, use a
+/// `Rechanneler` with the matrix from [`AmbisonicOrder::decoder_matrix`], and for headphones, use a
+/// [`BinauralDecoder`]. The output has no channel layout, since ambisonic channels aren't speakers and can't be
+/// rechanneled like them.
+pub struct AmbisonicBus {
+    mixer: Mixer,
+    order: AmbisonicOrder,
+    listener: Arc<ListenerParams>,
+    rotation: Rotation,
+    current: Box<[f32]>, // The rotation matrix at the start of the block
+    target: Box<[f32]>,  // The rotation matrix at the end of the block
+    input: Box<[Sample]>,
+    started: bool,
+    partial: PartialFrame,
+}
+
+/// Decodes an ambisonic sound field to binaural stereo for headphones, through an [`Hrtf`].
+///
+/// The sound field is decoded to a set of virtual speakers spread evenly around the Listener, and each of those is
+/// rendered through the Hrtf. All of that is combined into one pair of filters per ambisonic channel when the
+/// BinauralDecoder is created, so the cost doesn't depend on how many virtual speakers there are.
+///
+/// When the sound field ends, the BinauralDecoder carries on until the last of it has rung out of the filters.
+pub struct BinauralDecoder<S>
+where
+    S: Source,
+{
+    source: S,
+    length: usize,
+    filters: Box<[f32]>, // For each input channel, the left then right filter in oldest-first order for simd::dot
+    history: Box<[Sample]>, // For each input channel, the most recent input, stored twice over
+    position: usize,
+    buffer: Vec<Sample>,
+    ended: bool,
+    tail: usize, // How many frames of the filters' response are left to play once the source has ended
+    partial: PartialFrame,
+}
+
+// Encodes a mono signal into a sound field
+pub(super) struct Encoder {
+    order: AmbisonicOrder,
+    harmonics: Box<[f32]>,
+    gains: Box<[Smoothed]>,
+}
+
+// Works out the matrix which rotates a sound field from the world's axes to a Listener's, by evaluating the spherical
+// harmonics at rotated copies of a set of directions and fitting a matrix to them by least squares
+struct Rotation {
+    order: AmbisonicOrder,
+    points: Box<[Vec3]>,
+    pseudo_inverse: Box<[f32]>, // Channels by points
+    rotated: Box<[f32]>,        // Points by channels
+}
+
+impl AmbisonicOrder {
+    /// Returns how many channels a sound field of this order has.
+    pub fn channel_count(self) -> ChannelCount {
+        ChannelCount::new(self.channels() as u16).unwrap()
+    }
+
+    /// Creates a MixMatrix which decodes a sound field of this order to the speakers in `layout`, for use with
+    /// `Rechanneler::with_matrix()`.
+    ///
+    /// The sound field is decoded to a set of virtual speakers spread evenly around the Listener, and each of those
+    /// is panned between the real speakers in the same way as an [`Emitter`](super::Emitter) would be. This copes
+    /// with uneven layouts such as 5.1 and 7.1 far better than decoding to the speakers directly. The result is
+    /// scaled so that sounds come out at the same overall volume as they would from an Emitter.
+    pub fn decoder_matrix(self, layout: &ChannelLayout) -> MixMatrix {
+        let speakers = Speakers::new(layout);
+        let (inputs, outputs) = (self.channels(), layout.channels().len());
+        let points = sphere_points(VIRTUAL_SPEAKERS);
+        let decoder = self.virtual_decoder(&points);
+
+        let mut gains = vec![0.0; inputs * outputs];
+        let mut pan = vec![0.0; outputs];
+        for (point, row) in points.iter().zip(decoder.chunks_exact(inputs)) {
+            speakers.pan(*point, &mut pan);
+            for (gains, &pan) in gains.chunks_exact_mut(inputs).zip(pan.iter()) {
+                gains.iter_mut().zip(row).for_each(|(x, y)| *x += pan * y);
+            }
+        }
+
+        // Scale to unit power, averaged over every direction
+        let mut harmonics = vec![0.0; inputs];
+        let mut power = 0.0;
+        for &point in points.iter() {
+            self.harmonics(point, &mut harmonics);
+            power += gains.chunks_exact(inputs).map(|row| simd::dot(&harmonics, row, 1).powi(2)).sum::<f32>();
+        }
+        if power > 0.0 {
+            let scale = (points.len() as f32 / power).sqrt();
+            gains.iter_mut().for_each(|x| *x *= scale);
+        }
+
+        MixMatrix::new(self.channel_count(), layout.channel_count(), gains).unwrap()
+    }
+
+    #[inline]
+    fn order(self) -> usize {
+        match self {
+            Self::First => 1,
+            Self::Second => 2,
+            Self::Third => 3,
+        }
+    }
+
+    #[inline]
+    fn channels(self) -> usize {
+        (self.order() + 1) * (self.order() + 1)
+    }
+
+    // Writes the SN3D spherical harmonics for `direction`, in the Listener's space, into `out` in ACN order.
+    // A zero direction only has the omnidirectional component.
+    fn harmonics(self, direction: Vec3, out: &mut [f32]) {
+        out.iter_mut().for_each(|x| *x = 0.0);
+        out[0] = 1.0;
+        if direction == Vec3::ZERO {
+            return
+        }
+
+        // Ambisonics puts X in front, Y to the left and Z up
+        let (x, y, z) = (-direction.z, -direction.x, direction.y);
+        let order = self.order();
+        if order >= 1 {
+            out[1] = y;
+            out[2] = z;
+            out[3] = x;
+        }
+        if order >= 2 {
+            let root3 = 3.0f32.sqrt();
+            out[4] = root3 * x * y;
+            out[5] = root3 * y * z;
+            out[6] = 0.5 * (3.0 * z * z - 1.0);
+            out[7] = root3 * x * z;
+            out[8] = 0.5 * root3 * (x * x - y * y);
+        }
+        if order >= 3 {
+            let (root5_8, root15, root3_8) = ((5.0f32 / 8.0).sqrt(), 15.0f32.sqrt(), (3.0f32 / 8.0).sqrt());
+            out[9] = root5_8 * y * (3.0 * x * x - y * y);
+            out[10] = root15 * x * y * z;
+            out[11] = root3_8 * y * (5.0 * z * z - 1.0);
+            out[12] = 0.5 * z * (5.0 * z * z - 3.0);
+            out[13] = root3_8 * x * (5.0 * z * z - 1.0);
+            out[14] = 0.5 * root15 * z * (x * x - y * y);
+            out[15] = root5_8 * x * (x * x - 3.0 * y * y);
+        }
+    }
+
+    // Returns the gains for decoding to each of `points` in turn, one row of channels per point. The decoder uses
+    // max-rE weighting, which tightens up each virtual speaker's lobe so sounds are localised more precisely.
+    fn virtual_decoder(self, points: &[Vec3]) -> Vec<f32> {
+        let cos = (137.9f32.to_radians() / (self.order() as f32 + 1.51)).cos();
+        let mut legendre = [1.0, cos, 0.0, 0.0];
+        for l in 2..legendre.len() {
+            legendre[l] = ((2 * l - 1) as f32 * cos * legendre[l - 1] - (l - 1) as f32 * legendre[l - 2]) / l as f32;
+        }
+
+        let channels = self.channels();
+        let mut decoder = vec![0.0; points.len() * channels];
+        for (&point, row) in points.iter().zip(decoder.chunks_exact_mut(channels)) {
+            self.harmonics(point, row);
+            for (i, x) in row.iter_mut().enumerate() {
+                let l = (i as f32).sqrt() as usize;
+                *x *= (2 * l + 1) as f32 * legendre[l] / points.len() as f32;
+            }
+        }
+        decoder
+    }
+}
+
+impl AmbisonicBus {
+    /// Creates a new AmbisonicBus and a MixerHandle for adding Sources to it. Every Source added should be a sound
+    /// field of the same `order`, at `sample_rate`.
+    pub fn new(sample_rate: SampleRate, order: AmbisonicOrder, listener: &Listener) -> (Self, MixerHandle) {
+        let (mixer, handle) = Mixer::new(sample_rate, order.channel_count());
+        let channels = order.channels();
+        let bus = Self {
+            mixer,
+            order,
+            listener: listener.params(),
+            rotation: Rotation::new(order),
+            current: vec![0.0; channels * channels].into(),
+            target: vec![0.0; channels * channels].into(),
+            input: vec![0.0; channels].into(),
+            started: false,
+            partial: PartialFrame::new(channels),
+        };
+        (bus, handle)
+    }
+
+    /// Returns the order of the sound field this bus mixes.
+    pub fn order(&self) -> AmbisonicOrder {
+        self.order
+    }
+
+    fn write_frames(&mut self, buffer: &mut [Sample]) -> usize {
+        let count = self.mixer.write_samples(buffer);
+        std::mem::swap(&mut self.current, &mut self.target);
+        self.rotation.matrix(&self.listener.frame(), &mut self.target);
+        if !self.started {
+            self.current.copy_from_slice(&self.target);
+            self.started = true;
+        }
+
+        let channels = self.input.len();
+        let frames = count / channels;
+        for (n, frame) in buffer[..count].chunks_exact_mut(channels).enumerate() {
+            let t = (n + 1) as f32 / frames as f32;
+            self.input.copy_from_slice(frame);
+            let rows = self.current.chunks_exact(channels).zip(self.target.chunks_exact(channels));
+            for (output, (from, to)) in frame.iter_mut().zip(rows) {
+                let row = from.iter().zip(to.iter()).map(|(from, to)| from + (to - from) * t);
+                *output = row.zip(self.input.iter()).map(|(gain, x)| gain * x).sum();
+            }
+        }
+
+        count
+    }
+}
+
+impl Source for AmbisonicBus {
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.order.channel_count()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.mixer.sample_rate()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        PartialFrame::write_with(self, buffer, |bus| &mut bus.partial, Self::write_frames)
+    }
+
+    fn reset(&mut self) {
+        self.mixer.reset();
+        self.started = false;
+        self.partial.reset();
+    }
+}
+
+impl<S> BinauralDecoder<S>
+where
+    S: Source,
+{
+    /// Creates a new BinauralDecoder for a sound field of the given `order`.
+    ///
+    /// Panics if `source` doesn't have the right number of channels for `order`, or if `hrtf` was prepared for a
+    /// different sample rate from the Source's.
+    pub fn new(source: S, order: AmbisonicOrder, hrtf: &Hrtf) -> Self {
+        assert_eq!(source.channel_count(), order.channel_count(), "Source has the wrong number of channels");
+        assert_eq!(hrtf.sample_rate(), source.sample_rate(), "HRTF is for the wrong sample rate");
+        let channels = order.channels();
+        let length = hrtf.impulse_length();
+        let points = sphere_points(VIRTUAL_SPEAKERS);
+        let decoder = order.virtual_decoder(&points);
+
+        let mut filters = vec![0.0; channels * 2 * length];
+        let mut responses = [0, 1].map(|_| vec![0.0; length].into_boxed_slice());
+        for (&point, row) in points.iter().zip(decoder.chunks_exact(channels)) {
+            hrtf.impulses(point, &mut responses);
+            for (filters, &gain) in filters.chunks_exact_mut(length * 2).zip(row) {
+                for (filter, response) in filters.chunks_exact_mut(length).zip(responses.iter()) {
+                    filter.iter_mut().zip(response.iter()).for_each(|(x, y)| *x += gain * y);
+                }
+            }
+        }
+
+        // Scale to the same power as an Emitter rendered through the same Hrtf, averaged over every direction
+        let mut harmonics = vec![0.0; channels];
+        let mut response = vec![0.0; length];
+        let (mut power, mut reference) = (0.0, 0.0);
+        for &point in points.iter() {
+            hrtf.impulses(point, &mut responses);
+            reference += responses.iter().flat_map(|x| x.iter()).map(|x| x * x).sum::<f32>();
+            order.harmonics(point, &mut harmonics);
+            for ear in 0..2 {
+                response.iter_mut().for_each(|x| *x = 0.0);
+                for (filters, &gain) in filters.chunks_exact(length * 2).zip(harmonics.iter()) {
+                    let filter = &filters[ear * length..(ear + 1) * length];
+                    response.iter_mut().zip(filter).for_each(|(x, y)| *x += gain * y);
+                }
+                power += response.iter().map(|x| x * x).sum::<f32>();
+            }
+        }
+        let scale = if power > 0.0 { (reference / power).sqrt() } else { 1.0 };
+        for filter in filters.chunks_exact_mut(length) {
+            filter.iter_mut().for_each(|x| *x *= scale);
+            filter.reverse();
+        }
+
+        Self {
+            source,
+            length,
+            filters: filters.into(),
+            history: vec![0.0; channels * length * 2].into(),
+            position: 0,
+            buffer: Vec::new(),
+            ended: false,
+            tail: 0,
+            partial: PartialFrame::new(2),
+        }
+    }
+
+    fn write_frames(&mut self, buffer: &mut [Sample]) -> usize {
+        let channels = usize::from(self.source.channel_count().get());
+        let length = self.length;
+        let frames = buffer.len() / 2;
+        self.buffer.resize(frames * channels, 0.0);
+        let count = if self.ended { 0 } else { self.source.write_samples(&mut self.buffer) / channels };
+        if count < frames && !self.ended {
+            // Keep going with silence until the last input has passed all the way through the filters
+            self.ended = true;
+            self.tail = length - 1;
+        }
+        self.buffer[count * channels..].iter_mut().for_each(|x| *x = 0.0);
+        let written = frames.min(count + self.tail);
+        self.tail -= written - count;
+
+        for (input, output) in self.buffer.chunks_exact(channels).zip(buffer.chunks_exact_mut(2)).take(written) {
+            output.iter_mut().for_each(|x| *x = 0.0);
+            for ((&x, history), filters) in input
+                .iter()
+                .zip(self.history.chunks_exact_mut(length * 2))
+                .zip(self.filters.chunks_exact(length * 2))
+            {
+                history[self.position] = x;
+                history[self.position + length] = x;
+                let window = &history[self.position + 1..self.position + 1 + length];
+                output[0] += simd::dot(window, &filters[..length], 1);
+                output[1] += simd::dot(window, &filters[length..], 1);
+            }
+            self.position = (self.position + 1) % length;
+        }
+
+        written * 2
+    }
+}
+
+impl<S> Source for BinauralDecoder<S>
+where
+    S: Source,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        crate::source::consts::CH_STEREO
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        Some(ChannelLayout::STEREO)
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        PartialFrame::write_with(self, buffer, |decoder| &mut decoder.partial, Self::write_frames)
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.history.iter_mut().for_each(|x| *x = 0.0);
+        self.position = 0;
+        self.ended = false;
+        self.tail = 0;
+        self.partial.reset();
+    }
+}
+
+impl Encoder {
+    pub(super) fn new(order: AmbisonicOrder) -> Self {
+        let channels = order.channels();
+        Self {
+            order,
+            harmonics: vec![0.0; channels].into(),
+            gains: vec![Smoothed::new(0.0); channels].into(),
+        }
+    }
+
+    /// Starts ramping each channel towards its gain for a sound coming from `direction`.
+    pub(super) fn update(&mut self, direction: Vec3, steps: u32, settle: bool) {
+        self.order.harmonics(direction, &mut self.harmonics);
+        for (gain, &target) in self.gains.iter_mut().zip(self.harmonics.iter()) {
+            gain.set_target(target, steps);
+            if settle {
+                gain.settle();
+            }
+        }
+    }
+
+    #[inline]
+    pub(super) fn process(&mut self, sample: Sample, frame: &mut [Sample]) {
+        for (x, gain) in frame.iter_mut().zip(self.gains.iter_mut()) {
+            *x = sample * gain.next();
+        }
+    }
+}
+
+impl Rotation {
+    fn new(order: AmbisonicOrder) -> Self {
+        let channels = order.channels();
+        let points = sphere_points(VIRTUAL_SPEAKERS);
+        let mut harmonics = vec![0.0; points.len() * channels];
+        for (&point, row) in points.iter().zip(harmonics.chunks_exact_mut(channels)) {
+            order.harmonics(point, row);
+        }
+
+        // (YᵀY)⁻¹Yᵀ, where Y has the harmonics for one point in each row
+        let mut gram = vec![0.0f64; channels * channels];
+        for row in harmonics.chunks_exact(channels) {
+            for (i, &a) in row.iter().enumerate() {
+                for (j, &b) in row.iter().enumerate() {
+                    gram[i * channels + j] += f64::from(a) * f64::from(b);
+                }
+            }
+        }
+        let inverse = invert(&mut gram, channels);
+        let mut pseudo_inverse = vec![0.0; channels * points.len()];
+        for (i, row) in pseudo_inverse.chunks_exact_mut(points.len()).enumerate() {
+            for (x, point) in row.iter_mut().zip(harmonics.chunks_exact(channels)) {
+                let sum = (0..channels).map(|j| inverse[i * channels + j] * f64::from(point[j])).sum::<f64>();
+                *x = sum as f32;
+            }
+        }
+
+        Self {
+            order,
+            rotated: vec![0.0; points.len() * channels].into(),
+            points: points.into(),
+            pseudo_inverse: pseudo_inverse.into(),
+        }
+    }
+
+    // Writes the matrix which rotates a sound field from the world's axes to `listener`'s into `matrix`, with one row
+    // for each output channel
+    fn matrix(&mut self, listener: &ListenerFrame, matrix: &mut [f32]) {
+        let channels = self.order.channels();
+        for (&point, row) in self.points.iter().zip(self.rotated.chunks_exact_mut(channels)) {
+            self.order.harmonics(listener.to_local(point), row);
+        }
+        for (i, row) in matrix.chunks_exact_mut(channels).enumerate() {
+            for (x, inverse) in row.iter_mut().zip(self.pseudo_inverse.chunks_exact(self.points.len())) {
+                *x = simd::dot(&self.rotated[i..], inverse, channels);
+            }
+        }
+    }
+}
+
+// Returns `count` directions spread evenly over a sphere, along a Fibonacci spiral
+fn sphere_points(count: usize) -> Vec<Vec3> {
+    let angle = PI * (3.0 - 5.0f32.sqrt());
+    (0..count)
+        .map(|i| {
+            let y = 1.0 - 2.0 * (i as f32 + 0.5) / count as f32;
+            let radius = (1.0 - y * y).sqrt();
+            let (sin, cos) = (angle * i as f32).sin_cos();
+            Vec3::new(radius * cos, y, radius * sin)
+        })
+        .collect()
+}
+
+// Inverts a square matrix by Gauss-Jordan elimination, destroying the original
+fn invert(matrix: &mut [f64], n: usize) -> Vec<f64> {
+    let mut inverse = vec![0.0; n * n];
+    (0..n).for_each(|i| inverse[i * n + i] = 1.0);
+    for column in 0..n {
+        let pivot = (column..n).max_by(|&a, &b| matrix[a * n + column].abs().total_cmp(&matrix[b * n + column].abs()));
+        let pivot = pivot.unwrap_or(column);
+        for k in 0..n {
+            matrix.swap(column * n + k, pivot * n + k);
+            inverse.swap(column * n + k, pivot * n + k);
+        }
+        let scale = 1.0 / matrix[column * n + column];
+        for k in 0..n {
+            matrix[column * n + k] *= scale;
+            inverse[column * n + k] *= scale;
+        }
+        for row in (0..n).filter(|&row| row != column) {
+            let factor = matrix[row * n + column];
+            for k in 0..n {
+                matrix[row * n + k] -= factor * matrix[column * n + k];
+                inverse[row * n + k] -= factor * inverse[column * n + k];
+            }
+        }
+    }
+    inverse
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Player;
+
+    #[test]
+    fn bus_has_no_layout() {
+        for &order in &[AmbisonicOrder::First, AmbisonicOrder::Second, AmbisonicOrder::Third] {
+            let (bus, _) = AmbisonicBus::new(SampleRate::new(44100).unwrap(), order, &Listener::new());
+            assert_eq!(bus.channel_layout(), None);
+        }
+    }
+
+    #[test]
+    fn decodes_omni_evenly() {
+        let matrix = AmbisonicOrder::First.decoder_matrix(&ChannelLayout::STEREO);
+        let (left, right) = (matrix.gain(0, 0), matrix.gain(0, 1));
+        assert!(left > 0.0 && (left - right).abs() < 1e-3, "W decoded to {} and {}", left, right);
+
+        // Y points to the left, so it should push the left speaker up and the right one down
+        assert!(matrix.gain(1, 0) > 0.0 && matrix.gain(1, 1) < 0.0);
+    }
+
+    // Encodes `signal` as coming from `direction`
+    fn field(order: AmbisonicOrder, direction: Vec3, signal: &[Sample]) -> Player {
+        let mut harmonics = vec![0.0; order.channels()];
+        order.harmonics(direction, &mut harmonics);
+        let samples = signal.iter().flat_map(|&x| harmonics.iter().map(move |gain| x * gain)).collect::<Vec<_>>();
+        Player::new(order.channel_count(), SampleRate::new(48000).unwrap(), samples.into())
+    }
+
+    // Reads a Source to the end in buffers of `length` samples
+    fn read_all(source: &mut impl Source, length: usize) -> Vec<Sample> {
+        let mut output = Vec::new();
+        let mut buffer = vec![0.0; length];
+        loop {
+            let count = source.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < length {
+                return output
+            }
+        }
+    }
+
+    #[test]
+    fn binaural_odd_buffers_play_out_the_tail() {
+        let hrtf = Hrtf::spherical_head(SampleRate::new(48000).unwrap());
+        let signal = (0..1000).map(|i| (i as Sample * 0.05).sin()).collect::<Vec<_>>();
+        let direction = Vec3::new(-0.6, 0.0, -0.8);
+        let order = AmbisonicOrder::First;
+        let decoder = || BinauralDecoder::new(field(order, direction, &signal), order, &hrtf);
+        let expected = read_all(&mut decoder(), 1 << 16);
+        assert_eq!(expected.len(), (signal.len() + hrtf.impulse_length() - 1) * 2);
+        assert!(expected[signal.len() * 2..].iter().any(|x| x.abs() > 1e-3), "no tail after the input ended");
+
+        let mut odd = decoder();
+        assert_eq!(read_all(&mut odd, 101), expected);
+        assert_eq!(odd.write_samples(&mut [0.0; 8]), 0);
+
+        odd.reset();
+        assert_eq!(read_all(&mut odd, 33), expected);
+    }
+
+    const ORDERS: [AmbisonicOrder; 3] = [AmbisonicOrder::First, AmbisonicOrder::Second, AmbisonicOrder::Third];
+
+    #[test]
+    fn rotation_follows_the_listener() {
+        // Turned to face left, so what was in front is now to the right, and what was on the left is now in front
+        let listener = Listener::new();
+        listener.set_orientation(Vec3::new(-1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        let diagonal = 1.0 / 3.0f32.sqrt();
+        let cases = [
+            (Vec3::new(0.0, 0.0, -1.0), Vec3::new(1.0, 0.0, 0.0)),
+            (Vec3::new(-1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0)),
+            (Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 1.0, 0.0)),
+            (Vec3::new(diagonal, diagonal, -diagonal), Vec3::new(diagonal, diagonal, diagonal)),
+        ];
+
+        for &order in &ORDERS {
+            let channels = order.channels();
+            let mut matrix = vec![0.0; channels * channels];
+            Rotation::new(order).matrix(&listener.params().frame(), &mut matrix);
+            let (mut world, mut local) = (vec![0.0; channels], vec![0.0; channels]);
+            for &(direction, expected) in &cases {
+                order.harmonics(direction, &mut world);
+                order.harmonics(expected, &mut local);
+                for (row, &expected) in matrix.chunks_exact(channels).zip(local.iter()) {
+                    let rotated = simd::dot(row, &world, 1);
+                    assert!(
+                        (rotated - expected).abs() < 1e-3,
+                        "{:?} {:?}: {} != {}",
+                        order,
+                        direction,
+                        rotated,
+                        expected
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn harmonics_are_sn3d() {
+        // With SN3D, the squares of each degree's harmonics add up to 1 in every direction
+        let mut harmonics = [0.0; 16];
+        for point in sphere_points(50) {
+            AmbisonicOrder::Third.harmonics(point, &mut harmonics);
+            for degree in 0..4 {
+                let power = harmonics[degree * degree..(degree + 1) * (degree + 1)].iter().map(|x| x * x).sum::<f32>();
+                assert!((power - 1.0).abs() < 1e-4, "degree {} at {:?} has power {}", degree, point, power);
+            }
+        }
+
+        // Averaged over the sphere, each harmonic is orthogonal to the others, with power 1 / (2 * degree + 1)
+        let points = sphere_points(4000);
+        let mut gram = [[0.0; 16]; 16];
+        for &point in points.iter() {
+            AmbisonicOrder::Third.harmonics(point, &mut harmonics);
+            for (row, &a) in gram.iter_mut().zip(harmonics.iter()) {
+                row.iter_mut().zip(harmonics.iter()).for_each(|(x, &b)| *x += a * b / points.len() as f32);
+            }
+        }
+        for (i, row) in gram.iter().enumerate() {
+            for (j, &x) in row.iter().enumerate() {
+                let degree = (i as f32).sqrt() as usize;
+                let expected = if i == j { 1.0 / (2 * degree + 1) as f32 } else { 0.0 };
+                assert!((x - expected).abs() < 1e-3, "harmonics {} and {} give {}", i, j, x);
+            }
+        }
+
+        // Straight up, only the zonal harmonics are non-zero
+        AmbisonicOrder::Third.harmonics(Vec3::new(0.0, 1.0, 0.0), &mut harmonics);
+        for (i, &x) in harmonics.iter().enumerate() {
+            let expected = if [0, 2, 6, 12].contains(&i) { 1.0 } else { 0.0 };
+            assert!((x - expected).abs() < 1e-6, "harmonic {} is {} straight up", i, x);
+        }
+    }
+
+    #[test]
+    fn decodes_to_surround_speakers() {
+        // The azimuth of each speaker, or None for the LFE
+        let layouts = [
+            (ChannelLayout::SURROUND_5_1, &[Some(-30.0), Some(30.0), Some(0.0), Some(-90.0), Some(90.0), None][..]),
+            (
+                ChannelLayout::SURROUND_7_1,
+                &[Some(-30.0), Some(30.0), Some(0.0), Some(-90.0), Some(90.0), Some(-150.0), Some(150.0), None][..],
+            ),
+        ];
+        let direction = |degrees: f32| Vec3::new(degrees.to_radians().sin(), 0.0, -degrees.to_radians().cos());
+
+        for (layout, azimuths) in &layouts {
+            let mut shares = vec![0.0; azimuths.len()];
+            for &order in &ORDERS {
+                let matrix = order.decoder_matrix(layout);
+                let mut harmonics = vec![0.0; order.channels()];
+                let mut decode = |degrees: f32| {
+                    order.harmonics(direction(degrees), &mut harmonics);
+                    (0..azimuths.len())
+                        .map(|output| harmonics.iter().enumerate().map(|(i, x)| x * matrix.gain(i, output)).sum())
+                        .collect::<Vec<f32>>()
+                };
+
+                // A sound at each speaker comes out loudest from that speaker, and never from the LFE. First order
+                // can't pick the centre out from the front pair either side of it, but it should still get sharper
+                // at each order.
+                for (speaker, degrees) in azimuths.iter().enumerate().filter_map(|(i, x)| x.map(|x| (i, x))) {
+                    let gains = decode(degrees);
+                    let loudest = (0..gains.len()).max_by(|&a, &b| gains[a].abs().total_cmp(&gains[b].abs()));
+                    if order != AmbisonicOrder::First || degrees != 0.0 {
+                        assert_eq!(loudest, Some(speaker), "{:?} at {} degrees gave {:?}", order, degrees, gains);
+                    }
+                    assert!(gains[azimuths.len() - 1].abs() < 1e-6, "LFE got {:?}", gains);
+
+                    let share = gains[speaker].powi(2) / gains.iter().map(|x| x * x).sum::<f32>();
+                    assert!(share > shares[speaker], "{:?} at {} degrees is less focused", order, degrees);
+                    shares[speaker] = share;
+                }
+
+                // Sounds all the way round come out at about the same volume
+                for degrees in (0..360).step_by(15) {
+                    let power = decode(degrees as f32).iter().map(|x| x * x).sum::<f32>();
+                    assert!(power > 0.5 && power < 2.0, "{:?} at {} degrees has power {}", order, degrees, power);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn binaural_puts_sounds_on_the_right_side() {
+        let hrtf = Hrtf::spherical_head(SampleRate::new(48000).unwrap());
+        // About 1 kHz, well below where the virtual speakers start to interfere with each other at these orders
+        let signal = (0..4800).map(|i| (i as Sample * 0.15).sin()).collect::<Vec<_>>();
+        for &order in &ORDERS {
+            let mut levels = Vec::new();
+            for &x in &[-1.0, 0.0, 1.0] {
+                let direction = Vec3::new(x, 0.0, -(1.0 - x * x));
+                let mut decoder = BinauralDecoder::new(field(order, direction, &signal), order, &hrtf);
+                let output = read_all(&mut decoder, 512);
+                let power = |ear: usize| output.iter().skip(ear).step_by(2).map(|x| x * x).sum::<f32>();
+                levels.push(10.0 * (power(0) / power(1)).log10());
+            }
+            assert!(levels[0] > 3.0, "{:?}: left was {} dB", order, levels[0]);
+            assert!(levels[1].abs() < 0.5, "{:?}: front was {} dB", order, levels[1]);
+            assert!(levels[2] < -3.0, "{:?}: right was {} dB", order, levels[2]);
+        }
+    }
+}
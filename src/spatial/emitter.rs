@@ -0,0 +1,778 @@
+use super::{
+    ambisonic::{AmbisonicOrder, Encoder},
+    hrtf::{Binaural, Hrtf},
+    listener::{ListenerFrame, ListenerParams},
+    AtomicVec3,
+    Listener,
+    Vec3,
+};
+use crate::{
+    frame::PartialFrame,
+    param::{AtomicF32, Smoothed, SMOOTHING_TIME},
+    source::{consts::CH_STEREO, ChannelCount, ChannelId, ChannelLayout, Sample, SampleRate, Source},
+};
+use std::{
+    f32::consts::{FRAC_PI_2, TAU},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+};
+
+// The Doppler effect is limited to two octaves either way, so a sound moving at close to the speed of sound doesn't
+// need huge amounts of input at once
+const MAX_PITCH: f32 = 4.0;
+
+// How many frames of the inner Source are read at a time
+const CHUNK_FRAMES: usize = 256;
+
+/// How an [`Emitter`] gets quieter as it moves further from the [`Listener`].
+///
+/// Each model leaves the volume unchanged up to the minimum distance, and stops attenuating any further past the
+/// maximum distance. They match the "clamped" distance models in OpenAL.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub enum DistanceModel {
+    /// The volume is inversely proportional to the distance, so it halves each time the distance doubles when the
+    /// rolloff factor is 1.0. This is how sound behaves in open air.
+    #[default]
+    Inverse,
+
+    /// The volume fades out in a straight line, reaching silence at the maximum distance when the rolloff factor is
+    /// 1.0.
+    Linear,
+
+    /// The volume is the distance raised to the power of minus the rolloff factor, which gives more control over the
+    /// curve than the inverse model.
+    Exponential,
+}
+
+/// A cone which an [`Emitter`] points its sound along. Listeners inside the inner cone hear it at full volume,
+/// listeners outside the outer cone hear it at `outer_gain`, and it fades between the two in between.
+///
+/// The angles are in degrees, and measure the whole width of each cone. The default is 360 degrees for both, which
+/// sounds the same from every direction.
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub struct Cone {
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+    pub outer_gain: f32,
+}
+
+/// A Source placed somewhere in the world around a [`Listener`].
+///
+/// The inner Source is treated as mono, so Sources with more than one channel are mixed down first. The output can
+/// have any [`ChannelLayout`]: the sound is panned between whichever pair of speakers it falls between, based on its
+/// direction from the Listener. Sounds above or below the Listener are spread out over all the speakers, and
+/// channels without a position on the horizontal plane, such as LFE, are left silent.
+/// For headphones, an Emitter can instead be rendered binaurally through an [`Hrtf`], and for scenes with lots of
+/// sounds it can be encoded into an ambisonic sound field, as described in [`AmbisonicBus`](super::AmbisonicBus).
+///
+/// The volume is set by the [`DistanceModel`] and [`Cone`], and the pitch follows the Doppler effect based on the
+/// velocities of the Emitter and Listener. Changes are ramped in over a short time, so moving sounds don't click.
+pub struct Emitter<S>
+where
+    S: Source,
+{
+    reader: MonoReader<S>,
+    params: Arc<EmitterParams>,
+    listener: Arc<ListenerParams>,
+    channels: ChannelCount,
+    layout: Option<ChannelLayout>,
+    output: Output,
+    gain: Smoothed,
+    pitch: Smoothed,
+    started: bool,
+    partial: PartialFrame,
+}
+
+enum Output {
+    Speakers(Speakers),
+    Ambisonic(Encoder),
+    Binaural(Binaural),
+}
+
+// Pans a mono signal between the speakers in a layout
+pub(super) struct Speakers {
+    speakers: Box<[(usize, f32)]>, // Channel index and azimuth in radians, sorted by azimuth
+    targets: Box<[f32]>,
+    gains: Box<[Smoothed]>,
+}
+
+/// Used for moving an [`Emitter`] and changing its parameters while it's playing. Get one with `Emitter::handle()`.
+#[derive(Clone)]
+pub struct EmitterHandle(Arc<EmitterParams>);
+
+struct EmitterParams {
+    position: AtomicVec3,
+    velocity: AtomicVec3,
+    direction: AtomicVec3,
+    model: AtomicU8,
+    min_distance: AtomicF32,
+    max_distance: AtomicF32,
+    rolloff: AtomicF32,
+    cone_inner: AtomicF32,
+    cone_outer: AtomicF32,
+    cone_outer_gain: AtomicF32,
+}
+
+// Where an Emitter is from the Listener's point of view, worked out once per block
+struct Placement {
+    direction: Vec3, // Unit vector in the Listener's space, or zero if they're in the same place
+    world: Vec3,     // The same, but lined up with the world's axes instead of the Listener's
+    gain: f32,
+    pitch: f32,
+}
+
+// Reads a mono mixdown of a Source at a variable speed, with cubic interpolation between its samples
+struct MonoReader<S>
+where
+    S: Source,
+{
+    source: S,
+    input: Vec<Sample>,
+    frames: usize,
+    position: usize,
+    history: [Sample; 4],
+    fraction: f32, // How far the output is between history[1] and history[2]
+    ended: bool,
+    flushed: usize, // How many samples of silence have been shifted in since the Source ended
+}
+
+impl<S> Emitter<S>
+where
+    S: Source,
+{
+    /// Creates a new Emitter at the origin, which outputs in the given `layout`, and is heard by `listener`.
+    ///
+    /// The Emitter starts out with no velocity, an inverse distance model with a minimum distance of 1.0 and no
+    /// maximum, and no cone.
+    pub fn new(source: S, listener: &Listener, layout: ChannelLayout) -> Self {
+        let output = Output::Speakers(Speakers::new(&layout));
+        Self::with_output(source, listener, layout.channel_count(), Some(layout), output)
+    }
+
+    /// Creates a new Emitter at the origin, which encodes its sound into an ambisonic sound field of the given
+    /// `order` for `listener`. Otherwise it starts out the same as with `new()`.
+    ///
+    /// The direction is encoded relative to the world's axes rather than the Listener's, so that the sound field can
+    /// be rotated to match the Listener just once, after everything's been mixed together on an
+    /// [`AmbisonicBus`](super::AmbisonicBus).
+    pub fn with_ambisonics(source: S, listener: &Listener, order: AmbisonicOrder) -> Self {
+        let output = Output::Ambisonic(Encoder::new(order));
+        Self::with_output(source, listener, order.channel_count(), None, output)
+    }
+
+    /// Creates a new Emitter at the origin, which is rendered binaurally through `hrtf` for `listener`.
+    /// The output is always stereo. Otherwise it starts out the same as with `new()`.
+    ///
+    /// Panics if `hrtf` was prepared for a different sample rate from the Source's.
+    pub fn with_hrtf(source: S, listener: &Listener, hrtf: &Hrtf) -> Self {
+        assert_eq!(hrtf.sample_rate(), source.sample_rate(), "HRTF is for the wrong sample rate");
+        let output = Output::Binaural(Binaural::new(hrtf));
+        Self::with_output(source, listener, CH_STEREO, Some(ChannelLayout::STEREO), output)
+    }
+
+    /// Returns a handle for moving this Emitter and changing its parameters while it's playing.
+    pub fn handle(&self) -> EmitterHandle {
+        EmitterHandle(self.params.clone())
+    }
+
+    fn with_output(
+        source: S,
+        listener: &Listener,
+        channels: ChannelCount,
+        layout: Option<ChannelLayout>,
+        output: Output,
+    ) -> Self {
+        Self {
+            reader: MonoReader::new(source),
+            params: Arc::new(EmitterParams::new()),
+            listener: listener.params(),
+            channels,
+            layout,
+            output,
+            gain: Smoothed::new(0.0),
+            pitch: Smoothed::new(1.0),
+            started: false,
+            partial: PartialFrame::new(channels.get().into()),
+        }
+    }
+
+    // Writes a whole number of frames to `buffer`
+    fn write_frames(&mut self, buffer: &mut [Sample]) -> usize {
+        let placement = self.params.place(&self.listener.frame());
+        let sample_rate = u32::from(self.reader.source.sample_rate()) as f32;
+        let steps = (SMOOTHING_TIME * sample_rate) as u32;
+        let settle = !self.started;
+        self.started = true;
+
+        self.gain.set_target(placement.gain, steps);
+        self.pitch.set_target(placement.pitch, steps);
+        if settle {
+            self.gain.settle();
+            self.pitch.settle();
+        }
+        match &mut self.output {
+            Output::Speakers(speakers) => speakers.update(placement.direction, steps, settle),
+            Output::Ambisonic(encoder) => encoder.update(placement.world, steps, settle),
+            Output::Binaural(binaural) => binaural.update(placement.direction, settle),
+        }
+
+        let channels = usize::from(self.channels.get());
+        for (i, frame) in buffer.chunks_exact_mut(channels).enumerate() {
+            let sample = match self.reader.next(self.pitch.next()) {
+                Some(sample) => sample * self.gain.next(),
+                None => return i * channels,
+            };
+            match &mut self.output {
+                Output::Speakers(speakers) => speakers.process(sample, frame),
+                Output::Ambisonic(encoder) => encoder.process(sample, frame),
+                Output::Binaural(binaural) => binaural.process(sample, frame),
+            }
+        }
+        buffer.len()
+    }
+}
+
+impl<S> Source for Emitter<S>
+where
+    S: Source,
+{
+    #[inline]
+    fn channel_count(&self) -> ChannelCount {
+        self.channels
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.reader.source.sample_rate()
+    }
+
+    #[inline]
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        self.layout.clone()
+    }
+
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        // A buffer can end partway through a frame, in which case the rest of it starts the next one
+        PartialFrame::write_with(self, buffer, |emitter| &mut emitter.partial, Self::write_frames)
+    }
+
+    fn reset(&mut self) {
+        self.reader.reset();
+        if let Output::Binaural(binaural) = &mut self.output {
+            binaural.reset();
+        }
+        self.started = false;
+        self.partial.reset();
+    }
+}
+
+impl Speakers {
+    pub(super) fn new(layout: &ChannelLayout) -> Self {
+        let mut speakers = layout
+            .channels()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &channel)| azimuth(channel).map(|degrees| (i, degrees.to_radians())))
+            .collect::<Box<[_]>>();
+        speakers.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        // Layouts with only front speakers are stretched out so the outermost ones are at the sides. Otherwise a sound
+        // right beside the Listener would still come out of both sides.
+        let widest = speakers.iter().fold(0.0f32, |widest, speaker| widest.max(speaker.1.abs()));
+        if widest > 0.0 && widest < FRAC_PI_2 {
+            speakers.iter_mut().for_each(|speaker| speaker.1 *= FRAC_PI_2 / widest);
+        }
+
+        let channels = layout.channels().len();
+        Self {
+            speakers,
+            targets: vec![0.0; channels].into_boxed_slice(),
+            gains: vec![Smoothed::new(0.0); channels].into_boxed_slice(),
+        }
+    }
+
+    // Starts ramping each channel towards its gain for a sound coming from `direction`
+    fn update(&mut self, direction: Vec3, steps: u32, settle: bool) {
+        let mut targets = std::mem::take(&mut self.targets);
+        self.pan(direction, &mut targets);
+        for (gain, &target) in self.gains.iter_mut().zip(targets.iter()) {
+            gain.set_target(target, steps);
+            if settle {
+                gain.settle();
+            }
+        }
+        self.targets = targets;
+    }
+
+    /// Sets `gains` to the gain of each channel for a sound coming from `direction`, in the Listener's space.
+    pub(super) fn pan(&self, direction: Vec3, gains: &mut [f32]) {
+        gains.iter_mut().for_each(|x| *x = 0.0);
+        if let (Some(&first), Some(&last)) = (self.speakers.first(), self.speakers.last()) {
+            // Power is shared between the nearest pair of speakers in proportion to how far round the sound is, and
+            // between all of them in proportion to how far above or below it is
+            let horizontal = direction.x.hypot(direction.z);
+            let spread = (1.0 - horizontal * horizontal) / self.speakers.len() as f32;
+            let azimuth = direction.x.atan2(-direction.z);
+            let (left, right) = self
+                .speakers
+                .windows(2)
+                .map(|pair| (pair[0], pair[1]))
+                .find(|(left, right)| azimuth >= left.1 && azimuth < right.1)
+                .unwrap_or((last, (first.0, first.1 + TAU)));
+            let width = (right.1 - left.1).rem_euclid(TAU);
+            let position = if width > 0.0 { (azimuth - left.1).rem_euclid(TAU) / width } else { 0.0 };
+            let (sin, cos) = (position.clamp(0.0, 1.0) * FRAC_PI_2).sin_cos();
+
+            for &(channel, _) in self.speakers.iter() {
+                gains[channel] = spread;
+            }
+            gains[left.0] += cos * cos * horizontal * horizontal;
+            gains[right.0] += sin * sin * horizontal * horizontal;
+            gains.iter_mut().for_each(|x| *x = x.sqrt());
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, sample: Sample, frame: &mut [Sample]) {
+        for (x, gain) in frame.iter_mut().zip(self.gains.iter_mut()) {
+            *x = sample * gain.next();
+        }
+    }
+}
+
+impl DistanceModel {
+    #[inline]
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Inverse,
+            1 => Self::Linear,
+            _ => Self::Exponential,
+        }
+    }
+
+    #[inline]
+    fn gain(self, distance: f32, min_distance: f32, max_distance: f32, rolloff: f32) -> f32 {
+        let min_distance = min_distance.max(f32::EPSILON);
+        let max_distance = max_distance.max(min_distance);
+        let distance = distance.clamp(min_distance, max_distance);
+        match self {
+            Self::Inverse => min_distance / (min_distance + rolloff * (distance - min_distance)),
+            Self::Linear if max_distance > min_distance => {
+                (1.0 - rolloff * (distance - min_distance) / (max_distance - min_distance)).clamp(0.0, 1.0)
+            },
+            Self::Linear => 1.0,
+            Self::Exponential => (distance / min_distance).powf(-rolloff),
+        }
+    }
+}
+
+impl Cone {
+    /// A cone which sounds the same from every direction.
+    pub const OMNIDIRECTIONAL: Self = Self { inner_angle: 360.0, outer_angle: 360.0, outer_gain: 0.0 };
+
+    // Returns the gain for a Listener which is `angle` degrees away from where the cone is pointing
+    #[inline]
+    fn gain(self, angle: f32) -> f32 {
+        let (inner, outer) = (self.inner_angle * 0.5, (self.outer_angle * 0.5).max(self.inner_angle * 0.5));
+        if angle <= inner {
+            1.0
+        } else if angle >= outer {
+            self.outer_gain
+        } else {
+            1.0 + (self.outer_gain - 1.0) * (angle - inner) / (outer - inner)
+        }
+    }
+}
+
+impl Default for Cone {
+    fn default() -> Self {
+        Self::OMNIDIRECTIONAL
+    }
+}
+
+impl EmitterParams {
+    fn new() -> Self {
+        Self {
+            position: AtomicVec3::new(Vec3::ZERO),
+            velocity: AtomicVec3::new(Vec3::ZERO),
+            direction: AtomicVec3::new(Vec3::ZERO),
+            model: AtomicU8::new(DistanceModel::Inverse as u8),
+            min_distance: AtomicF32::new(1.0),
+            max_distance: AtomicF32::new(f32::MAX),
+            rolloff: AtomicF32::new(1.0),
+            cone_inner: AtomicF32::new(Cone::OMNIDIRECTIONAL.inner_angle),
+            cone_outer: AtomicF32::new(Cone::OMNIDIRECTIONAL.outer_angle),
+            cone_outer_gain: AtomicF32::new(Cone::OMNIDIRECTIONAL.outer_gain),
+        }
+    }
+
+    fn place(&self, listener: &ListenerFrame) -> Placement {
+        let position = self.position.load();
+        let to_listener = listener.position - position;
+        let distance = to_listener.length();
+        let towards = to_listener.normalize();
+
+        let model = DistanceModel::from_u8(self.model.load(Ordering::Acquire));
+        let mut gain =
+            model.gain(distance, self.min_distance.load(), self.max_distance.load(), self.rolloff.load().max(0.0));
+        let direction = self.direction.load().normalize();
+        if direction != Vec3::ZERO && distance > 0.0 {
+            let cone = Cone {
+                inner_angle: self.cone_inner.load(),
+                outer_angle: self.cone_outer.load(),
+                outer_gain: self.cone_outer_gain.load(),
+            };
+            gain *= cone.gain(direction.dot(towards).clamp(-1.0, 1.0).acos().to_degrees());
+        }
+
+        // The Doppler shift depends on how fast each of them is moving along the line between them, as in OpenAL.
+        // Speeds are capped at the speed of sound, past which the formula stops making sense.
+        let (speed_of_sound, factor) = (listener.speed_of_sound, listener.doppler_factor);
+        let pitch = if factor > 0.0 && speed_of_sound > 0.0 && distance > 0.0 {
+            let limit = speed_of_sound / factor;
+            let listener_speed = listener.velocity.dot(towards).min(limit);
+            let emitter_speed = self.velocity.load().dot(towards).min(limit);
+            let pitch = (speed_of_sound - factor * listener_speed) / (speed_of_sound - factor * emitter_speed);
+            if pitch.is_finite() { pitch.clamp(1.0 / MAX_PITCH, MAX_PITCH) } else { MAX_PITCH }
+        } else {
+            1.0
+        };
+
+        Placement { direction: listener.to_local(-towards), world: -towards, gain, pitch }
+    }
+}
+
+impl EmitterHandle {
+    /// Sets the position of the Emitter.
+    pub fn set_position(&self, position: Vec3) {
+        self.0.position.store(position)
+    }
+
+    /// Sets the velocity of the Emitter, in distance units per second. This is only used for the Doppler effect, and
+    /// doesn't move the Emitter by itself.
+    pub fn set_velocity(&self, velocity: Vec3) {
+        self.0.velocity.store(velocity)
+    }
+
+    /// Sets which way the Emitter's [`Cone`] points. It doesn't need to be normalized.
+    /// [`Vec3::ZERO`] makes the Emitter sound the same from every direction, whatever its cone.
+    pub fn set_direction(&self, direction: Vec3) {
+        self.0.direction.store(direction)
+    }
+
+    /// Sets the cone the Emitter's sound is pointed along.
+    pub fn set_cone(&self, cone: Cone) {
+        self.0.cone_inner.store(cone.inner_angle);
+        self.0.cone_outer.store(cone.outer_angle);
+        self.0.cone_outer_gain.store(cone.outer_gain);
+    }
+
+    /// Sets how the volume changes with distance.
+    pub fn set_distance_model(&self, model: DistanceModel) {
+        self.0.model.store(model as u8, Ordering::Release)
+    }
+
+    /// Sets the distance below which the Emitter is heard at full volume.
+    pub fn set_min_distance(&self, distance: f32) {
+        self.0.min_distance.store(distance)
+    }
+
+    /// Sets the distance past which the Emitter stops getting any quieter.
+    pub fn set_max_distance(&self, distance: f32) {
+        self.0.max_distance.store(distance)
+    }
+
+    /// Sets how quickly the volume drops off with distance, where 1.0 is the default and 0.0 turns attenuation off.
+    pub fn set_rolloff(&self, rolloff: f32) {
+        self.0.rolloff.store(rolloff)
+    }
+
+    /// Returns the most recently set position.
+    pub fn position(&self) -> Vec3 {
+        self.0.position.load()
+    }
+
+    /// Returns the most recently set velocity.
+    pub fn velocity(&self) -> Vec3 {
+        self.0.velocity.load()
+    }
+
+    /// Returns the most recently set direction.
+    pub fn direction(&self) -> Vec3 {
+        self.0.direction.load()
+    }
+
+    /// Returns the most recently set cone.
+    pub fn cone(&self) -> Cone {
+        Cone {
+            inner_angle: self.0.cone_inner.load(),
+            outer_angle: self.0.cone_outer.load(),
+            outer_gain: self.0.cone_outer_gain.load(),
+        }
+    }
+
+    /// Returns the most recently set distance model.
+    pub fn distance_model(&self) -> DistanceModel {
+        DistanceModel::from_u8(self.0.model.load(Ordering::Acquire))
+    }
+
+    /// Returns the most recently set minimum distance.
+    pub fn min_distance(&self) -> f32 {
+        self.0.min_distance.load()
+    }
+
+    /// Returns the most recently set maximum distance.
+    pub fn max_distance(&self) -> f32 {
+        self.0.max_distance.load()
+    }
+
+    /// Returns the most recently set rolloff factor.
+    pub fn rolloff(&self) -> f32 {
+        self.0.rolloff.load()
+    }
+}
+
+impl<S> MonoReader<S>
+where
+    S: Source,
+{
+    fn new(source: S) -> Self {
+        let channels = usize::from(source.channel_count().get());
+        Self {
+            source,
+            input: vec![0.0; CHUNK_FRAMES * channels],
+            frames: 0,
+            position: 0,
+            history: [0.0; 4],
+            fraction: 3.0, // Fills the history so the first sample comes out first, rather than after a delay
+            ended: false,
+            flushed: 0,
+        }
+    }
+
+    /// Returns the next sample, then moves forward by `step` samples of the inner Source.
+    /// Returns `None` once the inner Source has ended and everything before that has been played.
+    #[inline]
+    fn next(&mut self, step: f32) -> Option<Sample> {
+        while self.fraction >= 1.0 {
+            let sample = self.read()?;
+            self.fraction -= 1.0;
+            self.history = [self.history[1], self.history[2], self.history[3], sample];
+        }
+
+        // Catmull-Rom spline through the four samples around the current position
+        let [a, b, c, d] = self.history;
+        let t = self.fraction;
+        let sample = b + 0.5 * t * (c - a + t * (2.0 * a - 5.0 * b + 4.0 * c - d + t * (3.0 * (b - c) + d - a)));
+        self.fraction += step;
+        Some(sample)
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.frames = 0;
+        self.position = 0;
+        self.history = [0.0; 4];
+        self.fraction = 3.0;
+        self.ended = false;
+        self.flushed = 0;
+    }
+
+    // Returns the next sample of the mixdown, then silence once the Source has ended, until the last real sample has
+    // moved out of the middle of the history
+    #[inline]
+    fn read(&mut self) -> Option<Sample> {
+        if self.position == self.frames {
+            if self.ended {
+                self.flushed += 1;
+                return if self.flushed < 3 { Some(0.0) } else { None };
+            }
+            let channels = usize::from(self.source.channel_count().get());
+            let count = self.source.write_samples(&mut self.input);
+            self.ended = count < self.input.len();
+            self.frames = count / channels;
+            self.position = 0;
+            if channels > 1 {
+                let scale = 1.0 / channels as f32;
+                for i in 0..self.frames {
+                    self.input[i] = self.input[i * channels..(i + 1) * channels].iter().sum::<Sample>() * scale;
+                }
+            }
+            return self.read();
+        }
+        self.position += 1;
+        Some(self.input[self.position - 1])
+    }
+}
+
+// Returns the direction of a speaker in degrees clockwise from straight ahead, if it's on the horizontal plane
+fn azimuth(channel: ChannelId) -> Option<f32> {
+    Some(match channel {
+        ChannelId::FrontCenter => 0.0,
+        ChannelId::FrontLeftCenter => -15.0,
+        ChannelId::FrontRightCenter => 15.0,
+        ChannelId::FrontLeft => -30.0,
+        ChannelId::FrontRight => 30.0,
+        ChannelId::FrontLeftWide => -60.0,
+        ChannelId::FrontRightWide => 60.0,
+        ChannelId::SideLeft | ChannelId::HeadphonesLeft => -90.0,
+        ChannelId::SideRight | ChannelId::HeadphonesRight => 90.0,
+        ChannelId::BackLeft => -150.0,
+        ChannelId::BackRight => 150.0,
+        ChannelId::BackLeftCenter => -165.0,
+        ChannelId::BackRightCenter => 165.0,
+        ChannelId::BackCenter => 180.0,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Player;
+
+    fn player(frames: usize) -> Player {
+        Player::new(ChannelCount::new(1).unwrap(), SampleRate::new(44100).unwrap(), vec![0.5; frames].into())
+    }
+
+    #[test]
+    fn distance_models() {
+        let cases = [
+            (DistanceModel::Inverse, 4.0, 0.25),
+            (DistanceModel::Inverse, 0.5, 1.0),
+            (DistanceModel::Inverse, 100.0, 0.1),
+            (DistanceModel::Linear, 5.5, 0.5),
+            (DistanceModel::Linear, 20.0, 0.0),
+            (DistanceModel::Exponential, 2.0, 0.5),
+        ];
+        for &(model, distance, gain) in &cases {
+            let result = model.gain(distance, 1.0, 10.0, 1.0);
+            assert!((result - gain).abs() < 1e-6, "{:?} at {} gave {}, not {}", model, distance, result, gain);
+        }
+    }
+
+    #[test]
+    fn cone_fades_between_angles() {
+        let cone = Cone { inner_angle: 90.0, outer_angle: 180.0, outer_gain: 0.2 };
+        assert_eq!(cone.gain(0.0), 1.0);
+        assert_eq!(cone.gain(45.0), 1.0);
+        assert!((cone.gain(67.5) - 0.6).abs() < 1e-6);
+        assert_eq!(cone.gain(120.0), 0.2);
+    }
+
+    #[test]
+    fn pans_towards_the_emitter() {
+        let listener = Listener::new();
+        let mut emitter = Emitter::new(player(64), &listener, ChannelLayout::STEREO);
+        emitter.handle().set_position(Vec3::new(1.0, 0.0, 0.0));
+        let mut buffer = [0.0; 32];
+        assert_eq!(emitter.write_samples(&mut buffer), 32);
+        let (left, right) = (buffer[30], buffer[31]);
+        assert!(right > 0.4 && left.abs() < 1e-3, "panned to {} {}", left, right);
+    }
+
+    #[test]
+    fn turning_the_listener_flips_the_panning() {
+        let listener = Listener::new();
+        let mut emitter = Emitter::new(player(8820), &listener, ChannelLayout::STEREO);
+        emitter.handle().set_position(Vec3::new(1.0, 0.0, 0.0));
+        let mut buffer = [0.0; 2000];
+        assert_eq!(emitter.write_samples(&mut buffer), 2000);
+        assert!(buffer[1998].abs() < 1e-3 && buffer[1999] > 0.4, "panned to {:?}", &buffer[1998..]);
+
+        // Facing the other way puts the Emitter on the left, after the gains have had time to ramp over
+        listener.set_orientation(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(emitter.write_samples(&mut buffer), 2000);
+        assert!(buffer[1998] > 0.4 && buffer[1999].abs() < 1e-3, "panned to {:?}", &buffer[1998..]);
+
+        // Looking straight at it puts it in the middle
+        listener.set_orientation(Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(emitter.write_samples(&mut buffer), 2000);
+        assert!((buffer[1998] - buffer[1999]).abs() < 1e-3 && buffer[1998] > 0.3, "panned to {:?}", &buffer[1998..]);
+    }
+
+    #[test]
+    fn doppler_shifts_the_pitch() {
+        let listener = Listener::new();
+        let c = listener.speed_of_sound();
+        let params = EmitterParams::new();
+        params.position.store(Vec3::new(10.0, 0.0, 0.0));
+        let pitch = |params: &EmitterParams| params.place(&listener.params().frame()).pitch;
+        assert_eq!(pitch(&params), 1.0);
+
+        // Moving towards each other raises the pitch, and moving apart lowers it, by the ratio of the speeds
+        let cases = [
+            (Vec3::new(-0.1 * c, 0.0, 0.0), Vec3::ZERO, 1.0 / 0.9),
+            (Vec3::new(0.1 * c, 0.0, 0.0), Vec3::ZERO, 1.0 / 1.1),
+            (Vec3::ZERO, Vec3::new(0.1 * c, 0.0, 0.0), 1.1),
+            (Vec3::ZERO, Vec3::new(-0.1 * c, 0.0, 0.0), 0.9),
+            (Vec3::new(0.0, 0.0, c), Vec3::new(0.0, c, 0.0), 1.0), // Moving sideways doesn't count
+        ];
+        for &(emitter_velocity, listener_velocity, expected) in &cases {
+            params.velocity.store(emitter_velocity);
+            listener.set_velocity(listener_velocity);
+            let result = pitch(&params);
+            assert!((result - expected).abs() < 1e-4, "{:?} {:?} gave {}", emitter_velocity, listener_velocity, result);
+        }
+
+        // It's clamped to two octaves either way, including at and past the speed of sound
+        listener.set_velocity(Vec3::ZERO);
+        for &(speed, expected) in &[(-0.9, MAX_PITCH), (-1.0, MAX_PITCH), (-2.0, MAX_PITCH), (10.0, 1.0 / MAX_PITCH)] {
+            params.velocity.store(Vec3::new(speed * c, 0.0, 0.0));
+            assert_eq!(pitch(&params), expected, "at {} times the speed of sound", speed);
+        }
+        params.velocity.store(Vec3::ZERO);
+        listener.set_velocity(Vec3::new(-c, 0.0, 0.0));
+        assert_eq!(pitch(&params), 1.0 / MAX_PITCH);
+
+        // Turning the Doppler effect off leaves the pitch alone
+        listener.set_doppler_factor(0.0);
+        assert_eq!(pitch(&params), 1.0);
+    }
+
+    #[test]
+    fn doppler_changes_the_playback_rate() {
+        // An Emitter approaching at a third of the speed of sound plays 1.5 times as fast, so it ends sooner
+        let listener = Listener::new();
+        let c = listener.speed_of_sound();
+        let mut emitter = Emitter::new(player(4500), &listener, ChannelLayout::MONO);
+        emitter.handle().set_position(Vec3::new(10.0, 0.0, 0.0));
+        emitter.handle().set_velocity(Vec3::new(-c / 3.0, 0.0, 0.0));
+        let mut buffer = vec![0.0; 10000];
+        let count = emitter.write_samples(&mut buffer);
+        assert!((2998..=3002).contains(&count), "played {} frames", count);
+    }
+
+    #[test]
+    fn odd_buffers_keep_playing() {
+        // Buffers which end partway through a frame carry on where the last one left off
+        let listener = Listener::new();
+        let samples = (0..100).map(|x| x as Sample / 100.0).collect::<Box<[_]>>();
+        let new = || {
+            let player = Player::new(ChannelCount::new(1).unwrap(), SampleRate::new(44100).unwrap(), samples.clone());
+            let emitter = Emitter::new(player, &listener, ChannelLayout::STEREO);
+            emitter.handle().set_position(Vec3::new(1.0, 0.0, -1.0));
+            emitter
+        };
+        let mut expected = vec![0.0; 300];
+        let length = new().write_samples(&mut expected);
+        expected.truncate(length);
+
+        let mut emitter = new();
+        let mut buffer = [-1.0; 5];
+        let mut output = Vec::new();
+        loop {
+            let count = emitter.write_samples(&mut buffer);
+            output.extend_from_slice(&buffer[..count]);
+            if count < buffer.len() {
+                break
+            }
+        }
+        assert_eq!(output, expected);
+        assert_eq!(emitter.write_samples(&mut buffer), 0, "should stay ended");
+    }
+}
@@ -0,0 +1,548 @@
+use super::{Error, Vec3};
+use crate::{
+    effects::{read_all, DelayLine},
+    param::Smoothed,
+    resampler::Resampler,
+    simd,
+    source::{consts::CH_MONO, Sample, SampleRate},
+    Player,
+};
+use std::{f32::consts::FRAC_PI_2, sync::Arc};
+
+// How long it takes to crossfade to the filters for a new direction, in seconds
+const CROSSFADE_TIME: f32 = 0.02;
+
+// Directions closer together than this (the cosine of about one degree) are treated as the same
+const SAME_DIRECTION: f32 = 0.99985;
+
+// An impulse response starts where it first reaches this fraction of its peak
+const ONSET_THRESHOLD: f32 = 0.1;
+
+// How much silence is added to the end of each impulse response before resampling, in frames
+const RESAMPLER_PADDING: usize = 1024;
+
+// The spherical head model's radius in metres, and the speed of sound it uses in metres per second
+const HEAD_RADIUS: f32 = 0.0875;
+const SPEED_OF_SOUND: f32 = 343.0;
+
+/// A set of head-related impulse responses (HRIRs), which describe how sound coming from each direction reaches the
+/// left and right ears. An [`Emitter`](super::Emitter) uses one to render binaural audio for headphones.
+///
+/// The timing difference between the ears is separated out of each impulse response when it's loaded, so that
+/// impulse responses for nearby directions can be blended without the two ears' delays smearing together.
+///
+/// Cloning an Hrtf is cheap, as the data is reference-counted.
+#[derive(Clone)]
+pub struct Hrtf(Arc<HrtfData>);
+
+/// The impulse responses for both ears, for a sound coming from one direction.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde-derives", derive(serde::Serialize, serde::Deserialize))]
+pub struct Hrir {
+    /// Degrees clockwise from straight ahead, when seen from above.
+    pub azimuth: f32,
+
+    /// Degrees above the horizontal plane.
+    pub elevation: f32,
+
+    pub left: Vec<Sample>,
+    pub right: Vec<Sample>,
+}
+
+struct HrtfData {
+    sample_rate: SampleRate,
+    length: usize,
+    points: Box<[Point]>,
+}
+
+struct Point {
+    direction: Vec3,
+    delays: [f32; 2],         // Left and right, in frames
+    filters: [Box<[f32]>; 2], // Left and right with their delays taken out, in oldest-first order for simd::dot
+}
+
+// Renders a mono signal through an Hrtf, blending between its directions
+pub(super) struct Binaural {
+    hrtf: Hrtf,
+    input: Box<[Sample]>, // The most recent input, stored twice over so it can always be read as one slice
+    position: usize,
+    direction: Vec3,
+    filters: [Box<[f32]>; 2],
+    previous: [Box<[f32]>; 2],
+    fade: usize, // How many frames are left of the crossfade from `previous` to `filters`
+    fade_frames: usize,
+    delays: [Smoothed; 2],
+    lines: [DelayLine; 2],
+    nearest: Vec<(usize, f32)>,
+}
+
+impl Hrtf {
+    /// Creates an Hrtf from a list of measured directions, resampling them from `hrir_rate` to `sample_rate` if needed.
+    /// Impulse responses of different lengths are padded with silence to match the longest one.
+    ///
+    /// Panics if `hrirs` is empty.
+    pub fn new(hrirs: impl IntoIterator<Item = Hrir>, hrir_rate: SampleRate, sample_rate: SampleRate) -> Self {
+        let mut responses = Vec::new();
+        let mut directions = Vec::new();
+        for hrir in hrirs {
+            directions.push(direction(hrir.azimuth, hrir.elevation));
+            responses.push(if hrir_rate == sample_rate {
+                [hrir.left, hrir.right]
+            } else {
+                [resample(hrir.left, hrir_rate, sample_rate), resample(hrir.right, hrir_rate, sample_rate)]
+            });
+        }
+        assert!(!responses.is_empty(), "an HRTF needs at least one direction");
+
+        // Everything before the earliest onset is the same for all of them, so only the rest is counted as delay
+        let onsets = responses.iter().map(|ears| [onset(&ears[0]), onset(&ears[1])]).collect::<Vec<_>>();
+        let lead = onsets.iter().flatten().copied().min().unwrap_or(0);
+        let points = directions
+            .into_iter()
+            .zip(responses)
+            .zip(onsets)
+            .map(|((direction, ears), onsets)| {
+                let filters = [0, 1].map(|ear| ears[ear][onsets[ear] - lead..].to_vec());
+                Point { direction, delays: onsets.map(|onset| (onset - lead) as f32), filters: filters.map(Vec::into) }
+            })
+            .collect();
+        Self::from_points(sample_rate, points)
+    }
+
+    /// Parses an Hrtf from the contents of an HRIR file, resampling it to `sample_rate` if needed.
+    ///
+    /// The format is a simple one, with every value stored little-endian:
+    ///
+    /// | Type             | Contents                                                    |
+    /// |------------------|-------------------------------------------------------------|
+    /// | 4 bytes          | The ASCII characters `HRIR`                                 |
+    /// | u32              | The format version, which is 1                              |
+    /// | u32              | The sample rate in Hz                                       |
+    /// | u32              | The number of directions                                    |
+    /// | u32              | The length of each impulse response, in samples             |
+    ///
+    /// Then for each direction:
+    ///
+    /// | Type             | Contents                                                    |
+    /// |------------------|-------------------------------------------------------------|
+    /// | f32              | The azimuth, in degrees clockwise from straight ahead       |
+    /// | f32              | The elevation, in degrees above the horizontal plane        |
+    /// | f32 × length     | The impulse response for the left ear                       |
+    /// | f32 × length     | The impulse response for the right ear                      |
+    ///
+    /// Most SOFA files can be converted to this by reading out the impulse responses and source positions. Note that
+    /// SOFA measures azimuth anticlockwise, so it needs negating.
+    pub fn from_bytes(data: &[u8], sample_rate: SampleRate) -> Result<Self, Error> {
+        if data.get(0..4) != Some(b"HRIR") {
+            return Err(Error::InvalidFile)
+        }
+        let mut values = data[4..].chunks_exact(4).map(|x| [x[0], x[1], x[2], x[3]]);
+        let mut header = || values.next().map(u32::from_le_bytes).ok_or(Error::InvalidFile);
+        if header()? != 1 {
+            return Err(Error::UnknownVersion)
+        }
+        let hrir_rate = SampleRate::new(header()?).ok_or(Error::MalformedData)?;
+        let count = header()? as usize;
+        let length = header()? as usize;
+        if count == 0 || length == 0 || (data.len() - 20) / 4 / (2 + length * 2) < count {
+            return Err(Error::MalformedData)
+        }
+
+        let mut values = values.map(f32::from_le_bytes);
+        let hrirs = (0..count)
+            .map(|_| Hrir {
+                azimuth: values.next().unwrap_or_default(),
+                elevation: values.next().unwrap_or_default(),
+                left: values.by_ref().take(length).collect(),
+                right: values.by_ref().take(length).collect(),
+            })
+            .collect::<Vec<_>>();
+        Ok(Self::new(hrirs, hrir_rate, sample_rate))
+    }
+
+    /// Creates the built-in default Hrtf, which models the listener's head as a sphere.
+    ///
+    /// The model only gives the timing and level differences between the ears, and the way the head shadows high
+    /// frequencies from the far ear. It has none of the cues that real ears give about elevation and front and back,
+    /// so a measured set should be loaded for anything more than testing.
+    pub fn spherical_head(sample_rate: SampleRate) -> Self {
+        let rate = u32::from(sample_rate) as f32;
+        // At least two taps, for the one-zero part of the filter
+        let length = ((rate * 0.003).ceil() as usize).max(2);
+
+        // The head shadow is a one-pole, one-zero filter from Brown and Duda's structural model, through the
+        // bilinear transform
+        let k = rate * HEAD_RADIUS / SPEED_OF_SOUND;
+        let ear = |incidence: f32| -> (f32, Box<[f32]>) {
+            let alpha = 1.05 + 0.95 * (incidence * 1.2).cos();
+            let (b0, b1, a1) = ((1.0 + alpha * k) / (1.0 + k), (1.0 - alpha * k) / (1.0 + k), (1.0 - k) / (1.0 + k));
+            let mut filter = vec![0.0; length];
+            filter[0] = b0;
+            filter[1] = b1 - a1 * b0;
+            for i in 2..length {
+                filter[i] = -a1 * filter[i - 1];
+            }
+
+            // Woodworth's formula for the extra distance around the head, relative to the nearest point
+            let distance = if incidence < FRAC_PI_2 { 1.0 - incidence.cos() } else { 1.0 + incidence - FRAC_PI_2 };
+            (distance * k, filter.into())
+        };
+
+        let mut points = Vec::new();
+        for elevation in [-40.0f32, -20.0, 0.0, 20.0, 40.0, 60.0, 90.0] {
+            let azimuths = if elevation == 90.0 { 1 } else { 12 };
+            for i in 0..azimuths {
+                let direction = direction(i as f32 * 360.0 / azimuths as f32, elevation);
+                let (left_delay, left) = ear((-direction.x).clamp(-1.0, 1.0).acos());
+                let (right_delay, right) = ear(direction.x.clamp(-1.0, 1.0).acos());
+                points.push(Point { direction, delays: [left_delay, right_delay], filters: [left, right] });
+            }
+        }
+        Self::from_points(sample_rate, points)
+    }
+
+    /// Returns the sample rate this Hrtf was prepared for.
+    pub fn sample_rate(&self) -> SampleRate {
+        self.0.sample_rate
+    }
+
+    /// Returns how many directions this Hrtf has impulse responses for.
+    pub fn directions(&self) -> usize {
+        self.0.points.len()
+    }
+
+    /// Returns the length of each impulse response in frames, with the delays taken out.
+    pub fn length(&self) -> usize {
+        self.0.length
+    }
+
+    // Pads the filters to the same length, reverses them, and normalises them so that the average power over every
+    // direction matches that of a sound panned between two speakers
+    fn from_points(sample_rate: SampleRate, mut points: Vec<Point>) -> Self {
+        let filters = || points.iter().flat_map(|point| point.filters.iter());
+        let length = filters().map(|x| x.len()).max().unwrap_or(0).max(1);
+        let energy = filters().flat_map(|x| x.iter()).map(|x| x * x).sum::<f32>();
+        let scale = if energy > 0.0 { (points.len() as f32 / energy).sqrt() } else { 1.0 };
+        for point in points.iter_mut() {
+            for filter in point.filters.iter_mut() {
+                let mut padded = vec![0.0; length];
+                padded[..filter.len()].copy_from_slice(filter);
+                padded.iter_mut().for_each(|x| *x *= scale);
+                padded.reverse();
+                *filter = padded.into();
+            }
+        }
+        Self(Arc::new(HrtfData { sample_rate, length, points: points.into() }))
+    }
+
+    // Blends the filters and delays of the nearest directions into `filters`, returning the delays
+    fn blend(&self, direction: Vec3, nearest: &mut Vec<(usize, f32)>, filters: &mut [Box<[f32]>; 2]) -> [f32; 2] {
+        // The three nearest directions are weighted by how much closer they are than the fourth nearest, so each one
+        // fades out smoothly as the direction moves away from it
+        nearest.clear();
+        let angles = self.0.points.iter().map(|point| point.direction.dot(direction).clamp(-1.0, 1.0).acos());
+        nearest.extend(angles.enumerate());
+        let count = nearest.len().min(4);
+        nearest.select_nth_unstable_by(count - 1, |a, b| a.1.total_cmp(&b.1));
+        nearest.truncate(count);
+        nearest.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
+        let furthest = nearest[count - 1].1;
+        if count == 4 {
+            nearest.truncate(3);
+        }
+        let total = nearest.iter().map(|x| furthest - x.1).sum::<f32>();
+        for x in nearest.iter_mut() {
+            x.1 = if total > 0.0 { (furthest - x.1) / total } else { 1.0 / count as f32 };
+        }
+
+        let mut delays = [0.0; 2];
+        for (ear, filter) in filters.iter_mut().enumerate() {
+            filter.iter_mut().for_each(|x| *x = 0.0);
+            for &(i, weight) in nearest.iter() {
+                let point = &self.0.points[i];
+                delays[ear] += point.delays[ear] * weight;
+                filter.iter_mut().zip(point.filters[ear].iter()).for_each(|(x, y)| *x += y * weight);
+            }
+        }
+        delays
+    }
+
+    /// Writes the whole impulse response of each ear for `direction` into `responses`, with the delays put back in.
+    /// Each one should be `impulse_length()` long.
+    pub(super) fn impulses(&self, direction: Vec3, responses: &mut [Box<[f32]>; 2]) {
+        let mut filters = [0, 1].map(|_| vec![0.0; self.0.length].into_boxed_slice());
+        let delays = self.blend(direction, &mut Vec::with_capacity(self.directions()), &mut filters);
+        for ((response, filter), delay) in responses.iter_mut().zip(filters.iter()).zip(delays) {
+            response.iter_mut().for_each(|x| *x = 0.0);
+            let (whole, fraction) = (delay as usize, delay.fract());
+            for (i, &x) in filter.iter().rev().enumerate() {
+                response[whole + i] += x * (1.0 - fraction);
+                response[whole + i + 1] += x * fraction;
+            }
+        }
+    }
+
+    /// Returns the length of each impulse response with the longest delay put back in.
+    pub(super) fn impulse_length(&self) -> usize {
+        self.0.length + self.max_delay().ceil() as usize + 1
+    }
+
+    fn max_delay(&self) -> f32 {
+        self.0.points.iter().flat_map(|point| point.delays).fold(0.0, f32::max)
+    }
+}
+
+impl Binaural {
+    pub(super) fn new(hrtf: &Hrtf) -> Self {
+        let length = hrtf.length();
+        let max_delay = hrtf.max_delay().ceil() as usize + 1;
+        let sample_rate = u32::from(hrtf.sample_rate()) as f32;
+        Self {
+            hrtf: hrtf.clone(),
+            input: vec![0.0; length * 2].into(),
+            position: 0,
+            direction: Vec3::ZERO,
+            filters: [0, 1].map(|_| vec![0.0; length].into()),
+            previous: [0, 1].map(|_| vec![0.0; length].into()),
+            fade: 0,
+            fade_frames: ((CROSSFADE_TIME * sample_rate) as usize).max(1),
+            delays: [Smoothed::new(0.0), Smoothed::new(0.0)],
+            lines: [DelayLine::new(max_delay), DelayLine::new(max_delay)],
+            nearest: Vec::with_capacity(hrtf.directions()),
+        }
+    }
+
+    /// Starts moving towards the filters for `direction`, which is treated as straight ahead if it's zero.
+    /// If a crossfade is still going, the new direction is picked up once it's finished.
+    /// With `settle`, jumps straight there instead.
+    pub(super) fn update(&mut self, direction: Vec3, settle: bool) {
+        let direction = if direction == Vec3::ZERO { Vec3::new(0.0, 0.0, -1.0) } else { direction };
+        if settle || (self.fade == 0 && direction.dot(self.direction) < SAME_DIRECTION) {
+            std::mem::swap(&mut self.filters, &mut self.previous);
+            let delays = self.hrtf.blend(direction, &mut self.nearest, &mut self.filters);
+            for (smoothed, delay) in self.delays.iter_mut().zip(delays) {
+                smoothed.set_target(delay, self.fade_frames as u32);
+            }
+            self.direction = direction;
+            self.fade = self.fade_frames;
+            if settle {
+                self.delays.iter_mut().for_each(Smoothed::settle);
+                self.fade = 0;
+            }
+        }
+    }
+
+    #[inline]
+    pub(super) fn process(&mut self, sample: Sample, frame: &mut [Sample]) {
+        let length = self.input.len() / 2;
+        self.input[self.position] = sample;
+        self.input[self.position + length] = sample;
+        self.position = (self.position + 1) % length;
+        let window = &self.input[self.position..self.position + length];
+
+        let fade = self.fade as f32 / self.fade_frames as f32;
+        self.fade = self.fade.saturating_sub(1);
+        for (ear, output) in frame.iter_mut().enumerate() {
+            let mut wet = simd::dot(window, &self.filters[ear], 1);
+            if fade > 0.0 {
+                wet += (simd::dot(window, &self.previous[ear], 1) - wet) * fade;
+            }
+            self.lines[ear].write(wet);
+            *output = self.lines[ear].read(self.delays[ear].next() + 1.0);
+        }
+    }
+
+    pub(super) fn reset(&mut self) {
+        self.input.iter_mut().for_each(|x| *x = 0.0);
+        self.lines.iter_mut().for_each(DelayLine::clear);
+        self.fade = 0;
+    }
+}
+
+// Converts an azimuth and elevation in degrees into a unit vector in the Listener's space
+fn direction(azimuth: f32, elevation: f32) -> Vec3 {
+    let (azimuth, elevation) = (azimuth.to_radians(), elevation.to_radians());
+    Vec3::new(azimuth.sin() * elevation.cos(), elevation.sin(), -azimuth.cos() * elevation.cos())
+}
+
+fn onset(response: &[Sample]) -> usize {
+    let peak = response.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+    response.iter().position(|x| x.abs() >= peak * ONSET_THRESHOLD && peak > 0.0).unwrap_or(0)
+}
+
+// The Resampler cuts off the last few frames of what it's given, so the impulse response is padded with silence first
+fn resample(mut response: Vec<Sample>, from: SampleRate, to: SampleRate) -> Vec<Sample> {
+    let length = (response.len() as u64 * u64::from(u32::from(to))).div_ceil(u64::from(u32::from(from))) as usize;
+    response.resize(response.len() + RESAMPLER_PADDING, 0.0);
+    let mut response = read_all(Resampler::new(Player::new(CH_MONO, from, response.into()), to));
+    response.truncate(length);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        source::{ChannelCount, Source},
+        spatial::{Emitter, Listener},
+    };
+    use std::f32::consts::PI;
+
+    fn rate(hz: u32) -> SampleRate {
+        SampleRate::new(hz).unwrap()
+    }
+
+    fn hrirs() -> Vec<Hrir> {
+        // Sound from each side reaches the near ear two frames earlier and twice as loud
+        let near = vec![0.0, 1.0, 0.5, 0.25, 0.0, 0.0];
+        let far = vec![0.0, 0.0, 0.0, 0.5, 0.25, 0.125];
+        vec![
+            Hrir { azimuth: 0.0, elevation: 0.0, left: near.clone(), right: near.clone() },
+            Hrir { azimuth: 90.0, elevation: 0.0, left: far.clone(), right: near.clone() },
+            Hrir { azimuth: 180.0, elevation: 0.0, left: near.clone(), right: near.clone() },
+            Hrir { azimuth: -90.0, elevation: 0.0, left: near, right: far },
+        ]
+    }
+
+    fn to_bytes(hrirs: &[Hrir], sample_rate: u32) -> Vec<u8> {
+        let mut data = b"HRIR".to_vec();
+        for value in [1, sample_rate, hrirs.len() as u32, hrirs[0].left.len() as u32] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        for hrir in hrirs {
+            let angles = [hrir.azimuth, hrir.elevation];
+            let values = angles.iter().chain(&hrir.left).chain(&hrir.right);
+            values.for_each(|x| data.extend_from_slice(&x.to_le_bytes()));
+        }
+        data
+    }
+
+    fn assert_same(a: &Hrtf, b: &Hrtf) {
+        assert_eq!(a.sample_rate(), b.sample_rate());
+        assert_eq!(a.directions(), b.directions());
+        assert_eq!(a.length(), b.length());
+        for (a, b) in a.0.points.iter().zip(b.0.points.iter()) {
+            assert_eq!(a.direction, b.direction);
+            assert_eq!(a.delays, b.delays);
+            assert_eq!(a.filters, b.filters);
+        }
+    }
+
+    // Returns the onset and energy of each ear's impulse response for a direction
+    fn ears(hrtf: &Hrtf, azimuth: f32) -> [(usize, f32); 2] {
+        let mut responses = [0, 1].map(|_| vec![0.0; hrtf.impulse_length()].into_boxed_slice());
+        hrtf.impulses(direction(azimuth, 0.0), &mut responses);
+        responses.map(|x| (onset(&x), x.iter().map(|x| x * x).sum()))
+    }
+
+    #[test]
+    fn round_trip() {
+        let hrtf = Hrtf::from_bytes(&to_bytes(&hrirs(), 48000), rate(48000)).unwrap();
+        assert_same(&hrtf, &Hrtf::new(hrirs(), rate(48000), rate(48000)));
+        assert_eq!(hrtf.directions(), 4);
+        assert_eq!(hrtf.length(), 6, "only each ear's delay past the common lead should be taken out");
+
+        let resampled = Hrtf::from_bytes(&to_bytes(&hrirs(), 44100), rate(48000)).unwrap();
+        assert_same(&resampled, &Hrtf::new(hrirs(), rate(44100), rate(48000)));
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let valid = to_bytes(&hrirs(), 48000);
+        let with = |offset: usize, value: u32| {
+            let mut data = valid.clone();
+            data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            Hrtf::from_bytes(&data, rate(48000))
+        };
+
+        assert!(matches!(Hrtf::from_bytes(b"RIFF", rate(48000)), Err(Error::InvalidFile)));
+        assert!(matches!(Hrtf::from_bytes(&valid[..12], rate(48000)), Err(Error::InvalidFile)));
+        assert!(matches!(with(4, 2), Err(Error::UnknownVersion)));
+        assert!(matches!(with(8, 0), Err(Error::MalformedData)));
+        assert!(matches!(with(12, 0), Err(Error::MalformedData)));
+        assert!(matches!(with(12, 5), Err(Error::MalformedData)));
+        assert!(matches!(with(16, 0), Err(Error::MalformedData)));
+        assert!(matches!(with(16, 7), Err(Error::MalformedData)));
+        assert!(matches!(Hrtf::from_bytes(&valid[..valid.len() - 4], rate(48000)), Err(Error::MalformedData)));
+    }
+
+    #[test]
+    fn interaural_differences() {
+        for hrtf in [Hrtf::spherical_head(rate(48000)), Hrtf::new(hrirs(), rate(48000), rate(48000))] {
+            // Straight ahead, both ears hear the same thing
+            let [left, right] = ears(&hrtf, 0.0);
+            assert_eq!(left.0, right.0);
+            assert!((left.1 - right.1).abs() < 1e-4 * left.1);
+
+            // Azimuth is clockwise, so 90 degrees is to the right
+            let [left, right] = ears(&hrtf, 90.0);
+            assert!(right.0 < left.0, "right ear should hear it first, but onsets were {} and {}", left.0, right.0);
+            assert!(right.1 > left.1 * 1.5, "right ear should be louder, but energies were {} and {}", left.1, right.1);
+
+            let [left, right] = ears(&hrtf, -90.0);
+            assert!(left.0 < right.0, "left ear should hear it first, but onsets were {} and {}", left.0, right.0);
+            assert!(left.1 > right.1 * 1.5, "left ear should be louder, but energies were {} and {}", left.1, right.1);
+        }
+    }
+
+    #[test]
+    fn spherical_head_delay() {
+        // Woodworth's formula gives an interaural time difference of (r / c)(1 + pi / 2) at 90 degrees
+        let hrtf = Hrtf::spherical_head(rate(48000));
+        let right = direction(90.0, 0.0);
+        let point = hrtf.0.points.iter().find(|x| x.direction.dot(right) > SAME_DIRECTION).unwrap();
+        let [left, right] = point.delays;
+        let expected = HEAD_RADIUS / SPEED_OF_SOUND * (1.0 + FRAC_PI_2) * 48000.0;
+        assert!(((left - right) - expected).abs() < 1e-3, "ITD was {} frames, not {}", left - right, expected);
+    }
+
+    #[test]
+    fn spherical_head_at_low_rates() {
+        for hz in [1, 100, 333, 334, 1000] {
+            let hrtf = Hrtf::spherical_head(rate(hz));
+            assert!(hrtf.length() >= 2, "{} Hz gave {} taps", hz, hrtf.length());
+        }
+    }
+
+    #[test]
+    fn emitter_follows_a_moving_source() {
+        // A 500 Hz sine, moved in steps from the right to the left, through directions between the measured ones
+        let listener = Listener::new();
+        let samples = (0..48000).map(|i| (2.0 * PI * 500.0 * i as f32 / 48000.0).sin() * 0.5).collect::<Box<[_]>>();
+        let player = Player::new(ChannelCount::new(1).unwrap(), rate(48000), samples);
+        let mut emitter = Emitter::with_hrtf(player, &listener, &Hrtf::spherical_head(rate(48000)));
+        let handle = emitter.handle();
+
+        let mut output = Vec::new();
+        let mut differences = Vec::new();
+        for &azimuth in &[90.0f32, 75.0, 45.0, 15.0, 0.0, -15.0, -45.0, -75.0, -90.0] {
+            handle.set_position(direction(azimuth, 0.0));
+            let start = output.len();
+            // Odd buffers, so frames get split between them as well
+            while output.len() < start + 2400 * 2 {
+                let mut buffer = [0.0; 301];
+                assert_eq!(emitter.write_samples(&mut buffer), buffer.len());
+                output.extend_from_slice(&buffer);
+            }
+            // Once the crossfade's done, measure the level difference between the ears in dB
+            let settled = &output[output.len() - 960 * 2..];
+            let energy = |ear: usize| settled.iter().skip(ear).step_by(2).map(|x| x * x).sum::<f32>();
+            differences.push(10.0 * (energy(1) / energy(0)).log10());
+        }
+
+        // The right ear is louder to begin with, the ears match straight ahead, and the left is louder at the end,
+        // moving steadily from one to the other
+        assert!(differences[0] > 1.0 && differences[8] < -1.0, "level differences were {:?}", differences);
+        assert!(differences[4].abs() < 0.01, "level differences were {:?}", differences);
+        assert!(differences.windows(2).all(|x| x[1] < x[0]), "level differences were {:?}", differences);
+
+        // The crossfades and delay changes don't click, which would show up as a jump in the sine's curvature.
+        // The very start is skipped, since the sine starting from nothing has a corner of its own.
+        let peak = output.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+        for ear in 0..2 {
+            let samples = output.iter().skip(ear).step_by(2).copied().collect::<Vec<_>>();
+            let worst = samples[100..].windows(3).map(|x| (x[0] - 2.0 * x[1] + x[2]).abs()).fold(0.0f32, f32::max);
+            assert!(worst < peak * 0.01, "ear {} jumped by {} with a peak of {}", ear, worst, peak);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use super::{AtomicVec3, Vec3};
+use crate::param::AtomicF32;
+use std::sync::Arc;
+
+// In metres per second, through air at 20 degrees C
+const DEFAULT_SPEED_OF_SOUND: f32 = 343.3;
+
+/// The point a scene is heard from, which any number of [`Emitter`](super::Emitter)s are placed relative to.
+///
+/// Cloning a Listener gives another handle to the same one, so it can be moved from any thread while its Emitters are
+/// playing.
+#[derive(Clone)]
+pub struct Listener(Arc<ListenerParams>);
+
+pub(super) struct ListenerParams {
+    position: AtomicVec3,
+    forward: AtomicVec3,
+    up: AtomicVec3,
+    velocity: AtomicVec3,
+    speed_of_sound: AtomicF32,
+    doppler_factor: AtomicF32,
+}
+
+// A snapshot of a Listener's position and orientation, for converting world positions into its point of view
+pub(super) struct ListenerFrame {
+    pub(super) position: Vec3,
+    pub(super) velocity: Vec3,
+    pub(super) speed_of_sound: f32,
+    pub(super) doppler_factor: f32,
+    right: Vec3,
+    up: Vec3,
+    forward: Vec3,
+}
+
+impl Listener {
+    /// Creates a new Listener at the origin, facing towards -Z with +Y up.
+    /// The speed of sound defaults to 343.3, which is right for distances in metres.
+    pub fn new() -> Self {
+        Self(Arc::new(ListenerParams {
+            position: AtomicVec3::new(Vec3::ZERO),
+            forward: AtomicVec3::new(Vec3::new(0.0, 0.0, -1.0)),
+            up: AtomicVec3::new(Vec3::new(0.0, 1.0, 0.0)),
+            velocity: AtomicVec3::new(Vec3::ZERO),
+            speed_of_sound: AtomicF32::new(DEFAULT_SPEED_OF_SOUND),
+            doppler_factor: AtomicF32::new(1.0),
+        }))
+    }
+
+    /// Sets the position of the Listener.
+    pub fn set_position(&self, position: Vec3) {
+        self.0.position.store(position)
+    }
+
+    /// Sets which way the Listener is facing, and which way is up from its point of view.
+    /// Neither needs to be normalized, and `up` doesn't need to be exactly at right angles to `forward`.
+    pub fn set_orientation(&self, forward: Vec3, up: Vec3) {
+        self.0.forward.store(forward);
+        self.0.up.store(up);
+    }
+
+    /// Sets the velocity of the Listener, in distance units per second. This is only used for the Doppler effect,
+    /// and doesn't move the Listener by itself.
+    pub fn set_velocity(&self, velocity: Vec3) {
+        self.0.velocity.store(velocity)
+    }
+
+    /// Sets the speed of sound, in distance units per second.
+    pub fn set_speed_of_sound(&self, speed: f32) {
+        self.0.speed_of_sound.store(speed)
+    }
+
+    /// Sets how strong the Doppler effect is, where 1.0 is realistic and 0.0 turns it off.
+    pub fn set_doppler_factor(&self, factor: f32) {
+        self.0.doppler_factor.store(factor)
+    }
+
+    /// Returns the most recently set position.
+    pub fn position(&self) -> Vec3 {
+        self.0.position.load()
+    }
+
+    /// Returns the most recently set forward direction.
+    pub fn forward(&self) -> Vec3 {
+        self.0.forward.load()
+    }
+
+    /// Returns the most recently set up direction.
+    pub fn up(&self) -> Vec3 {
+        self.0.up.load()
+    }
+
+    /// Returns the most recently set velocity.
+    pub fn velocity(&self) -> Vec3 {
+        self.0.velocity.load()
+    }
+
+    /// Returns the most recently set speed of sound.
+    pub fn speed_of_sound(&self) -> f32 {
+        self.0.speed_of_sound.load()
+    }
+
+    /// Returns the most recently set Doppler factor.
+    pub fn doppler_factor(&self) -> f32 {
+        self.0.doppler_factor.load()
+    }
+
+    pub(super) fn params(&self) -> Arc<ListenerParams> {
+        self.0.clone()
+    }
+}
+
+impl Default for Listener {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ListenerParams {
+    pub(super) fn frame(&self) -> ListenerFrame {
+        let forward = self.forward.load().normalize();
+        let right = forward.cross(self.up.load()).normalize();
+        ListenerFrame {
+            position: self.position.load(),
+            velocity: self.velocity.load(),
+            speed_of_sound: self.speed_of_sound.load(),
+            doppler_factor: self.doppler_factor.load(),
+            up: right.cross(forward),
+            right,
+            forward,
+        }
+    }
+}
+
+impl ListenerFrame {
+    /// Converts a direction in the world into the Listener's point of view, where +X is to its right, +Y is above it
+    /// and -Z is in front of it.
+    #[inline]
+    pub(super) fn to_local(&self, direction: Vec3) -> Vec3 {
+        Vec3::new(direction.dot(self.right), direction.dot(self.up), -direction.dot(self.forward))
+    }
+}
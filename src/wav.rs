@@ -1,4 +1,4 @@
-use crate::source::{ChannelCount, Sample, SampleRate, Source};
+use crate::source::{ChannelCount, ChannelId, ChannelLayout, LoopPoints, Sample, SampleRate, Seekable, Source};
 use std::sync::Arc;
 
 /// A Source object for decoding and playing samples from a .wav file.
@@ -13,12 +13,14 @@ use std::sync::Arc;
 pub struct WavPlayer {
     file: Arc<[u8]>,
     channels: ChannelCount,
+    channel_mask: u32,
     sample_rate: SampleRate,
     sample_bytes: usize,
     data_start: usize,
     next_sample_offset: usize,
     format: Format,
     length: usize,
+    loop_points: Option<LoopPoints>,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -63,7 +65,8 @@ impl WavPlayer {
                 if is_data_chunk {
                     break data_len
                 } else {
-                    data_start += data_len;
+                    // Chunks are padded to an even length, but the pad byte isn't counted in their length
+                    data_start += data_len + (data_len & 1);
                 }
             };
             Some((data_start, data_len))
@@ -85,6 +88,10 @@ impl WavPlayer {
         let sample_rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]);
         let sample_bits = u16::from_le_bytes([fmt[14], fmt[15]]);
         let ext_guid = fmt.get(24..40);
+        let channel_mask = match (audio_format, fmt.get(20..24)) {
+            (-2, Some(mask)) => u32::from_le_bytes([mask[0], mask[1], mask[2], mask[3]]),
+            _ => 0,
+        };
 
         let data_format_pcm = &[1, 0, 0, 0, 0, 0, 16, 0, 128, 0, 0, 170, 0, 56, 155, 113];
         let data_format_float = &[3, 0, 0, 0, 0, 0, 16, 0, 128, 0, 0, 170, 0, 56, 155, 113];
@@ -107,6 +114,22 @@ impl WavPlayer {
 
         let (data_start, data_len) = find_section(&file, b"data").ok_or(Error::InvalidFile)?;
 
+        // The smpl chunk usually comes after the audio data, so it has to be read before the file is truncated.
+        // Only the first loop is used, and it's ignored unless it plays forwards and fits inside the audio data.
+        let frames = data_len / usize::from(sample_bits / 8).max(1) / usize::from(channels.get());
+        let loop_points = find_section(&file, b"smpl")
+            .and_then(|(start, len)| file.get(start..(start + len)))
+            .filter(|smpl| smpl.len() >= 60 && smpl[28..32] != [0, 0, 0, 0] && smpl[40..44] == [0, 0, 0, 0])
+            .map(|smpl| {
+                let read = |offset: usize| {
+                    u32::from_le_bytes([smpl[offset], smpl[offset + 1], smpl[offset + 2], smpl[offset + 3]])
+                };
+                // The end is inclusive, and a play count of 0 means forever
+                let (start, end, count) = (read(44) as usize, read(48) as usize + 1, read(56));
+                LoopPoints { start, end, count: if count == 0 { None } else { Some(count) } }
+            })
+            .filter(|points| points.start < points.end && points.end <= frames);
+
         let expected_file_length = data_len + data_start;
         if expected_file_length > file.len() {
             return Err(Error::MalformedData)
@@ -119,12 +142,14 @@ impl WavPlayer {
         Ok(Self {
             file: file.into(),
             channels,
+            channel_mask,
             sample_rate,
             sample_bytes,
             data_start,
             next_sample_offset: data_start,
             format,
             length: data_len / sample_bytes,
+            loop_points,
         })
     }
 
@@ -145,6 +170,39 @@ impl Source for WavPlayer {
         self.sample_rate
     }
 
+    fn channel_layout(&self) -> Option<ChannelLayout> {
+        // Speaker positions in the order of their bits in a WAVE_FORMAT_EXTENSIBLE channel mask.
+        // Channels are stored in the same order as their bits.
+        const SPEAKERS: [ChannelId; 18] = [
+            ChannelId::FrontLeft,
+            ChannelId::FrontRight,
+            ChannelId::FrontCenter,
+            ChannelId::Lfe,
+            ChannelId::BackLeft,
+            ChannelId::BackRight,
+            ChannelId::FrontLeftCenter,
+            ChannelId::FrontRightCenter,
+            ChannelId::BackCenter,
+            ChannelId::SideLeft,
+            ChannelId::SideRight,
+            ChannelId::TopCenter,
+            ChannelId::TopFrontLeft,
+            ChannelId::TopFrontCenter,
+            ChannelId::TopFrontRight,
+            ChannelId::TopBackLeft,
+            ChannelId::TopBackCenter,
+            ChannelId::TopBackRight,
+        ];
+
+        let channels = SPEAKERS
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.channel_mask & (1 << i) != 0)
+            .map(|(_, &id)| id)
+            .collect::<Vec<_>>();
+        if channels.len() == usize::from(self.channels.get()) { ChannelLayout::new(channels) } else { None }
+    }
+
     fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
         use std::convert::TryInto;
 
@@ -196,6 +254,28 @@ impl Source for WavPlayer {
     }
 }
 
+impl Seekable for WavPlayer {
+    #[inline]
+    fn frame_count(&self) -> usize {
+        self.length / usize::from(self.channels.get())
+    }
+
+    #[inline]
+    fn position(&self) -> usize {
+        (self.next_sample_offset - self.data_start) / self.sample_bytes / usize::from(self.channels.get())
+    }
+
+    fn seek(&mut self, frame: usize) {
+        let sample = frame.min(self.frame_count()) * usize::from(self.channels.get());
+        self.next_sample_offset = self.data_start + sample * self.sample_bytes;
+    }
+
+    #[inline]
+    fn loop_points(&self) -> Option<LoopPoints> {
+        self.loop_points
+    }
+}
+
 #[inline(always)]
 fn get_sample_u8(data: u8) -> f32 {
     let sample = i16::from(data) - 0x80;
@@ -226,3 +306,87 @@ fn get_sample_i32(data: &[u8; 4]) -> f32 {
 fn get_sample_f32(data: &[u8; 4]) -> f32 {
     f32::from_le_bytes(*data)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(name: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = name.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    // A smpl chunk with one loop, where `end` is inclusive as in the file
+    fn smpl(start: u32, end: u32, loop_type: u32, count: u32) -> Vec<u8> {
+        let mut data = vec![0; 36];
+        data[28..32].copy_from_slice(&1u32.to_le_bytes());
+        for value in &[0, loop_type, start, end, 0, count] {
+            data.extend_from_slice(&u32::to_le_bytes(*value));
+        }
+        chunk(b"smpl", &data)
+    }
+
+    // A mono 16-bit file with `frames` frames counting up from 0, with an odd-length chunk before the audio data
+    fn wav(frames: i16, extra: &[u8]) -> Vec<u8> {
+        let mut fmt = Vec::new();
+        fmt.extend_from_slice(&1u16.to_le_bytes());
+        fmt.extend_from_slice(&1u16.to_le_bytes());
+        fmt.extend_from_slice(&44100u32.to_le_bytes());
+        fmt.extend_from_slice(&88200u32.to_le_bytes());
+        fmt.extend_from_slice(&2u16.to_le_bytes());
+        fmt.extend_from_slice(&16u16.to_le_bytes());
+        let data = (0..frames).flat_map(|x| (x * 1024).to_le_bytes()).collect::<Vec<_>>();
+
+        let mut body = b"WAVE".to_vec();
+        body.extend(chunk(b"fmt ", &fmt));
+        body.extend(chunk(b"junk", &[1, 2, 3]));
+        body.extend(chunk(b"data", &data));
+        body.extend_from_slice(extra);
+
+        let mut file = b"RIFF".to_vec();
+        file.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        file.extend(body);
+        file
+    }
+
+    #[test]
+    fn skips_pad_bytes() {
+        let mut player = WavPlayer::new(wav(4, &[])).unwrap();
+        assert_eq!(player.frame_count(), 4);
+        let mut buffer = [0.0; 4];
+        assert_eq!(player.write_samples(&mut buffer), 4);
+        for (i, sample) in buffer.iter().enumerate() {
+            assert!((sample - i as f32 / 32.0).abs() < 1e-4, "sample {} was {}", i, sample);
+        }
+    }
+
+    #[test]
+    fn reads_loop_points() {
+        let player = WavPlayer::new(wav(8, &smpl(2, 5, 0, 3))).unwrap();
+        assert_eq!(player.loop_points(), Some(LoopPoints { start: 2, end: 6, count: Some(3) }));
+
+        let player = WavPlayer::new(wav(8, &smpl(0, 7, 0, 0))).unwrap();
+        assert_eq!(player.loop_points(), Some(LoopPoints { start: 0, end: 8, count: None }));
+    }
+
+    #[test]
+    fn ignores_unusable_loops() {
+        let player = WavPlayer::new(wav(8, &[])).unwrap();
+        assert_eq!(player.loop_points(), None);
+
+        // Ping-pong and backwards loops
+        for &loop_type in &[1, 2] {
+            let player = WavPlayer::new(wav(8, &smpl(2, 5, loop_type, 0))).unwrap();
+            assert_eq!(player.loop_points(), None, "loop type {} should be ignored", loop_type);
+        }
+
+        // Past the end of the audio data
+        let player = WavPlayer::new(wav(8, &smpl(2, 8, 0, 0))).unwrap();
+        assert_eq!(player.loop_points(), None);
+    }
+}